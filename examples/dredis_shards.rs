@@ -0,0 +1,92 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use concurrency::{execute, Backend, Session, ShardedBackend};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::task::JoinSet;
+
+const CLIENTS: u64 = 64;
+const OPS_PER_CLIENT: usize = 20_000;
+const KEYS: usize = 100_000;
+
+/// Compare the keyspace in one map shared by every connection with the
+/// keyspace split among worker threads: the same clients run the same mix
+/// of GET, SET and MGET against both. The number of shards is the first
+/// argument, a shard per core by default.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let shards = match std::env::args().nth(1) {
+        Some(shards) => shards.parse()?,
+        None => thread::available_parallelism()?.get(),
+    };
+    println!(
+        "{} clients x {} commands over {} keys",
+        CLIENTS, OPS_PER_CLIENT, KEYS
+    );
+
+    let single = single_map(Backend::new()).await?;
+    report("single map", single);
+    let sharded = sharded(ShardedBackend::with_shards(shards)).await?;
+    report(&format!("{} shards", shards), sharded);
+    println!(
+        "sharded / single map: {:.2}",
+        single.as_secs_f64() / sharded.as_secs_f64()
+    );
+    Ok(())
+}
+
+async fn single_map(backend: Backend) -> Result<Duration> {
+    let start = Instant::now();
+    let mut clients = JoinSet::new();
+    for client in 0..CLIENTS {
+        let mut session = Session::new(backend.clone());
+        clients.spawn(async move {
+            let mut rng = StdRng::seed_from_u64(client);
+            for _ in 0..OPS_PER_CLIENT {
+                execute(&mut session, &command(&mut rng));
+            }
+        });
+    }
+    while let Some(res) = clients.join_next().await {
+        res?;
+    }
+    Ok(start.elapsed())
+}
+
+async fn sharded(shards: ShardedBackend) -> Result<Duration> {
+    let start = Instant::now();
+    let mut clients = JoinSet::new();
+    for client in 0..CLIENTS {
+        let mut session = shards.connect();
+        clients.spawn(async move {
+            let mut rng = StdRng::seed_from_u64(client);
+            for _ in 0..OPS_PER_CLIENT {
+                session.execute(&command(&mut rng)).await;
+            }
+        });
+    }
+    while let Some(res) = clients.join_next().await {
+        res?;
+    }
+    Ok(start.elapsed())
+}
+
+/// Half GET, 40% SET and 10% MGET of 4 keys.
+fn command(rng: &mut StdRng) -> Vec<Bytes> {
+    let kind = rng.gen_range(0..10);
+    let mut key = || Bytes::from(format!("key:{}", rng.gen_range(0..KEYS)));
+    match kind {
+        0..=4 => vec!["GET".into(), key()],
+        5..=8 => vec!["SET".into(), key(), Bytes::from_static(b"value")],
+        _ => vec!["MGET".into(), key(), key(), key(), key()],
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let ops = (CLIENTS as usize * OPS_PER_CLIENT) as f64 / elapsed.as_secs_f64();
+    println!("{:>12}: {:>10.2?} {:>12.0} ops/s", name, elapsed, ops);
+}
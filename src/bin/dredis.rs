@@ -0,0 +1,306 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use concurrency::{
+    load_shards, parse_config, parse_replicaof, run_server_with_tls, run_sharded_server,
+    shutdown_signal, AppendFsync, Backend, ClusterNode, ClusterTopology, ShardedBackend, TlsConfig,
+    TlsListener,
+};
+use tokio::{net::TcpListener, task::JoinSet};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str = "\
+Usage: dredis [/path/to/redis.conf] [options]
+       dredis -v or --version
+       dredis -h or --help
+
+Options are config file directives prefixed by '--' and come after the
+config file, e.g. dredis /etc/redis/6380.conf --port 7777 --loglevel debug";
+
+/// Config directives as name and value.
+type Directives = Vec<(String, String)>;
+
+/// The config file, if any, and the directives in the order they apply:
+/// the file first, then the command line.
+fn parse_args(args: &[String]) -> Result<(Option<PathBuf>, Directives)> {
+    let mut args = args.iter().peekable();
+    let file = args
+        .next_if(|arg| !arg.starts_with("--"))
+        .map(PathBuf::from);
+    let mut directives = match &file {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("reading config file {}", path.display()))?;
+            parse_config(&text)?
+        }
+        None => Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let name = arg
+            .strip_prefix("--")
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("unexpected argument '{}'\n\n{}", arg, USAGE))?;
+        let mut values = Vec::new();
+        while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+            values.push(value.as_str());
+        }
+        if values.is_empty() {
+            return Err(anyhow!("missing value for option '--{}'", name));
+        }
+        directives.push((name.to_ascii_lowercase(), values.join(" ")));
+    }
+    Ok((file, directives))
+}
+
+/// The tracing filter for redis' `loglevel`, `RUST_LOG` wins when set.
+fn log_filter(loglevel: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match loglevel {
+            "debug" | "verbose" => "debug",
+            "warning" => "warn",
+            "nothing" => "off",
+            _ => "info",
+        })
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Some("-v" | "--version") => {
+            println!("dredis v={}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        _ => {}
+    }
+    let (file, directives) = parse_args(&args)?;
+
+    let backend = Backend::new();
+    backend.apply_config(&directives)?;
+    let config = |name: &str| backend.config_value(name).unwrap_or_default();
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter(&config("loglevel")))
+        .init();
+
+    if let Some(file) = file {
+        // CONFIG REWRITE must find the file after the chdir below
+        backend.set_config_file(fs::canonicalize(&file)?);
+        info!("configuration loaded from {}", file.display());
+    }
+    let dir = config("dir");
+    std::env::set_current_dir(&dir).with_context(|| format!("can't chdir to '{}'", dir))?;
+
+    let shards = config("shards").parse::<usize>()?;
+    let cluster = config("cluster-enabled").eq_ignore_ascii_case("yes");
+    let replica = parse_replicaof(&config("replicaof"))?.is_some();
+    if cluster && replica {
+        bail!("replicaof directive not allowed in cluster mode");
+    }
+    if shards > 0 {
+        if cluster {
+            bail!("shards can't be used with cluster-enabled");
+        }
+        // each shard would need its own AOF and replication stream
+        if config("appendonly").eq_ignore_ascii_case("yes") {
+            bail!("shards can't be used with appendonly");
+        }
+        if replica {
+            bail!("shards can't be used with replicaof");
+        }
+        return serve_sharded(backend, &directives, shards).await;
+    }
+    if !cluster {
+        let port = config("port").parse()?;
+        return serve(backend, port, None).await;
+    }
+    let ports = match config("cluster-ports").as_str() {
+        "" => vec![config("port").parse::<u16>()?],
+        ports => ports
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()?,
+    };
+    if ports.len() > 1 && config("tls-port") != "0" {
+        bail!("tls-port is not supported with several cluster-ports");
+    }
+    let ip = config("cluster-announce-ip");
+    let nodes = ports.iter().map(|&port| ClusterNode::new(&ip, port));
+    let topology = Arc::new(ClusterTopology::new(nodes.collect()));
+    let mut servers = JoinSet::new();
+    for (myself, &port) in ports.iter().enumerate() {
+        let backend = match myself {
+            0 => backend.clone(),
+            _ => {
+                let node = Backend::new();
+                node.apply_config(&directives)?;
+                node
+            }
+        };
+        let node = ClusterRole {
+            topology: topology.clone(),
+            myself,
+            shared: ports.len() > 1,
+        };
+        servers.spawn(serve(backend, port, Some(node)));
+    }
+    while let Some(res) = servers.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+/// Serve a keyspace split into `shards` shards, each configured by the
+/// directives, owned by a worker thread and saved to its own RDB file.
+async fn serve_sharded(backend: Backend, directives: &Directives, shards: usize) -> Result<()> {
+    let config = |name: &str| backend.config_value(name).unwrap_or_default();
+    let addr = format!("{}:{}", config("bind"), config("port"));
+    let listener = TcpListener::bind(&addr).await?;
+    info!("redis server address: {}", addr);
+    let databases = config("databases").parse()?;
+    let mut backends = vec![backend.clone()];
+    for _ in 1..shards {
+        let shard = Backend::new();
+        shard.apply_config(directives)?;
+        backends.push(shard);
+    }
+    let dbfilename = config("dbfilename");
+    for (i, shard) in backends.iter().enumerate() {
+        shard.set_databases(databases);
+        shard.set_rdb_path(shard_file(&dbfilename, i));
+        shard.set_save_on_shutdown(true);
+    }
+    // the keys of a shard this server doesn't have would be lost
+    let extra = shard_file(&dbfilename, shards);
+    if Path::new(&extra).exists() {
+        bail!("{} was saved with more than {} shards", extra, shards);
+    }
+    let loaded = load_shards(&backends)?;
+    info!("DB loaded from disk: {} keys in {} shards", loaded, shards);
+    run_sharded_server(listener, ShardedBackend::new(backends), shutdown_signal()).await
+}
+
+/// The part of a cluster a server plays.
+struct ClusterRole {
+    topology: Arc<ClusterTopology>,
+    myself: usize,
+    /// Whether other nodes run in this process, and so in its directory.
+    shared: bool,
+}
+
+/// `name` with `node` before its extension, for the files of cluster nodes
+/// sharing a directory: dump.rdb becomes dump-7000.rdb for port 7000.
+fn node_file(name: &str, node: impl Display) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}-{}.{}", stem, node, ext),
+        _ => format!("{}-{}", name, node),
+    }
+}
+
+/// The file of shard `shard`: the first has `name` itself, so it has the
+/// keys of a server without shards, the second dump-shard1.rdb.
+fn shard_file(name: &str, shard: usize) -> String {
+    match shard {
+        0 => name.to_string(),
+        _ => node_file(name, format!("shard{}", shard)),
+    }
+}
+
+/// Serve `backend` on `port` until shutdown: listen, load the keyspace and
+/// run the connections, as the node `cluster` when given.
+async fn serve(backend: Backend, port: u16, cluster: Option<ClusterRole>) -> Result<()> {
+    let config = |name: &str| backend.config_value(name).unwrap_or_default();
+    let addr = format!("{}:{}", config("bind"), port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("redis server address: {}", addr);
+    let tls = match config("tls-port").as_str() {
+        "0" => None,
+        port => {
+            let mut tls_config = TlsConfig::new(config("tls-cert-file"), config("tls-key-file"));
+            let ca = config("tls-ca-cert-file");
+            match config("tls-auth-clients").as_str() {
+                _ if ca.is_empty() => {}
+                "no" => {}
+                auth => tls_config = tls_config.with_client_ca(ca, auth == "yes"),
+            }
+            let addr = format!("{}:{}", config("bind"), port);
+            let tls = TlsListener::new(TcpListener::bind(&addr).await?, &tls_config)?;
+            info!("redis TLS server address: {}", addr);
+            Some(tls)
+        }
+    };
+    backend.set_listening_port(listener.local_addr()?.port());
+    backend.set_databases(config("databases").parse()?);
+    let file = |name: String| match &cluster {
+        Some(node) if node.shared => node_file(&name, port),
+        _ => name,
+    };
+    backend.set_rdb_path(file(config("dbfilename")));
+
+    // like redis the AOF, when enabled, wins over the RDB file
+    if config("appendonly").eq_ignore_ascii_case("yes") {
+        let fsync = config("appendfsync").parse::<AppendFsync>()?;
+        let replayed = backend.start_aof(file(config("appendfilename")), fsync)?;
+        info!("DB loaded from append only file: {} commands", replayed);
+    } else {
+        let sharded = shard_file(&config("dbfilename"), 1);
+        if cluster.is_none() && Path::new(&sharded).exists() {
+            bail!("{} was saved with shards, start with --shards", sharded);
+        }
+        let loaded = backend.load_rdb()?;
+        info!("DB loaded from disk: {} keys", loaded);
+        backend.set_save_on_shutdown(true);
+    }
+    if let Some(master) = parse_replicaof(&config("replicaof"))? {
+        backend.replicaof(Some(master));
+    }
+    if let Some(node) = cluster {
+        let slots = node.topology.slot_ranges();
+        let served = slots.iter().filter(|r| r.2 == node.myself);
+        let count = served.map(|r| (r.1 - r.0) as usize + 1).sum::<usize>();
+        info!("cluster node {} serving {} slots", port, count);
+        backend.enable_cluster(node.topology, node.myself);
+    }
+    let expire = backend.spawn_active_expire();
+
+    // SIGINT, SIGTERM or SHUTDOWN stop the server
+    run_server_with_tls(listener, tls, backend, shutdown_signal()).await?;
+    expire.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replicaof_option() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("dredis-{}-replica.conf", std::process::id()));
+        fs::write(&path, "replicaof 127.0.0.1 6380\n")?;
+        let args = [path.to_str().unwrap(), "--replicaof", "10.0.0.1", "7000"]
+            .map(String::from)
+            .to_vec();
+        let (_, directives) = parse_args(&args)?;
+        let backend = Backend::new();
+        backend.apply_config(&directives)?;
+        // the command line wins over the file
+        assert_eq!(
+            parse_replicaof(&backend.config_value("replicaof").unwrap_or_default())?,
+            Some(("10.0.0.1".to_string(), 7000))
+        );
+        let args = ["--replicaof", "10.0.0.1"].map(String::from).to_vec();
+        assert!(backend.apply_config(&parse_args(&args)?.1).is_err());
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
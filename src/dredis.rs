@@ -1,4 +0,0 @@
-#[allow(dead_code)]
-fn dummy() {
-    println!("dummy")
-}
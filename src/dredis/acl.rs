@@ -0,0 +1,463 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::{all_commands, glob_match, lookup_command, Backend, ClientFilter, CommandSpec};
+
+/// The user clients are logged in as until they `AUTH`.
+pub const DEFAULT_USER: &str = "default";
+
+/// The ACL categories of commands, a bit each.
+pub mod category {
+    pub const KEYSPACE: u32 = 1 << 0;
+    pub const READ: u32 = 1 << 1;
+    pub const WRITE: u32 = 1 << 2;
+    pub const STRING: u32 = 1 << 3;
+    pub const HASH: u32 = 1 << 4;
+    pub const LIST: u32 = 1 << 5;
+    pub const SET: u32 = 1 << 6;
+    pub const SORTEDSET: u32 = 1 << 7;
+    pub const PUBSUB: u32 = 1 << 8;
+    pub const TRANSACTION: u32 = 1 << 9;
+    pub const CONNECTION: u32 = 1 << 10;
+    pub const ADMIN: u32 = 1 << 11;
+    pub const DANGEROUS: u32 = 1 << 12;
+    pub const BLOCKING: u32 = 1 << 13;
+    pub const SCRIPTING: u32 = 1 << 14;
+    pub const STREAM: u32 = 1 << 15;
+
+    /// The names `+@<category>` rules and `ACL CAT` use.
+    pub const NAMES: &[(&str, u32)] = &[
+        ("keyspace", KEYSPACE),
+        ("read", READ),
+        ("write", WRITE),
+        ("string", STRING),
+        ("hash", HASH),
+        ("list", LIST),
+        ("set", SET),
+        ("sortedset", SORTEDSET),
+        ("pubsub", PUBSUB),
+        ("transaction", TRANSACTION),
+        ("connection", CONNECTION),
+        ("admin", ADMIN),
+        ("dangerous", DANGEROUS),
+        ("blocking", BLOCKING),
+        ("scripting", SCRIPTING),
+        ("stream", STREAM),
+    ];
+
+    pub fn lookup(name: &str) -> Option<u32> {
+        NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, bit)| *bit)
+    }
+}
+
+/// Commands anyone may run, logged in or not: the ones to log in with.
+const NO_AUTH_COMMANDS: &[&str] = &["auth", "hello", "quit"];
+
+/// An ACL user: whether it can log in and with which passwords, which
+/// commands it may run and on which keys.
+#[derive(Debug, Clone, Default)]
+struct User {
+    enabled: bool,
+    nopass: bool,
+    /// SHA-256 of the passwords, hex encoded like `ACL LIST` shows them.
+    passwords: BTreeSet<String>,
+    commands: BTreeSet<&'static str>,
+    /// The command rules as given since the last `allcommands` or
+    /// `nocommands`, for `ACL LIST`.
+    command_rules: Vec<String>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    /// The user everybody is logged in as when nothing is configured.
+    fn default_user() -> Self {
+        let mut user = User::default();
+        for rule in ["on", "nopass", "allkeys", "allcommands"] {
+            user.apply(rule).expect("valid rules");
+        }
+        user
+    }
+
+    fn apply(&mut self, rule: &str) -> Result<()> {
+        let lower = rule.to_ascii_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".into()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" | "+@all" => {
+                self.commands = all_commands().iter().map(|c| c.name).collect();
+                self.command_rules = vec!["+@all".into()];
+            }
+            "nocommands" | "-@all" => {
+                self.commands.clear();
+                self.command_rules.clear();
+            }
+            "reset" => *self = User::default(),
+            _ => {
+                self.apply_prefixed(rule)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The rules that start with a sigil and take the rest as argument.
+    fn apply_prefixed(&mut self, rule: &str) -> Result<()> {
+        let mut chars = rule.chars();
+        let (Some(sigil), arg) = (chars.next(), chars.as_str()) else {
+            bail!("Syntax error");
+        };
+        match sigil {
+            '>' => {
+                self.passwords.insert(hash_password(arg.as_bytes()));
+                self.nopass = false;
+            }
+            '<' => {
+                if !self.passwords.remove(&hash_password(arg.as_bytes())) {
+                    bail!("The password you are trying to remove from the user does not exist");
+                }
+            }
+            '#' => {
+                check_password_hash(arg)?;
+                self.passwords.insert(arg.into());
+                self.nopass = false;
+            }
+            '!' => {
+                check_password_hash(arg)?;
+                if !self.passwords.remove(arg) {
+                    bail!("The password you are trying to remove from the user does not exist");
+                }
+            }
+            '~' => {
+                if !self.key_patterns.iter().any(|p| p == "*" || p == arg) {
+                    self.key_patterns.push(arg.into());
+                }
+            }
+            '+' | '-' => {
+                let names = match arg.strip_prefix('@') {
+                    Some(name) => {
+                        let bit = category::lookup(name).ok_or_else(err_unknown_name)?;
+                        all_commands()
+                            .iter()
+                            .filter(|c| c.categories() & bit != 0)
+                            .map(|c| c.name)
+                            .collect()
+                    }
+                    None => {
+                        let cmd = lookup_command(arg.as_bytes()).ok_or_else(err_unknown_name)?;
+                        vec![cmd.name]
+                    }
+                };
+                for name in names {
+                    if sigil == '+' {
+                        self.commands.insert(name);
+                    } else {
+                        self.commands.remove(name);
+                    }
+                }
+                self.command_rules.push(rule.to_ascii_lowercase());
+            }
+            _ => bail!("Syntax error"),
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &[u8]) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+
+    /// The line of the user in `ACL LIST`, rules that recreate it.
+    fn describe(&self, name: &str) -> String {
+        let mut line = format!("user {} {}", name, if self.enabled { "on" } else { "off" });
+        if self.nopass {
+            line.push_str(" nopass");
+        }
+        for hash in &self.passwords {
+            let _ = write!(line, " #{}", hash);
+        }
+        if self.key_patterns.is_empty() {
+            line.push_str(" resetkeys");
+        }
+        for pattern in &self.key_patterns {
+            let _ = write!(line, " ~{}", pattern);
+        }
+        if self.command_rules.first().map(String::as_str) != Some("+@all") {
+            line.push_str(" -@all");
+        }
+        for rule in &self.command_rules {
+            let _ = write!(line, " {}", rule);
+        }
+        line
+    }
+}
+
+fn hash_password(password: &[u8]) -> String {
+    Sha256::digest(password)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+}
+
+fn check_password_hash(hash: &str) -> Result<()> {
+    if hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        Ok(())
+    } else {
+        bail!("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters")
+    }
+}
+
+fn err_unknown_name() -> anyhow::Error {
+    anyhow!("Unknown command or category name in ACL")
+}
+
+/// The ACL users by name.
+#[derive(Debug)]
+pub(crate) struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    /// The last `requirepass`, the password of the default user.
+    requirepass: Mutex<String>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            users: RwLock::new(BTreeMap::from([(
+                DEFAULT_USER.to_string(),
+                User::default_user(),
+            )])),
+            requirepass: Mutex::default(),
+        }
+    }
+}
+
+impl Backend {
+    /// Create the user `name` or change it with `rules`, all of them or none.
+    pub fn acl_setuser(&self, name: &str, rules: &[&str]) -> Result<()> {
+        let mut users = self.acl.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| anyhow!("ERR Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Remove users and close their connections, returns how many existed.
+    pub fn acl_deluser(&self, names: &[&str]) -> Result<usize> {
+        if names.contains(&DEFAULT_USER) {
+            bail!("ERR The 'default' user cannot be removed");
+        }
+        let removed = {
+            let mut users = self.acl.users.write().unwrap();
+            names
+                .iter()
+                .filter(|name| users.remove(**name).is_some())
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        for name in &removed {
+            self.kill_clients(&ClientFilter {
+                user: Some(name.to_string()),
+                ..Default::default()
+            });
+        }
+        Ok(removed.len())
+    }
+
+    /// The rules of every user, as `ACL LIST` shows them.
+    pub fn acl_list(&self) -> Vec<String> {
+        let users = self.acl.users.read().unwrap();
+        users
+            .iter()
+            .map(|(name, user)| user.describe(name))
+            .collect()
+    }
+
+    pub fn acl_users(&self) -> Vec<String> {
+        self.acl.users.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Require `password` to log in as the default user, or none when empty.
+    pub fn set_requirepass(&self, password: &str) {
+        let rules = match password {
+            "" => vec!["nopass".to_string()],
+            _ => vec!["resetpass".to_string(), format!(">{}", password)],
+        };
+        let rules = rules.iter().map(String::as_str).collect::<Vec<_>>();
+        self.acl_setuser(DEFAULT_USER, &rules).expect("valid rules");
+        *self.acl.requirepass.lock().unwrap() = password.to_string();
+    }
+
+    pub fn requirepass(&self) -> String {
+        self.acl.requirepass.lock().unwrap().clone()
+    }
+
+    /// Whether `user` exists, is enabled and has `password`.
+    pub fn authenticate(&self, user: &str, password: &[u8]) -> bool {
+        let users = self.acl.users.read().unwrap();
+        users
+            .get(user)
+            .is_some_and(|u| u.enabled && u.check_password(password))
+    }
+
+    /// Whether new connections are logged in as the default user without
+    /// `AUTH`.
+    pub(crate) fn default_user_nopass(&self) -> bool {
+        let users = self.acl.users.read().unwrap();
+        users
+            .get(DEFAULT_USER)
+            .is_some_and(|u| u.enabled && u.nopass)
+    }
+
+    /// Whether `user` may run `cmd` on the keys among `args`.
+    pub(crate) fn check_permission(
+        &self,
+        user: &str,
+        cmd: &CommandSpec,
+        args: &[Bytes],
+    ) -> Result<(), String> {
+        if is_no_auth_command(cmd) {
+            return Ok(());
+        }
+        let users = self.acl.users.read().unwrap();
+        let Some(u) = users.get(user).filter(|u| u.commands.contains(cmd.name)) else {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user, cmd.name
+            ));
+        };
+        let allowed = |key: &Bytes| u.key_patterns.iter().any(|p| glob_match(p.as_bytes(), key));
+        if !cmd.key_args(args).all(allowed) {
+            return Err("NOPERM No permissions to access a key".into());
+        }
+        Ok(())
+    }
+}
+
+/// Whether clients may run `cmd` before they logged in.
+pub(crate) fn is_no_auth_command(cmd: &CommandSpec) -> bool {
+    NO_AUTH_COMMANDS.contains(&cmd.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Bytes> {
+        args.iter().map(|a| Bytes::from(a.to_string())).collect()
+    }
+
+    fn check(backend: &Backend, user: &str, cmd: &[&str]) -> Result<(), String> {
+        let args = args(cmd);
+        let spec = lookup_command(&args[0]).unwrap();
+        backend.check_permission(user, spec, &args)
+    }
+
+    #[test]
+    fn test_default_user() {
+        let backend = Backend::new();
+        assert_eq!(backend.acl_list(), ["user default on nopass ~* +@all"]);
+        assert!(backend.default_user_nopass());
+        assert!(backend.authenticate("default", b"anything"));
+        assert!(check(&backend, "default", &["SHUTDOWN"]).is_ok());
+
+        backend.set_requirepass("s3cret");
+        assert!(!backend.default_user_nopass());
+        assert!(backend.authenticate("default", b"s3cret"));
+        assert!(!backend.authenticate("default", b"wrong"));
+        assert_eq!(backend.requirepass(), "s3cret");
+        backend.set_requirepass("");
+        assert!(backend.default_user_nopass());
+    }
+
+    #[test]
+    fn test_setuser_rules() {
+        let backend = Backend::new();
+        backend
+            .acl_setuser(
+                "alice",
+                &[
+                    "on", ">pw", "~cache:*", "+@read", "+@string", "-set", "+del",
+                ],
+            )
+            .unwrap();
+        assert!(backend.authenticate("alice", b"pw"));
+        assert!(!backend.authenticate("alice", b"other"));
+        assert!(check(&backend, "alice", &["GET", "cache:1"]).is_ok());
+        assert!(check(&backend, "alice", &["HGET", "cache:1", "f"]).is_ok());
+        assert!(check(&backend, "alice", &["DEL", "cache:1", "cache:2"]).is_ok());
+        assert_eq!(
+            check(&backend, "alice", &["SET", "cache:1", "v"]),
+            Err("NOPERM User alice has no permissions to run the 'set' command".into())
+        );
+        assert_eq!(
+            check(&backend, "alice", &["MGET", "cache:1", "users:1"]),
+            Err("NOPERM No permissions to access a key".into())
+        );
+        assert!(check(&backend, "alice", &["SAVE"]).is_err());
+        // logging in is always allowed
+        assert!(check(&backend, "alice", &["AUTH", "alice", "pw"]).is_ok());
+
+        let list = backend.acl_list();
+        assert_eq!(
+            list[0],
+            format!(
+                "user alice on #{} ~cache:* -@all +@read +@string -set +del",
+                hash_password(b"pw")
+            )
+        );
+
+        backend.acl_setuser("alice", &["off"]).unwrap();
+        assert!(!backend.authenticate("alice", b"pw"));
+    }
+
+    #[test]
+    fn test_setuser_errors_change_nothing() {
+        let backend = Backend::new();
+        backend.acl_setuser("bob", &["on", "nopass"]).unwrap();
+        let err = backend
+            .acl_setuser("bob", &["+@all", "+@nope"])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR Error in ACL SETUSER modifier '+@nope': Unknown command or category name in ACL"
+        );
+        assert!(check(&backend, "bob", &["GET", "k"]).is_err());
+        assert!(backend.acl_setuser("bob", &["#abc"]).is_err());
+        assert!(backend.acl_setuser("bob", &["<nope"]).is_err());
+        assert!(backend.acl_setuser("bob", &["bogus"]).is_err());
+        assert!(backend.acl_setuser("carol", &["bogus"]).is_err());
+        assert_eq!(backend.acl_users(), ["bob", "default"]);
+    }
+
+    #[test]
+    fn test_deluser() {
+        let backend = Backend::new();
+        backend.acl_setuser("bob", &[]).unwrap();
+        assert!(backend.acl_deluser(&["default"]).is_err());
+        assert_eq!(backend.acl_deluser(&["bob", "nobody"]).unwrap(), 1);
+        assert_eq!(backend.acl_users(), ["default"]);
+        assert!(check(&backend, "bob", &["PING"]).is_err());
+    }
+}
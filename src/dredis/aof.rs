@@ -0,0 +1,720 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use tracing::{info, warn};
+
+use crate::{execute, Backend, Entry, RespFrame, Session, Snapshot, Stream, Value};
+
+/// Aggregates are rewritten with at most this many elements per command.
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+
+/// When the append-only file is flushed to disk, redis' `appendfsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    /// After every write command, before replying.
+    Always,
+    /// Once per second from a background thread.
+    #[default]
+    EverySec,
+    /// Whenever the operating system decides.
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(anyhow!("invalid appendfsync value '{}'", s)),
+        }
+    }
+}
+
+impl AppendFsync {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Aof {
+    enabled: AtomicBool,
+    file: Mutex<Option<AofFile>>,
+    rewrite: Mutex<Option<JoinHandle<Result<()>>>>,
+}
+
+#[derive(Debug)]
+struct AofFile {
+    path: PathBuf,
+    file: File,
+    fsync: AppendFsync,
+    /// Written since the last fsync.
+    dirty: bool,
+    /// Commands logged while a rewrite runs, appended to the rewritten file.
+    rewrite_buf: Option<Vec<u8>>,
+    /// The database of the last logged command, `None` when the next one
+    /// needs a `SELECT` whatever its database.
+    selected_db: Option<usize>,
+}
+
+impl AofFile {
+    fn open(path: &Path, fsync: AppendFsync) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {:?}", path))?;
+        Ok(AofFile {
+            path: path.to_path_buf(),
+            file,
+            fsync,
+            dirty: false,
+            rewrite_buf: None,
+            selected_db: None,
+        })
+    }
+}
+
+impl Aof {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Append a command run on database `db`, preceded by a `SELECT` when the
+    /// last one ran on another. Flushed to disk right away with `always`.
+    pub(crate) fn feed(&self, db: usize, args: &[Bytes]) -> Result<()> {
+        let mut guard = self.file.lock().unwrap();
+        let Some(aof) = guard.as_mut() else {
+            return Ok(());
+        };
+        let mut cmd = BytesMut::new();
+        if aof.selected_db != Some(db) {
+            cmd = select_command(db);
+            aof.selected_db = Some(db);
+        }
+        cmd.extend_from_slice(&command(args.iter().cloned()));
+        aof.file.write_all(&cmd)?;
+        if let Some(buf) = aof.rewrite_buf.as_mut() {
+            buf.extend_from_slice(&cmd);
+        }
+        match aof.fsync {
+            AppendFsync::Always => aof.file.sync_data()?,
+            AppendFsync::EverySec => aof.dirty = true,
+            AppendFsync::No => {}
+        }
+        Ok(())
+    }
+
+    /// Flush everything logged so far to disk, whatever `appendfsync` says.
+    pub(crate) fn sync(&self) -> Result<()> {
+        if let Some(aof) = self.file.lock().unwrap().as_ref() {
+            aof.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn fsync_if_dirty(&self) -> Result<()> {
+        // sync a clone of the handle so writers are not held up by the disk
+        let file = {
+            let mut guard = self.file.lock().unwrap();
+            match guard.as_mut() {
+                Some(aof) if aof.dirty => {
+                    aof.dirty = false;
+                    aof.file.try_clone()?
+                }
+                _ => return Ok(()),
+            }
+        };
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// A command as the RESP array written to the log.
+fn command(args: impl IntoIterator<Item = Bytes>) -> BytesMut {
+    let mut buf = BytesMut::new();
+    RespFrame::command(args).encode(&mut buf);
+    buf
+}
+
+fn select_command(db: usize) -> BytesMut {
+    command(["SELECT".into(), db.to_string().into()])
+}
+
+/// The commands that rebuild `snapshot`, see `BGREWRITEAOF`.
+fn rewrite_commands(snapshot: &Snapshot, out: &mut impl Write) -> io::Result<()> {
+    for (db, entries) in snapshot {
+        out.write_all(&select_command(*db))?;
+        rewrite_entries(entries, out)?;
+    }
+    Ok(())
+}
+
+fn rewrite_entries(entries: &[(Bytes, Entry)], out: &mut impl Write) -> io::Result<()> {
+    fn chunks<'a>(
+        name: &'static str,
+        key: &'a Bytes,
+        items: impl Iterator<Item = Vec<Bytes>> + 'a,
+    ) -> impl Iterator<Item = Vec<Bytes>> + 'a {
+        let items = items.collect::<Vec<_>>();
+        let mut cmds = Vec::new();
+        for chunk in items.chunks(REWRITE_ITEMS_PER_CMD) {
+            let mut cmd = vec![Bytes::from(name), key.clone()];
+            cmd.extend(chunk.iter().flatten().cloned());
+            cmds.push(cmd);
+        }
+        cmds.into_iter()
+    }
+
+    for (key, entry) in entries {
+        let cmds: Vec<Vec<Bytes>> = match &entry.value {
+            Value::String(s) => vec![vec!["SET".into(), key.clone(), s.clone()]],
+            Value::List(l) => chunks("RPUSH", key, l.iter().map(|v| vec![v.clone()])).collect(),
+            Value::Set(s) => chunks("SADD", key, s.iter().map(|m| vec![m.clone()])).collect(),
+            Value::Hash(h) => chunks(
+                "HSET",
+                key,
+                h.iter().map(|(f, v)| vec![f.clone(), v.clone()]),
+            )
+            .collect(),
+            Value::ZSet(z) => chunks(
+                "ZADD",
+                key,
+                z.iter()
+                    .map(|(m, s)| vec![crate::format_double(s).into(), m.clone()]),
+            )
+            .collect(),
+            Value::Stream(s) => stream_commands(key, s),
+        };
+        for cmd in cmds {
+            out.write_all(&command(cmd))?;
+        }
+        if let Some(at) = entry.expire_at {
+            let cmd = ["PEXPIREAT".into(), key.clone(), at.to_string().into()];
+            out.write_all(&command(cmd))?;
+        }
+    }
+    Ok(())
+}
+
+/// The commands rebuilding a stream: its entries, its IDs and counters, then
+/// its consumer groups with their consumers and pending entries.
+fn stream_commands(key: &Bytes, stream: &Stream) -> Vec<Vec<Bytes>> {
+    let mut cmds = Vec::new();
+    for (id, fields) in stream.iter() {
+        let mut cmd = vec!["XADD".into(), key.clone(), id.to_bytes()];
+        cmd.extend(fields.iter().flat_map(|(f, v)| [f.clone(), v.clone()]));
+        cmds.push(cmd);
+    }
+    if stream.is_empty() {
+        // creates the key without entries, XSETID sets the real last ID
+        let mut cmd = vec!["XADD".into(), key.clone()];
+        cmd.extend(["MAXLEN", "0", "0-1", "x", "y"].map(Bytes::from));
+        cmds.push(cmd);
+    }
+    cmds.push(vec![
+        "XSETID".into(),
+        key.clone(),
+        stream.last_id().to_bytes(),
+        "ENTRIESADDED".into(),
+        stream.entries_added().to_string().into(),
+        "MAXDELETEDID".into(),
+        stream.max_deleted_id().to_bytes(),
+    ]);
+    for (name, group) in stream.groups() {
+        let mut create = vec![
+            "XGROUP".into(),
+            "CREATE".into(),
+            key.clone(),
+            name.clone(),
+            group.last_delivered.to_bytes(),
+        ];
+        if let Some(read) = group.entries_read {
+            create.extend(["ENTRIESREAD".into(), read.to_string().into()]);
+        }
+        cmds.push(create);
+        for (consumer, _) in group.consumers() {
+            cmds.push(vec![
+                "XGROUP".into(),
+                "CREATECONSUMER".into(),
+                key.clone(),
+                name.clone(),
+                consumer.clone(),
+            ]);
+        }
+        for (id, pending) in group.pending() {
+            cmds.push(vec![
+                "XCLAIM".into(),
+                key.clone(),
+                name.clone(),
+                pending.consumer.clone(),
+                "0".into(),
+                id.to_bytes(),
+                "TIME".into(),
+                pending.delivery_time.to_string().into(),
+                "RETRYCOUNT".into(),
+                pending.delivery_count.to_string().into(),
+                "JUSTID".into(),
+                "FORCE".into(),
+            ]);
+        }
+    }
+    cmds
+}
+
+impl Backend {
+    /// Replay the append-only file at `path` if there is one, then log every
+    /// write command to it. Returns the number of commands replayed.
+    pub fn start_aof(&self, path: impl AsRef<Path>, fsync: AppendFsync) -> Result<usize> {
+        let path = path.as_ref();
+        let replayed = self.replay_aof(path)?;
+        *self.aof.file.lock().unwrap() = Some(AofFile::open(path, fsync)?);
+        self.aof.enabled.store(true, Ordering::Release);
+
+        let aof = Arc::downgrade(&self.aof);
+        thread::spawn(move || fsync_every_second(aof));
+        Ok(replayed)
+    }
+
+    pub fn set_aof_fsync(&self, fsync: AppendFsync) {
+        if let Some(aof) = self.aof.file.lock().unwrap().as_mut() {
+            aof.fsync = fsync;
+        }
+    }
+
+    /// Run the commands of the file at `path`. A command cut short at the end
+    /// of the file, as a crash mid-write leaves it, is dropped from the file.
+    fn replay_aof(&self, path: &Path) -> Result<usize> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("read {:?}", path)),
+        };
+        let mut buf = BytesMut::from(&data[..]);
+        let mut session = Session::new(self.clone());
+        let mut replayed = 0;
+        // where the open transaction starts and the commands replayed before it
+        let mut multi_start = None;
+        loop {
+            let offset = data.len() - buf.len();
+            let frame = match RespFrame::decode(&mut buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    bail!("bad AOF format at offset {} of {:?}: {}", offset, path, e);
+                }
+            };
+            let args = crate::frame_to_args(frame)?;
+            let in_multi = session.multi.is_some();
+            if let RespFrame::Error(e) = execute(&mut session, &args) {
+                bail!("replaying AOF {:?}: {}", path, e);
+            }
+            replayed += 1;
+            match (in_multi, session.multi.is_some()) {
+                (false, true) => multi_start = Some((offset as u64, replayed - 1)),
+                (true, false) => multi_start = None,
+                _ => {}
+            }
+        }
+        let mut valid = (data.len() - buf.len()) as u64;
+        if !buf.is_empty() {
+            warn!(
+                "AOF {:?} ends with a truncated command, dropping its last {} bytes",
+                path,
+                buf.len()
+            );
+        }
+        if let Some((offset, before)) = multi_start {
+            // writes appended after a MULTI without its EXEC would never run
+            warn!(
+                "AOF {:?} ends inside a transaction, dropping it from offset {}",
+                path, offset
+            );
+            valid = offset;
+            replayed = before;
+        }
+        if valid < data.len() as u64 {
+            OpenOptions::new().write(true).open(path)?.set_len(valid)?;
+        }
+        Ok(replayed)
+    }
+
+    /// `BGREWRITEAOF` for callers holding the exec lock exclusively: write the
+    /// commands rebuilding the current keyspace to a new file on a background
+    /// thread, then swap it in with whatever was logged in the meantime.
+    pub(crate) fn rewrite_aof(&self) -> Result<()> {
+        let mut rewrite = self.aof.rewrite.lock().unwrap();
+        if rewrite.as_ref().is_some_and(|h| !h.is_finished()) {
+            bail!("ERR Background append only file rewriting already in progress");
+        }
+        let path = {
+            let mut guard = self.aof.file.lock().unwrap();
+            let Some(aof) = guard.as_mut() else {
+                bail!("ERR Background append only file rewriting needs appendonly enabled");
+            };
+            aof.rewrite_buf = Some(Vec::new());
+            // the buffer lands after the last database of the rewrite
+            aof.selected_db = None;
+            aof.path.clone()
+        };
+        let snapshot = self.snapshot();
+        let backend = self.clone();
+        *rewrite = Some(thread::spawn(move || {
+            let result = backend.finish_rewrite(&path, &snapshot);
+            if let Err(e) = &result {
+                warn!("background AOF rewrite failed: {:#}", e);
+                if let Some(aof) = backend.aof.file.lock().unwrap().as_mut() {
+                    aof.rewrite_buf = None;
+                }
+            }
+            result
+        }));
+        Ok(())
+    }
+
+    fn finish_rewrite(&self, path: &Path, snapshot: &Snapshot) -> Result<()> {
+        let tmp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let mut file = io::BufWriter::new(File::create(&tmp)?);
+        rewrite_commands(snapshot, &mut file)?;
+        let mut file = file.into_inner()?;
+
+        let mut guard = self.aof.file.lock().unwrap();
+        let aof = guard
+            .as_mut()
+            .ok_or_else(|| anyhow!("appendonly was turned off"))?;
+        file.write_all(&aof.rewrite_buf.take().unwrap_or_default())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &aof.path)?;
+        *aof = AofFile::open(&aof.path, aof.fsync)?;
+        info!(
+            "background AOF rewrite of {} keys terminated",
+            crate::snapshot_len(snapshot)
+        );
+        Ok(())
+    }
+
+    pub fn aof_rewrite_in_progress(&self) -> bool {
+        let rewrite = self.aof.rewrite.lock().unwrap();
+        rewrite.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    /// Wait for a running AOF rewrite.
+    pub fn wait_aof_rewrite(&self) -> Result<()> {
+        let handle = self.aof.rewrite.lock().unwrap().take();
+        match handle {
+            Some(handle) => handle.join().map_err(|_| anyhow!("AOF rewrite panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+fn fsync_every_second(aof: Weak<Aof>) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let Some(aof) = aof.upgrade() else {
+            return;
+        };
+        if let Err(e) = aof.fsync_if_dirty() {
+            warn!("AOF fsync failed: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::dredis::cmd::run;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("dredis-{}-{}-{}", std::process::id(), n, name))
+    }
+
+    fn restart(path: &Path) -> Result<(Session, usize)> {
+        let backend = Backend::new();
+        let replayed = backend.start_aof(path, AppendFsync::Always)?;
+        Ok((Session::new(backend), replayed))
+    }
+
+    #[test]
+    fn test_log_and_replay() -> Result<()> {
+        let path = temp_path("appendonly.aof");
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 0);
+        run(&mut s, &["SET", "a", "1", "EX", "100"]);
+        run(&mut s, &["INCR", "a"]);
+        run(&mut s, &["GET", "a"]);
+        run(&mut s, &["RPUSH", "l", "x", "y"]);
+        run(&mut s, &["BLPOP", "l", "0"]);
+        run(&mut s, &["BLPOP", "nothing", "0"]);
+        run(&mut s, &["EXPIRE", "l", "100"]);
+        // failed commands are not logged
+        run(&mut s, &["INCR", "l"]);
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["SADD", "s", "m"]);
+        run(&mut s, &["EXEC"]);
+        let now = s.backend.now_ms();
+
+        let log = String::from_utf8(fs::read(&path)?)?;
+        assert!(log.starts_with("*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n"));
+        assert!(log.contains("PXAT"));
+        assert!(log.contains("PEXPIREAT"));
+        assert!(log.contains("$4\r\nLPOP\r\n$1\r\nl\r\n"));
+        assert!(!log.contains("BLPOP") && !log.contains("GET"));
+
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 9);
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::bulk("2"));
+        assert_eq!(
+            run(&mut s, &["LRANGE", "l", "0", "-1"]),
+            RespFrame::array([RespFrame::bulk("y")])
+        );
+        let RespFrame::Integer(ttl) = run(&mut s, &["PEXPIRETIME", "a"]) else {
+            panic!("PEXPIRETIME should reply with an integer");
+        };
+        assert!(ttl as u64 > now && ttl as u64 <= now + 100_000);
+        assert_eq!(run(&mut s, &["SISMEMBER", "s", "m"]), RespFrame::Integer(1));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_keeps_databases() -> Result<()> {
+        let path = temp_path("databases.aof");
+        let (mut s, _) = restart(&path)?;
+        run(&mut s, &["SET", "a", "0"]);
+        run(&mut s, &["SELECT", "3"]);
+        run(&mut s, &["SET", "a", "3"]);
+        run(&mut s, &["SWAPDB", "3", "4"]);
+        run(&mut s, &["SET", "b", "3"]);
+        drop(s);
+
+        let (mut s, _) = restart(&path)?;
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::bulk("0"));
+        run(&mut s, &["SELECT", "3"]);
+        assert_eq!(run(&mut s, &["GET", "b"]), RespFrame::bulk("3"));
+        run(&mut s, &["SELECT", "4"]);
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::bulk("3"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scripts_are_logged_as_their_writes() -> Result<()> {
+        let path = temp_path("scripts.aof");
+        let (mut s, _) = restart(&path)?;
+        let script = "redis.call('SET', KEYS[1], 1) redis.call('INCR', KEYS[1])";
+        run(&mut s, &["EVAL", script, "1", "a"]);
+        run(&mut s, &["EVAL", "return redis.call('GET', 'a')", "0"]);
+        // inside EXEC the transaction wraps the script's writes
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["SET", "b", "1"]);
+        run(&mut s, &["EVAL", script, "1", "c"]);
+        run(&mut s, &["EXEC"]);
+        drop(s);
+
+        let log = String::from_utf8(fs::read(&path)?)?;
+        assert!(!log.contains("EVAL"));
+        assert_eq!(log.matches("MULTI").count(), 2);
+        let (mut s, _) = restart(&path)?;
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::bulk("2"));
+        assert_eq!(run(&mut s, &["GET", "c"]), RespFrame::bulk("2"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_tail_is_dropped() -> Result<()> {
+        let path = temp_path("truncated.aof");
+        fs::write(
+            &path,
+            "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n$3\r\nSET\r\n$1\r\nb",
+        )?;
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 1);
+        assert_eq!(run(&mut s, &["EXISTS", "a", "b"]), RespFrame::Integer(1));
+        run(&mut s, &["SET", "c", "3"]);
+        drop(s);
+
+        // SET c comes with the SELECT of its database
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 3);
+        assert_eq!(run(&mut s, &["GET", "c"]), RespFrame::bulk("3"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unfinished_transaction_is_dropped() -> Result<()> {
+        let path = temp_path("multi.aof");
+        fs::write(
+            &path,
+            "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*1\r\n$5\r\nMULTI\r\n\
+             *3\r\n$3\r\nSET\r\n$1\r\nx\r\n$1\r\n1\r\n",
+        )?;
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 1);
+        assert_eq!(run(&mut s, &["EXISTS", "a", "x"]), RespFrame::Integer(1));
+        run(&mut s, &["SET", "b", "2"]);
+        drop(s);
+
+        // SET b is not queued into the transaction that never ran
+        let (mut s, replayed) = restart(&path)?;
+        assert_eq!(replayed, 3);
+        assert_eq!(run(&mut s, &["GET", "b"]), RespFrame::bulk("2"));
+        assert!(!String::from_utf8(fs::read(&path)?)?.contains("MULTI"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_file_is_an_error() -> Result<()> {
+        let path = temp_path("corrupt.aof");
+        fs::write(&path, "*1\r\n$4\r\nPING\r\n!garbage\r\n")?;
+        assert!(restart(&path).is_err());
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// The stream at `key` without the consumer seen and active times, replay
+    /// takes those from its own clock.
+    fn stream_state(s: &Session, key: &[u8]) -> Stream {
+        let mut stream = s
+            .backend
+            .read(key, |e| e.and_then(|e| e.value.as_stream()).cloned())
+            .unwrap();
+        let names = stream
+            .groups()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in names {
+            let group = stream.group_mut(&name).unwrap();
+            let consumers = group
+                .consumers()
+                .map(|(c, _)| c.clone())
+                .collect::<Vec<_>>();
+            for consumer in consumers {
+                group.seen(&consumer, 0).active_time = None;
+            }
+        }
+        stream
+    }
+
+    #[test]
+    fn test_streams_replay_and_rewrite() -> Result<()> {
+        let path = temp_path("streams.aof");
+        let (mut s, _) = restart(&path)?;
+        for i in 1..=5 {
+            run(
+                &mut s,
+                &["XADD", "s", "MAXLEN", "4", &format!("{}-1", i), "f", "v"],
+            );
+        }
+        run(&mut s, &["XGROUP", "CREATE", "s", "g", "0"]);
+        run(&mut s, &["XGROUP", "CREATE", "s", "later", "$"]);
+        run(
+            &mut s,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "a",
+                "COUNT",
+                "3",
+                "STREAMS",
+                "s",
+                ">",
+            ],
+        );
+        run(
+            &mut s,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "b",
+                "NOACK",
+                "STREAMS",
+                "s",
+                ">",
+            ],
+        );
+        run(
+            &mut s,
+            &["XAUTOCLAIM", "s", "g", "c", "0", "0", "COUNT", "1"],
+        );
+        run(&mut s, &["XGROUP", "CREATECONSUMER", "s", "g", "idle"]);
+        run(&mut s, &["XDEL", "s", "5-1"]);
+        run(&mut s, &["XADD", "emptied", "MAXLEN", "0", "*", "f", "v"]);
+        let (stream, emptied) = (stream_state(&s, b"s"), stream_state(&s, b"emptied"));
+        assert_eq!(stream.group(b"g").unwrap().pending().len(), 3);
+        drop(s);
+
+        let (s, _) = restart(&path)?;
+        assert_eq!(stream_state(&s, b"s"), stream);
+        assert_eq!(stream_state(&s, b"emptied"), emptied);
+        run(&mut Session::new(s.backend.clone()), &["BGREWRITEAOF"]);
+        s.backend.wait_aof_rewrite()?;
+        drop(s);
+
+        let (s, _) = restart(&path)?;
+        assert_eq!(stream_state(&s, b"s"), stream);
+        assert_eq!(stream_state(&s, b"emptied"), emptied);
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgrewriteaof() -> Result<()> {
+        let path = temp_path("rewrite.aof");
+        let (mut s, _) = restart(&path)?;
+        for i in 0..100 {
+            run(&mut s, &["INCR", "counter"]);
+            run(&mut s, &["RPUSH", "list", &i.to_string()]);
+        }
+        run(&mut s, &["ZADD", "z", "1.5", "a", "-inf", "b"]);
+        run(&mut s, &["HSET", "h", "f", "v"]);
+        run(&mut s, &["PEXPIRE", "h", "100000"]);
+        let before = fs::metadata(&path)?.len();
+        assert_eq!(
+            run(&mut s, &["BGREWRITEAOF"]),
+            RespFrame::simple("Background append only file rewriting started")
+        );
+        run(&mut s, &["SET", "after", "1"]);
+        s.backend.wait_aof_rewrite()?;
+        run(&mut s, &["SET", "after", "2"]);
+        assert!(fs::metadata(&path)?.len() < before);
+
+        let (mut s, _) = restart(&path)?;
+        assert_eq!(run(&mut s, &["GET", "counter"]), RespFrame::bulk("100"));
+        assert_eq!(run(&mut s, &["LLEN", "list"]), RespFrame::Integer(100));
+        assert_eq!(run(&mut s, &["ZSCORE", "z", "b"]), RespFrame::bulk("-inf"));
+        assert_eq!(run(&mut s, &["HGET", "h", "f"]), RespFrame::bulk("v"));
+        assert!(matches!(run(&mut s, &["PTTL", "h"]), RespFrame::Integer(ms) if ms > 0));
+        assert_eq!(run(&mut s, &["GET", "after"]), RespFrame::bulk("2"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry as MapEntry;
+use tracing::warn;
+
+use crate::{
+    event, new_dbs, Acl, Aof, BlockingKeys, Clients, Clock, ClusterState, ConfigState, Db, KeyMeta,
+    Memory, PubSub, RdbState, Replication, Scripting, Shutdown, Stream, SystemClock, Watches, ZSet,
+    DEFAULT_DATABASES,
+};
+
+/// A value in the keyspace. Aggregates are shared copy-on-write so a snapshot
+/// of the keyspace is cheap to take, writers copy what they change after.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(Arc<VecDeque<Bytes>>),
+    Hash(Arc<HashMap<Bytes, Bytes>>),
+    Set(Arc<HashSet<Bytes>>),
+    ZSet(Arc<ZSet>),
+    Stream(Arc<Stream>),
+}
+
+impl From<VecDeque<Bytes>> for Value {
+    fn from(list: VecDeque<Bytes>) -> Self {
+        Value::List(Arc::new(list))
+    }
+}
+
+impl From<HashMap<Bytes, Bytes>> for Value {
+    fn from(hash: HashMap<Bytes, Bytes>) -> Self {
+        Value::Hash(Arc::new(hash))
+    }
+}
+
+impl From<HashSet<Bytes>> for Value {
+    fn from(set: HashSet<Bytes>) -> Self {
+        Value::Set(Arc::new(set))
+    }
+}
+
+impl From<ZSet> for Value {
+    fn from(zset: ZSet) -> Self {
+        Value::ZSet(Arc::new(zset))
+    }
+}
+
+impl From<Stream> for Value {
+    fn from(stream: Stream) -> Self {
+        Value::Stream(Arc::new(stream))
+    }
+}
+
+impl Value {
+    /// The name `TYPE` reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Aggregates without elements are removed from the keyspace, streams
+    /// stay as they keep their last ID and consumer groups.
+    fn is_empty_aggregate(&self) -> bool {
+        match self {
+            Value::String(_) | Value::Stream(_) => false,
+            Value::List(l) => l.is_empty(),
+            Value::Hash(h) => h.is_empty(),
+            Value::Set(s) => s.is_empty(),
+            Value::ZSet(z) => z.is_empty(),
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&Bytes> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&VecDeque<Bytes>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut VecDeque<Bytes>> {
+        match self {
+            Value::List(l) => Some(Arc::make_mut(l)),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash(&self) -> Option<&HashMap<Bytes, Bytes>> {
+        match self {
+            Value::Hash(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Option<&mut HashMap<Bytes, Bytes>> {
+        match self {
+            Value::Hash(h) => Some(Arc::make_mut(h)),
+            _ => None,
+        }
+    }
+
+    pub fn as_set(&self) -> Option<&HashSet<Bytes>> {
+        match self {
+            Value::Set(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Option<&mut HashSet<Bytes>> {
+        match self {
+            Value::Set(s) => Some(Arc::make_mut(s)),
+            _ => None,
+        }
+    }
+
+    pub fn as_zset(&self) -> Option<&ZSet> {
+        match self {
+            Value::ZSet(z) => Some(z),
+            _ => None,
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Option<&mut ZSet> {
+        match self {
+            Value::ZSet(z) => Some(Arc::make_mut(z)),
+            _ => None,
+        }
+    }
+
+    pub fn as_stream(&self) -> Option<&Stream> {
+        match self {
+            Value::Stream(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Option<&mut Stream> {
+        match self {
+            Value::Stream(s) => Some(Arc::make_mut(s)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub value: Value,
+    /// absolute unix time in milliseconds
+    pub expire_at: Option<u64>,
+    pub(crate) meta: KeyMeta,
+}
+
+impl Entry {
+    pub fn new(value: Value) -> Self {
+        Self::with_expire_at(value, None)
+    }
+
+    pub fn with_expire_at(value: Value, expire_at: Option<u64>) -> Self {
+        Entry {
+            value,
+            expire_at,
+            meta: KeyMeta::default(),
+        }
+    }
+
+    // stands in for an entry moved out of the map while `Backend::write` runs
+    fn placeholder() -> Self {
+        Entry::new(Value::String(Bytes::new()))
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// The shared keyspace, cheap to clone and safe to use from every connection.
+/// A handle works on one of the logical databases, see `Backend::select`.
+#[derive(Debug, Clone)]
+pub struct Backend {
+    /// Swapped wholesale by `SWAPDB` and `FLUSHDB`, under the exec lock.
+    pub(crate) dbs: Arc<RwLock<Vec<Arc<Db>>>>,
+    pub(crate) db: usize,
+    pub(crate) memory: Arc<Memory>,
+    pub(crate) blocking: Arc<BlockingKeys>,
+    pub(crate) pubsub: Arc<PubSub>,
+    pub(crate) watches: Arc<Watches>,
+    /// Commands run holding it shared, `EXEC` holds it exclusively so a
+    /// transaction never interleaves with other clients.
+    pub(crate) exec_lock: Arc<RwLock<()>>,
+    pub(crate) rdb: Arc<RdbState>,
+    pub(crate) aof: Arc<Aof>,
+    pub(crate) repl: Arc<Replication>,
+    pub(crate) shutdown: Arc<Shutdown>,
+    pub(crate) clients: Arc<Clients>,
+    pub(crate) acl: Arc<Acl>,
+    pub(crate) config: Arc<ConfigState>,
+    /// Held by write commands while they run and are logged, so the log has
+    /// them in the order they were applied.
+    pub(crate) propagate_lock: Arc<Mutex<()>>,
+    pub(crate) scripting: Arc<Scripting>,
+    pub(crate) cluster: Arc<ClusterState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Backend {
+            dbs: Arc::new(RwLock::new(new_dbs(DEFAULT_DATABASES))),
+            db: 0,
+            memory: Arc::new(Memory::default()),
+            blocking: Arc::new(BlockingKeys::default()),
+            pubsub: Arc::new(PubSub::default()),
+            watches: Arc::new(Watches::default()),
+            exec_lock: Arc::new(RwLock::new(())),
+            rdb: Arc::new(RdbState::default()),
+            aof: Arc::new(Aof::default()),
+            repl: Arc::new(Replication::default()),
+            shutdown: Arc::new(Shutdown::default()),
+            clients: Arc::new(Clients::default()),
+            acl: Arc::new(Acl::default()),
+            config: Arc::new(ConfigState::default()),
+            propagate_lock: Arc::new(Mutex::new(())),
+            scripting: Arc::new(Scripting::default()),
+            cluster: Arc::new(ClusterState::default()),
+            clock,
+        }
+    }
+
+    /// Whether write commands are logged or replicated.
+    pub(crate) fn propagating(&self) -> bool {
+        self.aof.is_enabled() || self.repl.is_active()
+    }
+
+    /// Log a write command and send it to the replicas, as run on the
+    /// current database.
+    pub(crate) fn propagate(&self, args: &[Bytes]) {
+        self.log(args);
+        self.repl.feed_command(self.db, args);
+    }
+
+    /// Log a write command to the AOF only, for commands a replica gets from
+    /// its master: its own replicas get those as they came.
+    pub(crate) fn log(&self, args: &[Bytes]) {
+        if let Err(e) = self.aof.feed(self.db, args) {
+            warn!("writing to the AOF failed: {:#}", e);
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    /// Look at the live entry of `key`, which counts as a use of it for
+    /// eviction. An expired entry is removed and seen as missing.
+    pub fn read<R>(&self, key: &[u8], f: impl FnOnce(Option<&Entry>) -> R) -> R {
+        let now = self.now_ms();
+        {
+            let db = self.db();
+            let entry = db.data.get(key);
+            match entry.as_deref() {
+                Some(e) if e.is_expired(now) => {}
+                Some(e) => {
+                    e.meta.touch(now);
+                    return f(Some(e));
+                }
+                None => return f(None),
+            }
+        }
+        self.remove_expired(key);
+        f(None)
+    }
+
+    /// Look at the live entry of `key` without it counting as a use.
+    pub fn peek<R>(&self, key: &[u8], f: impl FnOnce(Option<&Entry>) -> R) -> R {
+        let now = self.now_ms();
+        let db = self.db();
+        let entry = db.data.get(key);
+        f(entry.as_deref().filter(|e| !e.is_expired(now)))
+    }
+
+    /// Read and modify `key` atomically. `f` gets the live entry (or `None`)
+    /// and may replace, change or remove it by writing to the slot. An
+    /// aggregate left without elements is removed. Clients watching `key` are
+    /// flagged when the entry changed.
+    pub fn write<R>(&self, key: &Bytes, f: impl FnOnce(&mut Option<Entry>) -> R) -> R {
+        let now = self.now_ms();
+        let db = self.db();
+        let (mut expired, mut created) = (false, false);
+        let ret = match db.data.entry(key.clone()) {
+            MapEntry::Occupied(mut e) => {
+                let was_volatile = e.get().expire_at.is_some();
+                let old_size = e.get().meta.size;
+                expired = e.get().is_expired(now);
+                let mut slot = if expired {
+                    None
+                } else {
+                    Some(std::mem::replace(e.get_mut(), Entry::placeholder()))
+                };
+                let before = self.watches.is_watched(self.db, key).then(|| slot.clone());
+                let ret = f(&mut slot);
+                drop_empty(&mut slot);
+                if before.is_some_and(|before| before != slot) {
+                    self.watches.touch(self.db, key);
+                }
+                update_expires(&db, key, was_volatile, slot.as_ref());
+                self.account(&db, key, old_size, slot.as_mut());
+                created = expired && slot.is_some();
+                match slot {
+                    Some(entry) => *e.get_mut() = entry,
+                    None => {
+                        e.remove();
+                        db.keys.lock().unwrap().remove(key);
+                    }
+                }
+                ret
+            }
+            MapEntry::Vacant(e) => {
+                let mut slot = None;
+                let ret = f(&mut slot);
+                drop_empty(&mut slot);
+                if slot.is_some() {
+                    self.watches.touch(self.db, key);
+                }
+                update_expires(&db, key, false, slot.as_ref());
+                self.account(&db, key, 0, slot.as_mut());
+                if let Some(entry) = slot {
+                    e.insert(entry);
+                    db.keys.lock().unwrap().insert(key);
+                    created = true;
+                }
+                ret
+            }
+        };
+        // published once the shard lock is released
+        if expired {
+            self.notify(event::EXPIRED, "expired", key);
+        }
+        if created {
+            self.notify(event::NEW, "new", key);
+        }
+        ret
+    }
+
+    /// Remove `key`, returns whether a live key was removed.
+    pub fn remove(&self, key: &[u8]) -> bool {
+        let now = self.now_ms();
+        let db = self.db();
+        let expired = db
+            .data
+            .remove_if(key, |_, e| {
+                if e.expire_at.is_some() {
+                    db.expires.lock().unwrap().remove(key);
+                }
+                db.keys.lock().unwrap().remove(key);
+                self.unaccount(&db, e);
+                true
+            })
+            .map(|(_, e)| e.is_expired(now));
+        match expired {
+            Some(false) => self.watches.touch(self.db, key),
+            Some(true) => self.notify(event::EXPIRED, "expired", key),
+            None => {}
+        }
+        expired == Some(false)
+    }
+
+    /// Take the live entry of `key` out of the database as it is, for it to
+    /// live elsewhere for a while. Unlike a removal nobody hears about it,
+    /// clients watching the key are not flagged.
+    pub(crate) fn take_entry(&self, key: &[u8]) -> Option<Entry> {
+        let now = self.now_ms();
+        let db = self.db();
+        let (_, entry) = db.data.remove(key)?;
+        if entry.expire_at.is_some() {
+            db.expires.lock().unwrap().remove(key);
+        }
+        db.keys.lock().unwrap().remove(key);
+        self.unaccount(&db, &entry);
+        (!entry.is_expired(now)).then_some(entry)
+    }
+
+    /// Put an entry taken with `take_entry` under `key`, which is missing.
+    pub(crate) fn put_entry(&self, key: &Bytes, mut entry: Entry) {
+        let db = self.db();
+        update_expires(&db, key, false, Some(&entry));
+        self.account(&db, key, 0, Some(&mut entry));
+        db.data.insert(key.clone(), entry);
+        db.keys.lock().unwrap().insert(key);
+    }
+
+    /// Remove `key` if its ttl has passed, returns whether it was removed.
+    pub fn remove_expired(&self, key: &[u8]) -> bool {
+        let now = self.now_ms();
+        let db = self.db();
+        let removed = db
+            .data
+            .remove_if(key, |_, e| {
+                let expired = e.is_expired(now);
+                if expired {
+                    db.expires.lock().unwrap().remove(key);
+                    db.keys.lock().unwrap().remove(key);
+                    self.unaccount(&db, e);
+                }
+                expired
+            })
+            .is_some();
+        if removed {
+            self.watches.touch(self.db, key);
+            self.notify(event::EXPIRED, "expired", key);
+        }
+        removed
+    }
+
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.read(key, |e| e.is_some())
+    }
+
+    /// Number of keys in the current database.
+    pub fn len(&self) -> usize {
+        self.db().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db().data.is_empty()
+    }
+
+    /// Number of keys with a ttl in the current database.
+    pub fn volatile_len(&self) -> usize {
+        self.db().expires.lock().unwrap().len()
+    }
+}
+
+// called with the shard lock of `key` held, so the index never disagrees
+// with the map for longer than the write itself
+fn update_expires(db: &Db, key: &Bytes, was_volatile: bool, entry: Option<&Entry>) {
+    let is_volatile = entry.is_some_and(|e| e.expire_at.is_some());
+    if is_volatile && !was_volatile {
+        db.expires.lock().unwrap().insert(key);
+    } else if was_volatile && !is_volatile {
+        db.expires.lock().unwrap().remove(key);
+    }
+}
+
+fn drop_empty(slot: &mut Option<Entry>) {
+    if slot.as_ref().is_some_and(|e| e.value.is_empty_aggregate()) {
+        *slot = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_insert_update_remove() {
+        let backend = Backend::new();
+        let key = Bytes::from("k");
+        backend.write(&key, |slot| {
+            *slot = Some(Entry::new(Value::String("1".into())))
+        });
+        assert!(backend.exists(b"k"));
+        backend.write(&key, |slot| {
+            let entry = slot.as_mut().unwrap();
+            entry.value = Value::String("2".into());
+        });
+        let value = backend.read(b"k", |e| e.map(|e| e.value.clone()));
+        assert_eq!(value, Some(Value::String("2".into())));
+        backend.write(&key, |slot| *slot = None);
+        assert!(!backend.exists(b"k"));
+        assert!(backend.is_empty());
+    }
+
+    #[test]
+    fn test_expired_entry_is_missing() {
+        let backend = Backend::new();
+        let key = Bytes::from("k");
+        let mut entry = Entry::new(Value::String("v".into()));
+        entry.expire_at = Some(backend.now_ms() - 1);
+        backend.write(&key, |slot| *slot = Some(entry));
+        assert!(!backend.exists(b"k"));
+        assert_eq!(backend.len(), 0);
+        assert!(!backend.remove(b"k"));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{Backend, RespFrame, RespVersion};
+
+/// A client blocked on one or more keys.
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    notify: Notify,
+    keys: Vec<Bytes>,
+    /// Held while the command runs, by the client itself or by the write
+    /// serving it, so it never runs twice at once.
+    pub(crate) state: Mutex<WaiterState>,
+}
+
+/// The command of a blocked client and what became of it.
+#[derive(Debug)]
+pub(crate) struct WaiterState {
+    /// The database and protocol the command runs with.
+    pub(crate) backend: Backend,
+    pub(crate) protocol: RespVersion,
+    pub(crate) args: Vec<Bytes>,
+    /// The reply of the write that served the client.
+    pub(crate) served: Option<RespFrame>,
+    /// The client stopped waiting, it can't be served any more.
+    pub(crate) gone: bool,
+}
+
+/// The clients blocked on each key of a database.
+type DbWaiters = HashMap<Bytes, VecDeque<Arc<Waiter>>>;
+
+/// Clients blocked per database and key, in the order they blocked.
+#[derive(Debug, Default)]
+pub(crate) struct BlockingKeys {
+    waiters: Mutex<HashMap<usize, DbWaiters>>,
+    /// Keys that got something for their clients since the last time those
+    /// were served.
+    ready: Mutex<Vec<(usize, Bytes)>>,
+}
+
+impl BlockingKeys {
+    fn register(&self, db: usize, keys: &[Bytes], state: WaiterState) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            notify: Notify::new(),
+            keys: keys.to_vec(),
+            state: Mutex::new(state),
+        });
+        let mut waiters = self.waiters.lock().unwrap();
+        let db_waiters = waiters.entry(db).or_default();
+        for key in keys {
+            db_waiters
+                .entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        waiter
+    }
+
+    fn unregister(&self, db: usize, keys: &[Bytes], waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let Some(db_waiters) = waiters.get_mut(&db) else {
+            return;
+        };
+        for key in keys {
+            if let Some(queue) = db_waiters.get_mut(key) {
+                queue.retain(|w| !Arc::ptr_eq(w, waiter));
+                if queue.is_empty() {
+                    db_waiters.remove(key);
+                }
+            }
+        }
+        if db_waiters.is_empty() {
+            waiters.remove(&db);
+        }
+    }
+
+    /// Wake the client that has waited longest on `key` of `db`, and mark
+    /// the key ready for the clients to be served before the next command.
+    pub(crate) fn wake(&self, db: usize, key: &[u8]) {
+        let waiters = self.waiters.lock().unwrap();
+        let queue = waiters.get(&db).and_then(|keys| keys.get(key));
+        if let Some(waiter) = queue.and_then(|q| q.front()) {
+            waiter.notify.notify_one();
+            let mut ready = self.ready.lock().unwrap();
+            if !ready.iter().any(|(d, k)| *d == db && k == key) {
+                ready.push((db, Bytes::copy_from_slice(key)));
+            }
+        }
+    }
+
+    /// Whether clients block on any of `keys` of `db`.
+    pub(crate) fn has_waiters<'a>(
+        &self,
+        db: usize,
+        mut keys: impl Iterator<Item = &'a Bytes>,
+    ) -> bool {
+        let waiters = self.waiters.lock().unwrap();
+        waiters
+            .get(&db)
+            .is_some_and(|db_waiters| keys.any(|key| db_waiters.contains_key(key)))
+    }
+
+    /// The next key marked ready by `wake`.
+    pub(crate) fn take_ready(&self) -> Option<(usize, Bytes)> {
+        self.ready.lock().unwrap().pop()
+    }
+
+    /// The clients blocked on `key` of `db`, the longest waiting first.
+    pub(crate) fn queue(&self, db: usize, key: &[u8]) -> Vec<Arc<Waiter>> {
+        let waiters = self.waiters.lock().unwrap();
+        let queue = waiters.get(&db).and_then(|keys| keys.get(key));
+        queue.into_iter().flatten().cloned().collect()
+    }
+
+    /// Hand `reply` to a client served by a write, which takes it off the
+    /// keys it blocked on.
+    pub(crate) fn serve(
+        &self,
+        db: usize,
+        waiter: &Arc<Waiter>,
+        state: &mut WaiterState,
+        reply: RespFrame,
+    ) {
+        state.served = Some(reply);
+        self.unregister(db, &waiter.keys, waiter);
+        waiter.notify.notify_one();
+    }
+
+    /// Wake every client waiting on `key` of `db`, for changes all of them
+    /// may be served by like an entry added to a stream.
+    pub(crate) fn wake_all(&self, db: usize, key: &[u8]) {
+        let waiters = self.waiters.lock().unwrap();
+        let queue = waiters.get(&db).and_then(|keys| keys.get(key));
+        for waiter in queue.into_iter().flatten() {
+            waiter.notify.notify_one();
+        }
+    }
+
+    /// Wake the first client of every key of `db`, whose keys may have
+    /// changed all at once. Those with nothing to pop block again.
+    pub(crate) fn wake_db(&self, db: usize) {
+        let waiters = self.waiters.lock().unwrap();
+        for queue in waiters.get(&db).into_iter().flat_map(|keys| keys.values()) {
+            if let Some(waiter) = queue.front() {
+                waiter.notify.notify_one();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn blocked_clients(&self) -> usize {
+        let waiters = self.waiters.lock().unwrap();
+        let mut all = waiters
+            .values()
+            .flat_map(|keys| keys.values())
+            .flatten()
+            .collect::<Vec<_>>();
+        all.sort_by_key(|w| Arc::as_ptr(w));
+        all.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        all.len()
+    }
+}
+
+/// Keeps a client registered on its keys while it blocks. Dropping the guard,
+/// because the client was served, timed out or went away, unregisters it and
+/// passes the turn to the next client so a wakeup is never lost.
+#[derive(Debug)]
+pub(crate) struct BlockGuard {
+    backend: Backend,
+    keys: Vec<Bytes>,
+    waiter: Arc<Waiter>,
+}
+
+impl BlockGuard {
+    /// Block on `keys` of the database `backend` works on, to run `args`
+    /// when they are ready.
+    pub(crate) fn new(
+        backend: &Backend,
+        keys: Vec<Bytes>,
+        protocol: RespVersion,
+        args: Vec<Bytes>,
+    ) -> Self {
+        let state = WaiterState {
+            backend: backend.clone(),
+            protocol,
+            args,
+            served: None,
+            gone: false,
+        };
+        let waiter = backend.blocking.register(backend.db, &keys, state);
+        BlockGuard {
+            backend: backend.clone(),
+            keys,
+            waiter,
+        }
+    }
+
+    pub(crate) async fn notified(&self) {
+        self.waiter.notify.notified().await
+    }
+
+    /// The reply of a write that served the client or else of `run`ning
+    /// its command, which returns the reply and the command to retry with.
+    /// A null reply means the client has to wait on.
+    pub(crate) fn attempt(
+        &self,
+        run: impl FnOnce(&[Bytes]) -> (RespFrame, Option<Vec<Bytes>>),
+    ) -> Result<RespFrame, RespFrame> {
+        let mut state = self.waiter.state.lock().unwrap();
+        if let Some(reply) = state.served.take() {
+            return Ok(reply);
+        }
+        let (reply, retry) = run(&state.args);
+        if let Some(retry) = retry {
+            state.args = retry;
+        }
+        if reply.is_null() {
+            Err(reply)
+        } else {
+            Ok(reply)
+        }
+    }
+
+    /// Stop waiting, with the reply of a write that served the client just
+    /// now or else `timeout`.
+    pub(crate) fn give_up(&self, timeout: RespFrame) -> RespFrame {
+        let mut state = self.waiter.state.lock().unwrap();
+        state.gone = true;
+        state.served.take().unwrap_or(timeout)
+    }
+}
+
+impl Drop for BlockGuard {
+    fn drop(&mut self) {
+        self.waiter.state.lock().unwrap().gone = true;
+        let (blocking, db) = (&self.backend.blocking, self.backend.db);
+        blocking.unregister(db, &self.keys, &self.waiter);
+        for key in &self.keys {
+            blocking.wake(db, key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wake_in_fifo_order() {
+        let backend = Backend::new();
+        let key = Bytes::from("k");
+        let block = || {
+            let args = vec!["BLPOP".into(), key.clone(), "0".into()];
+            BlockGuard::new(&backend, vec![key.clone()], RespVersion::Resp2, args)
+        };
+        let (first, second) = (block(), block());
+        assert_eq!(backend.blocking.blocked_clients(), 2);
+
+        backend.blocking.wake(0, &key);
+        tokio::time::timeout(Duration::from_secs(1), first.notified())
+            .await
+            .expect("first waiter should be woken");
+        let second_woken = tokio::time::timeout(Duration::from_millis(50), second.notified());
+        assert!(second_woken.await.is_err());
+
+        // the first client leaving hands the turn over
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), second.notified())
+            .await
+            .expect("second waiter should be woken");
+        drop(second);
+        assert_eq!(backend.blocking.blocked_clients(), 0);
+    }
+}
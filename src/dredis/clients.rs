@@ -0,0 +1,548 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fmt::Write as _,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::watch;
+
+use crate::{Backend, RespVersion, Session, DEFAULT_USER};
+
+/// Redis' default `maxclients`.
+const DEFAULT_MAXCLIENTS: usize = 10000;
+/// Redis' default `client-query-buffer-limit`, 1gb.
+const DEFAULT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// The classes of clients `client-output-buffer-limit` sets limits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientClass {
+    Normal,
+    Replica,
+    PubSub,
+}
+
+impl ClientClass {
+    pub const ALL: [ClientClass; 3] = [
+        ClientClass::Normal,
+        ClientClass::Replica,
+        ClientClass::PubSub,
+    ];
+
+    /// The name in `client-output-buffer-limit`, replicas still go by slave.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientClass::Normal => "normal",
+            ClientClass::Replica => "slave",
+            ClientClass::PubSub => "pubsub",
+        }
+    }
+}
+
+impl FromStr for ClientClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "normal" => Ok(ClientClass::Normal),
+            "replica" | "slave" => Ok(ClientClass::Replica),
+            "pubsub" => Ok(ClientClass::PubSub),
+            _ => Err(anyhow!("Invalid client class specified: {}", s)),
+        }
+    }
+}
+
+/// How much output may pile up for a client: past `hard` bytes it is
+/// disconnected right away, past `soft` bytes once that lasted `soft_secs`.
+/// A zero limit is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutputLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_secs: u64,
+}
+
+impl OutputLimit {
+    pub const fn new(hard: usize, soft: usize, soft_secs: u64) -> Self {
+        OutputLimit {
+            hard,
+            soft,
+            soft_secs,
+        }
+    }
+}
+
+impl fmt::Display for OutputLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.hard, self.soft, self.soft_secs)
+    }
+}
+
+/// Applies an `OutputLimit` to one client, remembering since when it is
+/// over the soft limit.
+#[derive(Debug, Default)]
+pub(crate) struct OutputLimitCheck {
+    soft_since: Option<Instant>,
+}
+
+impl OutputLimitCheck {
+    /// Whether a client with `pending` bytes of output is to be disconnected.
+    pub(crate) fn exceeded(&mut self, limit: &OutputLimit, pending: usize) -> bool {
+        self.exceeded_at(limit, pending, Instant::now())
+    }
+
+    fn exceeded_at(&mut self, limit: &OutputLimit, pending: usize, now: Instant) -> bool {
+        if limit.hard > 0 && pending > limit.hard {
+            return true;
+        }
+        if limit.soft == 0 || pending <= limit.soft {
+            self.soft_since = None;
+            return false;
+        }
+        let since = *self.soft_since.get_or_insert(now);
+        now.duration_since(since) > Duration::from_secs(limit.soft_secs)
+    }
+}
+
+/// What `CLIENT LIST` shows of a connection, kept up to date by the
+/// connection and shared with the registry.
+#[derive(Debug)]
+pub(crate) struct ClientHandle {
+    pub(crate) id: u64,
+    created: Instant,
+    stats: Mutex<ClientStats>,
+    killed: watch::Sender<bool>,
+}
+
+#[derive(Debug, Clone)]
+struct ClientStats {
+    addr: Option<SocketAddr>,
+    laddr: Option<SocketAddr>,
+    name: Option<String>,
+    user: String,
+    last_interaction: Instant,
+    last_cmd: &'static str,
+    replica: bool,
+    master: bool,
+    blocked: bool,
+    db: usize,
+    sub: usize,
+    psub: usize,
+    multi: i64,
+    resp: u8,
+    /// Bytes read and not parsed yet.
+    qbuf: usize,
+    /// Bytes of replies not written yet.
+    omem: usize,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(id: u64) -> Self {
+        let now = Instant::now();
+        ClientHandle {
+            id,
+            created: now,
+            stats: Mutex::new(ClientStats {
+                addr: None,
+                laddr: None,
+                name: None,
+                user: DEFAULT_USER.into(),
+                last_interaction: now,
+                last_cmd: "NULL",
+                replica: false,
+                master: false,
+                blocked: false,
+                db: 0,
+                sub: 0,
+                psub: 0,
+                multi: -1,
+                resp: 2,
+                qbuf: 0,
+                omem: 0,
+            }),
+            killed: watch::channel(false).0,
+        }
+    }
+
+    /// Record that the client runs `cmd` now.
+    pub(crate) fn command_started(&self, cmd: &'static str) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.last_cmd = cmd;
+        stats.last_interaction = Instant::now();
+    }
+
+    /// Take over the state of `session` after a command.
+    pub(crate) fn update(&self, session: &Session) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.name.clone_from(&session.name);
+        stats.user = session.user.as_deref().unwrap_or(DEFAULT_USER).into();
+        stats.sub = session
+            .subscriptions
+            .as_ref()
+            .map_or(0, |s| s.channels.len());
+        stats.psub = session
+            .subscriptions
+            .as_ref()
+            .map_or(0, |s| s.patterns.len());
+        stats.multi = session
+            .multi
+            .as_ref()
+            .map_or(-1, |tx| tx.queued.len() as i64);
+        stats.resp = match session.protocol {
+            RespVersion::Resp2 => 2,
+            RespVersion::Resp3 => 3,
+        };
+        stats.master = session.master_link;
+        stats.db = session.backend.db_index();
+    }
+
+    pub(crate) fn set_blocked(&self, blocked: bool) {
+        self.stats.lock().unwrap().blocked = blocked;
+    }
+
+    /// Mark the connection as feeding a replica since `PSYNC`.
+    pub(crate) fn set_replica(&self) {
+        self.stats.lock().unwrap().replica = true;
+    }
+
+    pub(crate) fn set_buffers(&self, qbuf: usize, omem: usize) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.qbuf = qbuf;
+        stats.omem = omem;
+    }
+
+    /// Close the connection once it is done with the command it runs.
+    pub(crate) fn kill(&self) {
+        self.killed.send_replace(true);
+    }
+
+    /// Resolves once the client was killed with `CLIENT KILL`.
+    pub(crate) async fn killed(&self) {
+        let mut killed = self.killed.subscribe();
+        let _ = killed.wait_for(|k| *k).await;
+    }
+
+    fn class(&self) -> ClientClass {
+        let stats = self.stats.lock().unwrap();
+        if stats.replica {
+            ClientClass::Replica
+        } else if stats.sub + stats.psub > 0 {
+            ClientClass::PubSub
+        } else {
+            ClientClass::Normal
+        }
+    }
+
+    fn addr(&self) -> Option<SocketAddr> {
+        self.stats.lock().unwrap().addr
+    }
+
+    fn laddr(&self) -> Option<SocketAddr> {
+        self.stats.lock().unwrap().laddr
+    }
+
+    /// The line of this client in `CLIENT LIST`.
+    pub(crate) fn describe(&self) -> String {
+        let stats = self.stats.lock().unwrap().clone();
+        let addr = |a: Option<SocketAddr>| a.map(|a| a.to_string()).unwrap_or_default();
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} qbuf={} obl={} oll=0 omem={} cmd={} user={} resp={}",
+            self.id,
+            addr(stats.addr),
+            addr(stats.laddr),
+            stats.name.as_deref().unwrap_or(""),
+            self.created.elapsed().as_secs(),
+            stats.last_interaction.elapsed().as_secs(),
+            stats.flags(),
+            stats.db,
+            stats.sub,
+            stats.psub,
+            stats.multi,
+            stats.qbuf,
+            stats.omem,
+            stats.omem,
+            stats.last_cmd,
+            stats.user,
+            stats.resp,
+        );
+        line
+    }
+}
+
+impl ClientStats {
+    /// The flags of `CLIENT LIST`: S replica, M master, P subscriber,
+    /// x in `MULTI`, b blocked, N none of those.
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        for (set, flag) in [
+            (self.replica, 'S'),
+            (self.master, 'M'),
+            (self.sub + self.psub > 0, 'P'),
+            (self.multi >= 0, 'x'),
+            (self.blocked, 'b'),
+        ] {
+            if set {
+                flags.push(flag);
+            }
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+/// Which clients `CLIENT KILL` closes, every given filter has to match.
+#[derive(Debug, Default)]
+pub struct ClientFilter {
+    pub id: Option<u64>,
+    pub addr: Option<SocketAddr>,
+    pub laddr: Option<SocketAddr>,
+    pub class: Option<ClientClass>,
+    pub user: Option<String>,
+    /// A client never matches itself unless this is false.
+    pub skip_me: Option<u64>,
+}
+
+impl ClientFilter {
+    pub(crate) fn matches(&self, client: &ClientHandle) -> bool {
+        self.id.is_none_or(|id| id == client.id)
+            && self.addr.is_none_or(|a| Some(a) == client.addr())
+            && self.laddr.is_none_or(|a| Some(a) == client.laddr())
+            && self.class.is_none_or(|c| c == client.class())
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|u| *u == client.stats.lock().unwrap().user)
+            && self.skip_me != Some(client.id)
+    }
+}
+
+/// The connected clients and the limits they are held to.
+#[derive(Debug)]
+pub(crate) struct Clients {
+    registry: Mutex<BTreeMap<u64, Arc<ClientHandle>>>,
+    connected: AtomicUsize,
+    max: AtomicUsize,
+    /// Seconds a client may stay idle, 0 is forever.
+    idle_timeout: AtomicU64,
+    normal_output_limit: Mutex<OutputLimit>,
+    /// Bytes a client may send ahead of the command being parsed.
+    query_buffer_limit: AtomicUsize,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Clients {
+            registry: Mutex::default(),
+            connected: AtomicUsize::new(0),
+            max: AtomicUsize::new(DEFAULT_MAXCLIENTS),
+            idle_timeout: AtomicU64::new(0),
+            normal_output_limit: Mutex::default(),
+            query_buffer_limit: AtomicUsize::new(DEFAULT_QUERY_BUFFER_LIMIT),
+        }
+    }
+}
+
+/// A connection counted against `maxclients` and listed by `CLIENT LIST`,
+/// dropping it frees the slot.
+#[derive(Debug)]
+pub(crate) struct ClientSlot {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.registry.lock().unwrap().remove(&self.id);
+        self.clients.connected.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Backend {
+    pub fn set_maxclients(&self, max: usize) {
+        self.clients.max.store(max, Ordering::Relaxed);
+    }
+
+    pub fn maxclients(&self) -> usize {
+        self.clients.max.load(Ordering::Relaxed)
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.clients.connected.load(Ordering::Acquire)
+    }
+
+    /// Close connections idle for longer than `timeout`, `None` never does.
+    /// Subscribers, blocked clients and replicas are not idle.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        let secs = timeout.map_or(0, |t| t.as_secs().max(1));
+        self.clients.idle_timeout.store(secs, Ordering::Relaxed);
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.clients.idle_timeout.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Close clients whose unparsed input grows beyond `limit` bytes, redis'
+    /// `client-query-buffer-limit`.
+    pub fn set_query_buffer_limit(&self, limit: usize) {
+        self.clients
+            .query_buffer_limit
+            .store(limit, Ordering::Relaxed);
+    }
+
+    pub fn query_buffer_limit(&self) -> usize {
+        self.clients.query_buffer_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_output_limit(&self, class: ClientClass, limit: OutputLimit) {
+        match class {
+            ClientClass::Normal => *self.clients.normal_output_limit.lock().unwrap() = limit,
+            ClientClass::Replica => self.repl.set_output_limit(limit),
+            ClientClass::PubSub => self.pubsub.set_output_limit(limit),
+        }
+    }
+
+    pub fn output_limit(&self, class: ClientClass) -> OutputLimit {
+        match class {
+            ClientClass::Normal => *self.clients.normal_output_limit.lock().unwrap(),
+            ClientClass::Replica => self.repl.output_limit(),
+            ClientClass::PubSub => self.pubsub.output_limit(),
+        }
+    }
+
+    /// Count and list a new connection, `None` when `maxclients` are
+    /// connected.
+    pub(crate) fn register_client(
+        &self,
+        client: &Arc<ClientHandle>,
+        addr: SocketAddr,
+        laddr: SocketAddr,
+    ) -> Option<ClientSlot> {
+        let max = self.maxclients();
+        self.clients
+            .connected
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        {
+            let mut stats = client.stats.lock().unwrap();
+            stats.addr = Some(addr);
+            stats.laddr = Some(laddr);
+        }
+        self.clients
+            .registry
+            .lock()
+            .unwrap()
+            .insert(client.id, client.clone());
+        Some(ClientSlot {
+            clients: self.clients.clone(),
+            id: client.id,
+        })
+    }
+
+    /// The connected clients, oldest first.
+    pub(crate) fn client_list(&self) -> Vec<Arc<ClientHandle>> {
+        self.clients
+            .registry
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Close the clients matching `filter`, returns how many.
+    pub fn kill_clients(&self, filter: &ClientFilter) -> usize {
+        let clients = self.client_list();
+        let mut killed = 0;
+        for client in clients.iter().filter(|c| filter.matches(c)) {
+            client.kill();
+            killed += 1;
+        }
+        killed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_limit_check() {
+        let limit = OutputLimit::new(100, 50, 2);
+        let mut check = OutputLimitCheck::default();
+        let start = Instant::now();
+        assert!(!check.exceeded_at(&limit, 40, start));
+        assert!(check.exceeded_at(&limit, 101, start));
+
+        // over the soft limit for longer than allowed
+        assert!(!check.exceeded_at(&limit, 60, start));
+        assert!(!check.exceeded_at(&limit, 60, start + Duration::from_secs(2)));
+        assert!(check.exceeded_at(&limit, 60, start + Duration::from_secs(3)));
+
+        // dropping below the soft limit starts over
+        assert!(!check.exceeded_at(&limit, 10, start + Duration::from_secs(4)));
+        assert!(!check.exceeded_at(&limit, 60, start + Duration::from_secs(5)));
+
+        let unlimited = OutputLimit::default();
+        assert!(!check.exceeded_at(&unlimited, usize::MAX, start));
+    }
+
+    fn register(backend: &Backend, id: u64, port: u16) -> Option<ClientSlot> {
+        let client = Arc::new(ClientHandle::new(id));
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let laddr = SocketAddr::from(([127, 0, 0, 1], 6379));
+        backend.register_client(&client, addr, laddr)
+    }
+
+    #[test]
+    fn test_maxclients() {
+        let backend = Backend::new();
+        backend.set_maxclients(2);
+        let a = register(&backend, 1, 1000).unwrap();
+        let _b = register(&backend, 2, 1001).unwrap();
+        assert!(register(&backend, 3, 1002).is_none());
+        drop(a);
+        assert_eq!(backend.connected_clients(), 1);
+        assert!(register(&backend, 4, 1003).is_some());
+    }
+
+    #[test]
+    fn test_kill_filters() {
+        let backend = Backend::new();
+        let _slots = (1..=3)
+            .map(|id| register(&backend, id, 1000 + id as u16))
+            .collect::<Vec<_>>();
+        let by_addr = ClientFilter {
+            addr: Some(SocketAddr::from(([127, 0, 0, 1], 1002))),
+            ..Default::default()
+        };
+        assert_eq!(backend.kill_clients(&by_addr), 1);
+        let others = ClientFilter {
+            class: Some(ClientClass::Normal),
+            skip_me: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(backend.kill_clients(&others), 2);
+        let killed = backend
+            .client_list()
+            .iter()
+            .map(|c| *c.killed.borrow())
+            .collect::<Vec<_>>();
+        assert_eq!(killed, [false, true, true]);
+    }
+}
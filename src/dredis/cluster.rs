@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+
+use crate::{crc16, Backend};
+
+/// The hash slots of a cluster, every key belongs to one of them.
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// The slot of `key`: the CRC16 of its hash tag, the part between the first
+/// `{` and the `}` after it when that is not empty, or else of the whole key.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        rest.iter()
+            .position(|&b| b == b'}')
+            .filter(|&close| close > 0)
+            .map(|close| &rest[..close])
+    });
+    crc16(tag.unwrap_or(key)) & (CLUSTER_SLOTS - 1)
+}
+
+/// A node of the cluster, a dredis server listening on `ip:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterNode {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl ClusterNode {
+    /// A node with an id derived from its address, so servers started on the
+    /// same topology agree on the ids without talking to each other.
+    pub fn new(ip: impl Into<String>, port: u16) -> Self {
+        let ip = ip.into();
+        let digest = Sha1::digest(format!("{}:{}", ip, port));
+        let id = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        ClusterNode { id, ip, port }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// The nodes of a static cluster and the slot each of them serves. The nodes
+/// started by one process share it, so moving a slot with `CLUSTER SETSLOT
+/// NODE` on one of them moves it for all.
+#[derive(Debug)]
+pub struct ClusterTopology {
+    nodes: Vec<ClusterNode>,
+    /// The index of the node serving each slot.
+    owners: RwLock<Vec<usize>>,
+}
+
+impl ClusterTopology {
+    /// `nodes` with the slots split among them in contiguous ranges, the
+    /// way `redis-cli --cluster create` assigns them.
+    pub fn new(nodes: Vec<ClusterNode>) -> Self {
+        assert!(!nodes.is_empty(), "a cluster has at least one node");
+        let per_node = CLUSTER_SLOTS as f64 / nodes.len() as f64;
+        let owners = (0..CLUSTER_SLOTS)
+            .map(|slot| ((slot as f64 + 0.5) / per_node) as usize)
+            .map(|node| node.min(nodes.len() - 1))
+            .collect();
+        ClusterTopology {
+            nodes,
+            owners: RwLock::new(owners),
+        }
+    }
+
+    pub fn nodes(&self) -> &[ClusterNode] {
+        &self.nodes
+    }
+
+    /// The index of the node serving `slot`.
+    pub fn owner(&self, slot: u16) -> usize {
+        self.owners.read().unwrap()[slot as usize]
+    }
+
+    /// The ranges of slots served by the same node as `(first, last, node)`,
+    /// in slot order.
+    pub fn slot_ranges(&self) -> Vec<(u16, u16, usize)> {
+        let owners = self.owners.read().unwrap();
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, &node) in owners.iter().enumerate() {
+            match ranges.last_mut() {
+                Some((_, last, owner)) if *owner == node => *last = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, node)),
+            }
+        }
+        ranges
+    }
+
+    fn node_index(&self, id: &str) -> Result<usize> {
+        self.nodes
+            .iter()
+            .position(|n| n.id == id)
+            .ok_or_else(|| anyhow!("ERR I don't know about node {}", id))
+    }
+}
+
+/// How `CLUSTER SETSLOT` changes a slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SetSlot {
+    /// Keys of the slot missing here are asked for on the node `id`.
+    Migrating(String),
+    /// Keys of the slot are served here to clients that sent `ASKING`.
+    Importing(String),
+    /// The slot is served by the node `id` from now on.
+    Node(String),
+    /// The slot neither migrates nor is imported anymore.
+    Stable,
+}
+
+/// A server's view of the cluster, `None` while cluster mode is disabled.
+#[derive(Debug, Default)]
+pub(crate) struct ClusterState {
+    node: RwLock<Option<ClusterNodeState>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ClusterNodeState {
+    pub(crate) topology: Arc<ClusterTopology>,
+    /// The index of this server among the nodes of the topology.
+    pub(crate) myself: usize,
+    /// The slots moving to another node, with the index of that node.
+    pub(crate) migrating: HashMap<u16, usize>,
+    /// The slots moving here from another node, with the index of that node.
+    pub(crate) importing: HashMap<u16, usize>,
+}
+
+impl ClusterNodeState {
+    pub(crate) fn me(&self) -> &ClusterNode {
+        &self.topology.nodes[self.myself]
+    }
+}
+
+impl Backend {
+    /// Run as the node `myself` of `topology`: commands whose keys belong to
+    /// slots served elsewhere are redirected there. Enable it once the
+    /// keyspace was loaded, loading is never redirected.
+    pub fn enable_cluster(&self, topology: Arc<ClusterTopology>, myself: usize) {
+        assert!(myself < topology.nodes.len(), "myself is one of the nodes");
+        *self.cluster.node.write().unwrap() = Some(ClusterNodeState {
+            topology,
+            myself,
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        });
+    }
+
+    pub fn cluster_enabled(&self) -> bool {
+        self.cluster.node.read().unwrap().is_some()
+    }
+
+    /// This node of the cluster, `None` while cluster mode is disabled.
+    pub(crate) fn cluster_node(&self) -> Option<ClusterNodeState> {
+        self.cluster.node.read().unwrap().clone()
+    }
+
+    /// Where a command with `keys` runs: the slot of its keys when they are
+    /// served here, `None` without keys or cluster mode. Otherwise the
+    /// error the client gets instead, a redirection for most. `asking` is
+    /// whether the client sent `ASKING` for this command.
+    pub(crate) fn cluster_route(
+        &self,
+        keys: &[&Bytes],
+        asking: bool,
+    ) -> Result<Option<u16>, String> {
+        let state = self.cluster.node.read().unwrap();
+        let Some(node) = state.as_ref() else {
+            return Ok(None);
+        };
+        let mut slot = None;
+        for key in keys {
+            let key_slot = key_hash_slot(key);
+            if slot.is_some_and(|slot| slot != key_slot) {
+                return Err("CROSSSLOT Keys in request don't hash to the same slot".into());
+            }
+            slot = Some(key_slot);
+        }
+        let Some(slot) = slot else {
+            return Ok(None);
+        };
+        let missing = || {
+            keys.iter()
+                .filter(|k| !self.peek(k, |e| e.is_some()))
+                .count()
+        };
+        let topology = &node.topology;
+        let owner = topology.owner(slot);
+        if owner == node.myself {
+            if let Some(&target) = node.migrating.get(&slot) {
+                // the keys that are gone here went to the target already
+                match missing() {
+                    0 => {}
+                    n if n == keys.len() => {
+                        return Err(format!("ASK {} {}", slot, topology.nodes[target].addr()))
+                    }
+                    _ => {
+                        return Err("TRYAGAIN Multiple keys request during rehashing of slot".into())
+                    }
+                }
+            }
+            return Ok(Some(slot));
+        }
+        if asking && node.importing.contains_key(&slot) {
+            if keys.len() > 1 && missing() > 0 {
+                return Err("TRYAGAIN Multiple keys request during rehashing of slot".into());
+            }
+            return Ok(Some(slot));
+        }
+        Err(format!("MOVED {} {}", slot, topology.nodes[owner].addr()))
+    }
+
+    /// Up to `count` keys of `slot` in the current database.
+    pub(crate) fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Bytes> {
+        let now = self.now_ms();
+        self.db()
+            .data
+            .iter()
+            .filter(|e| !e.value().is_expired(now) && key_hash_slot(e.key()) == slot)
+            .take(count)
+            .map(|e| e.key().clone())
+            .collect()
+    }
+
+    /// `CLUSTER SETSLOT`, checked like redis does.
+    pub(crate) fn cluster_setslot(&self, slot: u16, change: SetSlot) -> Result<()> {
+        let mut state = self.cluster.node.write().unwrap();
+        let Some(node) = state.as_mut() else {
+            bail!("ERR This instance has cluster support disabled");
+        };
+        let topology = node.topology.clone();
+        let owner = topology.owner(slot);
+        match change {
+            SetSlot::Migrating(id) => {
+                if owner != node.myself {
+                    bail!("ERR I'm not the owner of hash slot {}", slot);
+                }
+                let target = topology.node_index(&id)?;
+                if target == node.myself {
+                    bail!("ERR I'm the owner of hash slot {}", slot);
+                }
+                node.migrating.insert(slot, target);
+            }
+            SetSlot::Importing(id) => {
+                if owner == node.myself {
+                    bail!("ERR I'm already the owner of hash slot {}", slot);
+                }
+                let source = topology.node_index(&id)?;
+                node.importing.insert(slot, source);
+            }
+            SetSlot::Node(id) => {
+                let new_owner = topology.node_index(&id)?;
+                if owner == node.myself
+                    && new_owner != node.myself
+                    && !self.keys_in_slot(slot, 1).is_empty()
+                {
+                    bail!("ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.", slot);
+                }
+                if new_owner != node.myself {
+                    node.migrating.remove(&slot);
+                } else {
+                    node.importing.remove(&slot);
+                }
+                topology.owners.write().unwrap()[slot as usize] = new_owner;
+            }
+            SetSlot::Stable => {
+                node.migrating.remove(&slot);
+                node.importing.remove(&slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// `CLUSTER NODES`, a line per node in the format of redis' nodes.conf.
+    pub(crate) fn cluster_nodes(&self) -> Option<String> {
+        let node = self.cluster_node()?;
+        let topology = &node.topology;
+        let ranges = topology.slot_ranges();
+        let mut out = String::new();
+        for (index, n) in topology.nodes.iter().enumerate() {
+            let myself = index == node.myself;
+            let _ = write!(
+                out,
+                "{} {}@{} {} - 0 0 {} connected",
+                n.id,
+                n.addr(),
+                n.port as u32 + 10000,
+                if myself { "myself,master" } else { "master" },
+                index + 1
+            );
+            for &(first, last, owner) in &ranges {
+                match (owner == index, first == last) {
+                    (false, _) => {}
+                    (true, true) => {
+                        let _ = write!(out, " {}", first);
+                    }
+                    (true, false) => {
+                        let _ = write!(out, " {}-{}", first, last);
+                    }
+                }
+            }
+            if myself {
+                for (slot, target) in sorted(&node.migrating) {
+                    let _ = write!(out, " [{}->-{}]", slot, topology.nodes[target].id);
+                }
+                for (slot, source) in sorted(&node.importing) {
+                    let _ = write!(out, " [{}-<-{}]", slot, topology.nodes[source].id);
+                }
+            }
+            out.push('\n');
+        }
+        Some(out)
+    }
+
+    /// `CLUSTER INFO`, every slot is always served by a node that is up.
+    pub(crate) fn cluster_info(&self) -> Option<String> {
+        let node = self.cluster_node()?;
+        let ranges = node.topology.slot_ranges();
+        let mut serving = ranges.iter().map(|r| r.2).collect::<Vec<_>>();
+        serving.sort_unstable();
+        serving.dedup();
+        Some(format!(
+            "cluster_state:ok\r\ncluster_slots_assigned:{slots}\r\ncluster_slots_ok:{slots}\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\ncluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            node.topology.nodes.len(),
+            serving.len(),
+            node.topology.nodes.len(),
+            node.myself + 1,
+            slots = CLUSTER_SLOTS,
+        ))
+    }
+
+    pub(crate) fn info_cluster(&self) -> String {
+        format!(
+            "# Cluster\r\ncluster_enabled:{}\r\n",
+            self.cluster_enabled() as u8
+        )
+    }
+}
+
+fn sorted(slots: &HashMap<u16, usize>) -> Vec<(u16, usize)> {
+    let mut slots = slots.iter().map(|(&s, &n)| (s, n)).collect::<Vec<_>>();
+    slots.sort_unstable();
+    slots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(ports: &[u16]) -> Arc<ClusterTopology> {
+        let nodes = ports
+            .iter()
+            .map(|&port| ClusterNode::new("127.0.0.1", port))
+            .collect();
+        Arc::new(ClusterTopology::new(nodes))
+    }
+
+    #[test]
+    fn test_key_hash_slot() {
+        // the examples of the redis cluster specification
+        assert_eq!(key_hash_slot(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"{user1000}.followers")
+        );
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"user1000")
+        );
+        // only the first tag counts and an empty one hashes the whole key
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & 16383);
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        assert_eq!(key_hash_slot(b"foo{bar"), crc16(b"foo{bar") & 16383);
+    }
+
+    #[test]
+    fn test_slots_split_like_redis_cli() {
+        let t = topology(&[7000, 7001, 7002]);
+        assert_eq!(
+            t.slot_ranges(),
+            vec![(0, 5460, 0), (5461, 10922, 1), (10923, 16383, 2)]
+        );
+        assert_eq!(topology(&[7000]).slot_ranges(), vec![(0, 16383, 0)]);
+        assert_eq!(t.nodes()[0].id.len(), 40);
+        assert_eq!(t.nodes()[0], ClusterNode::new("127.0.0.1", 7000));
+        assert_ne!(t.nodes()[0].id, t.nodes()[1].id);
+    }
+
+    #[test]
+    fn test_route() {
+        let t = topology(&[7000, 7001]);
+        let a = Backend::new();
+        let b = Backend::new();
+        assert_eq!(a.cluster_route(&[&"foo".into()], false), Ok(None));
+        a.enable_cluster(t.clone(), 0);
+        b.enable_cluster(t.clone(), 1);
+
+        let (here, there) = (Bytes::from("a"), Bytes::from("foo"));
+        assert_eq!(key_hash_slot(&here), 15495);
+        assert_eq!(b.cluster_route(&[&here], false), Ok(Some(15495)));
+        assert_eq!(
+            a.cluster_route(&[&here], false),
+            Err("MOVED 15495 127.0.0.1:7001".into())
+        );
+        assert_eq!(a.cluster_route(&[], false), Ok(None));
+        assert_eq!(
+            b.cluster_route(&[&here, &there], false),
+            Err("CROSSSLOT Keys in request don't hash to the same slot".into())
+        );
+
+        // slot 15495 moves from b to a
+        let (a_id, b_id) = (t.nodes()[0].id.clone(), t.nodes()[1].id.clone());
+        b.write(&here, |e| {
+            *e = Some(crate::Entry::new(crate::Value::String("v".into())))
+        });
+        b.cluster_setslot(15495, SetSlot::Migrating(a_id.clone()))
+            .unwrap();
+        a.cluster_setslot(15495, SetSlot::Importing(b_id.clone()))
+            .unwrap();
+        assert_eq!(b.cluster_route(&[&here], false), Ok(Some(15495)));
+        let gone = Bytes::from("{a}gone");
+        assert_eq!(
+            b.cluster_route(&[&gone], false),
+            Err("ASK 15495 127.0.0.1:7000".into())
+        );
+        assert_eq!(
+            b.cluster_route(&[&here, &gone], false),
+            Err("TRYAGAIN Multiple keys request during rehashing of slot".into())
+        );
+        assert_eq!(
+            a.cluster_route(&[&gone], false),
+            Err("MOVED 15495 127.0.0.1:7001".into())
+        );
+        assert_eq!(a.cluster_route(&[&gone], true), Ok(Some(15495)));
+        assert!(b
+            .cluster_nodes()
+            .unwrap()
+            .contains(&format!("[15495->-{}]", a_id)));
+        assert!(a
+            .cluster_nodes()
+            .unwrap()
+            .contains(&format!("[15495-<-{}]", b_id)));
+
+        assert!(b
+            .cluster_setslot(15495, SetSlot::Node(a_id.clone()))
+            .is_err());
+        b.remove(&here);
+        b.cluster_setslot(15495, SetSlot::Node(a_id.clone()))
+            .unwrap();
+        a.cluster_setslot(15495, SetSlot::Node(a_id)).unwrap();
+        assert_eq!(a.cluster_route(&[&here], false), Ok(Some(15495)));
+        assert_eq!(
+            b.cluster_route(&[&here], false),
+            Err("MOVED 15495 127.0.0.1:7000".into())
+        );
+        assert!(!a.cluster_nodes().unwrap().contains('['));
+        assert!(!b.cluster_nodes().unwrap().contains('['));
+    }
+}
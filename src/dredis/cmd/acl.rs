@@ -0,0 +1,177 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{all_commands, arg_str};
+use crate::{category, RespFrame, Session, DEFAULT_USER};
+
+/// `ACL SETUSER | DELUSER | WHOAMI | LIST | USERS | CAT`
+pub(super) fn acl(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("setuser", 3..) => {
+            let rules = args[3..]
+                .iter()
+                .map(|r| arg_str(r))
+                .collect::<Result<Vec<_>>>()?;
+            session.backend.acl_setuser(arg_str(&args[2])?, &rules)?;
+            Ok(RespFrame::ok())
+        }
+        ("deluser", 3..) => {
+            let names = args[2..]
+                .iter()
+                .map(|n| arg_str(n))
+                .collect::<Result<Vec<_>>>()?;
+            let removed = session.backend.acl_deluser(&names)?;
+            Ok(RespFrame::Integer(removed as i64))
+        }
+        ("whoami", 2) => Ok(RespFrame::bulk(
+            session.user.as_deref().unwrap_or(DEFAULT_USER).to_string(),
+        )),
+        ("list", 2) => Ok(bulk_array(session.backend.acl_list())),
+        ("users", 2) => Ok(bulk_array(session.backend.acl_users())),
+        ("cat", 2) => Ok(bulk_array(
+            category::NAMES.iter().map(|(name, _)| name.to_string()),
+        )),
+        ("cat", 3) => {
+            let name = arg_str(&args[2])?;
+            let bit =
+                category::lookup(name).ok_or_else(|| anyhow!("ERR Unknown category '{}'", name))?;
+            Ok(bulk_array(
+                all_commands()
+                    .iter()
+                    .filter(|c| c.categories() & bit != 0)
+                    .map(|c| c.name.to_string()),
+            ))
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try ACL HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+fn bulk_array(items: impl IntoIterator<Item = String>) -> RespFrame {
+    RespFrame::Array(items.into_iter().map(RespFrame::bulk).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute, Backend};
+
+    fn run(s: &mut Session, args: &[&str]) -> RespFrame {
+        let args = args
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect::<Vec<_>>();
+        execute(s, &args)
+    }
+
+    /// A session the way a network client starts out.
+    fn client(backend: &Backend) -> Session {
+        let mut s = Session::new(backend.clone());
+        s.user = Some(DEFAULT_USER.to_string());
+        s.authenticated = backend.default_user_nopass();
+        s
+    }
+
+    #[test]
+    fn test_requirepass_and_auth() {
+        let backend = Backend::new();
+        backend.set_requirepass("s3cret");
+        let mut s = client(&backend);
+        assert_eq!(
+            run(&mut s, &["GET", "k"]),
+            RespFrame::error("NOAUTH Authentication required.")
+        );
+        assert!(matches!(run(&mut s, &["HELLO", "3"]), RespFrame::Error(_)));
+        assert_eq!(
+            run(&mut s, &["AUTH", "wrong"]),
+            RespFrame::error("WRONGPASS invalid username-password pair or user is disabled.")
+        );
+        assert_eq!(run(&mut s, &["AUTH", "s3cret"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::NullBulkString);
+
+        let mut other = client(&backend);
+        assert!(matches!(
+            run(&mut other, &["HELLO", "3", "AUTH", "default", "s3cret"]),
+            RespFrame::Map(_)
+        ));
+        assert_eq!(
+            run(&mut other, &["ACL", "WHOAMI"]),
+            RespFrame::bulk("default")
+        );
+    }
+
+    #[test]
+    fn test_user_permissions() {
+        let backend = Backend::new();
+        let mut admin = client(&backend);
+        assert_eq!(
+            run(
+                &mut admin,
+                &["ACL", "SETUSER", "svc", "on", ">pw", "~svc:*", "+@read", "+multi", "+exec"]
+            ),
+            RespFrame::ok()
+        );
+        let mut s = client(&backend);
+        assert_eq!(run(&mut s, &["AUTH", "svc", "pw"]), RespFrame::ok());
+        assert!(matches!(
+            run(&mut s, &["ACL", "WHOAMI"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(run(&mut s, &["GET", "svc:1"]), RespFrame::NullBulkString);
+        assert_eq!(
+            run(&mut s, &["GET", "other"]),
+            RespFrame::error("NOPERM No permissions to access a key")
+        );
+        // a refused command aborts the transaction
+        run(&mut s, &["MULTI"]);
+        assert_eq!(
+            run(&mut s, &["SET", "svc:1", "v"]),
+            RespFrame::error("NOPERM User svc has no permissions to run the 'set' command")
+        );
+        assert!(
+            matches!(run(&mut s, &["EXEC"]), RespFrame::Error(e) if e.starts_with("EXECABORT"))
+        );
+
+        assert_eq!(
+            run(&mut admin, &["ACL", "USERS"]),
+            RespFrame::array([RespFrame::bulk("default"), RespFrame::bulk("svc")])
+        );
+        assert!(matches!(
+            run(&mut admin, &["ACL", "LIST"]),
+            RespFrame::Array(lines) if lines.len() == 2
+        ));
+        assert_eq!(
+            run(&mut admin, &["ACL", "DELUSER", "svc"]),
+            RespFrame::Integer(1)
+        );
+        assert!(matches!(
+            run(&mut s, &["GET", "svc:1"]),
+            RespFrame::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_cat() {
+        let mut s = client(&Backend::new());
+        let RespFrame::Array(names) = run(&mut s, &["ACL", "CAT"]) else {
+            panic!("not an array");
+        };
+        assert!(names.contains(&RespFrame::bulk("sortedset")));
+        let RespFrame::Array(cmds) = run(&mut s, &["ACL", "CAT", "blocking"]) else {
+            panic!("not an array");
+        };
+        assert_eq!(
+            cmds,
+            ["blpop", "brpop", "blmove", "xread", "xreadgroup"]
+                .map(RespFrame::bulk)
+                .to_vec()
+        );
+        assert!(matches!(
+            run(&mut s, &["ACL", "CAT", "nope"]),
+            RespFrame::Error(_)
+        ));
+    }
+}
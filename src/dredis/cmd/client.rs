@@ -0,0 +1,275 @@
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, err_syntax};
+use crate::{ClientClass, ClientFilter, RespFrame, Session};
+
+/// `CLIENT LIST | INFO | SETNAME | GETNAME | ID | KILL`
+pub(super) fn client(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("list", _) => list(session, &args[2..]),
+        ("info", 2) => {
+            session.client.update(session);
+            Ok(RespFrame::VerbatimString(
+                "txt".into(),
+                format!("{}\n", session.client.describe()).into(),
+            ))
+        }
+        ("setname", 3) => {
+            let name = arg_str(&args[2])?;
+            check_client_name(name)?;
+            session.name = (!name.is_empty()).then(|| name.to_string());
+            Ok(RespFrame::ok())
+        }
+        ("getname", 2) => Ok(match &session.name {
+            Some(name) => RespFrame::bulk(name.clone()),
+            None => RespFrame::NullBulkString,
+        }),
+        ("id", 2) => Ok(RespFrame::Integer(session.id as i64)),
+        ("kill", 3) => {
+            // the old form, closes the client at ip:port
+            let filter = ClientFilter {
+                addr: Some(parse_addr(&args[2])?),
+                ..Default::default()
+            };
+            match session.backend.kill_clients(&filter) {
+                0 => Err(anyhow!("ERR No such client")),
+                _ => Ok(RespFrame::ok()),
+            }
+        }
+        ("kill", n) if n >= 4 && n.is_multiple_of(2) => {
+            let filter = parse_kill_filter(session, &args[2..])?;
+            Ok(RespFrame::Integer(
+                session.backend.kill_clients(&filter) as i64
+            ))
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try CLIENT HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+/// `CLIENT LIST [TYPE NORMAL|MASTER|REPLICA|PUBSUB] [ID client-id ...]`
+fn list(session: &Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut class = None;
+    let mut ids = None;
+    match args {
+        [] => {}
+        [opt, name] if arg_str(opt)?.eq_ignore_ascii_case("type") => {
+            class = Some(parse_class(name)?);
+        }
+        [opt, rest @ ..] if !rest.is_empty() && arg_str(opt)?.eq_ignore_ascii_case("id") => {
+            let parsed = rest
+                .iter()
+                .map(|id| parse_id(id))
+                .collect::<Result<Vec<_>>>()?;
+            ids = Some(parsed);
+        }
+        _ => return Err(err_syntax()),
+    }
+    session.client.update(session);
+    let mut lines = String::new();
+    for client in session.backend.client_list() {
+        let filter = ClientFilter {
+            class,
+            ..Default::default()
+        };
+        if !filter.matches(&client) || ids.as_ref().is_some_and(|ids| !ids.contains(&client.id)) {
+            continue;
+        }
+        lines.push_str(&client.describe());
+        lines.push('\n');
+    }
+    Ok(RespFrame::VerbatimString("txt".into(), lines.into()))
+}
+
+/// The filters of `CLIENT KILL <filter> <value> ...`.
+fn parse_kill_filter(session: &Session, args: &[Bytes]) -> Result<ClientFilter> {
+    let mut filter = ClientFilter {
+        skip_me: Some(session.id),
+        ..Default::default()
+    };
+    for pair in args.chunks(2) {
+        let value = &pair[1];
+        match arg_str(&pair[0])?.to_ascii_lowercase().as_str() {
+            "id" => filter.id = Some(parse_id(value)?),
+            "addr" => filter.addr = Some(parse_addr(value)?),
+            "laddr" => filter.laddr = Some(parse_addr(value)?),
+            "type" => filter.class = Some(parse_class(value)?),
+            "user" => filter.user = Some(arg_str(value)?.to_string()),
+            "skipme" => {
+                filter.skip_me = match arg_str(value)?.to_ascii_lowercase().as_str() {
+                    "yes" => Some(session.id),
+                    "no" => None,
+                    _ => return Err(err_syntax()),
+                }
+            }
+            _ => return Err(err_syntax()),
+        }
+    }
+    Ok(filter)
+}
+
+/// Names show up in `CLIENT LIST` lines, which are split at spaces.
+pub(super) fn check_client_name(name: &str) -> Result<()> {
+    if name.bytes().all(|b| (b'!'..=b'~').contains(&b)) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "ERR Client names cannot contain spaces, newlines or special characters."
+        ))
+    }
+}
+
+fn parse_class(arg: &[u8]) -> Result<ClientClass> {
+    let name = arg_str(arg)?;
+    name.parse()
+        .map_err(|_| anyhow!("ERR Unknown client type '{}'", name))
+}
+
+fn parse_id(arg: &[u8]) -> Result<u64> {
+    match arg_i64(arg) {
+        Ok(id) if id > 0 => Ok(id as u64),
+        _ => Err(anyhow!("ERR Invalid client ID")),
+    }
+}
+
+fn parse_addr(arg: &[u8]) -> Result<SocketAddr> {
+    arg_str(arg)?
+        .parse()
+        .map_err(|_| anyhow!("ERR No such client"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{execute, Backend, ClientSlot};
+
+    fn run(s: &mut Session, args: &[&str]) -> RespFrame {
+        let args = args
+            .iter()
+            .map(|a| Bytes::from(a.to_string()))
+            .collect::<Vec<_>>();
+        execute(s, &args)
+    }
+
+    fn text(frame: RespFrame) -> String {
+        match frame {
+            RespFrame::VerbatimString(_, text) => String::from_utf8(text.to_vec()).unwrap(),
+            other => panic!("not a verbatim string: {:?}", other),
+        }
+    }
+
+    fn connect(backend: &Backend, port: u16) -> (Session, ClientSlot) {
+        let s = Session::new(backend.clone());
+        let client = Arc::clone(&s.client);
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let laddr = SocketAddr::from(([127, 0, 0, 1], 6379));
+        let slot = backend.register_client(&client, addr, laddr).unwrap();
+        (s, slot)
+    }
+
+    #[test]
+    fn test_setname_getname_id() {
+        let backend = Backend::new();
+        let (mut s, _slot) = connect(&backend, 5000);
+        assert_eq!(
+            run(&mut s, &["CLIENT", "GETNAME"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(
+            run(&mut s, &["CLIENT", "SETNAME", "worker-1"]),
+            RespFrame::ok()
+        );
+        assert_eq!(
+            run(&mut s, &["CLIENT", "GETNAME"]),
+            RespFrame::bulk("worker-1")
+        );
+        assert!(matches!(
+            run(&mut s, &["CLIENT", "SETNAME", "bad name"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(run(&mut s, &["CLIENT", "SETNAME", ""]), RespFrame::ok());
+        assert_eq!(
+            run(&mut s, &["CLIENT", "GETNAME"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(
+            run(&mut s, &["CLIENT", "ID"]),
+            RespFrame::Integer(s.id as i64)
+        );
+    }
+
+    #[test]
+    fn test_list_and_info() {
+        let backend = Backend::new();
+        let (mut a, _a) = connect(&backend, 5000);
+        let (mut b, _b) = connect(&backend, 5001);
+        run(&mut a, &["CLIENT", "SETNAME", "alice"]);
+        run(&mut b, &["MULTI"]);
+        run(&mut b, &["SET", "k", "v"]);
+        // as the connection does after every reply
+        b.client.update(&b);
+
+        let info = text(run(&mut a, &["CLIENT", "INFO"]));
+        assert!(info.starts_with(&format!(
+            "id={} addr=127.0.0.1:5000 laddr=127.0.0.1:6379 name=alice age=0 idle=0 flags=N db=0",
+            a.id
+        )));
+        assert!(info.ends_with("cmd=client user=default resp=2\n"));
+
+        let list = text(run(&mut a, &["CLIENT", "LIST"]));
+        let lines = list.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("flags=x"));
+        assert!(lines[1].contains("multi=1"));
+        assert!(lines[1].contains("cmd=set"));
+
+        let only_b = text(run(&mut a, &["CLIENT", "LIST", "ID", &b.id.to_string()]));
+        assert_eq!(only_b.lines().count(), 1);
+        let pubsub = text(run(&mut a, &["CLIENT", "LIST", "TYPE", "pubsub"]));
+        assert_eq!(pubsub, "");
+        assert!(matches!(
+            run(&mut a, &["CLIENT", "LIST", "TYPE", "nope"]),
+            RespFrame::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_kill() {
+        let backend = Backend::new();
+        let (mut a, _a) = connect(&backend, 5000);
+        let (b, _b) = connect(&backend, 5001);
+        let a_id = a.id.to_string();
+        assert_eq!(
+            run(&mut a, &["CLIENT", "KILL", "127.0.0.1:5001"]),
+            RespFrame::ok()
+        );
+        assert!(matches!(
+            run(&mut a, &["CLIENT", "KILL", "127.0.0.1:5002"]),
+            RespFrame::Error(_)
+        ));
+        // skips itself by default
+        assert_eq!(
+            run(&mut a, &["CLIENT", "KILL", "TYPE", "normal"]),
+            RespFrame::Integer(1)
+        );
+        assert_eq!(
+            run(&mut a, &["CLIENT", "KILL", "ID", &a_id, "SKIPME", "no"]),
+            RespFrame::Integer(1)
+        );
+        assert_eq!(
+            run(
+                &mut a,
+                &["CLIENT", "KILL", "ID", &b.id.to_string(), "bogus", "x"]
+            ),
+            RespFrame::error("ERR syntax error")
+        );
+    }
+}
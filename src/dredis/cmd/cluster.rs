@@ -0,0 +1,370 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, CommandSpec};
+use crate::{key_hash_slot, ClusterNode, RespFrame, Session, SetSlot, CLUSTER_SLOTS};
+
+/// `CLUSTER KEYSLOT | SLOTS | SHARDS | NODES | INFO | MYID | COUNTKEYSINSLOT
+/// | GETKEYSINSLOT | SETSLOT`
+pub(super) fn cluster(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let backend = &session.backend;
+    let Some(node) = backend.cluster_node() else {
+        return Err(anyhow!("ERR This instance has cluster support disabled"));
+    };
+    let topology = &node.topology;
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("keyslot", 3) => Ok(RespFrame::Integer(key_hash_slot(&args[2]) as i64)),
+        ("slots", 2) => Ok(RespFrame::Array(
+            topology
+                .slot_ranges()
+                .into_iter()
+                .map(|(first, last, owner)| {
+                    RespFrame::array([
+                        RespFrame::Integer(first as i64),
+                        RespFrame::Integer(last as i64),
+                        slots_node(&topology.nodes()[owner]),
+                    ])
+                })
+                .collect(),
+        )),
+        ("shards", 2) => {
+            let ranges = topology.slot_ranges();
+            Ok(RespFrame::Array(
+                topology
+                    .nodes()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, n)| {
+                        let slots = ranges
+                            .iter()
+                            .filter(|r| r.2 == index)
+                            .flat_map(|r| [r.0, r.1])
+                            .map(|slot| RespFrame::Integer(slot as i64))
+                            .collect::<Vec<_>>();
+                        RespFrame::map([
+                            (RespFrame::bulk("slots"), RespFrame::Array(slots)),
+                            (RespFrame::bulk("nodes"), RespFrame::array([shards_node(n)])),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        ("nodes", 2) => Ok(RespFrame::VerbatimString(
+            "txt".into(),
+            backend.cluster_nodes().unwrap_or_default().into(),
+        )),
+        ("info", 2) => Ok(RespFrame::VerbatimString(
+            "txt".into(),
+            backend.cluster_info().unwrap_or_default().into(),
+        )),
+        ("myid", 2) => Ok(RespFrame::bulk(node.me().id.clone())),
+        ("countkeysinslot", 3) => {
+            let slot = arg_slot(&args[2])?;
+            let keys = backend.keys_in_slot(slot, usize::MAX);
+            Ok(RespFrame::Integer(keys.len() as i64))
+        }
+        ("getkeysinslot", 4) => {
+            let slot = arg_slot(&args[2])?;
+            let count = usize::try_from(arg_i64(&args[3])?)
+                .map_err(|_| anyhow!("ERR Invalid number of keys"))?;
+            Ok(RespFrame::Array(
+                backend
+                    .keys_in_slot(slot, count)
+                    .into_iter()
+                    .map(RespFrame::BulkString)
+                    .collect(),
+            ))
+        }
+        ("setslot", 4 | 5) => {
+            let slot = arg_slot(&args[2])?;
+            let action = arg_str(&args[3])?.to_ascii_lowercase();
+            let id = args.get(4).map(|id| arg_str(id).map(str::to_string));
+            let change = match (action.as_str(), id) {
+                ("migrating", Some(id)) => SetSlot::Migrating(id?),
+                ("importing", Some(id)) => SetSlot::Importing(id?),
+                ("node", Some(id)) => SetSlot::Node(id?),
+                ("stable", None) => SetSlot::Stable,
+                _ => {
+                    return Err(anyhow!(
+                    "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                ))
+                }
+            };
+            backend.cluster_setslot(slot, change)?;
+            Ok(RespFrame::ok())
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try CLUSTER HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+/// `ASKING`, lets the next command use a slot this node is importing.
+pub(super) fn asking(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    if !session.backend.cluster_enabled() {
+        return Err(anyhow!("ERR This instance has cluster support disabled"));
+    }
+    session.asking = true;
+    Ok(RespFrame::ok())
+}
+
+/// Check that the keys of `cmd` are served by this node and, inside
+/// `MULTI`, that they are in the slot of the keys queued before.
+pub(super) fn check_slot(
+    session: &mut Session,
+    cmd: &CommandSpec,
+    args: &[Bytes],
+    asking: bool,
+) -> Result<(), String> {
+    let keys = cmd.key_args(args).collect::<Vec<_>>();
+    let Some(slot) = session.backend.cluster_route(&keys, asking)? else {
+        return Ok(());
+    };
+    if let Some(tx) = session.multi.as_mut() {
+        match tx.slot {
+            Some(queued) if queued != slot => {
+                return Err("CROSSSLOT Keys in request don't hash to the same slot".into())
+            }
+            _ => tx.slot = Some(slot),
+        }
+    }
+    Ok(())
+}
+
+fn arg_slot(arg: &[u8]) -> Result<u16> {
+    arg_i64(arg)
+        .ok()
+        .and_then(|slot| u16::try_from(slot).ok())
+        .filter(|&slot| slot < CLUSTER_SLOTS)
+        .ok_or_else(|| anyhow!("ERR Invalid or out of range slot"))
+}
+
+fn slots_node(node: &ClusterNode) -> RespFrame {
+    RespFrame::array([
+        RespFrame::bulk(node.ip.clone()),
+        RespFrame::Integer(node.port as i64),
+        RespFrame::bulk(node.id.clone()),
+        RespFrame::map([]),
+    ])
+}
+
+fn shards_node(node: &ClusterNode) -> RespFrame {
+    RespFrame::map([
+        (RespFrame::bulk("id"), RespFrame::bulk(node.id.clone())),
+        (
+            RespFrame::bulk("port"),
+            RespFrame::Integer(node.port as i64),
+        ),
+        (RespFrame::bulk("ip"), RespFrame::bulk(node.ip.clone())),
+        (
+            RespFrame::bulk("endpoint"),
+            RespFrame::bulk(node.ip.clone()),
+        ),
+        (RespFrame::bulk("role"), RespFrame::bulk("master")),
+        (RespFrame::bulk("replication-offset"), RespFrame::Integer(0)),
+        (RespFrame::bulk("health"), RespFrame::bulk("online")),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::dredis::cmd::run;
+    use crate::{Backend, ClusterNode, ClusterTopology, RespFrame, Session};
+
+    /// A session on each node of a cluster on ports 7000 and 7001.
+    fn cluster() -> (Session, Session) {
+        let nodes = [7000, 7001].map(|port| ClusterNode::new("127.0.0.1", port));
+        let topology = Arc::new(ClusterTopology::new(nodes.to_vec()));
+        let sessions = [0, 1].map(|myself| {
+            let backend = Backend::new();
+            backend.enable_cluster(topology.clone(), myself);
+            Session::new(backend)
+        });
+        let [a, b] = sessions;
+        (a, b)
+    }
+
+    fn id(port: u16) -> String {
+        ClusterNode::new("127.0.0.1", port).id
+    }
+
+    #[test]
+    fn test_redirections() {
+        let (mut a, mut b) = cluster();
+        // "foo" is in slot 12182 served by b, "bar" in 5061 served by a
+        assert_eq!(
+            run(&mut a, &["CLUSTER", "KEYSLOT", "foo"]),
+            RespFrame::Integer(12182)
+        );
+        assert_eq!(
+            run(&mut a, &["SET", "foo", "v"]),
+            RespFrame::error("MOVED 12182 127.0.0.1:7001")
+        );
+        assert_eq!(run(&mut b, &["SET", "foo", "v"]), RespFrame::ok());
+        assert_eq!(run(&mut a, &["SET", "bar", "v"]), RespFrame::ok());
+        assert_eq!(
+            run(&mut a, &["MSET", "bar", "1", "foo", "2"]),
+            RespFrame::error("CROSSSLOT Keys in request don't hash to the same slot")
+        );
+        assert_eq!(
+            run(&mut b, &["MSET", "{foo}a", "1", "{foo}b", "2"]),
+            RespFrame::ok()
+        );
+        // commands without keys run anywhere
+        assert_eq!(run(&mut a, &["PING"]), RespFrame::simple("PONG"));
+        assert_eq!(
+            run(&mut a, &["SELECT", "1"]),
+            RespFrame::error("ERR SELECT is not allowed in cluster mode")
+        );
+
+        // a transaction sticks to one slot
+        run(&mut b, &["MULTI"]);
+        run(&mut b, &["GET", "foo"]);
+        assert_eq!(run(&mut b, &["GET", "{foo}a"]), RespFrame::simple("QUEUED"));
+        assert_eq!(
+            run(&mut b, &["GET", "a"]),
+            RespFrame::error("CROSSSLOT Keys in request don't hash to the same slot")
+        );
+        assert!(
+            matches!(run(&mut b, &["EXEC"]), RespFrame::Error(e) if e.starts_with("EXECABORT"))
+        );
+
+        // scripts only reach keys served here
+        assert_eq!(
+            run(
+                &mut a,
+                &["EVAL", "return redis.call('GET', KEYS[1])", "1", "foo"]
+            ),
+            RespFrame::error("MOVED 12182 127.0.0.1:7001")
+        );
+        assert_eq!(
+            run(&mut a, &["EVAL", "return redis.call('GET', 'foo')", "0"]),
+            RespFrame::error("ERR Script attempted to access a non local key in a cluster node")
+        );
+    }
+
+    #[test]
+    fn test_ask_while_migrating() {
+        let (mut a, mut b) = cluster();
+        run(&mut b, &["SET", "foo", "v"]);
+        let setslot = |s: &mut Session, action: &str, port: u16| {
+            run(s, &["CLUSTER", "SETSLOT", "12182", action, &id(port)])
+        };
+        assert_eq!(
+            setslot(&mut a, "MIGRATING", 7001),
+            RespFrame::error("ERR I'm not the owner of hash slot 12182")
+        );
+        assert_eq!(setslot(&mut b, "MIGRATING", 7000), RespFrame::ok());
+        assert_eq!(setslot(&mut a, "IMPORTING", 7001), RespFrame::ok());
+
+        // keys still here are served, the others are asked for on a
+        assert_eq!(run(&mut b, &["GET", "foo"]), RespFrame::bulk("v"));
+        assert_eq!(
+            run(&mut b, &["GET", "{foo}new"]),
+            RespFrame::error("ASK 12182 127.0.0.1:7000")
+        );
+        assert_eq!(
+            run(&mut a, &["SET", "{foo}new", "v"]),
+            RespFrame::error("MOVED 12182 127.0.0.1:7001")
+        );
+        assert_eq!(run(&mut a, &["ASKING"]), RespFrame::ok());
+        assert_eq!(run(&mut a, &["SET", "{foo}new", "v"]), RespFrame::ok());
+        // ASKING only holds for one command
+        assert_eq!(
+            run(&mut a, &["GET", "{foo}new"]),
+            RespFrame::error("MOVED 12182 127.0.0.1:7001")
+        );
+        assert_eq!(
+            run(&mut a, &["CLUSTER", "GETKEYSINSLOT", "12182", "10"]),
+            RespFrame::array([RespFrame::bulk("{foo}new")])
+        );
+
+        run(&mut b, &["DEL", "foo"]);
+        assert_eq!(setslot(&mut b, "NODE", 7000), RespFrame::ok());
+        assert_eq!(setslot(&mut a, "NODE", 7000), RespFrame::ok());
+        assert_eq!(run(&mut a, &["GET", "{foo}new"]), RespFrame::bulk("v"));
+        assert_eq!(
+            run(&mut b, &["GET", "{foo}new"]),
+            RespFrame::error("MOVED 12182 127.0.0.1:7000")
+        );
+        assert_eq!(
+            run(&mut a, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]),
+            RespFrame::Integer(1)
+        );
+    }
+
+    #[test]
+    fn test_topology_replies() {
+        let (mut a, _b) = cluster();
+        assert_eq!(run(&mut a, &["CLUSTER", "MYID"]), RespFrame::bulk(id(7000)));
+        let node = |port: u16| {
+            RespFrame::array([
+                RespFrame::bulk("127.0.0.1"),
+                RespFrame::Integer(port as i64),
+                RespFrame::bulk(id(port)),
+                RespFrame::map([]),
+            ])
+        };
+        assert_eq!(
+            run(&mut a, &["CLUSTER", "SLOTS"]),
+            RespFrame::array([
+                RespFrame::array([RespFrame::Integer(0), RespFrame::Integer(8191), node(7000)]),
+                RespFrame::array([
+                    RespFrame::Integer(8192),
+                    RespFrame::Integer(16383),
+                    node(7001)
+                ]),
+            ])
+        );
+        let RespFrame::VerbatimString(_, nodes) = run(&mut a, &["CLUSTER", "NODES"]) else {
+            panic!("CLUSTER NODES should reply with text");
+        };
+        assert_eq!(
+            String::from_utf8_lossy(&nodes),
+            format!(
+                "{} 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-8191\n{} 127.0.0.1:7001@17001 master - 0 0 2 connected 8192-16383\n",
+                id(7000),
+                id(7001)
+            )
+        );
+        let RespFrame::Array(shards) = run(&mut a, &["CLUSTER", "SHARDS"]) else {
+            panic!("CLUSTER SHARDS should reply with an array");
+        };
+        assert_eq!(shards.len(), 2);
+        assert_eq!(
+            run(&mut a, &["CLUSTER", "KEYSLOT"]),
+            RespFrame::error(
+                "ERR unknown subcommand or wrong number of arguments for 'KEYSLOT'. Try CLUSTER HELP."
+            )
+        );
+
+        let mut standalone = Session::default();
+        assert_eq!(
+            run(&mut standalone, &["CLUSTER", "SLOTS"]),
+            RespFrame::error("ERR This instance has cluster support disabled")
+        );
+    }
+
+    #[test]
+    fn test_hello_mode() {
+        let mode = |session: &mut Session| {
+            let RespFrame::Map(fields) = run(session, &["HELLO"]) else {
+                panic!("HELLO should reply with a map");
+            };
+            fields
+                .into_iter()
+                .find(|(field, _)| *field == RespFrame::bulk("mode"))
+                .map(|(_, mode)| mode)
+        };
+        let (mut a, _) = cluster();
+        assert_eq!(mode(&mut a), Some(RespFrame::bulk("cluster")));
+        assert_eq!(
+            mode(&mut Session::default()),
+            Some(RespFrame::bulk("standalone"))
+        );
+    }
+}
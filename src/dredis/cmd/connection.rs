@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_db, arg_i64, arg_str, client::check_client_name, err_syntax};
+use crate::{RespFrame, RespVersion, Session, DEFAULT_USER, REDIS_VERSION};
+
+pub(super) fn ping(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if session.in_subscribed_mode() && args.len() <= 2 {
+        let msg = args.get(1).cloned().unwrap_or_default();
+        return Ok(RespFrame::array([
+            RespFrame::bulk("pong"),
+            RespFrame::bulk(msg),
+        ]));
+    }
+    match args {
+        [_] => Ok(RespFrame::simple("PONG")),
+        [_, msg] => Ok(RespFrame::bulk(msg.clone())),
+        _ => Err(anyhow!("ERR wrong number of arguments for 'ping' command")),
+    }
+}
+
+pub(super) fn echo(_session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::bulk(args[1].clone()))
+}
+
+pub(super) fn quit(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    session.quit = true;
+    Ok(RespFrame::ok())
+}
+
+pub(super) fn select(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let db = arg_db(session, &args[1])?;
+    if db != 0 && session.backend.cluster_enabled() {
+        return Err(anyhow!("ERR SELECT is not allowed in cluster mode"));
+    }
+    session.backend = session.backend.select(db);
+    Ok(RespFrame::ok())
+}
+
+/// `AUTH [username] password`
+pub(super) fn auth(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (user, password) = match args {
+        [_, password] => {
+            if session.backend.default_user_nopass() {
+                return Err(anyhow!("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"));
+            }
+            (DEFAULT_USER, password)
+        }
+        [_, user, password] => (arg_str(user)?, password),
+        _ => return Err(err_syntax()),
+    };
+    login(session, user, password)?;
+    Ok(RespFrame::ok())
+}
+
+fn login(session: &mut Session, user: &str, password: &[u8]) -> Result<()> {
+    if !session.backend.authenticate(user, password) {
+        return Err(anyhow!(
+            "WRONGPASS invalid username-password pair or user is disabled."
+        ));
+    }
+    session.user = Some(user.to_string());
+    session.authenticated = true;
+    Ok(())
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+pub(super) fn hello(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut protocol = session.protocol;
+    let mut name = None;
+    let mut auth = None;
+    if let Some(ver) = args.get(1) {
+        protocol = match arg_i64(ver) {
+            Ok(2) => RespVersion::Resp2,
+            Ok(3) => RespVersion::Resp3,
+            Ok(_) => return Err(anyhow!("NOPROTO unsupported protocol version")),
+            Err(_) => {
+                return Err(anyhow!(
+                    "ERR Protocol version is not an integer or out of range"
+                ))
+            }
+        };
+    }
+    let mut i = 2;
+    while i < args.len() {
+        match arg_str(&args[i])?.to_ascii_lowercase().as_str() {
+            "auth" if i + 2 < args.len() => {
+                auth = Some((arg_str(&args[i + 1])?, &args[i + 2]));
+                i += 3;
+            }
+            "setname" if i + 1 < args.len() => {
+                let setname = arg_str(&args[i + 1])?;
+                check_client_name(setname)?;
+                name = Some(setname.to_string());
+                i += 2;
+            }
+            _ => return Err(err_syntax()),
+        }
+    }
+    match auth {
+        Some((user, password)) => login(session, user, password)?,
+        None if !session.authenticated => {
+            return Err(anyhow!("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"));
+        }
+        None => {}
+    }
+
+    session.protocol = protocol;
+    if name.is_some() {
+        session.name = name;
+    }
+    let proto = match protocol {
+        RespVersion::Resp2 => 2,
+        RespVersion::Resp3 => 3,
+    };
+    Ok(RespFrame::map([
+        (RespFrame::bulk("server"), RespFrame::bulk("redis")),
+        (RespFrame::bulk("version"), RespFrame::bulk(REDIS_VERSION)),
+        (RespFrame::bulk("proto"), RespFrame::Integer(proto)),
+        (RespFrame::bulk("id"), RespFrame::Integer(session.id as i64)),
+        (
+            RespFrame::bulk("mode"),
+            RespFrame::bulk(if session.backend.cluster_enabled() {
+                "cluster"
+            } else {
+                "standalone"
+            }),
+        ),
+        (
+            RespFrame::bulk("role"),
+            RespFrame::bulk(if session.backend.is_replica() {
+                "replica"
+            } else {
+                "master"
+            }),
+        ),
+        (RespFrame::bulk("modules"), RespFrame::array([])),
+    ]))
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, err_arity, typed, typed_mut, typed_or_insert, ScanArgs};
+use crate::{event, scan_aggregate, RespFrame, Session, Value};
+
+pub(super) fn hset(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if !args.len().is_multiple_of(2) {
+        return Err(anyhow!(err_arity("hset")));
+    }
+    let added = session.backend.write(&args[1], |slot| {
+        let hash = typed_or_insert(slot, Value::as_hash_mut)?;
+        let added = args[2..]
+            .chunks(2)
+            .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+            .count();
+        Ok::<_, anyhow::Error>(added)
+    })?;
+    session.backend.notify(event::HASH, "hset", &args[1]);
+    Ok(RespFrame::Integer(added as i64))
+}
+
+pub(super) fn hget(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let value = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_hash)?.and_then(|h| h.get(&args[2]).cloned()))
+    })?;
+    Ok(value.map_or(RespFrame::NullBulkString, RespFrame::bulk))
+}
+
+pub(super) fn hmget(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.read(&args[1], |e| {
+        let hash = typed(e, Value::as_hash)?;
+        let values = args[2..]
+            .iter()
+            .map(|field| {
+                hash.and_then(|h| h.get(field))
+                    .map_or(RespFrame::NullBulkString, |v| RespFrame::bulk(v.clone()))
+            })
+            .collect::<Vec<_>>();
+        Ok(RespFrame::Array(values))
+    })
+}
+
+/// Replies with a map, sent as a flat field/value array to RESP2 clients.
+pub(super) fn hgetall(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.read(&args[1], |e| {
+        let pairs = typed(e, Value::as_hash)?
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| (RespFrame::bulk(k.clone()), RespFrame::bulk(v.clone())))
+            .collect::<Vec<_>>();
+        Ok(RespFrame::Map(pairs))
+    })
+}
+
+pub(super) fn hkeys(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.read(&args[1], |e| {
+        let keys = typed(e, Value::as_hash)?
+            .into_iter()
+            .flat_map(|h| h.keys())
+            .map(|k| RespFrame::bulk(k.clone()))
+            .collect::<Vec<_>>();
+        Ok(RespFrame::Array(keys))
+    })
+}
+
+pub(super) fn hvals(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.read(&args[1], |e| {
+        let values = typed(e, Value::as_hash)?
+            .into_iter()
+            .flat_map(|h| h.values())
+            .map(|v| RespFrame::bulk(v.clone()))
+            .collect::<Vec<_>>();
+        Ok(RespFrame::Array(values))
+    })
+}
+
+pub(super) fn hdel(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let removed = session.backend.write(&args[1], |slot| {
+        let removed = match typed_mut(slot, Value::as_hash_mut)? {
+            Some(hash) => args[2..]
+                .iter()
+                .filter(|f| hash.remove(*f).is_some())
+                .count(),
+            None => 0,
+        };
+        Ok::<_, anyhow::Error>(removed)
+    })?;
+    if removed > 0 {
+        session
+            .backend
+            .notify_removing(event::HASH, "hdel", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+pub(super) fn hexists(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let found = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_hash)?.is_some_and(|h| h.contains_key(&args[2])))
+    })?;
+    Ok(RespFrame::Integer(found as i64))
+}
+
+pub(super) fn hlen(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_hash)?.map_or(0, |h| h.len()))
+    })?;
+    Ok(RespFrame::Integer(len as i64))
+}
+
+/// `HSCAN key cursor [MATCH pattern] [COUNT count]`
+pub(super) fn hscan(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let scan = ScanArgs::parse(&args[2..], false)?;
+    session.backend.read(&args[1], |e| {
+        let Some(hash) = typed(e, Value::as_hash)? else {
+            return Ok(ScanArgs::reply(0, Vec::new()));
+        };
+        let (page, cursor) = scan_aggregate(hash.iter(), |(f, _)| f, scan.cursor, scan.count);
+        let items = page
+            .into_iter()
+            .filter(|(f, _)| scan.matches(f))
+            .flat_map(|(f, v)| [RespFrame::bulk(f.clone()), RespFrame::bulk(v.clone())])
+            .collect();
+        Ok(ScanArgs::reply(cursor, items))
+    })
+}
+
+pub(super) fn hincrby(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let delta = arg_i64(&args[3])?;
+    let n = session.backend.write(&args[1], |slot| {
+        let hash = typed_or_insert(slot, Value::as_hash_mut)?;
+        let current = match hash.get(&args[2]) {
+            Some(v) => arg_i64(v).map_err(|_| anyhow!("ERR hash value is not an integer"))?,
+            None => 0,
+        };
+        let n = current
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("ERR increment or decrement would overflow"))?;
+        hash.insert(args[2].clone(), n.to_string().into());
+        Ok::<_, anyhow::Error>(n)
+    })?;
+    session.backend.notify(event::HASH, "hincrby", &args[1]);
+    Ok(RespFrame::Integer(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, RespVersion, Session};
+
+    #[test]
+    fn test_hash_commands() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["HSET", "h", "a", "1", "b", "2"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(run(&mut s, &["HSET", "h", "a", "3"]), RespFrame::Integer(0));
+        assert_eq!(run(&mut s, &["HGET", "h", "a"]), RespFrame::bulk("3"));
+        assert_eq!(run(&mut s, &["HGET", "h", "x"]), RespFrame::NullBulkString);
+        assert_eq!(
+            run(&mut s, &["HMGET", "h", "b", "x"]),
+            RespFrame::array([RespFrame::bulk("2"), RespFrame::NullBulkString])
+        );
+        assert_eq!(run(&mut s, &["HLEN", "h"]), RespFrame::Integer(2));
+        assert_eq!(run(&mut s, &["HEXISTS", "h", "b"]), RespFrame::Integer(1));
+        assert_eq!(
+            run(&mut s, &["HINCRBY", "h", "b", "5"]),
+            RespFrame::Integer(7)
+        );
+        assert_eq!(
+            run(&mut s, &["HDEL", "h", "a", "b", "x"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(run(&mut s, &["EXISTS", "h"]), RespFrame::Integer(0));
+    }
+
+    #[test]
+    fn test_hscan() {
+        let mut s = Session::default();
+        run(&mut s, &["HSET", "h", "a", "1", "b", "2", "c", "3"]);
+        let RespFrame::Array(reply) = run(&mut s, &["HSCAN", "h", "0", "MATCH", "[ab]"]) else {
+            panic!("HSCAN should reply with an array");
+        };
+        assert_eq!(reply[0], RespFrame::bulk("0"));
+        let RespFrame::Array(items) = &reply[1] else {
+            panic!("HSCAN should reply with a page");
+        };
+        let mut pairs = items.chunks(2).map(|c| c.to_vec()).collect::<Vec<_>>();
+        pairs.sort_by_key(|p| format!("{:?}", p));
+        assert_eq!(
+            pairs,
+            [
+                vec![RespFrame::bulk("a"), RespFrame::bulk("1")],
+                vec![RespFrame::bulk("b"), RespFrame::bulk("2")]
+            ]
+        );
+        assert_eq!(
+            run(&mut s, &["HSCAN", "missing", "0"]),
+            RespFrame::array([RespFrame::bulk("0"), RespFrame::array([])])
+        );
+    }
+
+    #[test]
+    fn test_hgetall_per_protocol() {
+        let mut s = Session::default();
+        run(&mut s, &["HSET", "h", "f", "v"]);
+        let reply = run(&mut s, &["HGETALL", "h"]);
+        assert_eq!(
+            reply.to_bytes_with(RespVersion::Resp2),
+            "*2\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(
+            reply.to_bytes_with(RespVersion::Resp3),
+            "%1\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+    }
+}
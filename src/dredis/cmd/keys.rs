@@ -0,0 +1,491 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_db, arg_i64, arg_str, err_syntax, ScanArgs};
+use crate::{event, Entry, RespFrame, Session};
+
+pub(super) fn del(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut removed = 0;
+    for key in &args[1..] {
+        if session.backend.remove(key) {
+            session.backend.notify(event::GENERIC, "del", key);
+            removed += 1;
+        }
+    }
+    Ok(RespFrame::Integer(removed))
+}
+
+pub(super) fn exists(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let found = args[1..]
+        .iter()
+        .filter(|key| session.backend.exists(key))
+        .count();
+    Ok(RespFrame::Integer(found as i64))
+}
+
+pub(super) fn type_(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let name = session
+        .backend
+        .read(&args[1], |e| e.map_or("none", |e| e.value.type_name()));
+    Ok(RespFrame::simple(name))
+}
+
+pub(super) fn expire(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    set_expire(session, args, 1000, false)
+}
+
+pub(super) fn pexpire(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    set_expire(session, args, 1, false)
+}
+
+pub(super) fn expireat(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    set_expire(session, args, 1000, true)
+}
+
+pub(super) fn pexpireat(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    set_expire(session, args, 1, true)
+}
+
+/// `EXPIRE key seconds [NX | XX | GT | LT]` and friends; `unit` is the number
+/// of milliseconds per argument unit, `absolute` for the `*AT` variants.
+fn set_expire(
+    session: &mut Session,
+    args: &[Bytes],
+    unit: i64,
+    absolute: bool,
+) -> Result<RespFrame> {
+    let cmd = arg_str(&args[0])?.to_ascii_lowercase();
+    let invalid = || anyhow!("ERR invalid expire time in '{}' command", cmd);
+    let now = session.backend.now_ms() as i64;
+    let base = if absolute { 0 } else { now };
+    let expire_at = arg_i64(&args[2])?
+        .checked_mul(unit)
+        .and_then(|ms| ms.checked_add(base))
+        .ok_or_else(invalid)?;
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for opt in &args[3..] {
+        match arg_str(opt)?.to_ascii_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            other => return Err(anyhow!("ERR Unsupported option {}", other)),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(anyhow!(
+            "ERR NX and XX, GT or LT options at the same time are not compatible"
+        ));
+    }
+    if gt && lt {
+        return Err(anyhow!(
+            "ERR GT and LT options at the same time are not compatible"
+        ));
+    }
+
+    let updated = session.backend.write(&args[1], |slot| {
+        let Some(entry) = slot else {
+            return None;
+        };
+        // a key without ttl counts as an infinite ttl for GT and LT
+        let allowed = match entry.expire_at {
+            Some(current) => {
+                !nx && (!gt || expire_at > current as i64) && (!lt || expire_at < current as i64)
+            }
+            None => !xx && !gt,
+        };
+        if !allowed {
+            return None;
+        }
+        if expire_at <= now {
+            *slot = None;
+            Some("del")
+        } else {
+            entry.expire_at = Some(expire_at as u64);
+            Some("expire")
+        }
+    });
+    if let Some(name) = updated {
+        session.backend.notify(event::GENERIC, name, &args[1]);
+    }
+    let updated = updated.is_some();
+    // logged with an absolute time, replaying it later must not extend the ttl
+    session.propagate_as = Some(if updated {
+        let mut cmd = vec![
+            "PEXPIREAT".into(),
+            args[1].clone(),
+            expire_at.to_string().into(),
+        ];
+        cmd.extend_from_slice(&args[3..]);
+        vec![cmd]
+    } else {
+        Vec::new()
+    });
+    Ok(RespFrame::Integer(updated as i64))
+}
+
+pub(super) fn ttl(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(remaining_ttl(session, &args[1], |ms| {
+        (ms + 500) / 1000
+    })))
+}
+
+pub(super) fn pttl(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(remaining_ttl(session, &args[1], |ms| {
+        ms
+    })))
+}
+
+pub(super) fn expiretime(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(expire_time(session, &args[1], |ms| {
+        ms / 1000
+    })))
+}
+
+pub(super) fn pexpiretime(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(expire_time(session, &args[1], |ms| ms)))
+}
+
+/// -2 when the key does not exist, -1 when it has no ttl.
+fn remaining_ttl(session: &Session, key: &[u8], scale: impl Fn(i64) -> i64) -> i64 {
+    let now = session.backend.now_ms() as i64;
+    expire_time(session, key, |at| scale((at - now).max(0)))
+}
+
+fn expire_time(session: &Session, key: &[u8], scale: impl Fn(i64) -> i64) -> i64 {
+    session.backend.read(key, |e| match e {
+        None => -2,
+        Some(Entry {
+            expire_at: None, ..
+        }) => -1,
+        Some(Entry {
+            expire_at: Some(at),
+            ..
+        }) => scale(*at as i64),
+    })
+}
+
+pub(super) fn persist(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let removed = session.backend.write(&args[1], |slot| {
+        slot.as_mut()
+            .is_some_and(|entry| entry.expire_at.take().is_some())
+    });
+    if removed {
+        session.backend.notify(event::GENERIC, "persist", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+/// `OBJECT IDLETIME key | FREQ key`, both tracked whatever the
+/// `maxmemory-policy`.
+pub(super) fn object(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    let access = match (sub.as_str(), args.len()) {
+        ("idletime" | "freq", 3) => session.backend.key_access(&args[2]),
+        _ => {
+            return Err(anyhow!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try OBJECT HELP.",
+                arg_str(&args[1])?
+            ))
+        }
+    };
+    Ok(match access {
+        Some((idle_ms, _)) if sub == "idletime" => RespFrame::Integer((idle_ms / 1000) as i64),
+        Some((_, freq)) => RespFrame::Integer(freq as i64),
+        None => RespFrame::NullBulkString,
+    })
+}
+
+pub(super) fn keys(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let keys = session.backend.keys_matching(&args[1]);
+    Ok(RespFrame::array(
+        keys.into_iter().map(RespFrame::bulk).collect::<Vec<_>>(),
+    ))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`, the filters
+/// apply to the page `COUNT` asked for, which may come back empty.
+pub(super) fn scan(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let scan = ScanArgs::parse(&args[1..], true)?;
+    let (keys, cursor) = session.backend.scan(scan.cursor, scan.count);
+    let keys = keys
+        .into_iter()
+        .filter(|key| scan.matches(key))
+        .filter(|key| {
+            scan.type_name.as_ref().is_none_or(|name| {
+                session
+                    .backend
+                    .peek(key, |e| e.is_some_and(|e| e.value.type_name() == name))
+            })
+        })
+        .map(RespFrame::bulk)
+        .collect();
+    Ok(ScanArgs::reply(cursor, keys))
+}
+
+/// `MOVE key db`
+pub(super) fn move_(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if session.backend.cluster_enabled() {
+        return Err(anyhow!("ERR MOVE is not allowed in cluster mode"));
+    }
+    let db = arg_db(session, &args[2])?;
+    if db == session.backend.db_index() {
+        return Err(anyhow!("ERR source and destination objects are the same"));
+    }
+    let moved = session.backend.move_key(&args[1], db);
+    if moved {
+        session
+            .backend
+            .notify(event::GENERIC, "move_from", &args[1]);
+        let target = session.backend.select(db);
+        target.notify(event::GENERIC, "move_to", &args[1]);
+    }
+    Ok(RespFrame::Integer(moved as i64))
+}
+
+pub(super) fn dbsize(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(session.backend.len() as i64))
+}
+
+/// `FLUSHDB [ASYNC | SYNC]`
+pub(super) fn flushdb(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.flushdb(flush_async(args)?);
+    Ok(RespFrame::ok())
+}
+
+/// `FLUSHALL [ASYNC | SYNC]`
+pub(super) fn flushall(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.flushall(flush_async(args)?);
+    Ok(RespFrame::ok())
+}
+
+/// Whether the flushed keys are freed on a background thread.
+fn flush_async(args: &[Bytes]) -> Result<bool> {
+    match args {
+        [_] => Ok(false),
+        [_, mode] if mode.eq_ignore_ascii_case(b"async") => Ok(true),
+        [_, mode] if mode.eq_ignore_ascii_case(b"sync") => Ok(false),
+        _ => Err(err_syntax()),
+    }
+}
+
+/// `SWAPDB index1 index2`
+pub(super) fn swapdb(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if session.backend.cluster_enabled() {
+        return Err(anyhow!("ERR SWAPDB is not allowed in cluster mode"));
+    }
+    let index = |arg: &[u8], which: &str| {
+        usize::try_from(arg_i64(arg).map_err(|_| anyhow!("ERR invalid {} DB index", which))?)
+            .ok()
+            .filter(|&db| db < session.backend.databases())
+            .ok_or_else(|| anyhow!("ERR DB index is out of range"))
+    };
+    let (a, b) = (index(&args[1], "first")?, index(&args[2], "second")?);
+    session.backend.swapdb(a, b);
+    Ok(RespFrame::ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::dredis::cmd::run;
+    use crate::{Backend, ManualClock, RespFrame, Session};
+
+    fn session_with_clock() -> (Session, ManualClock) {
+        let clock = ManualClock::new(1_000_000);
+        let session = Session::new(Backend::with_clock(Arc::new(clock.clone())));
+        (session, clock)
+    }
+
+    fn int(n: i64) -> RespFrame {
+        RespFrame::Integer(n)
+    }
+
+    #[test]
+    fn test_expire_and_ttl() {
+        let (mut s, clock) = session_with_clock();
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "10"]), int(0));
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(-2));
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(-1));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "10"]), int(1));
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(10));
+        assert_eq!(run(&mut s, &["PTTL", "k"]), int(10_000));
+        assert_eq!(run(&mut s, &["EXPIRETIME", "k"]), int(1_010));
+
+        clock.advance(Duration::from_millis(9_400));
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(1));
+        assert_eq!(run(&mut s, &["PTTL", "k"]), int(600));
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::NullBulkString);
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(-2));
+    }
+
+    #[test]
+    fn test_object_idletime() {
+        let (mut s, clock) = session_with_clock();
+        assert_eq!(
+            run(&mut s, &["OBJECT", "IDLETIME", "k"]),
+            RespFrame::NullBulkString
+        );
+        run(&mut s, &["SET", "k", "v"]);
+        clock.advance(Duration::from_secs(7));
+        assert_eq!(run(&mut s, &["OBJECT", "IDLETIME", "k"]), int(7));
+        assert!(matches!(run(&mut s, &["OBJECT", "FREQ", "k"]), RespFrame::Integer(f) if f > 0));
+        run(&mut s, &["GET", "k"]);
+        assert_eq!(run(&mut s, &["OBJECT", "IDLETIME", "k"]), int(0));
+        assert!(matches!(
+            run(&mut s, &["OBJECT", "ENCODING", "k"]),
+            RespFrame::Error(e) if e.starts_with("ERR unknown subcommand")
+        ));
+    }
+
+    #[test]
+    fn test_expire_at_and_persist() {
+        let (mut s, clock) = session_with_clock();
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(run(&mut s, &["PEXPIREAT", "k", "1000500"]), int(1));
+        assert_eq!(run(&mut s, &["PTTL", "k"]), int(500));
+        assert_eq!(run(&mut s, &["PERSIST", "k"]), int(1));
+        assert_eq!(run(&mut s, &["PERSIST", "k"]), int(0));
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(-1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(run(&mut s, &["EXISTS", "k"]), int(1));
+
+        // a time in the past deletes the key right away
+        assert_eq!(run(&mut s, &["EXPIREAT", "k", "1"]), int(1));
+        assert_eq!(run(&mut s, &["EXISTS", "k"]), int(0));
+        assert_eq!(s.backend.volatile_len(), 0);
+    }
+
+    #[test]
+    fn test_select_move_swapdb() {
+        let (mut s, _clock) = session_with_clock();
+        let ok = RespFrame::ok();
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(run(&mut s, &["SELECT", "1"]), ok);
+        assert_eq!(run(&mut s, &["DBSIZE"]), int(0));
+        assert_eq!(
+            run(&mut s, &["SELECT", "16"]),
+            RespFrame::error("ERR DB index is out of range")
+        );
+        assert_eq!(run(&mut s, &["MOVE", "k", "0"]), int(0));
+        run(&mut s, &["SELECT", "0"]);
+        assert_eq!(
+            run(&mut s, &["MOVE", "k", "0"]),
+            RespFrame::error("ERR source and destination objects are the same")
+        );
+        assert_eq!(run(&mut s, &["MOVE", "k", "1"]), int(1));
+        assert_eq!(run(&mut s, &["DBSIZE"]), int(0));
+
+        assert_eq!(run(&mut s, &["SWAPDB", "0", "1"]), ok);
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::bulk("v"));
+        assert_eq!(
+            run(&mut s, &["SWAPDB", "x", "1"]),
+            RespFrame::error("ERR invalid first DB index")
+        );
+        assert_eq!(
+            run(&mut s, &["SWAPDB", "0", "99"]),
+            RespFrame::error("ERR DB index is out of range")
+        );
+    }
+
+    #[test]
+    fn test_flushdb_and_flushall() {
+        let (mut s, _clock) = session_with_clock();
+        run(&mut s, &["SET", "a", "1"]);
+        run(&mut s, &["SELECT", "2"]);
+        run(&mut s, &["SET", "b", "2"]);
+        assert_eq!(run(&mut s, &["FLUSHDB", "ASYNC"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["DBSIZE"]), int(0));
+        run(&mut s, &["SELECT", "0"]);
+        assert_eq!(run(&mut s, &["DBSIZE"]), int(1));
+        assert!(matches!(
+            run(&mut s, &["FLUSHALL", "LAZY"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(run(&mut s, &["FLUSHALL", "SYNC"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["DBSIZE"]), int(0));
+    }
+
+    #[test]
+    fn test_keys_and_scan() {
+        let (mut s, clock) = session_with_clock();
+        for i in 0..100 {
+            run(&mut s, &["SET", &format!("key:{}", i), "v"]);
+        }
+        run(&mut s, &["RPUSH", "list", "x"]);
+        run(&mut s, &["SET", "gone", "v", "PX", "10"]);
+        clock.advance(Duration::from_millis(10));
+
+        let RespFrame::Array(keys) = run(&mut s, &["KEYS", "key:1?"]) else {
+            panic!("KEYS should reply with an array");
+        };
+        assert_eq!(keys.len(), 10);
+
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = run(&mut s, &["SCAN", &cursor, "COUNT", "7"]);
+            let RespFrame::Array(reply) = reply else {
+                panic!("SCAN should reply with an array");
+            };
+            let (RespFrame::BulkString(next), RespFrame::Array(page)) = (&reply[0], &reply[1])
+            else {
+                panic!("unexpected SCAN reply {:?}", reply);
+            };
+            seen.extend(page.iter().cloned());
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        seen.sort_by_key(|k| format!("{:?}", k));
+        seen.dedup();
+        assert_eq!(seen.len(), 101);
+
+        let all = run(&mut s, &["SCAN", "0", "COUNT", "1000", "TYPE", "list"]);
+        assert_eq!(
+            all,
+            RespFrame::array([
+                RespFrame::bulk("0"),
+                RespFrame::array([RespFrame::bulk("list")])
+            ])
+        );
+        let RespFrame::Array(matched) =
+            run(&mut s, &["SCAN", "0", "MATCH", "key:5*", "COUNT", "1000"])
+        else {
+            panic!("SCAN should reply with an array");
+        };
+        assert!(matches!(&matched[1], RespFrame::Array(page) if page.len() == 11));
+        assert_eq!(
+            run(&mut s, &["SCAN", "x"]),
+            RespFrame::error("ERR invalid cursor")
+        );
+        assert_eq!(
+            run(&mut s, &["SCAN", "0", "COUNT", "0"]),
+            RespFrame::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn test_expire_options() {
+        let (mut s, _clock) = session_with_clock();
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "10", "XX"]), int(0));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "10", "GT"]), int(0));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "10", "NX"]), int(1));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "20", "NX"]), int(0));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "5", "GT"]), int(0));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "20", "GT"]), int(1));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "30", "LT"]), int(0));
+        assert_eq!(run(&mut s, &["EXPIRE", "k", "15", "LT", "XX"]), int(1));
+        assert_eq!(run(&mut s, &["TTL", "k"]), int(15));
+        assert!(matches!(
+            run(&mut s, &["EXPIRE", "k", "15", "NX", "LT"]),
+            RespFrame::Error(_)
+        ));
+    }
+}
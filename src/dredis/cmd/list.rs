@@ -0,0 +1,470 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{
+    arg_i64, arg_str, arg_timeout, err_arity, err_syntax, index_range, typed, typed_mut,
+    typed_or_insert, Block,
+};
+use crate::{event, RespFrame, Session, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn push_event(self) -> &'static str {
+        match self {
+            End::Left => "lpush",
+            End::Right => "rpush",
+        }
+    }
+
+    fn pop_event(self) -> &'static str {
+        match self {
+            End::Left => "lpop",
+            End::Right => "rpop",
+        }
+    }
+}
+
+pub(super) fn lpush(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    push(session, args, End::Left)
+}
+
+pub(super) fn rpush(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    push(session, args, End::Right)
+}
+
+fn push(session: &mut Session, args: &[Bytes], end: End) -> Result<RespFrame> {
+    let len = session.backend.write(&args[1], |slot| {
+        let list = typed_or_insert(slot, Value::as_list_mut)?;
+        for value in &args[2..] {
+            match end {
+                End::Left => list.push_front(value.clone()),
+                End::Right => list.push_back(value.clone()),
+            }
+        }
+        Ok::<_, anyhow::Error>(list.len())
+    })?;
+    session
+        .backend
+        .notify(event::LIST, end.push_event(), &args[1]);
+    session.backend.blocking.wake(session.backend.db, &args[1]);
+    Ok(RespFrame::Integer(len as i64))
+}
+
+pub(super) fn lpop(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    pop(session, args, End::Left)
+}
+
+pub(super) fn rpop(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    pop(session, args, End::Right)
+}
+
+/// `LPOP key [count]`: a single bulk string without count, an array with it.
+fn pop(session: &mut Session, args: &[Bytes], end: End) -> Result<RespFrame> {
+    if args.len() > 3 {
+        return Err(anyhow!(err_arity(end.pop_event())));
+    }
+    let count = match args.get(2) {
+        Some(n) => {
+            let n = arg_i64(n)?;
+            if n < 0 {
+                return Err(anyhow!("ERR value is out of range, must be positive"));
+            }
+            Some(n as usize)
+        }
+        None => None,
+    };
+    let (reply, popped) = session.backend.write(&args[1], |slot| {
+        let Some(list) = typed_mut(slot, Value::as_list_mut)? else {
+            let reply = match count {
+                Some(_) => RespFrame::NullArray,
+                None => RespFrame::NullBulkString,
+            };
+            return Ok::<_, anyhow::Error>((reply, false));
+        };
+        let mut pop_one = || match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        };
+        Ok(match count {
+            Some(n) => {
+                let items = std::iter::from_fn(pop_one)
+                    .take(n)
+                    .map(RespFrame::bulk)
+                    .collect::<Vec<_>>();
+                let popped = !items.is_empty();
+                (RespFrame::Array(items), popped)
+            }
+            None => match pop_one() {
+                Some(value) => (RespFrame::bulk(value), true),
+                None => (RespFrame::NullBulkString, false),
+            },
+        })
+    })?;
+    if popped {
+        session
+            .backend
+            .notify_removing(event::LIST, end.pop_event(), &args[1]);
+    }
+    Ok(reply)
+}
+
+/// `BLPOP key [key ...] timeout`, without waiting: pop from the first non
+/// empty list and reply with its key and the element.
+pub(super) fn blpop(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    pop_first(session, &args[1..args.len() - 1], End::Left)
+}
+
+pub(super) fn brpop(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    pop_first(session, &args[1..args.len() - 1], End::Right)
+}
+
+fn pop_first(session: &mut Session, keys: &[Bytes], end: End) -> Result<RespFrame> {
+    for key in keys {
+        let value = session.backend.write(key, |slot| {
+            Ok::<_, anyhow::Error>(typed_mut(slot, Value::as_list_mut)?.and_then(
+                |list| match end {
+                    End::Left => list.pop_front(),
+                    End::Right => list.pop_back(),
+                },
+            ))
+        })?;
+        if let Some(value) = value {
+            session
+                .backend
+                .notify_removing(event::LIST, end.pop_event(), key);
+            let pop = end.pop_event().to_ascii_uppercase();
+            session.propagate_as = Some(vec![vec![pop.into(), key.clone()]]);
+            return Ok(RespFrame::array([
+                RespFrame::bulk(key.clone()),
+                RespFrame::bulk(value),
+            ]));
+        }
+    }
+    session.propagate_as = Some(Vec::new());
+    Ok(RespFrame::NullArray)
+}
+
+pub(super) fn block_on_pop(args: &[Bytes]) -> Result<Option<Block>> {
+    let timeout = arg_timeout(&args[args.len() - 1])?;
+    Ok(Some((args[1..args.len() - 1].to_vec(), timeout)))
+}
+
+/// `LMOVE source destination LEFT | RIGHT LEFT | RIGHT`
+pub(super) fn lmove(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (from, to) = (parse_end(&args[3])?, parse_end(&args[4])?);
+    let value = move_element(session, &args[1], &args[2], from, to)?;
+    Ok(value.map_or(RespFrame::NullBulkString, RespFrame::bulk))
+}
+
+/// `BLMOVE source destination LEFT | RIGHT LEFT | RIGHT timeout`
+pub(super) fn blmove(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut lmove_args = args[..5].to_vec();
+    lmove_args[0] = "LMOVE".into();
+    let reply = lmove(session, &lmove_args)?;
+    session.propagate_as = Some(vec![lmove_args]);
+    Ok(reply)
+}
+
+pub(super) fn block_on_move(args: &[Bytes]) -> Result<Option<Block>> {
+    parse_end(&args[3])?;
+    parse_end(&args[4])?;
+    Ok(Some((vec![args[1].clone()], arg_timeout(&args[5])?)))
+}
+
+fn parse_end(arg: &[u8]) -> Result<End> {
+    match arg_str(arg)?.to_ascii_lowercase().as_str() {
+        "left" => Ok(End::Left),
+        "right" => Ok(End::Right),
+        _ => Err(err_syntax()),
+    }
+}
+
+fn move_element(
+    session: &Session,
+    src: &Bytes,
+    dst: &Bytes,
+    from: End,
+    to: End,
+) -> Result<Option<Bytes>> {
+    let push = |list: &mut std::collections::VecDeque<Bytes>, value: Bytes| match to {
+        End::Left => list.push_front(value),
+        End::Right => list.push_back(value),
+    };
+    let pop = |list: &mut std::collections::VecDeque<Bytes>| match from {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    };
+    if src == dst {
+        let value = session.backend.write(src, |slot| {
+            let Some(list) = typed_mut(slot, Value::as_list_mut)? else {
+                return Ok::<_, anyhow::Error>(None);
+            };
+            let value = pop(list);
+            if let Some(value) = &value {
+                push(list, value.clone());
+            }
+            Ok(value)
+        })?;
+        if value.is_some() {
+            session.backend.notify(event::LIST, from.pop_event(), src);
+            session.backend.notify(event::LIST, to.push_event(), dst);
+        }
+        return Ok(value);
+    }
+
+    // like redis, a destination of the wrong type fails before anything is popped
+    session
+        .backend
+        .read(dst, |e| typed(e, Value::as_list).map(|_| ()))?;
+    let value = session.backend.write(src, |slot| {
+        Ok::<_, anyhow::Error>(typed_mut(slot, Value::as_list_mut)?.and_then(pop))
+    })?;
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    session
+        .backend
+        .notify_removing(event::LIST, from.pop_event(), src);
+    session.backend.write(dst, |slot| {
+        let list = typed_or_insert(slot, Value::as_list_mut)?;
+        push(list, value.clone());
+        Ok::<_, anyhow::Error>(())
+    })?;
+    session.backend.notify(event::LIST, to.push_event(), dst);
+    session.backend.blocking.wake(session.backend.db, dst);
+    Ok(Some(value))
+}
+
+pub(super) fn lrange(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (start, stop) = (arg_i64(&args[2])?, arg_i64(&args[3])?);
+    session.backend.read(&args[1], |e| {
+        let Some(list) = typed(e, Value::as_list)? else {
+            return Ok(RespFrame::array([]));
+        };
+        let items = match index_range(start, stop, list.len()) {
+            Some(range) => list
+                .range(range)
+                .map(|v| RespFrame::bulk(v.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(RespFrame::Array(items))
+    })
+}
+
+pub(super) fn llen(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_list)?.map_or(0, |l| l.len()))
+    })?;
+    Ok(RespFrame::Integer(len as i64))
+}
+
+pub(super) fn lindex(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let index = arg_i64(&args[2])?;
+    session.backend.read(&args[1], |e| {
+        let value = typed(e, Value::as_list)?.and_then(|list| {
+            let index = if index < 0 {
+                list.len() as i64 + index
+            } else {
+                index
+            };
+            usize::try_from(index).ok().and_then(|i| list.get(i))
+        });
+        Ok(value.map_or(RespFrame::NullBulkString, |v| RespFrame::bulk(v.clone())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::dredis::cmd::run;
+    use crate::{execute_blocking, RespFrame, Session};
+
+    fn bulks(items: &[&str]) -> RespFrame {
+        RespFrame::Array(
+            items
+                .iter()
+                .map(|i| RespFrame::bulk(i.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_push_pop_range() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["LPUSH", "l", "b", "a"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(
+            run(&mut s, &["RPUSH", "l", "c", "d"]),
+            RespFrame::Integer(4)
+        );
+        assert_eq!(
+            run(&mut s, &["LRANGE", "l", "0", "-1"]),
+            bulks(&["a", "b", "c", "d"])
+        );
+        assert_eq!(run(&mut s, &["LRANGE", "l", "1", "2"]), bulks(&["b", "c"]));
+        assert_eq!(
+            run(&mut s, &["LRANGE", "l", "-2", "100"]),
+            bulks(&["c", "d"])
+        );
+        assert_eq!(run(&mut s, &["LRANGE", "l", "3", "1"]), bulks(&[]));
+        assert_eq!(run(&mut s, &["LINDEX", "l", "-1"]), RespFrame::bulk("d"));
+        assert_eq!(
+            run(&mut s, &["LINDEX", "l", "9"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(run(&mut s, &["LLEN", "l"]), RespFrame::Integer(4));
+        assert_eq!(run(&mut s, &["RPOP", "l"]), RespFrame::bulk("d"));
+        assert_eq!(run(&mut s, &["LPOP", "l", "5"]), bulks(&["a", "b", "c"]));
+        assert_eq!(run(&mut s, &["EXISTS", "l"]), RespFrame::Integer(0));
+        assert_eq!(run(&mut s, &["LPOP", "l"]), RespFrame::NullBulkString);
+        assert_eq!(run(&mut s, &["LPOP", "l", "1"]), RespFrame::NullArray);
+    }
+
+    #[test]
+    fn test_lmove() {
+        let mut s = Session::default();
+        run(&mut s, &["RPUSH", "a", "1", "2", "3"]);
+        assert_eq!(
+            run(&mut s, &["LMOVE", "a", "a", "LEFT", "RIGHT"]),
+            RespFrame::bulk("1")
+        );
+        assert_eq!(
+            run(&mut s, &["LRANGE", "a", "0", "-1"]),
+            bulks(&["2", "3", "1"])
+        );
+        assert_eq!(
+            run(&mut s, &["LMOVE", "a", "b", "RIGHT", "LEFT"]),
+            RespFrame::bulk("1")
+        );
+        assert_eq!(run(&mut s, &["LRANGE", "b", "0", "-1"]), bulks(&["1"]));
+        assert_eq!(
+            run(&mut s, &["LMOVE", "x", "b", "RIGHT", "LEFT"]),
+            RespFrame::NullBulkString
+        );
+        run(&mut s, &["SET", "str", "v"]);
+        assert!(matches!(
+            run(&mut s, &["LMOVE", "a", "str", "RIGHT", "LEFT"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(run(&mut s, &["LLEN", "a"]), RespFrame::Integer(2));
+    }
+
+    #[test]
+    fn test_blocking_pop_without_waiting() {
+        let mut s = Session::default();
+        run(&mut s, &["RPUSH", "b", "x"]);
+        assert_eq!(
+            run(&mut s, &["BLPOP", "a", "b", "0"]),
+            RespFrame::array([RespFrame::bulk("b"), RespFrame::bulk("x")])
+        );
+        // the synchronous path never waits
+        assert_eq!(run(&mut s, &["BRPOP", "a", "b", "1"]), RespFrame::NullArray);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_waits_for_push() {
+        let mut s = Session::default();
+        let mut pusher = Session::new(s.backend.clone());
+        let waiter = tokio::spawn(async move {
+            let args = ["BLPOP", "q", "5"].map(Bytes::from).to_vec();
+            execute_blocking(&mut s, &args).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        run(&mut pusher, &["RPUSH", "q", "job"]);
+        assert_eq!(
+            waiter.await.unwrap(),
+            RespFrame::array([RespFrame::bulk("q"), RespFrame::bulk("job")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_woken_waiter_is_served_before_other_clients() {
+        let mut s = Session::default();
+        let mut pusher = Session::new(s.backend.clone());
+        let mut racer = Session::new(s.backend.clone());
+        let waiter = tokio::spawn(async move {
+            let args = ["BLPOP", "q", "5"].map(Bytes::from).to_vec();
+            execute_blocking(&mut s, &args).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the racer pops before the woken waiter gets to run again
+        run(&mut pusher, &["RPUSH", "q", "job"]);
+        assert_eq!(run(&mut racer, &["LPOP", "q"]), RespFrame::NullBulkString);
+        assert_eq!(
+            waiter.await.unwrap(),
+            RespFrame::array([RespFrame::bulk("q"), RespFrame::bulk("job")])
+        );
+        assert_eq!(racer.backend.blocking.blocked_clients(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_waiters_are_served_in_order() {
+        let backend = crate::Backend::new();
+        let mut handles = Vec::new();
+        for i in 0..3 {
+            let mut s = Session::new(backend.clone());
+            handles.push(tokio::spawn(async move {
+                let args = ["BLMOVE", "q", &format!("done:{}", i), "LEFT", "RIGHT", "5"]
+                    .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+                    .to_vec();
+                execute_blocking(&mut s, &args).await
+            }));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut pusher = Session::new(backend.clone());
+        run(&mut pusher, &["RPUSH", "q", "a", "b", "c"]);
+        for (handle, expected) in handles.into_iter().zip(["a", "b", "c"]) {
+            assert_eq!(handle.await.unwrap(), RespFrame::bulk(expected));
+        }
+        assert_eq!(backend.blocking.blocked_clients(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_timeout() {
+        let mut s = Session::default();
+        let args = ["BRPOP", "q", "0.05"].map(Bytes::from).to_vec();
+        assert_eq!(execute_blocking(&mut s, &args).await, RespFrame::NullArray);
+        assert_eq!(s.backend.blocking.blocked_clients(), 0);
+        let args = ["BRPOP", "q", "-1"].map(Bytes::from).to_vec();
+        assert_eq!(
+            execute_blocking(&mut s, &args).await,
+            RespFrame::error("ERR timeout is negative")
+        );
+        for timeout in ["1e300", "9223372036854776"] {
+            let args = ["BLPOP", "q", timeout].map(Bytes::from).to_vec();
+            assert_eq!(
+                execute_blocking(&mut s, &args).await,
+                RespFrame::error("ERR timeout is out of range")
+            );
+        }
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let mut s = Session::default();
+        run(&mut s, &["SET", "k", "v"]);
+        let wrongtype =
+            RespFrame::error("WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(run(&mut s, &["LPUSH", "k", "a"]), wrongtype);
+        assert_eq!(run(&mut s, &["LRANGE", "k", "0", "-1"]), wrongtype);
+        assert_eq!(run(&mut s, &["HGET", "k", "a"]), wrongtype);
+        assert_eq!(run(&mut s, &["SADD", "k", "a"]), wrongtype);
+        assert_eq!(run(&mut s, &["ZADD", "k", "1", "a"]), wrongtype);
+        run(&mut s, &["RPUSH", "l", "a"]);
+        assert_eq!(run(&mut s, &["GET", "l"]), wrongtype);
+        assert_eq!(run(&mut s, &["TYPE", "l"]), RespFrame::simple("list"));
+        assert_eq!(run(&mut s, &["TYPE", "k"]), RespFrame::simple("string"));
+        assert_eq!(run(&mut s, &["TYPE", "x"]), RespFrame::simple("none"));
+    }
+}
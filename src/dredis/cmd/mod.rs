@@ -0,0 +1,998 @@
+mod acl;
+mod client;
+mod cluster;
+mod connection;
+mod hash;
+mod keys;
+mod list;
+mod pubsub;
+mod replication;
+mod scripting;
+mod server;
+mod set;
+mod stream;
+mod string;
+mod transaction;
+mod zset;
+
+use std::{
+    collections::HashMap,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use crate::{
+    category as cat, glob_match, is_no_auth_command, Backend, BlockGuard, Entry, RespFrame,
+    Session, Value,
+};
+
+type Handler = fn(&mut Session, &[Bytes]) -> Result<RespFrame>;
+/// The keys a blocking command waits on and for how long, `None` is forever.
+type Block = (Vec<Bytes>, Option<Duration>);
+/// Where a blocking command waits, `None` when these arguments never block.
+type BlockOn = fn(&[Bytes]) -> Result<Option<Block>>;
+
+/// A redis command: `arity` counts the command name itself, a negative arity
+/// means at least `-arity` arguments.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    handler: Handler,
+    block_on: Option<BlockOn>,
+    exclusive: bool,
+    write: bool,
+    may_write: bool,
+    denyoom: bool,
+    /// The ACL categories, besides the ones that follow from the flags.
+    acl: u32,
+    keys: Option<KeySpec>,
+}
+
+/// Where the keys of a command are.
+#[derive(Debug, Clone, Copy)]
+enum KeySpec {
+    /// From argument `first` to `last`, every `step`th one. A negative
+    /// `last` counts from the end, -1 is the last.
+    Range {
+        first: usize,
+        last: i32,
+        step: usize,
+    },
+    /// The arguments a function finds, for keys that follow a keyword like
+    /// the `STREAMS` of `XREAD`.
+    Find(fn(&[Bytes]) -> std::ops::Range<usize>),
+}
+
+impl CommandSpec {
+    const fn new(name: &'static str, arity: i32, handler: Handler) -> Self {
+        Self {
+            name,
+            arity,
+            handler,
+            block_on: None,
+            exclusive: false,
+            write: false,
+            may_write: false,
+            denyoom: false,
+            acl: 0,
+            keys: None,
+        }
+    }
+
+    const fn acl(mut self, categories: u32) -> Self {
+        self.acl = categories;
+        self
+    }
+
+    const fn keys(mut self, first: usize, last: i32, step: usize) -> Self {
+        self.keys = Some(KeySpec::Range { first, last, step });
+        self
+    }
+
+    const fn keys_by(mut self, find: fn(&[Bytes]) -> std::ops::Range<usize>) -> Self {
+        self.keys = Some(KeySpec::Find(find));
+        self
+    }
+
+    /// The ACL categories of the command, `@all` aside.
+    pub fn categories(&self) -> u32 {
+        let mut categories = self.acl;
+        if self.write {
+            categories |= cat::WRITE;
+        } else if self.keys.is_some() && !self.may_write {
+            categories |= cat::READ;
+        }
+        if self.block_on.is_some() {
+            categories |= cat::BLOCKING;
+        }
+        categories
+    }
+
+    /// The keys among `args`, which passed the arity check.
+    pub(crate) fn key_args<'a>(&self, args: &'a [Bytes]) -> impl Iterator<Item = &'a Bytes> {
+        let (range, step) = match self.keys {
+            Some(KeySpec::Range { first, last, step }) => {
+                let last = if last >= 0 {
+                    last as usize
+                } else {
+                    (args.len() as i32 + last).max(0) as usize
+                };
+                (first..last + 1, step)
+            }
+            Some(KeySpec::Find(find)) => (find(args), 1),
+            None => (0..0, 1),
+        };
+        let end = range.end.min(args.len());
+        args.get(range.start..end)
+            .unwrap_or_default()
+            .iter()
+            .step_by(step)
+    }
+
+    /// A command that may modify the keyspace, it is logged to the AOF.
+    const fn write(mut self) -> Self {
+        self.write = true;
+        self
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.write
+    }
+
+    /// A command that runs write commands without being one, like `EVAL`:
+    /// memory is freed ahead of it, the commands it runs are checked one
+    /// by one.
+    const fn may_write(mut self) -> Self {
+        self.may_write = true;
+        self
+    }
+
+    /// A write command that may grow the keyspace, refused while it is over
+    /// `maxmemory` and nothing can be evicted.
+    const fn denyoom(mut self) -> Self {
+        self.denyoom = true;
+        self
+    }
+
+    /// A command that runs alone: it holds the exec lock exclusively, where
+    /// other commands share it.
+    const fn exclusive(mut self) -> Self {
+        self.exclusive = true;
+        self
+    }
+
+    /// A command that waits for `block_on` keys when its handler has nothing
+    /// to return yet, i.e. replies with a null.
+    const fn blocking(mut self, block_on: BlockOn) -> Self {
+        self.block_on = Some(block_on);
+        self
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.block_on.is_some()
+    }
+
+    /// Whether `args` would wait for its keys: a blocking command, with the
+    /// `BLOCK` option for the stream ones.
+    pub(crate) fn blocks(&self, args: &[Bytes]) -> bool {
+        self.block_on
+            .is_some_and(|block_on| matches!(block_on(args), Ok(Some(_))))
+    }
+
+    pub(crate) fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+}
+
+static COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("ping", -1, connection::ping).acl(cat::CONNECTION),
+    CommandSpec::new("echo", 2, connection::echo).acl(cat::CONNECTION),
+    CommandSpec::new("hello", -1, connection::hello).acl(cat::CONNECTION),
+    CommandSpec::new("auth", -2, connection::auth).acl(cat::CONNECTION),
+    CommandSpec::new("quit", -1, connection::quit).acl(cat::CONNECTION),
+    CommandSpec::new("select", 2, connection::select).acl(cat::CONNECTION),
+    CommandSpec::new("client", -2, client::client)
+        .acl(cat::CONNECTION | cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("del", -2, keys::del)
+        .acl(cat::KEYSPACE)
+        .keys(1, -1, 1)
+        .write(),
+    CommandSpec::new("exists", -2, keys::exists)
+        .acl(cat::KEYSPACE)
+        .keys(1, -1, 1),
+    CommandSpec::new("expire", -3, keys::expire)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("pexpire", -3, keys::pexpire)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("expireat", -3, keys::expireat)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("pexpireat", -3, keys::pexpireat)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("ttl", 2, keys::ttl)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1),
+    CommandSpec::new("pttl", 2, keys::pttl)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1),
+    CommandSpec::new("expiretime", 2, keys::expiretime)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1),
+    CommandSpec::new("pexpiretime", 2, keys::pexpiretime)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1),
+    CommandSpec::new("persist", 2, keys::persist)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("object", -2, keys::object)
+        .acl(cat::KEYSPACE)
+        .keys(2, 2, 1),
+    CommandSpec::new("type", 2, keys::type_)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1),
+    CommandSpec::new("keys", 2, keys::keys).acl(cat::KEYSPACE | cat::READ | cat::DANGEROUS),
+    CommandSpec::new("scan", -2, keys::scan).acl(cat::KEYSPACE | cat::READ),
+    CommandSpec::new("move", 3, keys::move_)
+        .acl(cat::KEYSPACE)
+        .keys(1, 1, 1)
+        .write()
+        .exclusive(),
+    CommandSpec::new("dbsize", 1, keys::dbsize).acl(cat::KEYSPACE | cat::READ),
+    CommandSpec::new("flushdb", -1, keys::flushdb)
+        .acl(cat::KEYSPACE | cat::DANGEROUS)
+        .write()
+        .exclusive(),
+    CommandSpec::new("flushall", -1, keys::flushall)
+        .acl(cat::KEYSPACE | cat::DANGEROUS)
+        .write()
+        .exclusive(),
+    CommandSpec::new("swapdb", 3, keys::swapdb)
+        .acl(cat::KEYSPACE | cat::DANGEROUS)
+        .write()
+        .exclusive(),
+    CommandSpec::new("get", 2, string::get)
+        .acl(cat::STRING)
+        .keys(1, 1, 1),
+    CommandSpec::new("set", -3, string::set)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("mget", -2, string::mget)
+        .acl(cat::STRING)
+        .keys(1, -1, 1),
+    CommandSpec::new("mset", -3, string::mset)
+        .acl(cat::STRING)
+        .keys(1, -1, 2)
+        .write()
+        .denyoom(),
+    CommandSpec::new("append", 3, string::append)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("strlen", 2, string::strlen)
+        .acl(cat::STRING)
+        .keys(1, 1, 1),
+    CommandSpec::new("incr", 2, string::incr)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("decr", 2, string::decr)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("incrby", 3, string::incrby)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("decrby", 3, string::decrby)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("incrbyfloat", 3, string::incrbyfloat)
+        .acl(cat::STRING)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("hset", -4, hash::hset)
+        .acl(cat::HASH)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("hget", 3, hash::hget)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hmget", -3, hash::hmget)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hgetall", 2, hash::hgetall)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hkeys", 2, hash::hkeys)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hvals", 2, hash::hvals)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hdel", -3, hash::hdel)
+        .acl(cat::HASH)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("hexists", 3, hash::hexists)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hlen", 2, hash::hlen)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hscan", -3, hash::hscan)
+        .acl(cat::HASH)
+        .keys(1, 1, 1),
+    CommandSpec::new("hincrby", 4, hash::hincrby)
+        .acl(cat::HASH)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("lpush", -3, list::lpush)
+        .acl(cat::LIST)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("rpush", -3, list::rpush)
+        .acl(cat::LIST)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("lpop", -2, list::lpop)
+        .acl(cat::LIST)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("rpop", -2, list::rpop)
+        .acl(cat::LIST)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("lrange", 4, list::lrange)
+        .acl(cat::LIST)
+        .keys(1, 1, 1),
+    CommandSpec::new("llen", 2, list::llen)
+        .acl(cat::LIST)
+        .keys(1, 1, 1),
+    CommandSpec::new("lindex", 3, list::lindex)
+        .acl(cat::LIST)
+        .keys(1, 1, 1),
+    CommandSpec::new("lmove", 5, list::lmove)
+        .acl(cat::LIST)
+        .keys(1, 2, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("blpop", -3, list::blpop)
+        .acl(cat::LIST)
+        .keys(1, -2, 1)
+        .write()
+        .blocking(list::block_on_pop),
+    CommandSpec::new("brpop", -3, list::brpop)
+        .acl(cat::LIST)
+        .keys(1, -2, 1)
+        .write()
+        .blocking(list::block_on_pop),
+    CommandSpec::new("blmove", 6, list::blmove)
+        .acl(cat::LIST)
+        .keys(1, 2, 1)
+        .write()
+        .denyoom()
+        .blocking(list::block_on_move),
+    CommandSpec::new("sadd", -3, set::sadd)
+        .acl(cat::SET)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("srem", -3, set::srem)
+        .acl(cat::SET)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("smembers", 2, set::smembers)
+        .acl(cat::SET)
+        .keys(1, 1, 1),
+    CommandSpec::new("sismember", 3, set::sismember)
+        .acl(cat::SET)
+        .keys(1, 1, 1),
+    CommandSpec::new("sscan", -3, set::sscan)
+        .acl(cat::SET)
+        .keys(1, 1, 1),
+    CommandSpec::new("scard", 2, set::scard)
+        .acl(cat::SET)
+        .keys(1, 1, 1),
+    CommandSpec::new("zadd", -4, zset::zadd)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("zincrby", 4, zset::zincrby)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("zrem", -3, zset::zrem)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("zscore", 3, zset::zscore)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1),
+    CommandSpec::new("zscan", -3, zset::zscan)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1),
+    CommandSpec::new("zcard", 2, zset::zcard)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1),
+    CommandSpec::new("zrange", -4, zset::zrange)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1),
+    CommandSpec::new("zrangebyscore", -4, zset::zrangebyscore)
+        .acl(cat::SORTEDSET)
+        .keys(1, 1, 1),
+    CommandSpec::new("xadd", -5, stream::xadd)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("xtrim", -4, stream::xtrim)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xdel", -3, stream::xdel)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xlen", 2, stream::xlen)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1),
+    CommandSpec::new("xrange", -4, stream::xrange)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1),
+    CommandSpec::new("xrevrange", -4, stream::xrevrange)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1),
+    CommandSpec::new("xread", -4, stream::xread)
+        .acl(cat::STREAM)
+        .keys_by(stream::read_keys)
+        .blocking(stream::block_on_read),
+    CommandSpec::new("xreadgroup", -7, stream::xreadgroup)
+        .acl(cat::STREAM)
+        .keys_by(stream::read_keys)
+        .write()
+        .blocking(stream::block_on_read),
+    CommandSpec::new("xack", -4, stream::xack)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xpending", -3, stream::xpending)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1),
+    CommandSpec::new("xclaim", -6, stream::xclaim)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xautoclaim", -6, stream::xautoclaim)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xsetid", -3, stream::xsetid)
+        .acl(cat::STREAM)
+        .keys(1, 1, 1)
+        .write(),
+    CommandSpec::new("xgroup", -2, stream::xgroup)
+        .acl(cat::STREAM)
+        .keys(2, 2, 1)
+        .write()
+        .denyoom(),
+    CommandSpec::new("xinfo", -2, stream::xinfo)
+        .acl(cat::STREAM)
+        .keys(2, 2, 1),
+    CommandSpec::new("subscribe", -2, pubsub::subscribe).acl(cat::PUBSUB),
+    CommandSpec::new("unsubscribe", -1, pubsub::unsubscribe).acl(cat::PUBSUB),
+    CommandSpec::new("psubscribe", -2, pubsub::psubscribe).acl(cat::PUBSUB),
+    CommandSpec::new("punsubscribe", -1, pubsub::punsubscribe).acl(cat::PUBSUB),
+    CommandSpec::new("publish", 3, pubsub::publish).acl(cat::PUBSUB),
+    CommandSpec::new("pubsub", -2, pubsub::pubsub).acl(cat::PUBSUB),
+    CommandSpec::new("multi", 1, transaction::multi).acl(cat::TRANSACTION),
+    CommandSpec::new("exec", 1, transaction::exec)
+        .acl(cat::TRANSACTION)
+        .exclusive(),
+    CommandSpec::new("discard", 1, transaction::discard).acl(cat::TRANSACTION),
+    CommandSpec::new("watch", -2, transaction::watch)
+        .acl(cat::TRANSACTION)
+        .keys(1, -1, 1),
+    CommandSpec::new("unwatch", 1, transaction::unwatch).acl(cat::TRANSACTION),
+    CommandSpec::new("eval", -3, scripting::eval)
+        .acl(cat::SCRIPTING)
+        .keys_by(scripting::eval_keys)
+        .may_write()
+        .exclusive(),
+    CommandSpec::new("evalsha", -3, scripting::evalsha)
+        .acl(cat::SCRIPTING)
+        .keys_by(scripting::eval_keys)
+        .may_write()
+        .exclusive(),
+    CommandSpec::new("script", -2, scripting::script).acl(cat::SCRIPTING),
+    CommandSpec::new("save", 1, server::save)
+        .acl(cat::ADMIN | cat::DANGEROUS)
+        .exclusive(),
+    CommandSpec::new("bgsave", -1, server::bgsave)
+        .acl(cat::ADMIN | cat::DANGEROUS)
+        .exclusive(),
+    CommandSpec::new("lastsave", 1, server::lastsave).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("bgrewriteaof", 1, server::bgrewriteaof)
+        .acl(cat::ADMIN | cat::DANGEROUS)
+        .exclusive(),
+    CommandSpec::new("shutdown", -1, server::shutdown).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("info", -1, server::info).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("memory", -2, server::memory)
+        .acl(cat::KEYSPACE)
+        .keys(2, 2, 1),
+    CommandSpec::new("config", -2, server::config).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("replicaof", 3, replication::replicaof).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("slaveof", 3, replication::replicaof).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("replconf", -1, replication::replconf).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("acl", -2, acl::acl).acl(cat::ADMIN | cat::DANGEROUS),
+    CommandSpec::new("psync", -3, replication::psync)
+        .acl(cat::ADMIN | cat::DANGEROUS)
+        .exclusive(),
+    CommandSpec::new("cluster", -2, cluster::cluster),
+    CommandSpec::new("asking", 1, cluster::asking).acl(cat::CONNECTION),
+];
+
+/// Every command dredis knows.
+pub fn all_commands() -> &'static [CommandSpec] {
+    COMMANDS
+}
+
+pub fn lookup_command(name: &[u8]) -> Option<&'static CommandSpec> {
+    static TABLE: OnceLock<HashMap<&'static str, &'static CommandSpec>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| COMMANDS.iter().map(|c| (c.name, c)).collect());
+    let name = std::str::from_utf8(name).ok()?.to_ascii_lowercase();
+    table.get(name.as_str()).copied()
+}
+
+/// Turn a request frame into the command arguments, the command name first.
+pub fn frame_to_args(frame: RespFrame) -> Result<Vec<Bytes>> {
+    let RespFrame::Array(items) = frame else {
+        return Err(anyhow!("Protocol error: expected an array of bulk strings"));
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            RespFrame::BulkString(b) => Ok(b),
+            _ => Err(anyhow!("Protocol error: expected an array of bulk strings")),
+        })
+        .collect()
+}
+
+/// Execute one command for `session`, failures are turned into error replies.
+/// Inside `MULTI` commands are queued instead.
+pub fn execute(session: &mut Session, args: &[Bytes]) -> RespFrame {
+    let cmd = match check_command(args) {
+        Ok(cmd) => {
+            session.client.command_started(cmd.name);
+            cmd
+        }
+        Err(e) => {
+            if let Some(tx) = session.multi.as_mut() {
+                tx.aborted = true;
+            }
+            return RespFrame::error(e);
+        }
+    };
+    // like redis, ASKING holds for the next command only, or the whole of a
+    // transaction
+    let asking = session.asking;
+    if session.multi.is_none() {
+        session.asking = false;
+    }
+    if !session.authenticated && !is_no_auth_command(cmd) {
+        return RespFrame::error("NOAUTH Authentication required.");
+    }
+    if let Some(user) = &session.user {
+        if let Err(e) = session.backend.check_permission(user, cmd, args) {
+            if let Some(tx) = session.multi.as_mut() {
+                tx.aborted = true;
+            }
+            return RespFrame::error(e);
+        }
+    }
+    if session.in_subscribed_mode() && !pubsub::SUBSCRIBED_MODE_COMMANDS.contains(&cmd.name) {
+        return RespFrame::error(pubsub::err_subscribed_mode(cmd.name));
+    }
+    if !session.master_link {
+        if let Err(e) = cluster::check_slot(session, cmd, args, asking) {
+            if let Some(tx) = session.multi.as_mut() {
+                tx.aborted = true;
+            }
+            return RespFrame::error(e);
+        }
+    }
+    if cmd.write && !session.master_link && session.backend.is_replica() {
+        if let Some(tx) = session.multi.as_mut() {
+            tx.aborted = true;
+        }
+        return RespFrame::error("READONLY You can't write against a read only replica.");
+    }
+    if (cmd.write || cmd.may_write) && !session.master_link {
+        let fits = session.backend.free_memory();
+        if !fits && cmd.denyoom {
+            if let Some(tx) = session.multi.as_mut() {
+                tx.aborted = true;
+            }
+            return RespFrame::error("OOM command not allowed when used memory > 'maxmemory'.");
+        }
+    }
+    if let Some(tx) = session.multi.as_mut() {
+        if !transaction::NOT_QUEUED.contains(&cmd.name) {
+            tx.queued.push(args.to_vec());
+            return RespFrame::simple("QUEUED");
+        }
+    }
+    let backend = session.backend.clone();
+    if scripting::runs_while_busy(cmd, args) {
+        // it must not wait for the script it stops
+        return call(session, cmd, args);
+    }
+    // a write to keys clients block on serves them before anyone else
+    let serves = cmd.write && backend.blocking.has_waiters(backend.db, cmd.key_args(args));
+    if cmd.exclusive || serves {
+        let _exclusive = match backend.lock_unless_busy(RwLock::try_write, RwLock::write) {
+            Ok(guard) => guard,
+            Err(e) => return RespFrame::error(e.to_string()),
+        };
+        let reply = call(session, cmd, args);
+        serve_blocked(&backend);
+        reply
+    } else {
+        let _shared = match backend.lock_unless_busy(RwLock::try_read, RwLock::read) {
+            Ok(guard) => guard,
+            Err(e) => return RespFrame::error(e.to_string()),
+        };
+        // writes are logged in the order they modify the keyspace
+        let _ordered =
+            (cmd.write && backend.propagating()).then(|| backend.propagate_lock.lock().unwrap());
+        call(session, cmd, args)
+    }
+}
+
+fn check_command(args: &[Bytes]) -> Result<&'static CommandSpec, String> {
+    let name = args.first().ok_or("ERR empty command")?;
+    let cmd = lookup_command(name).ok_or_else(|| unknown_command(args))?;
+    if !cmd.check_arity(args.len()) {
+        return Err(err_arity(cmd.name));
+    }
+    Ok(cmd)
+}
+
+/// Run the handler and log a successful write, as the commands the handler
+/// asked for in `propagate_as` or else as it was sent.
+fn call(session: &mut Session, cmd: &CommandSpec, args: &[Bytes]) -> RespFrame {
+    let reply = (cmd.handler)(session, args).unwrap_or_else(|e| RespFrame::error(e.to_string()));
+    let propagate_as = session.propagate_as.take();
+    if cmd.write && !matches!(reply, RespFrame::Error(_)) && session.backend.propagating() {
+        match propagate_as {
+            Some(cmds) => cmds.iter().for_each(|c| session.propagate(c)),
+            None => session.propagate(args),
+        }
+    }
+    reply
+}
+
+/// Execute a command that may block: run it and, while it has nothing to
+/// return, wait for one of its keys to be signaled ready or for the timeout.
+/// Inside `MULTI` or scripts blocking commands go through `execute` instead,
+/// which never waits, like redis.
+pub async fn execute_blocking(session: &mut Session, args: &[Bytes]) -> RespFrame {
+    if session.multi.is_some() {
+        return execute(session, args);
+    }
+    let Some(cmd) = args.first().and_then(|name| lookup_command(name)) else {
+        return execute(session, args);
+    };
+    let (Some(block_on), true) = (cmd.block_on, cmd.check_arity(args.len())) else {
+        return execute(session, args);
+    };
+    let (keys, timeout) = match block_on(args) {
+        Ok(Some(block)) => block,
+        Ok(None) => return execute(session, args),
+        Err(e) => return RespFrame::error(e.to_string()),
+    };
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+    // register before the first attempt so a push in between is not missed
+    let guard = BlockGuard::new(&session.backend, keys, session.protocol, args.to_vec());
+    loop {
+        let null = match guard.attempt(|args| {
+            session.retry_as = None;
+            let reply = execute(session, args);
+            (reply, session.retry_as.take())
+        }) {
+            Ok(reply) => return reply,
+            Err(null) => null,
+        };
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, guard.notified())
+                    .await
+                    .is_err()
+                {
+                    return guard.give_up(null);
+                }
+            }
+            None => guard.notified().await,
+        }
+    }
+}
+
+/// Serve the clients blocked on the keys a write made ready, the longest
+/// waiting first, before any other command can take what they wait for.
+/// The caller holds the exec lock exclusively.
+fn serve_blocked(backend: &Backend) {
+    let blocking = &backend.blocking;
+    while let Some((db, key)) = blocking.take_ready() {
+        for waiter in blocking.queue(db, &key) {
+            // a client running its command itself goes next anyway
+            let Ok(mut state) = waiter.state.try_lock() else {
+                break;
+            };
+            if state.gone || state.served.is_some() {
+                continue;
+            }
+            let Some(cmd) = state.args.first().and_then(|name| lookup_command(name)) else {
+                continue;
+            };
+            let mut session = Session::new(state.backend.clone());
+            session.protocol = state.protocol;
+            let args = state.args.clone();
+            let reply = call(&mut session, cmd, &args);
+            if let Some(retry) = session.retry_as.take() {
+                state.args = retry;
+            }
+            if !reply.is_null() {
+                blocking.serve(db, &waiter, &mut state, reply);
+            }
+        }
+    }
+}
+
+fn unknown_command(args: &[Bytes]) -> String {
+    let quoted = args[1..]
+        .iter()
+        .map(|a| format!("'{}' ", String::from_utf8_lossy(a)))
+        .collect::<String>();
+    format!(
+        "ERR unknown command '{}', with args beginning with: {}",
+        String::from_utf8_lossy(&args[0]),
+        quoted
+    )
+}
+
+fn err_arity(name: &str) -> String {
+    format!("ERR wrong number of arguments for '{}' command", name)
+}
+
+fn err_syntax() -> anyhow::Error {
+    anyhow!("ERR syntax error")
+}
+
+fn err_wrongtype() -> anyhow::Error {
+    anyhow!("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+fn arg_str(arg: &[u8]) -> Result<&str> {
+    std::str::from_utf8(arg).map_err(|_| err_syntax())
+}
+
+fn arg_i64(arg: &[u8]) -> Result<i64> {
+    arg_str(arg)?
+        .parse()
+        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))
+}
+
+/// A database index, `SELECT` and `MOVE` style.
+fn arg_db(session: &Session, arg: &[u8]) -> Result<usize> {
+    usize::try_from(arg_i64(arg)?)
+        .ok()
+        .filter(|&db| db < session.backend.databases())
+        .ok_or_else(|| anyhow!("ERR DB index is out of range"))
+}
+
+fn arg_f64(arg: &[u8]) -> Result<f64> {
+    arg_str(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| !f.is_nan())
+        .ok_or_else(|| anyhow!("ERR value is not a valid float"))
+}
+
+/// The longest blocking timeout, the milliseconds must fit an `i64` as in
+/// redis.
+const MAX_TIMEOUT: Duration = Duration::from_millis(i64::MAX as u64);
+
+/// Blocking timeouts are seconds with decimals, zero blocks forever.
+fn arg_timeout(arg: &[u8]) -> Result<Option<Duration>> {
+    let secs = arg_str(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| f.is_finite())
+        .ok_or_else(|| anyhow!("ERR timeout is not a float or out of range"))?;
+    if secs < 0.0 {
+        return Err(anyhow!("ERR timeout is negative"));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|&timeout| timeout <= MAX_TIMEOUT)
+        .map(Some)
+        .ok_or_else(|| anyhow!("ERR timeout is out of range"))
+}
+
+/// `cursor [MATCH pattern] [COUNT count] [TYPE type]` of `SCAN` and friends,
+/// `TYPE` only where `with_type`.
+struct ScanArgs {
+    cursor: u64,
+    pattern: Option<Bytes>,
+    count: usize,
+    type_name: Option<String>,
+}
+
+impl ScanArgs {
+    fn parse(args: &[Bytes], with_type: bool) -> Result<Self> {
+        let cursor = arg_str(&args[0])?
+            .parse()
+            .map_err(|_| anyhow!("ERR invalid cursor"))?;
+        let mut scan = ScanArgs {
+            cursor,
+            pattern: None,
+            count: 10,
+            type_name: None,
+        };
+        let mut opts = args[1..].iter();
+        while let Some(opt) = opts.next() {
+            let value = opts.next().ok_or_else(err_syntax)?;
+            match arg_str(opt)?.to_ascii_lowercase().as_str() {
+                "match" => scan.pattern = Some(value.clone()),
+                "count" => {
+                    scan.count = usize::try_from(arg_i64(value)?)
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(err_syntax)?
+                }
+                "type" if with_type => scan.type_name = Some(arg_str(value)?.to_ascii_lowercase()),
+                _ => return Err(err_syntax()),
+            }
+        }
+        Ok(scan)
+    }
+
+    fn matches(&self, item: &[u8]) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| glob_match(pattern, item))
+    }
+
+    /// The reply: the next cursor and the page.
+    fn reply(cursor: u64, items: Vec<RespFrame>) -> RespFrame {
+        RespFrame::array([RespFrame::bulk(cursor.to_string()), RespFrame::Array(items)])
+    }
+}
+
+/// The aggregate in `entry` as `T`, `WRONGTYPE` when it holds something else.
+fn typed<T>(entry: Option<&Entry>, cast: fn(&Value) -> Option<&T>) -> Result<Option<&T>> {
+    entry
+        .map(|e| cast(&e.value).ok_or_else(err_wrongtype))
+        .transpose()
+}
+
+fn typed_mut<T>(
+    slot: &mut Option<Entry>,
+    cast: fn(&mut Value) -> Option<&mut T>,
+) -> Result<Option<&mut T>> {
+    slot.as_mut()
+        .map(|e| cast(&mut e.value).ok_or_else(err_wrongtype))
+        .transpose()
+}
+
+/// Like `typed_mut` but creates an empty aggregate for a missing key.
+fn typed_or_insert<T: Default + Into<Value>>(
+    slot: &mut Option<Entry>,
+    cast: fn(&mut Value) -> Option<&mut T>,
+) -> Result<&mut T> {
+    let entry = slot.get_or_insert_with(|| Entry::new(T::default().into()));
+    cast(&mut entry.value).ok_or_else(err_wrongtype)
+}
+
+/// Resolve redis style inclusive `start`/`stop` indexes, negative ones count
+/// from the end, into a range of `0..len`.
+fn index_range(start: i64, stop: i64, len: usize) -> Option<std::ops::RangeInclusive<usize>> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some(start as usize..=stop as usize)
+}
+
+#[cfg(test)]
+pub(crate) fn run(session: &mut Session, args: &[&str]) -> RespFrame {
+    let args = args
+        .iter()
+        .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+        .collect::<Vec<_>>();
+    execute(session, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RespVersion;
+
+    #[test]
+    fn test_ping_and_echo() {
+        let mut session = Session::default();
+        assert_eq!(run(&mut session, &["PING"]), RespFrame::simple("PONG"));
+        assert_eq!(run(&mut session, &["ping", "hi"]), RespFrame::bulk("hi"));
+        assert_eq!(run(&mut session, &["echo", "x"]), RespFrame::bulk("x"));
+        assert_eq!(
+            run(&mut session, &["echo"]),
+            RespFrame::error("ERR wrong number of arguments for 'echo' command")
+        );
+        assert_eq!(
+            run(&mut session, &["nope", "a"]),
+            RespFrame::error("ERR unknown command 'nope', with args beginning with: 'a' ")
+        );
+    }
+
+    #[test]
+    fn test_hello_switches_protocol() {
+        let mut session = Session::default();
+        assert_eq!(session.protocol, RespVersion::Resp2);
+        let RespFrame::Map(reply) = run(&mut session, &["HELLO", "3", "SETNAME", "app"]) else {
+            panic!("HELLO should reply with a map");
+        };
+        assert!(reply.contains(&(RespFrame::bulk("proto"), RespFrame::Integer(3))));
+        assert_eq!(session.protocol, RespVersion::Resp3);
+        assert_eq!(session.name.as_deref(), Some("app"));
+
+        let reply = run(&mut session, &["HELLO", "4"]);
+        assert!(matches!(reply, RespFrame::Error(e) if e.starts_with("NOPROTO")));
+        assert_eq!(session.protocol, RespVersion::Resp3);
+
+        run(&mut session, &["HELLO", "2"]);
+        assert_eq!(session.protocol, RespVersion::Resp2);
+    }
+}
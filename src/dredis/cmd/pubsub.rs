@@ -0,0 +1,267 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::arg_str;
+use crate::{Kind, RespFrame, Session, Subscriptions};
+
+/// Commands a RESP2 client in subscribed mode may still use.
+pub(super) const SUBSCRIBED_MODE_COMMANDS: &[&str] = &[
+    "subscribe",
+    "psubscribe",
+    "unsubscribe",
+    "punsubscribe",
+    "ping",
+    "quit",
+];
+
+/// The error for `cmd` sent in subscribed mode, naming the commands allowed.
+pub(super) fn err_subscribed_mode(cmd: &str) -> String {
+    let allowed = SUBSCRIBED_MODE_COMMANDS
+        .iter()
+        .map(|name| name.to_ascii_uppercase())
+        .collect::<Vec<_>>();
+    format!(
+        "ERR Can't execute '{}': only {} are allowed in this context",
+        cmd,
+        allowed.join(" / ")
+    )
+}
+
+pub(super) fn subscribe(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    add(session, Kind::Channel, &args[1..])
+}
+
+pub(super) fn psubscribe(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    add(session, Kind::Pattern, &args[1..])
+}
+
+pub(super) fn unsubscribe(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    remove(session, Kind::Channel, &args[1..])
+}
+
+pub(super) fn punsubscribe(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    remove(session, Kind::Pattern, &args[1..])
+}
+
+fn add(session: &mut Session, kind: Kind, topics: &[Bytes]) -> Result<RespFrame> {
+    let subs = session
+        .subscriptions
+        .get_or_insert_with(|| Subscriptions::new(session.backend.pubsub.clone(), session.id));
+    let replies = topics
+        .iter()
+        .map(|topic| {
+            subs.subscribe(kind, topic);
+            confirmation(kind, true, RespFrame::bulk(topic.clone()), subs.len())
+        })
+        .collect();
+    Ok(reply_each(session, replies))
+}
+
+/// Unsubscribe from `topics`, or from every channel or pattern when empty.
+fn remove(session: &mut Session, kind: Kind, topics: &[Bytes]) -> Result<RespFrame> {
+    let Some(subs) = session.subscriptions.as_mut() else {
+        return Ok(confirmation(kind, false, RespFrame::NullBulkString, 0));
+    };
+    let topics = if topics.is_empty() {
+        match kind {
+            Kind::Channel => subs.channels.iter().cloned().collect(),
+            Kind::Pattern => subs.patterns.iter().cloned().collect(),
+        }
+    } else {
+        topics.to_vec()
+    };
+    let mut replies = topics
+        .into_iter()
+        .map(|topic| {
+            subs.unsubscribe(kind, &topic);
+            confirmation(kind, false, RespFrame::bulk(topic), subs.len())
+        })
+        .collect::<Vec<_>>();
+    if replies.is_empty() {
+        replies.push(confirmation(
+            kind,
+            false,
+            RespFrame::NullBulkString,
+            subs.len(),
+        ));
+    }
+    if subs.is_empty() {
+        session.subscriptions = None;
+    }
+    Ok(reply_each(session, replies))
+}
+
+fn confirmation(kind: Kind, subscribe: bool, topic: RespFrame, count: usize) -> RespFrame {
+    let name = match (kind, subscribe) {
+        (Kind::Channel, true) => "subscribe",
+        (Kind::Channel, false) => "unsubscribe",
+        (Kind::Pattern, true) => "psubscribe",
+        (Kind::Pattern, false) => "punsubscribe",
+    };
+    RespFrame::Push(vec![
+        RespFrame::bulk(name),
+        topic,
+        RespFrame::Integer(count as i64),
+    ])
+}
+
+/// Send one reply per topic: all but the last go out ahead of the last one.
+fn reply_each(session: &mut Session, mut replies: Vec<RespFrame>) -> RespFrame {
+    let last = replies.pop().expect("at least one reply");
+    session.pushes.extend(replies);
+    last
+}
+
+pub(super) fn publish(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let receivers = session.backend.pubsub.publish(&args[1], &args[2]);
+    Ok(RespFrame::Integer(receivers as i64))
+}
+
+/// `PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT`
+pub(super) fn pubsub(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let pubsub = &session.backend.pubsub;
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("channels", 2 | 3) => {
+            let channels = pubsub.channels(args.get(2).map(|p| &p[..]));
+            Ok(RespFrame::Array(
+                channels.into_iter().map(RespFrame::bulk).collect(),
+            ))
+        }
+        ("numsub", _) => Ok(RespFrame::Map(
+            args[2..]
+                .iter()
+                .map(|c| {
+                    let count = pubsub.numsub(c) as i64;
+                    (RespFrame::bulk(c.clone()), RespFrame::Integer(count))
+                })
+                .collect(),
+        )),
+        ("numpat", 2) => Ok(RespFrame::Integer(pubsub.numpat() as i64)),
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, RespVersion, Session};
+
+    /// All the replies to one command, in the order they are written.
+    fn run_all(session: &mut Session, args: &[&str]) -> Vec<RespFrame> {
+        let reply = run(session, args);
+        let mut replies = mem::take(&mut session.pushes);
+        replies.push(reply);
+        replies
+    }
+
+    fn push(items: &[&'static str], count: i64) -> RespFrame {
+        let mut items = items
+            .iter()
+            .map(|i| RespFrame::bulk(*i))
+            .collect::<Vec<_>>();
+        items.push(RespFrame::Integer(count));
+        RespFrame::Push(items)
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let mut s = Session::default();
+        assert_eq!(
+            run_all(&mut s, &["SUBSCRIBE", "a", "b"]),
+            vec![push(&["subscribe", "a"], 1), push(&["subscribe", "b"], 2)]
+        );
+        assert_eq!(
+            run_all(&mut s, &["PSUBSCRIBE", "a*"]),
+            vec![push(&["psubscribe", "a*"], 3)]
+        );
+        assert_eq!(
+            run_all(&mut s, &["UNSUBSCRIBE"]),
+            vec![
+                push(&["unsubscribe", "a"], 2),
+                push(&["unsubscribe", "b"], 1)
+            ]
+        );
+        assert!(s.in_subscribed_mode());
+        assert_eq!(
+            run_all(&mut s, &["PUNSUBSCRIBE", "a*"]),
+            vec![push(&["punsubscribe", "a*"], 0)]
+        );
+        assert!(!s.in_subscribed_mode());
+        assert_eq!(
+            run(&mut s, &["UNSUBSCRIBE"]),
+            RespFrame::Push(vec![
+                RespFrame::bulk("unsubscribe"),
+                RespFrame::NullBulkString,
+                RespFrame::Integer(0)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_subscribed_mode_restricts_commands() {
+        let mut s = Session::default();
+        run(&mut s, &["SUBSCRIBE", "a"]);
+        assert_eq!(
+            run(&mut s, &["GET", "k"]),
+            RespFrame::error(
+                "ERR Can't execute 'get': only SUBSCRIBE / PSUBSCRIBE / UNSUBSCRIBE / PUNSUBSCRIBE / PING / QUIT are allowed in this context"
+            )
+        );
+        assert_eq!(
+            run(&mut s, &["PING"]),
+            RespFrame::array([RespFrame::bulk("pong"), RespFrame::bulk("")])
+        );
+
+        // RESP3 clients can run any command while subscribed
+        s.protocol = RespVersion::Resp3;
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::NullBulkString);
+        assert_eq!(run(&mut s, &["PING"]), RespFrame::simple("PONG"));
+    }
+
+    #[test]
+    fn test_publish_and_introspection() {
+        let mut publisher = Session::default();
+        let mut a = Session::new(publisher.backend.clone());
+        let mut b = Session::new(publisher.backend.clone());
+        run(&mut a, &["SUBSCRIBE", "news.tech", "news.art"]);
+        run(&mut b, &["SUBSCRIBE", "news.tech"]);
+        run(&mut b, &["PSUBSCRIBE", "news.*"]);
+        assert_eq!(
+            run(&mut publisher, &["PUBLISH", "news.tech", "m"]),
+            RespFrame::Integer(3)
+        );
+        assert_eq!(
+            run(&mut publisher, &["PUBLISH", "other", "m"]),
+            RespFrame::Integer(0)
+        );
+        let RespFrame::Array(mut channels) = run(&mut publisher, &["PUBSUB", "CHANNELS", "*t*"])
+        else {
+            panic!("PUBSUB CHANNELS should reply with an array");
+        };
+        channels.sort_by_key(|c| format!("{:?}", c));
+        assert_eq!(
+            channels,
+            vec![RespFrame::bulk("news.art"), RespFrame::bulk("news.tech")]
+        );
+        assert_eq!(
+            run(&mut publisher, &["PUBSUB", "NUMSUB", "news.tech", "x"]).to_bytes(),
+            "%2\r\n$9\r\nnews.tech\r\n:2\r\n$1\r\nx\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&mut publisher, &["PUBSUB", "NUMPAT"]),
+            RespFrame::Integer(1)
+        );
+
+        drop(b);
+        assert_eq!(
+            run(&mut publisher, &["PUBLISH", "news.tech", "m"]),
+            RespFrame::Integer(1)
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, err_syntax};
+use crate::{RespFrame, Session};
+
+/// `REPLICAOF host port` or `REPLICAOF NO ONE`
+pub(super) fn replicaof(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let host = arg_str(&args[1])?;
+    let port = arg_str(&args[2])?;
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        session.backend.replicaof(None);
+        return Ok(RespFrame::ok());
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| anyhow!("ERR Invalid master port"))?;
+    if session.backend.replicaof(Some((host.to_string(), port))) {
+        Ok(RespFrame::ok())
+    } else {
+        Ok(RespFrame::simple(
+            "OK Already connected to specified master",
+        ))
+    }
+}
+
+/// `REPLCONF <option> <value> ...`, sent by replicas during the handshake.
+pub(super) fn replconf(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if args.len().is_multiple_of(2) {
+        return Err(err_syntax());
+    }
+    for pair in args[1..].chunks(2) {
+        match arg_str(&pair[0])?.to_ascii_lowercase().as_str() {
+            "listening-port" => {
+                let port = u16::try_from(arg_i64(&pair[1])?)
+                    .map_err(|_| anyhow!("ERR Invalid listening port"))?;
+                session.replica_port = Some(port);
+            }
+            "capa" | "ip-address" | "ack" | "getack" => {}
+            other => {
+                return Err(anyhow!("ERR Unrecognized REPLCONF option: {}", other));
+            }
+        }
+    }
+    Ok(RespFrame::ok())
+}
+
+/// `PSYNC replicationid offset`, the connection becomes a replica's.
+pub(super) fn psync(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let replid = arg_str(&args[1])?;
+    let offset = arg_i64(&args[2])?;
+    let (reply, feed) = session.backend.psync(session, replid, offset)?;
+    session.replica_feed = Some(feed);
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, Session};
+
+    #[test]
+    fn test_replconf() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(
+                &mut s,
+                &["REPLCONF", "listening-port", "6380", "capa", "psync2"]
+            ),
+            RespFrame::ok()
+        );
+        assert_eq!(s.replica_port, Some(6380));
+        assert_eq!(
+            run(&mut s, &["REPLCONF", "listening-port"]),
+            RespFrame::error("ERR syntax error")
+        );
+        assert!(matches!(
+            run(&mut s, &["REPLCONF", "nope", "1"]),
+            RespFrame::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_psync_replies_full_resync() {
+        let mut s = Session::default();
+        run(&mut s, &["SET", "a", "1"]);
+        let RespFrame::SimpleString(reply) = run(&mut s, &["PSYNC", "?", "-1"]) else {
+            panic!("PSYNC should reply with a status");
+        };
+        let (replid, offset) = s.backend.repl_offset();
+        assert_eq!(reply, format!("FULLRESYNC {} {}", replid, offset));
+        assert!(s.replica_feed.is_some());
+        assert_eq!(
+            run(&mut s, &["REPLICAOF", "localhost", "port"]),
+            RespFrame::error("ERR Invalid master port")
+        );
+    }
+}
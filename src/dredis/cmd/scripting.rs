@@ -0,0 +1,415 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, call, err_syntax, lookup_command, CommandSpec};
+use crate::{RespFrame, Session};
+
+/// Commands scripts may not call: the ones about the connection, which a
+/// script does not have, and the ones that would run a script or stop the
+/// server in the middle of one.
+const NOSCRIPT: &[&str] = &[
+    "auth",
+    "hello",
+    "quit",
+    "client",
+    "multi",
+    "exec",
+    "discard",
+    "watch",
+    "unwatch",
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "eval",
+    "evalsha",
+    "script",
+    "save",
+    "bgsave",
+    "bgrewriteaof",
+    "shutdown",
+    "config",
+    "acl",
+    "replicaof",
+    "slaveof",
+    "replconf",
+    "psync",
+    "asking",
+];
+
+/// Whether the command runs while a script holds the exec lock, where others
+/// get `BUSY`: `SCRIPT KILL` and `SHUTDOWN NOSAVE`, which stop the script.
+pub(super) fn runs_while_busy(cmd: &CommandSpec, args: &[Bytes]) -> bool {
+    match cmd.name {
+        "script" => args.len() == 2 && args[1].eq_ignore_ascii_case(b"kill"),
+        "shutdown" => args[1..].iter().any(|a| a.eq_ignore_ascii_case(b"nosave")),
+        _ => false,
+    }
+}
+
+/// `EVAL script numkeys [key ...] [arg ...]`, runs holding the exec lock
+/// exclusively so no other command sees the script halfway.
+pub(super) fn eval(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sha = session.backend.scripting.load(&args[1])?;
+    run(session, &sha, &args[2..])
+}
+
+/// `EVALSHA sha1 numkeys [key ...] [arg ...]`
+pub(super) fn evalsha(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    run(session, &String::from_utf8_lossy(&args[1]), &args[2..])
+}
+
+/// The keys of `EVAL` and `EVALSHA`, the `numkeys` after the script.
+pub(super) fn eval_keys(args: &[Bytes]) -> Range<usize> {
+    match arg_i64(&args[2]) {
+        Ok(numkeys) if numkeys >= 0 => 3..(3 + numkeys as usize).min(args.len()),
+        _ => 0..0,
+    }
+}
+
+fn run(session: &mut Session, sha: &str, args: &[Bytes]) -> Result<RespFrame> {
+    let numkeys = arg_i64(&args[0])?;
+    if numkeys < 0 {
+        bail!("ERR Number of keys can't be negative");
+    }
+    let Some((keys, argv)) = args[1..].split_at_checked(numkeys as usize) else {
+        bail!("ERR Number of keys can't be greater than number of args");
+    };
+    let backend = session.backend.clone();
+    let mut client = ScriptClient::new(session);
+    let reply = backend
+        .scripting
+        .run(sha, keys, argv, |args| client.call(args));
+    client.finish();
+    reply
+}
+
+/// `SCRIPT LOAD script | EXISTS sha1 [sha1 ...] | FLUSH [ASYNC|SYNC] | KILL`
+pub(super) fn script(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let scripting = &session.backend.scripting;
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("load", 3) => Ok(RespFrame::bulk(scripting.load(&args[2])?)),
+        ("exists", 3..) => Ok(RespFrame::Array(
+            args[2..]
+                .iter()
+                .map(|sha| {
+                    RespFrame::Integer(scripting.exists(&String::from_utf8_lossy(sha)) as i64)
+                })
+                .collect(),
+        )),
+        ("flush", 2 | 3) => {
+            if let Some(mode) = args.get(2) {
+                if !mode.eq_ignore_ascii_case(b"async") && !mode.eq_ignore_ascii_case(b"sync") {
+                    return Err(err_syntax());
+                }
+            }
+            scripting.flush();
+            Ok(RespFrame::ok())
+        }
+        ("kill", 2) => {
+            session.backend.kill_script(false)?;
+            Ok(RespFrame::ok())
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try SCRIPT HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+/// The client a script's commands run as: the caller's user and database,
+/// with the writes logged as one transaction like redis' effects
+/// replication.
+struct ScriptClient {
+    session: Session,
+    /// Whether the `EXEC` running the script wraps its writes already.
+    in_exec: bool,
+    /// Whether `MULTI` went out ahead of the first write.
+    wrapped: bool,
+}
+
+impl ScriptClient {
+    fn new(caller: &Session) -> Self {
+        let mut session = Session::new(caller.backend.clone());
+        session.user = caller.user.clone();
+        session.master_link = caller.master_link;
+        ScriptClient {
+            session,
+            in_exec: caller.propagating_multi,
+            wrapped: false,
+        }
+    }
+
+    /// `redis.call`, the checks `execute` does but for the exec lock, which
+    /// the script holds already.
+    fn call(&mut self, args: &[Bytes]) -> RespFrame {
+        let cmd = match self.check(args) {
+            Ok(cmd) => cmd,
+            Err(e) => return RespFrame::error(e),
+        };
+        let backend = &self.session.backend;
+        if cmd.is_write() {
+            backend.scripting.mark_write();
+            if !self.in_exec && !self.wrapped && backend.propagating() {
+                self.session.propagate(&[Bytes::from("MULTI")]);
+                self.wrapped = true;
+            }
+        }
+        call(&mut self.session, cmd, args)
+    }
+
+    fn check(&self, args: &[Bytes]) -> Result<&'static CommandSpec, String> {
+        let name = args
+            .first()
+            .ok_or("ERR Please specify at least one argument for this redis lib call")?;
+        let cmd = lookup_command(name).ok_or("ERR Unknown Redis command called from script")?;
+        if !cmd.check_arity(args.len()) {
+            return Err("ERR Wrong number of args calling Redis command from script".into());
+        }
+        if NOSCRIPT.contains(&cmd.name) {
+            return Err("ERR This Redis command is not allowed from script".into());
+        }
+        let backend = &self.session.backend;
+        if let Some(user) = &self.session.user {
+            backend.check_permission(user, cmd, args)?;
+        }
+        if !self.session.master_link {
+            let keys = cmd.key_args(args).collect::<Vec<_>>();
+            match backend.cluster_route(&keys, false) {
+                Ok(_) => {}
+                Err(e) if e.starts_with("CROSSSLOT") => {
+                    return Err(
+                        "ERR Script attempted to access keys that do not hash to the same slot"
+                            .into(),
+                    )
+                }
+                Err(_) => {
+                    return Err(
+                        "ERR Script attempted to access a non local key in a cluster node".into(),
+                    )
+                }
+            }
+        }
+        if cmd.is_write() && !self.session.master_link && backend.is_replica() {
+            return Err("READONLY You can't write against a read only replica.".into());
+        }
+        // memory was freed before the script ran, what is still over is
+        // only let through once the script wrote
+        if cmd.denyoom && !backend.scripting.wrote() && backend.over_maxmemory() {
+            return Err("OOM command not allowed when used memory > 'maxmemory'.".into());
+        }
+        Ok(cmd)
+    }
+
+    fn finish(self) {
+        if self.wrapped {
+            self.session.propagate(&[Bytes::from("EXEC")]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::dredis::cmd::run;
+    use crate::{Backend, RespFrame, Session};
+
+    #[test]
+    fn test_eval_and_evalsha() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(
+                &mut s,
+                &[
+                    "EVAL",
+                    "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('INCRBY', KEYS[1], ARGV[2])",
+                    "1",
+                    "counter",
+                    "10",
+                    "5",
+                ]
+            ),
+            RespFrame::Integer(15)
+        );
+        let sha = match run(
+            &mut s,
+            &["SCRIPT", "LOAD", "return redis.call('GET', KEYS[1])"],
+        ) {
+            RespFrame::BulkString(sha) => String::from_utf8(sha.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            run(&mut s, &["EVALSHA", &sha.to_uppercase(), "1", "counter"]),
+            RespFrame::bulk("15")
+        );
+        assert_eq!(
+            run(&mut s, &["SCRIPT", "EXISTS", &sha, "nope"]),
+            RespFrame::array([RespFrame::Integer(1), RespFrame::Integer(0)])
+        );
+        assert_eq!(run(&mut s, &["SCRIPT", "FLUSH"]), RespFrame::ok());
+        assert_eq!(
+            run(&mut s, &["EVALSHA", &sha, "1", "counter"]),
+            RespFrame::error("NOSCRIPT No matching script. Please use EVAL.")
+        );
+        assert_eq!(
+            run(&mut s, &["EVAL", "return 1", "2", "a"]),
+            RespFrame::error("ERR Number of keys can't be greater than number of args")
+        );
+        assert_eq!(
+            run(&mut s, &["EVAL", "return 1", "-1"]),
+            RespFrame::error("ERR Number of keys can't be negative")
+        );
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let mut s = Session::default();
+        run(&mut s, &["LPUSH", "list", "x"]);
+        // redis.call raises the error, redis.pcall returns it
+        assert_eq!(
+            run(&mut s, &["EVAL", "return redis.call('INCR', 'list')", "0"]),
+            RespFrame::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &[
+                    "EVAL",
+                    "local r = redis.pcall('INCR', 'list') return {r.err ~= nil, 'after'}",
+                    "0"
+                ]
+            ),
+            RespFrame::array([RespFrame::Integer(1), RespFrame::bulk("after")])
+        );
+        assert_eq!(
+            run(&mut s, &["EVAL", "return redis.call('NOPE')", "0"]),
+            RespFrame::error("ERR Unknown Redis command called from script")
+        );
+        assert_eq!(
+            run(&mut s, &["EVAL", "return redis.call('GET')", "0"]),
+            RespFrame::error("ERR Wrong number of args calling Redis command from script")
+        );
+        assert_eq!(
+            run(&mut s, &["EVAL", "return redis.call('MULTI')", "0"]),
+            RespFrame::error("ERR This Redis command is not allowed from script")
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["EVAL", "return redis.call('GET', 'missing')", "0"]
+            ),
+            RespFrame::NullBulkString
+        );
+    }
+
+    #[test]
+    fn test_scripts_run_as_the_caller() {
+        let mut s = Session::default();
+        run(&mut s, &["SELECT", "3"]);
+        run(&mut s, &["EVAL", "redis.call('SET', 'k', 'v')", "0"]);
+        assert_eq!(run(&mut s, &["DBSIZE"]), RespFrame::Integer(1));
+
+        run(
+            &mut s,
+            &[
+                "ACL",
+                "SETUSER",
+                "limited",
+                "on",
+                "nopass",
+                "+@all",
+                "~allowed*",
+            ],
+        );
+        run(&mut s, &["AUTH", "limited", "x"]);
+        let RespFrame::Error(e) = run(&mut s, &["EVAL", "return redis.call('GET', 'k')", "0"])
+        else {
+            panic!("read a forbidden key")
+        };
+        assert!(e.starts_with("NOPERM"));
+    }
+
+    #[test]
+    fn test_script_kill() {
+        let mut s = Session::default();
+        run(&mut s, &["CONFIG", "SET", "busy-reply-threshold", "10"]);
+        assert_eq!(
+            run(&mut s, &["SCRIPT", "KILL"]),
+            RespFrame::error("NOTBUSY No scripts in execution right now.")
+        );
+        let mut script = Session::new(s.backend.clone());
+        let busy = thread::spawn(move || run(&mut script, &["EVAL", "while true do end", "0"]));
+        // other clients wait, then get BUSY
+        while run(&mut s, &["GET", "a"]) == RespFrame::NullBulkString {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(run(&mut s, &["GET", "a"]), RespFrame::Error(e) if e.starts_with("BUSY")));
+        assert_eq!(run(&mut s, &["SCRIPT", "KILL"]), RespFrame::ok());
+        assert_eq!(
+            busy.join().unwrap(),
+            RespFrame::error("ERR Script killed by user with SCRIPT KILL...")
+        );
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::NullBulkString);
+
+        // a script that wrote can't be killed
+        let mut script = Session::new(s.backend.clone());
+        let busy = thread::spawn(move || {
+            run(
+                &mut script,
+                &["EVAL", "redis.call('SET', 'a', 1) while true do end", "0"],
+            )
+        });
+        while !s.backend.scripting.wrote() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let RespFrame::Error(e) = run(&mut s, &["SCRIPT", "KILL"]) else {
+            panic!("killed a script that wrote")
+        };
+        assert!(e.starts_with("UNKILLABLE"));
+        assert_eq!(run(&mut s, &["SHUTDOWN", "NOSAVE"]), RespFrame::ok());
+        assert!(matches!(busy.join().unwrap(), RespFrame::Error(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_script_kill_with_one_worker() {
+        let backend = Backend::new();
+        let mut s = Session::new(backend.clone());
+        run(&mut s, &["CONFIG", "SET", "busy-reply-threshold", "10"]);
+        let mut script = Session::new(backend.clone());
+        let busy =
+            tokio::spawn(async move { run(&mut script, &["EVAL", "while true do end", "0"]) });
+        // neither the script nor the clients waiting for it hold the worker
+        let waiting = (0..4)
+            .map(|_| {
+                let mut client = Session::new(backend.clone());
+                tokio::spawn(async move { run(&mut client, &["GET", "a"]) })
+            })
+            .collect::<Vec<_>>();
+        let kill = tokio::spawn(async move {
+            while run(&mut s, &["SCRIPT", "KILL"]) != RespFrame::ok() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        let killed = tokio::time::timeout(Duration::from_secs(5), async {
+            kill.await.unwrap();
+            busy.await.unwrap()
+        })
+        .await
+        .expect("the script was never killed");
+        assert_eq!(
+            killed,
+            RespFrame::error("ERR Script killed by user with SCRIPT KILL...")
+        );
+        for client in waiting {
+            let reply = client.await.unwrap();
+            assert!(
+                reply == RespFrame::NullBulkString
+                    || matches!(&reply, RespFrame::Error(e) if e.starts_with("BUSY"))
+            );
+        }
+    }
+}
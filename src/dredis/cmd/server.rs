@@ -0,0 +1,355 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, err_syntax};
+use crate::{Backend, RespFrame, Session, ShutdownSave, REDIS_VERSION};
+
+type InfoSection = fn(&Backend) -> String;
+
+/// The sections `INFO` shows without arguments, in order.
+const INFO_SECTIONS: &[(&str, InfoSection)] = &[
+    ("server", info_server),
+    ("memory", Backend::info_memory),
+    ("persistence", info_persistence),
+    ("stats", info_stats),
+    ("replication", Backend::info_replication),
+    ("cluster", Backend::info_cluster),
+    ("keyspace", info_keyspace),
+];
+
+/// `SAVE`, runs holding the exec lock exclusively like the whole server
+/// stops for it in redis.
+pub(super) fn save(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.save_snapshot()?;
+    Ok(RespFrame::ok())
+}
+
+/// `BGSAVE [SCHEDULE]`
+pub(super) fn bgsave(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if let Some(arg) = args.get(1) {
+        if !arg_str(arg)?.eq_ignore_ascii_case("schedule") || args.len() > 2 {
+            return Err(err_syntax());
+        }
+    }
+    session.backend.bgsave_snapshot()?;
+    Ok(RespFrame::simple("Background saving started"))
+}
+
+pub(super) fn bgrewriteaof(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.rewrite_aof()?;
+    Ok(RespFrame::simple(
+        "Background append only file rewriting started",
+    ))
+}
+
+/// `SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE] [ABORT]`, the server stops once
+/// every connection finished the commands it read. There is nothing to
+/// abort, the shutdown starts right away.
+pub(super) fn shutdown(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut save = ShutdownSave::Default;
+    for arg in &args[1..] {
+        match arg_str(arg)?.to_ascii_lowercase().as_str() {
+            "save" if save == ShutdownSave::Default => save = ShutdownSave::Save,
+            "nosave" if save == ShutdownSave::Default => save = ShutdownSave::NoSave,
+            "now" | "force" => {}
+            "abort" if args.len() == 2 => return Err(anyhow!("ERR No shutdown in progress.")),
+            _ => return Err(err_syntax()),
+        }
+    }
+    if save == ShutdownSave::NoSave {
+        // a script running forever would keep the server from stopping
+        let _ = session.backend.kill_script(true);
+    }
+    session.backend.request_shutdown(save);
+    session.quit = true;
+    Ok(RespFrame::ok())
+}
+
+/// `CONFIG GET pattern [pattern ...] | SET parameter value [parameter value ...]
+/// | REWRITE`
+pub(super) fn config(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("get", 3..) => {
+            let mut params = Vec::new();
+            for pattern in &args[2..] {
+                for (name, value) in session.backend.config_get(arg_str(pattern)?) {
+                    if !params.iter().any(|(n, _)| *n == name) {
+                        params.push((name, value));
+                    }
+                }
+            }
+            Ok(RespFrame::Map(
+                params
+                    .into_iter()
+                    .map(|(name, value)| (RespFrame::bulk(name), RespFrame::bulk(value)))
+                    .collect(),
+            ))
+        }
+        ("set", n) if n >= 4 && n.is_multiple_of(2) => {
+            let pairs = args[2..]
+                .chunks(2)
+                .map(|pair| Ok((arg_str(&pair[0])?, arg_str(&pair[1])?)))
+                .collect::<Result<Vec<_>>>()?;
+            session.backend.config_set(&pairs)?;
+            Ok(RespFrame::ok())
+        }
+        ("rewrite", 2) => {
+            session.backend.config_rewrite()?;
+            Ok(RespFrame::ok())
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try CONFIG HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+/// `MEMORY USAGE key [SAMPLES count]`, the estimate is always made from
+/// the same few elements.
+pub(super) fn memory(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("usage", 3 | 5) => {
+            if let Some(option) = args.get(3) {
+                if !arg_str(option)?.eq_ignore_ascii_case("samples") || arg_i64(&args[4])? < 0 {
+                    return Err(err_syntax());
+                }
+            }
+            Ok(match session.backend.memory_usage(&args[2]) {
+                Some(bytes) => RespFrame::Integer(bytes as i64),
+                None => RespFrame::NullBulkString,
+            })
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try MEMORY HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+pub(super) fn lastsave(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    Ok(RespFrame::Integer(session.backend.last_save() as i64))
+}
+
+/// `INFO [section ...]`
+pub(super) fn info(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let wanted = args[1..]
+        .iter()
+        .map(|a| arg_str(a).map(str::to_ascii_lowercase))
+        .collect::<Result<Vec<_>>>()?;
+    let all = wanted.is_empty()
+        || wanted
+            .iter()
+            .any(|w| matches!(w.as_str(), "all" | "everything" | "default"));
+    let sections = INFO_SECTIONS
+        .iter()
+        .filter(|(name, _)| all || wanted.iter().any(|w| w == name))
+        .map(|(_, section)| section(&session.backend))
+        .collect::<Vec<_>>();
+    Ok(RespFrame::VerbatimString(
+        "txt".into(),
+        sections.join("\r\n").into(),
+    ))
+}
+
+fn info_server(backend: &Backend) -> String {
+    format!(
+        "# Server\r\nredis_version:{}\r\nredis_mode:{}\r\narch_bits:{}\r\nprocess_id:{}\r\n",
+        REDIS_VERSION,
+        if backend.cluster_enabled() {
+            "cluster"
+        } else {
+            "standalone"
+        },
+        usize::BITS,
+        std::process::id()
+    )
+}
+
+fn info_persistence(backend: &Backend) -> String {
+    format!(
+        "# Persistence\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\naof_enabled:{}\r\naof_rewrite_in_progress:{}\r\n",
+        backend.bgsave_in_progress() as u8,
+        backend.last_save(),
+        backend.aof.is_enabled() as u8,
+        backend.aof_rewrite_in_progress() as u8,
+    )
+}
+
+fn info_stats(backend: &Backend) -> String {
+    format!("# Stats\r\nevicted_keys:{}\r\n", backend.evicted_keys())
+}
+
+fn info_keyspace(backend: &Backend) -> String {
+    let mut info = String::from("# Keyspace\r\n");
+    for (index, db) in backend.all_dbs() {
+        if !db.data.is_empty() {
+            let _ = write!(
+                info,
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                index,
+                db.data.len(),
+                db.expires.lock().unwrap().len()
+            );
+        }
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use crate::dredis::cmd::run;
+    use crate::{Backend, ManualClock, RespFrame, Session};
+
+    #[test]
+    fn test_save_and_bgsave() {
+        let backend = Backend::with_clock(Arc::new(ManualClock::new(5_000)));
+        let path = std::env::temp_dir().join(format!("dredis-{}-cmd.rdb", std::process::id()));
+        backend.set_rdb_path(&path);
+        let mut s = Session::new(backend.clone());
+        assert_eq!(run(&mut s, &["LASTSAVE"]), RespFrame::Integer(0));
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(run(&mut s, &["SAVE"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["LASTSAVE"]), RespFrame::Integer(5));
+        assert_eq!(
+            run(&mut s, &["BGSAVE"]),
+            RespFrame::simple("Background saving started")
+        );
+        backend.wait_bgsave().unwrap();
+        assert!(matches!(
+            run(&mut s, &["BGSAVE", "NOW"]),
+            RespFrame::Error(_)
+        ));
+
+        // transactions run it with the lock already held
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["SAVE"]);
+        assert_eq!(run(&mut s, &["EXEC"]), RespFrame::array([RespFrame::ok()]));
+
+        let restarted = Backend::new();
+        restarted.set_rdb_path(&path);
+        assert_eq!(restarted.load_rdb().unwrap(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_config() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(
+                &mut s,
+                &["CONFIG", "SET", "timeout", "10", "maxclients", "3"]
+            ),
+            RespFrame::ok()
+        );
+        assert_eq!(
+            run(&mut s, &["CONFIG", "GET", "timeout", "max*", "timeout"]),
+            RespFrame::map([
+                (RespFrame::bulk("timeout"), RespFrame::bulk("10")),
+                (RespFrame::bulk("maxmemory"), RespFrame::bulk("0")),
+                (
+                    RespFrame::bulk("maxmemory-policy"),
+                    RespFrame::bulk("noeviction")
+                ),
+                (RespFrame::bulk("maxmemory-samples"), RespFrame::bulk("5")),
+                (RespFrame::bulk("maxclients"), RespFrame::bulk("3")),
+            ])
+        );
+        assert!(matches!(
+            run(&mut s, &["CONFIG", "SET", "timeout", "x"]),
+            RespFrame::Error(e) if e.starts_with("ERR CONFIG SET failed")
+        ));
+        assert!(matches!(
+            run(&mut s, &["CONFIG", "SET", "timeout"]),
+            RespFrame::Error(e) if e.starts_with("ERR unknown subcommand")
+        ));
+        assert_eq!(
+            run(&mut s, &["CONFIG", "REWRITE"]),
+            RespFrame::error("ERR The server is running without a config file")
+        );
+    }
+
+    #[test]
+    fn test_maxmemory() {
+        let mut s = Session::default();
+        run(&mut s, &["SET", "a", "1"]);
+        let usage = match run(&mut s, &["MEMORY", "USAGE", "a"]) {
+            RespFrame::Integer(n) => n,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(
+            run(&mut s, &["MEMORY", "USAGE", "nope", "SAMPLES", "5"]),
+            RespFrame::NullBulkString
+        );
+        let max = (usage + usage / 2).to_string();
+        run(&mut s, &["CONFIG", "SET", "maxmemory", &max]);
+        let oom = RespFrame::error("OOM command not allowed when used memory > 'maxmemory'.");
+        assert_eq!(run(&mut s, &["SET", "b", "2"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["SET", "c", "3"]), oom);
+        assert_eq!(run(&mut s, &["DEL", "b"]), RespFrame::Integer(1));
+
+        // in a transaction the command is refused when queued
+        run(&mut s, &["SET", "b", "2"]);
+        run(&mut s, &["MULTI"]);
+        assert_eq!(run(&mut s, &["SET", "c", "3"]), oom);
+        assert!(matches!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::Error(e) if e.starts_with("EXECABORT")
+        ));
+
+        run(
+            &mut s,
+            &["CONFIG", "SET", "maxmemory-policy", "allkeys-random"],
+        );
+        assert_eq!(run(&mut s, &["SET", "c", "3"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["SET", "d", "4"]), RespFrame::ok());
+        assert_eq!(s.backend.len(), 2);
+        assert_eq!(s.backend.evicted_keys(), 2);
+        let RespFrame::VerbatimString(_, stats) = run(&mut s, &["INFO", "stats"]) else {
+            panic!("INFO should reply with text");
+        };
+        assert_eq!(&stats[..], b"# Stats\r\nevicted_keys:2\r\n");
+    }
+
+    #[test]
+    fn test_shutdown_arguments() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["SHUTDOWN", "SAVE", "NOSAVE"]),
+            RespFrame::error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&mut s, &["SHUTDOWN", "ABORT"]),
+            RespFrame::error("ERR No shutdown in progress.")
+        );
+        assert!(!s.backend.is_shutting_down());
+        assert_eq!(run(&mut s, &["SHUTDOWN", "NOSAVE", "NOW"]), RespFrame::ok());
+        assert!(s.backend.is_shutting_down());
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let mut s = Session::default();
+        run(&mut s, &["SET", "k", "v", "EX", "10"]);
+        let RespFrame::VerbatimString(_, all) = run(&mut s, &["INFO"]) else {
+            panic!("INFO should reply with text");
+        };
+        let all = String::from_utf8_lossy(&all);
+        assert!(all.starts_with("# Server\r\n"));
+        assert!(all.contains("role:master\r\n"));
+        assert!(all.contains("db0:keys=1,expires=1,avg_ttl=0\r\n"));
+
+        let RespFrame::VerbatimString(_, some) = run(&mut s, &["INFO", "Keyspace", "nope"]) else {
+            panic!("INFO should reply with text");
+        };
+        assert_eq!(
+            &some[..],
+            b"# Keyspace\r\ndb0:keys=1,expires=1,avg_ttl=0\r\n"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{typed, typed_mut, typed_or_insert, ScanArgs};
+use crate::{event, scan_aggregate, RespFrame, Session, Value};
+
+pub(super) fn sadd(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let added = session.backend.write(&args[1], |slot| {
+        let set = typed_or_insert(slot, Value::as_set_mut)?;
+        let added = args[2..]
+            .iter()
+            .filter(|m| set.insert((*m).clone()))
+            .count();
+        Ok::<_, anyhow::Error>(added)
+    })?;
+    if added > 0 {
+        session.backend.notify(event::SET, "sadd", &args[1]);
+    }
+    Ok(RespFrame::Integer(added as i64))
+}
+
+pub(super) fn srem(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let removed = session.backend.write(&args[1], |slot| {
+        let removed = match typed_mut(slot, Value::as_set_mut)? {
+            Some(set) => args[2..].iter().filter(|m| set.remove(*m)).count(),
+            None => 0,
+        };
+        Ok::<_, anyhow::Error>(removed)
+    })?;
+    if removed > 0 {
+        session
+            .backend
+            .notify_removing(event::SET, "srem", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+/// Replies with a set, sent as an array to RESP2 clients.
+pub(super) fn smembers(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    session.backend.read(&args[1], |e| {
+        let members = typed(e, Value::as_set)?
+            .into_iter()
+            .flatten()
+            .map(|m| RespFrame::bulk(m.clone()))
+            .collect();
+        Ok(RespFrame::Set(members))
+    })
+}
+
+/// `SSCAN key cursor [MATCH pattern] [COUNT count]`
+pub(super) fn sscan(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let scan = ScanArgs::parse(&args[2..], false)?;
+    session.backend.read(&args[1], |e| {
+        let Some(set) = typed(e, Value::as_set)? else {
+            return Ok(ScanArgs::reply(0, Vec::new()));
+        };
+        let (page, cursor) = scan_aggregate(set.iter(), |m| m, scan.cursor, scan.count);
+        let members = page
+            .into_iter()
+            .filter(|m| scan.matches(m))
+            .map(|m| RespFrame::bulk(m.clone()))
+            .collect();
+        Ok(ScanArgs::reply(cursor, members))
+    })
+}
+
+pub(super) fn sismember(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let found = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_set)?.is_some_and(|s| s.contains(&args[2])))
+    })?;
+    Ok(RespFrame::Integer(found as i64))
+}
+
+pub(super) fn scard(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_set)?.map_or(0, |s| s.len()))
+    })?;
+    Ok(RespFrame::Integer(len as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, Session};
+
+    #[test]
+    fn test_set_commands() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["SADD", "s", "a", "b", "a"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(run(&mut s, &["SADD", "s", "b", "c"]), RespFrame::Integer(1));
+        assert_eq!(run(&mut s, &["SCARD", "s"]), RespFrame::Integer(3));
+        assert_eq!(run(&mut s, &["SISMEMBER", "s", "c"]), RespFrame::Integer(1));
+        assert_eq!(run(&mut s, &["SISMEMBER", "s", "x"]), RespFrame::Integer(0));
+        let RespFrame::Set(mut members) = run(&mut s, &["SMEMBERS", "s"]) else {
+            panic!("SMEMBERS should reply with a set");
+        };
+        members.sort_by_key(|m| format!("{:?}", m));
+        assert_eq!(
+            members,
+            vec![
+                RespFrame::bulk("a"),
+                RespFrame::bulk("b"),
+                RespFrame::bulk("c")
+            ]
+        );
+        assert_eq!(
+            run(&mut s, &["SREM", "s", "a", "b", "c", "d"]),
+            RespFrame::Integer(3)
+        );
+        assert_eq!(run(&mut s, &["TYPE", "s"]), RespFrame::simple("none"));
+    }
+
+    #[test]
+    fn test_sscan_pages_a_large_set() {
+        let mut s = Session::default();
+        for i in 0..500 {
+            run(&mut s, &["SADD", "s", &i.to_string()]);
+        }
+        let (mut cursor, mut seen, mut calls) = ("0".to_string(), Vec::new(), 0);
+        loop {
+            let RespFrame::Array(reply) = run(&mut s, &["SSCAN", "s", &cursor, "COUNT", "50"])
+            else {
+                panic!("SSCAN should reply with an array");
+            };
+            let (RespFrame::BulkString(next), RespFrame::Array(page)) = (&reply[0], &reply[1])
+            else {
+                panic!("unexpected SSCAN reply {:?}", reply);
+            };
+            seen.extend(page.iter().map(|m| format!("{:?}", m)));
+            calls += 1;
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert!(calls >= 10);
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 500);
+    }
+}
@@ -0,0 +1,1831 @@
+use std::{ops::Range, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_i64, arg_str, err_arity, err_syntax, typed, typed_mut, typed_or_insert, Block};
+use crate::{
+    event, ConsumerGroup, PendingEntry, RespFrame, RespVersion, Session, Stream, StreamFields,
+    StreamId, Trim, Value,
+};
+
+fn err_invalid_id() -> anyhow::Error {
+    anyhow!("ERR Invalid stream ID specified as stream command argument")
+}
+
+fn err_no_key() -> anyhow::Error {
+    anyhow!("ERR no such key")
+}
+
+fn err_nogroup(key: &[u8], group: &[u8]) -> anyhow::Error {
+    anyhow!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    )
+}
+
+/// An entry ID argument, a lone `ms` gets `seq` as sequence number.
+fn arg_id(arg: &[u8], seq: u64) -> Result<StreamId> {
+    StreamId::parse(arg, seq).ok_or_else(err_invalid_id)
+}
+
+/// A bound of `XRANGE` and friends: `-` and `+` are the ends of the stream,
+/// a `(` in front leaves the ID itself out.
+fn arg_bound(arg: &[u8], start: bool) -> Result<StreamId> {
+    let seq = if start { 0 } else { u64::MAX };
+    match arg {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = arg_id(id, seq)?;
+            let (moved, which) = match start {
+                true => (id.next(), "start"),
+                false => (id.prev(), "end"),
+            };
+            moved.ok_or_else(|| anyhow!("ERR invalid {} ID for the interval", which))
+        }
+        id => arg_id(id, seq),
+    }
+}
+
+/// A millisecond duration or time, negative ones are taken as zero.
+fn arg_ms(arg: &[u8]) -> Result<u64> {
+    Ok(arg_i64(arg)?.max(0) as u64)
+}
+
+fn id_frame(id: StreamId) -> RespFrame {
+    RespFrame::bulk(id.to_bytes())
+}
+
+fn entry_frame(id: &StreamId, fields: &StreamFields) -> RespFrame {
+    let fields = fields
+        .iter()
+        .flat_map(|(f, v)| [RespFrame::bulk(f.clone()), RespFrame::bulk(v.clone())]);
+    RespFrame::array([id_frame(*id), RespFrame::Array(fields.collect())])
+}
+
+fn info_map<const N: usize>(fields: [(&str, RespFrame); N]) -> RespFrame {
+    RespFrame::map(fields.map(|(name, value)| (RespFrame::bulk(name.to_string()), value)))
+}
+
+fn opt_integer(n: Option<u64>) -> RespFrame {
+    n.map_or(RespFrame::Null, |n| RespFrame::Integer(n as i64))
+}
+
+/// Run `f` on the stream at `key`, `None` when there is no key.
+fn read_stream<R>(
+    session: &Session,
+    key: &[u8],
+    f: impl FnOnce(&Stream) -> R,
+) -> Result<Option<R>> {
+    session
+        .backend
+        .read(key, |e| Ok(typed(e, Value::as_stream)?.map(f)))
+}
+
+/// `XCLAIM` as replicas apply a change of the pending entry `id`: the
+/// entry as the group has it, or its removal when it was deleted.
+fn xclaim_command(key: &Bytes, group: &Bytes, id: StreamId, pending: &PendingEntry) -> Vec<Bytes> {
+    vec![
+        "XCLAIM".into(),
+        key.clone(),
+        group.clone(),
+        pending.consumer.clone(),
+        "0".into(),
+        id.to_bytes(),
+        "TIME".into(),
+        pending.delivery_time.to_string().into(),
+        "RETRYCOUNT".into(),
+        pending.delivery_count.to_string().into(),
+        "FORCE".into(),
+        "JUSTID".into(),
+    ]
+}
+
+/// `XGROUP SETID` with the last delivered ID and read counter of a group.
+fn setid_command(key: &Bytes, name: &Bytes, group: &ConsumerGroup) -> Vec<Bytes> {
+    let mut cmd = vec![
+        "XGROUP".into(),
+        "SETID".into(),
+        key.clone(),
+        name.clone(),
+        group.last_delivered.to_bytes(),
+    ];
+    if let Some(read) = group.entries_read {
+        cmd.extend(["ENTRIESREAD".into(), read.to_string().into()]);
+    }
+    cmd
+}
+
+fn createconsumer_command(key: &Bytes, group: &Bytes, consumer: &Bytes) -> Vec<Bytes> {
+    let cmd = ["XGROUP", "CREATECONSUMER"].map(Bytes::from);
+    [&cmd[..], &[key.clone(), group.clone(), consumer.clone()]].concat()
+}
+
+/// The ID argument of `XADD`.
+enum NewId {
+    /// `*`: the current time, or after the last ID if that is ahead.
+    Auto,
+    /// `ms-*`: the next sequence number of `ms`.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    fn parse(arg: &[u8]) -> Result<Self> {
+        if arg == b"*" {
+            return Ok(NewId::Auto);
+        }
+        if let Some(ms) = arg.strip_suffix(b"-*").filter(|ms| !ms.contains(&b'-')) {
+            return Ok(NewId::AutoSeq(arg_id(ms, 0)?.ms));
+        }
+        let id = arg_id(arg, 0)?;
+        if id == StreamId::MIN {
+            return Err(anyhow!(
+                "ERR The ID specified in XADD must be greater than 0-0"
+            ));
+        }
+        Ok(NewId::Explicit(id))
+    }
+
+    /// The ID of the next entry of `stream`.
+    fn resolve(&self, stream: &Stream, now: u64) -> Result<StreamId> {
+        let too_small = || {
+            anyhow!(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        };
+        match *self {
+            NewId::Auto => stream.next_id(now).ok_or_else(|| {
+                anyhow!(
+                    "ERR The stream has exhausted the last possible ID, unable to add more items"
+                )
+            }),
+            NewId::AutoSeq(ms) => stream.next_seq(ms).ok_or_else(too_small),
+            NewId::Explicit(id) if id > stream.last_id() => Ok(id),
+            NewId::Explicit(_) => Err(too_small()),
+        }
+    }
+}
+
+/// The options of `XADD`, `XTRIM` only takes the trimming ones. `~` trims
+/// exactly as well, there are no nodes to keep whole here, but only with it
+/// may `LIMIT` cap the entries removed.
+#[derive(Default)]
+struct TrimArgs {
+    nomkstream: bool,
+    trim: Option<Trim>,
+    approx: bool,
+    limit: Option<usize>,
+}
+
+impl TrimArgs {
+    /// Parse the options from `args[i]` on, returns them with the index of
+    /// the first argument that is not one.
+    fn parse(args: &[Bytes], mut i: usize, xadd: bool) -> Result<(Self, usize)> {
+        let mut opts = TrimArgs::default();
+        while i < args.len() {
+            match args[i].to_ascii_lowercase().as_slice() {
+                b"nomkstream" if xadd => {
+                    opts.nomkstream = true;
+                    i += 1;
+                }
+                kind @ (b"maxlen" | b"minid") => {
+                    let maxlen = kind == b"maxlen";
+                    i += 1;
+                    match args.get(i).map(|a| a.as_ref()) {
+                        Some(b"~") => {
+                            opts.approx = true;
+                            i += 1;
+                        }
+                        Some(b"=") => i += 1,
+                        _ => {}
+                    }
+                    let threshold = args.get(i).ok_or_else(err_syntax)?;
+                    opts.trim = Some(if maxlen {
+                        let max = usize::try_from(arg_i64(threshold)?)
+                            .map_err(|_| anyhow!("ERR The MAXLEN argument must be >= 0."))?;
+                        Trim::MaxLen(max)
+                    } else {
+                        Trim::MinId(arg_id(threshold, 0)?)
+                    });
+                    i += 1;
+                }
+                b"limit" => {
+                    let limit = arg_i64(args.get(i + 1).ok_or_else(err_syntax)?)?;
+                    let limit = usize::try_from(limit)
+                        .map_err(|_| anyhow!("ERR The LIMIT argument must be >= 0."))?;
+                    opts.limit = Some(limit);
+                    i += 2;
+                }
+                _ => break,
+            }
+        }
+        if opts.limit.is_some() && !opts.approx {
+            return Err(anyhow!(
+                "ERR syntax error, LIMIT cannot be used without the special ~ option"
+            ));
+        }
+        Ok((opts, i))
+    }
+}
+
+/// `XADD key [NOMKSTREAM] [MAXLEN | MINID [= | ~] threshold [LIMIT count]]
+/// * | id field value [field value ...]`
+pub(super) fn xadd(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (opts, i) = TrimArgs::parse(args, 2, true)?;
+    if i >= args.len() || args.len() - i < 3 || !(args.len() - i - 1).is_multiple_of(2) {
+        return Err(anyhow!(err_arity("xadd")));
+    }
+    let new_id = NewId::parse(&args[i])?;
+    let fields = args[i + 1..]
+        .chunks(2)
+        .map(|p| (p[0].clone(), p[1].clone()))
+        .collect::<StreamFields>();
+    let now = session.backend.now_ms();
+    let added = session.backend.write(&args[1], |slot| {
+        let current = typed(slot.as_ref(), Value::as_stream)?;
+        if current.is_none() && opts.nomkstream {
+            return Ok(None);
+        }
+        // the ID is checked before an empty stream is created for it
+        let id = new_id.resolve(current.unwrap_or(&Stream::default()), now)?;
+        let stream = typed_or_insert(slot, Value::as_stream_mut)?;
+        stream.add(id, fields);
+        let trimmed = opts.trim.map_or(0, |trim| stream.trim(trim, opts.limit));
+        Ok::<_, anyhow::Error>(Some((id, trimmed)))
+    })?;
+    let Some((id, trimmed)) = added else {
+        session.propagate_as = Some(Vec::new());
+        return Ok(RespFrame::NullBulkString);
+    };
+    session.backend.notify(event::STREAM, "xadd", &args[1]);
+    if trimmed > 0 {
+        session.backend.notify(event::STREAM, "xtrim", &args[1]);
+    }
+    let mut propagated = args.to_vec();
+    propagated[i] = id.to_bytes();
+    session.propagate_as = Some(vec![propagated]);
+    session
+        .backend
+        .blocking
+        .wake_all(session.backend.db, &args[1]);
+    Ok(id_frame(id))
+}
+
+/// `XTRIM key MAXLEN | MINID [= | ~] threshold [LIMIT count]`
+pub(super) fn xtrim(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (opts, i) = TrimArgs::parse(args, 2, false)?;
+    let (Some(trim), true) = (opts.trim, i == args.len()) else {
+        return Err(err_syntax());
+    };
+    let removed = session.backend.write(&args[1], |slot| {
+        let stream = typed_mut(slot, Value::as_stream_mut)?;
+        Ok::<_, anyhow::Error>(stream.map_or(0, |s| s.trim(trim, opts.limit)))
+    })?;
+    if removed > 0 {
+        session.backend.notify(event::STREAM, "xtrim", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+/// `XDEL key id [id ...]`
+pub(super) fn xdel(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let ids = args[2..]
+        .iter()
+        .map(|a| arg_id(a, 0))
+        .collect::<Result<Vec<_>>>()?;
+    let removed = session.backend.write(&args[1], |slot| {
+        let stream = typed_mut(slot, Value::as_stream_mut)?;
+        Ok::<_, anyhow::Error>(stream.map_or(0, |s| ids.iter().filter(|&&id| s.remove(id)).count()))
+    })?;
+    if removed > 0 {
+        session.backend.notify(event::STREAM, "xdel", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+pub(super) fn xlen(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = read_stream(session, &args[1], |s| s.len())?;
+    Ok(RespFrame::Integer(len.unwrap_or(0) as i64))
+}
+
+/// `XRANGE key start end [COUNT count]`
+pub(super) fn xrange(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    range(session, &args[2], &args[3], args, false)
+}
+
+/// `XREVRANGE key end start [COUNT count]`
+pub(super) fn xrevrange(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    range(session, &args[3], &args[2], args, true)
+}
+
+fn range(
+    session: &Session,
+    start: &[u8],
+    end: &[u8],
+    args: &[Bytes],
+    rev: bool,
+) -> Result<RespFrame> {
+    let (start, end) = (arg_bound(start, true)?, arg_bound(end, false)?);
+    let count = match &args[4..] {
+        [] => usize::MAX,
+        [opt, n] if opt.eq_ignore_ascii_case(b"count") => arg_ms(n)? as usize,
+        _ => return Err(err_syntax()),
+    };
+    let entries = read_stream(session, &args[1], |stream| -> Vec<RespFrame> {
+        let range = stream.range(start, end);
+        let frame = |(id, fields)| entry_frame(id, fields);
+        match rev {
+            true => range.rev().take(count).map(frame).collect(),
+            false => range.take(count).map(frame).collect(),
+        }
+    })?;
+    Ok(match entries {
+        Some(_) if count == 0 => RespFrame::NullArray,
+        entries => RespFrame::Array(entries.unwrap_or_default()),
+    })
+}
+
+/// The arguments of `XREAD` and `XREADGROUP`.
+struct ReadArgs {
+    /// The group and the consumer of `XREADGROUP`.
+    group: Option<(Bytes, Bytes)>,
+    count: usize,
+    /// Milliseconds to block for, zero is forever.
+    block: Option<u64>,
+    noack: bool,
+    /// Where the keys start, the IDs follow them.
+    streams: usize,
+}
+
+impl ReadArgs {
+    fn parse(args: &[Bytes], xreadgroup: bool) -> Result<Self> {
+        let mut read = ReadArgs {
+            group: None,
+            count: usize::MAX,
+            block: None,
+            noack: false,
+            streams: 0,
+        };
+        let mut i = 1;
+        while i < args.len() {
+            let value = |n: usize| args.get(i + n).ok_or_else(err_syntax);
+            match args[i].to_ascii_lowercase().as_slice() {
+                b"count" => {
+                    read.count = match arg_i64(value(1)?)? {
+                        n if n > 0 => n as usize,
+                        _ => usize::MAX,
+                    };
+                    i += 2;
+                }
+                b"block" => {
+                    let ms = arg_i64(value(1)?)
+                        .map_err(|_| anyhow!("ERR timeout is not an integer or out of range"))?;
+                    if ms < 0 {
+                        return Err(anyhow!("ERR timeout is negative"));
+                    }
+                    read.block = Some(ms as u64);
+                    i += 2;
+                }
+                b"group" if xreadgroup => {
+                    read.group = Some((value(1)?.clone(), value(2)?.clone()));
+                    i += 3;
+                }
+                b"noack" if xreadgroup => {
+                    read.noack = true;
+                    i += 1;
+                }
+                b"streams" => {
+                    read.streams = i + 1;
+                    break;
+                }
+                b"group" => {
+                    return Err(anyhow!(
+                        "ERR The GROUP option is only supported by XREADGROUP. You called XREAD instead."
+                    ))
+                }
+                _ => return Err(err_syntax()),
+            }
+        }
+        if read.streams == 0 {
+            return Err(err_syntax());
+        }
+        let rest = args.len() - read.streams;
+        if rest == 0 || !rest.is_multiple_of(2) {
+            let (name, id) = match xreadgroup {
+                true => ("xreadgroup", ">"),
+                false => ("xread", "$"),
+            };
+            return Err(anyhow!(
+                "ERR Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+                name,
+                id
+            ));
+        }
+        if xreadgroup && read.group.is_none() {
+            return Err(anyhow!("ERR Missing GROUP option for XREADGROUP"));
+        }
+        Ok(read)
+    }
+
+    fn keys<'a>(&self, args: &'a [Bytes]) -> &'a [Bytes] {
+        &args[self.streams..self.streams + (args.len() - self.streams) / 2]
+    }
+
+    fn ids<'a>(&self, args: &'a [Bytes]) -> &'a [Bytes] {
+        &args[self.streams + (args.len() - self.streams) / 2..]
+    }
+}
+
+fn is_xreadgroup(args: &[Bytes]) -> bool {
+    args[0].eq_ignore_ascii_case(b"xreadgroup")
+}
+
+/// The keys of `XREAD` and `XREADGROUP`, after `STREAMS`.
+pub(super) fn read_keys(args: &[Bytes]) -> Range<usize> {
+    match ReadArgs::parse(args, is_xreadgroup(args)) {
+        Ok(read) => read.streams..read.streams + read.keys(args).len(),
+        Err(_) => 0..0,
+    }
+}
+
+/// `XREAD` and `XREADGROUP` only block with the `BLOCK` option.
+pub(super) fn block_on_read(args: &[Bytes]) -> Result<Option<Block>> {
+    let read = ReadArgs::parse(args, is_xreadgroup(args))?;
+    Ok(read.block.map(|ms| {
+        let timeout = (ms > 0).then(|| Duration::from_millis(ms));
+        (read.keys(args).to_vec(), timeout)
+    }))
+}
+
+/// The reply of `XREAD` and `XREADGROUP`, a map of the keys to their
+/// entries. RESP2 gets pairs, a flattened map would lose the nesting.
+fn streams_reply(protocol: RespVersion, streams: Vec<(RespFrame, RespFrame)>) -> RespFrame {
+    match protocol {
+        RespVersion::Resp3 => RespFrame::map(streams),
+        RespVersion::Resp2 => RespFrame::Array(
+            streams
+                .into_iter()
+                .map(|(key, entries)| RespFrame::array([key, entries]))
+                .collect(),
+        ),
+    }
+}
+
+/// `XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]`
+///
+/// `$` stands for the last ID of the stream, it is resolved once so a
+/// blocked client gets the entries added after it blocked.
+pub(super) fn xread(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let read = ReadArgs::parse(args, false)?;
+    let (keys, ids) = (read.keys(args), read.ids(args));
+    let mut after = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        after.push(match id.as_ref() {
+            b"$" => read_stream(session, key, |s| s.last_id())?.unwrap_or(StreamId::MIN),
+            id => arg_id(id, 0)?,
+        });
+    }
+    let mut streams = Vec::new();
+    for (key, &id) in keys.iter().zip(&after) {
+        let entries = read_stream(session, key, |s| {
+            s.after(id)
+                .take(read.count)
+                .map(|(id, fields)| entry_frame(id, fields))
+                .collect::<Vec<_>>()
+        })?;
+        if let Some(entries) = entries.filter(|e| !e.is_empty()) {
+            streams.push((RespFrame::bulk(key.clone()), RespFrame::Array(entries)));
+        }
+    }
+    if streams.is_empty() {
+        if ids.iter().any(|id| id.as_ref() == b"$") {
+            let mut retry = args.to_vec();
+            let first_id = args.len() - ids.len();
+            for (arg, id) in retry[first_id..].iter_mut().zip(&after) {
+                *arg = id.to_bytes();
+            }
+            session.retry_as = Some(retry);
+        }
+        return Ok(RespFrame::NullArray);
+    }
+    Ok(streams_reply(session.protocol, streams))
+}
+
+/// One stream `XREADGROUP` reads, with the commands that make replicas end
+/// up with the same group.
+struct GroupRead<'a> {
+    key: &'a Bytes,
+    group: &'a Bytes,
+    consumer: &'a Bytes,
+    count: usize,
+    noack: bool,
+    now: u64,
+    /// Whether the read created the consumer.
+    created: bool,
+    propagate: Vec<Vec<Bytes>>,
+}
+
+impl GroupRead<'_> {
+    /// The consumer's pending entries after `after`, or for `None` the
+    /// entries the group never delivered, `None` when there are none of
+    /// those.
+    fn read(&mut self, stream: &mut Stream, after: Option<StreamId>) -> Option<Vec<RespFrame>> {
+        let group = stream.group_mut(self.group)?;
+        if group.create_consumer(self.consumer, self.now) {
+            let cmd = createconsumer_command(self.key, self.group, self.consumer);
+            self.propagate.push(cmd);
+            self.created = true;
+        }
+        group.seen(self.consumer, self.now);
+        match after {
+            Some(after) => Some(self.read_history(stream, after)),
+            None => self.read_new(stream),
+        }
+    }
+
+    /// Pending entries are delivered again, deleted ones without fields.
+    fn read_history(&self, stream: &mut Stream, after: StreamId) -> Vec<RespFrame> {
+        let group = stream.group(self.group).expect("group checked by read");
+        let ids = match (after.next(), group.consumer(self.consumer)) {
+            (Some(start), Some(consumer)) => consumer
+                .pending()
+                .range(start..)
+                .take(self.count)
+                .copied()
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let Some(fields) = stream.get(id) else {
+                entries.push(RespFrame::array([id_frame(id), RespFrame::NullArray]));
+                continue;
+            };
+            entries.push(entry_frame(&id, fields));
+            let group = stream.group_mut(self.group).expect("group checked by read");
+            let count = group.pending()[&id].delivery_count;
+            group.claim(id, self.consumer, self.now, count + 1);
+        }
+        entries
+    }
+
+    fn read_new(&mut self, stream: &mut Stream) -> Option<Vec<RespFrame>> {
+        let last_delivered = stream.group(self.group)?.last_delivered;
+        let new = stream
+            .after(last_delivered)
+            .take(self.count)
+            .map(|(id, fields)| (*id, entry_frame(id, fields)))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return None;
+        }
+        for (id, _) in &new {
+            stream.advance_group(self.group, *id);
+            if !self.noack {
+                let group = stream.group_mut(self.group)?;
+                group.claim(*id, self.consumer, self.now, 1);
+                let cmd = xclaim_command(self.key, self.group, *id, &group.pending()[id]);
+                self.propagate.push(cmd);
+            }
+        }
+        let group = stream.group_mut(self.group)?;
+        group.seen(self.consumer, self.now).active_time = Some(self.now);
+        self.propagate
+            .push(setid_command(self.key, self.group, group));
+        Some(new.into_iter().map(|(_, frame)| frame).collect())
+    }
+}
+
+/// `XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds]
+/// [NOACK] STREAMS key [key ...] id [id ...]`
+///
+/// `>` reads the entries the group never delivered, making them pending for
+/// the consumer unless `NOACK`, other IDs reread the consumer's pending
+/// entries after them. Only new entries are waited for.
+pub(super) fn xreadgroup(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let read = ReadArgs::parse(args, true)?;
+    let (group, consumer) = read.group.as_ref().expect("checked by parse");
+    let keys = read.keys(args);
+    let ids = read
+        .ids(args)
+        .iter()
+        .map(|id| match id.as_ref() {
+            b">" => Ok(None),
+            id => arg_id(id, 0).map(Some),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for key in keys {
+        if read_stream(session, key, |s| s.group(group).is_some())? != Some(true) {
+            return Err(anyhow!(
+                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(group)
+            ));
+        }
+    }
+    let mut streams = Vec::new();
+    let mut propagate = Vec::new();
+    for (key, after) in keys.iter().zip(ids) {
+        let mut group_read = GroupRead {
+            key,
+            group,
+            consumer,
+            count: read.count,
+            noack: read.noack,
+            now: session.backend.now_ms(),
+            created: false,
+            propagate: Vec::new(),
+        };
+        let entries = session.backend.write(key, |slot| {
+            let stream = typed_mut(slot, Value::as_stream_mut)?;
+            Ok::<_, anyhow::Error>(stream.and_then(|s| group_read.read(s, after)))
+        })?;
+        propagate.append(&mut group_read.propagate);
+        if group_read.created {
+            session
+                .backend
+                .notify(event::STREAM, "xgroup-createconsumer", key);
+        }
+        if let Some(entries) = entries {
+            streams.push((RespFrame::bulk(key.clone()), RespFrame::Array(entries)));
+        }
+    }
+    session.propagate_as = Some(propagate);
+    if streams.is_empty() {
+        return Ok(RespFrame::NullArray);
+    }
+    Ok(streams_reply(session.protocol, streams))
+}
+
+/// `XACK key group id [id ...]`
+pub(super) fn xack(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let ids = args[3..]
+        .iter()
+        .map(|a| arg_id(a, 0))
+        .collect::<Result<Vec<_>>>()?;
+    let acked = session.backend.write(&args[1], |slot| {
+        let group = typed_mut(slot, Value::as_stream_mut)?.and_then(|s| s.group_mut(&args[2]));
+        Ok::<_, anyhow::Error>(group.map_or(0, |g| ids.iter().filter(|&&id| g.ack(id)).count()))
+    })?;
+    Ok(RespFrame::Integer(acked as i64))
+}
+
+/// `XPENDING key group [[IDLE min-idle-time] start end count [consumer]]`
+pub(super) fn xpending(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (key, name) = (&args[1], &args[2]);
+    let (min_idle, range) = match &args[3..] {
+        [opt, idle, range @ ..] if opt.eq_ignore_ascii_case(b"idle") => (arg_ms(idle)?, range),
+        range => (0, range),
+    };
+    let extended = match range {
+        [] if args.len() == 3 => None,
+        [start, end, count, consumer @ ..] if consumer.len() <= 1 => Some((
+            arg_bound(start, true)?,
+            arg_bound(end, false)?,
+            arg_ms(count)? as usize,
+            consumer.first(),
+        )),
+        _ => return Err(err_syntax()),
+    };
+    let now = session.backend.now_ms();
+    let reply = read_stream(session, key, |stream| {
+        let group = stream.group(name)?;
+        Some(match extended {
+            None => pending_summary(group),
+            Some((start, end, count, consumer)) => {
+                let ids: Box<dyn Iterator<Item = StreamId>> = match consumer {
+                    _ if start > end => Box::new(std::iter::empty()),
+                    Some(consumer) => match group.consumer(consumer) {
+                        Some(c) => Box::new(c.pending().range(start..=end).copied()),
+                        None => Box::new(std::iter::empty()),
+                    },
+                    None => Box::new(group.pending().range(start..=end).map(|(id, _)| *id)),
+                };
+                let entries = ids
+                    .map(|id| (id, &group.pending()[&id]))
+                    .filter(|(_, p)| now.saturating_sub(p.delivery_time) >= min_idle)
+                    .take(count)
+                    .map(|(id, p)| {
+                        RespFrame::array([
+                            id_frame(id),
+                            RespFrame::bulk(p.consumer.clone()),
+                            RespFrame::Integer(now.saturating_sub(p.delivery_time) as i64),
+                            RespFrame::Integer(p.delivery_count as i64),
+                        ])
+                    });
+                RespFrame::Array(entries.collect())
+            }
+        })
+    })?;
+    reply.flatten().ok_or_else(|| err_nogroup(key, name))
+}
+
+/// The pending entries count, the smallest and greatest of their IDs and
+/// how many each consumer has.
+fn pending_summary(group: &ConsumerGroup) -> RespFrame {
+    let pending = group.pending();
+    let (Some((first, _)), Some((last, _))) = (pending.first_key_value(), pending.last_key_value())
+    else {
+        return RespFrame::array([
+            RespFrame::Integer(0),
+            RespFrame::NullBulkString,
+            RespFrame::NullBulkString,
+            RespFrame::NullArray,
+        ]);
+    };
+    let consumers = group
+        .consumers()
+        .filter(|(_, c)| !c.pending().is_empty())
+        .map(|(name, c)| {
+            RespFrame::array([
+                RespFrame::bulk(name.clone()),
+                RespFrame::bulk(c.pending().len().to_string()),
+            ])
+        });
+    RespFrame::array([
+        RespFrame::Integer(pending.len() as i64),
+        id_frame(*first),
+        id_frame(*last),
+        RespFrame::Array(consumers.collect()),
+    ])
+}
+
+/// `XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms]
+/// [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID]
+/// [LASTID lastid]`
+pub(super) fn xclaim(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (key, name, consumer) = (&args[1], &args[2], &args[3]);
+    let min_idle =
+        arg_ms(&args[4]).map_err(|_| anyhow!("ERR Invalid min-idle-time argument for XCLAIM"))?;
+    let mut i = 5;
+    let mut ids = Vec::new();
+    while let Some(id) = args.get(i).and_then(|a| StreamId::parse(a, 0)) {
+        ids.push(id);
+        i += 1;
+    }
+    let now = session.backend.now_ms();
+    let (mut time, mut retry_count, mut force, mut justid, mut last_id) =
+        (now, None, false, false, None);
+    while i < args.len() {
+        let value = args.get(i + 1).ok_or_else(err_syntax);
+        match args[i].to_ascii_lowercase().as_slice() {
+            b"force" => force = true,
+            b"justid" => justid = true,
+            b"idle" => {
+                time = now.saturating_sub(arg_ms(value?)?);
+                i += 1;
+            }
+            b"time" => {
+                time = arg_ms(value?)?;
+                i += 1;
+            }
+            b"retrycount" => {
+                retry_count = Some(arg_ms(value?)?);
+                i += 1;
+            }
+            b"lastid" => {
+                last_id = Some(arg_id(value?, 0)?);
+                i += 1;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "ERR Unrecognized XCLAIM option '{}'",
+                    String::from_utf8_lossy(&args[i])
+                ))
+            }
+        }
+        i += 1;
+    }
+    // claims are never dated in the future
+    let time = time.min(now);
+
+    let (mut propagate, mut created) = (Vec::new(), false);
+    let claimed = session.backend.write(key, |slot| {
+        let stream =
+            typed_mut(slot, Value::as_stream_mut)?.ok_or_else(|| err_nogroup(key, name))?;
+        let found = ids
+            .iter()
+            .map(|&id| (id, stream.get(id).cloned()))
+            .collect::<Vec<_>>();
+        let group = stream
+            .group_mut(name)
+            .ok_or_else(|| err_nogroup(key, name))?;
+        if group.create_consumer(consumer, now) {
+            propagate.push(createconsumer_command(key, name, consumer));
+            created = true;
+        }
+        group.seen(consumer, now);
+        let mut claimed = Vec::new();
+        for (id, fields) in found {
+            let Some(fields) = fields else {
+                // deleted entries leave the pending list
+                if let Some(pending) = group.pending().get(&id).cloned() {
+                    group.ack(id);
+                    propagate.push(xclaim_command(key, name, id, &pending));
+                }
+                continue;
+            };
+            let count = match group.pending().get(&id) {
+                Some(p) if min_idle > 0 && now.saturating_sub(p.delivery_time) < min_idle => {
+                    continue
+                }
+                Some(p) => p.delivery_count,
+                None if force => 0,
+                None => continue,
+            };
+            let count = retry_count.unwrap_or(if justid { count } else { count + 1 });
+            group.claim(id, consumer, time, count);
+            propagate.push(xclaim_command(key, name, id, &group.pending()[&id]));
+            claimed.push(match justid {
+                true => id_frame(id),
+                false => entry_frame(&id, &fields),
+            });
+        }
+        if !claimed.is_empty() {
+            group.seen(consumer, now).active_time = Some(now);
+        }
+        if let Some(last_id) = last_id.filter(|&id| id > group.last_delivered) {
+            group.last_delivered = last_id;
+            propagate.push(setid_command(key, name, group));
+        }
+        Ok::<_, anyhow::Error>(claimed)
+    })?;
+    if created {
+        session
+            .backend
+            .notify(event::STREAM, "xgroup-createconsumer", key);
+    }
+    session.propagate_as = Some(propagate);
+    Ok(RespFrame::Array(claimed))
+}
+
+/// `XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]`
+///
+/// Claims the entries idle long enough from the pending list on, looking at
+/// ten times `count` of them at most. Replies with where to go on from, the
+/// claimed entries and the IDs of the deleted ones it dropped.
+pub(super) fn xautoclaim(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (key, name, consumer) = (&args[1], &args[2], &args[3]);
+    let min_idle = arg_ms(&args[4])
+        .map_err(|_| anyhow!("ERR Invalid min-idle-time argument for XAUTOCLAIM"))?;
+    let start = arg_bound(&args[5], true)?;
+    let (mut count, mut justid) = (100, false);
+    let mut opts = args[6..].iter();
+    while let Some(opt) = opts.next() {
+        match opt.to_ascii_lowercase().as_slice() {
+            b"count" => {
+                count = usize::try_from(arg_i64(opts.next().ok_or_else(err_syntax)?)?)
+                    .ok()
+                    .filter(|&n| n > 0 && n <= i64::MAX as usize / 10)
+                    .ok_or_else(|| anyhow!("ERR COUNT must be > 0"))?;
+            }
+            b"justid" => justid = true,
+            _ => return Err(err_syntax()),
+        }
+    }
+    let now = session.backend.now_ms();
+
+    let (mut propagate, mut created) = (Vec::new(), false);
+    let reply = session.backend.write(key, |slot| {
+        let stream =
+            typed_mut(slot, Value::as_stream_mut)?.ok_or_else(|| err_nogroup(key, name))?;
+        let group = stream.group(name).ok_or_else(|| err_nogroup(key, name))?;
+        // one more than it may look at, to tell where the next call starts
+        let attempts = count * 10;
+        let candidates = group
+            .pending()
+            .range(start..)
+            .take(attempts + 1)
+            .map(|(&id, p)| (id, p.clone(), stream.get(id).cloned()))
+            .collect::<Vec<_>>();
+        let group = stream.group_mut(name).expect("group checked above");
+        if group.create_consumer(consumer, now) {
+            propagate.push(createconsumer_command(key, name, consumer));
+            created = true;
+        }
+        group.seen(consumer, now);
+        let (mut claimed, mut deleted, mut next) = (Vec::new(), Vec::new(), StreamId::MIN);
+        for (i, (id, pending, fields)) in candidates.iter().enumerate() {
+            if i == attempts || claimed.len() == count {
+                next = *id;
+                break;
+            }
+            let Some(fields) = fields else {
+                group.ack(*id);
+                propagate.push(xclaim_command(key, name, *id, pending));
+                deleted.push(id_frame(*id));
+                continue;
+            };
+            if now.saturating_sub(pending.delivery_time) < min_idle {
+                continue;
+            }
+            let delivery_count = pending.delivery_count + u64::from(!justid);
+            group.claim(*id, consumer, now, delivery_count);
+            propagate.push(xclaim_command(key, name, *id, &group.pending()[id]));
+            claimed.push(match justid {
+                true => id_frame(*id),
+                false => entry_frame(id, fields),
+            });
+        }
+        if !claimed.is_empty() {
+            group.seen(consumer, now).active_time = Some(now);
+        }
+        Ok::<_, anyhow::Error>(RespFrame::array([
+            id_frame(next),
+            RespFrame::Array(claimed),
+            RespFrame::Array(deleted),
+        ]))
+    })?;
+    if created {
+        session
+            .backend
+            .notify(event::STREAM, "xgroup-createconsumer", key);
+    }
+    session.propagate_as = Some(propagate);
+    Ok(reply)
+}
+
+/// `XSETID key last-id [ENTRIESADDED entries-added] [MAXDELETEDID max-deleted-id]`
+pub(super) fn xsetid(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let last_id = arg_id(&args[2], 0)?;
+    let (mut entries_added, mut max_deleted_id) = (None, None);
+    let mut opts = args[3..].iter();
+    while let Some(opt) = opts.next() {
+        let value = opts.next().ok_or_else(err_syntax)?;
+        match opt.to_ascii_lowercase().as_slice() {
+            b"entriesadded" => {
+                let added = u64::try_from(arg_i64(value)?)
+                    .map_err(|_| anyhow!("ERR entries_added must be positive"))?;
+                entries_added = Some(added);
+            }
+            b"maxdeletedid" => {
+                let id = arg_id(value, 0)?;
+                if last_id < id {
+                    return Err(anyhow!("ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id"));
+                }
+                max_deleted_id = Some(id);
+            }
+            _ => return Err(err_syntax()),
+        }
+    }
+    session.backend.write(&args[1], |slot| {
+        let stream = typed_mut(slot, Value::as_stream_mut)?.ok_or_else(err_no_key)?;
+        if entries_added.is_some_and(|added| added < stream.len() as u64) {
+            return Err(anyhow!("ERR The entries_added specified in XSETID is smaller than the target stream length"));
+        }
+        if stream.last().is_some_and(|(&top, _)| last_id < top) {
+            return Err(anyhow!(
+                "ERR The ID specified in XSETID is smaller than the target stream top item"
+            ));
+        }
+        stream.set_meta(
+            last_id,
+            max_deleted_id.unwrap_or(stream.max_deleted_id()),
+            entries_added.unwrap_or(stream.entries_added()),
+        );
+        Ok(())
+    })?;
+    session.backend.notify(event::STREAM, "xsetid", &args[1]);
+    Ok(RespFrame::ok())
+}
+
+fn err_xgroup_no_key() -> anyhow::Error {
+    anyhow!("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.")
+}
+
+fn err_xgroup_nogroup(key: &[u8], group: &[u8]) -> anyhow::Error {
+    anyhow!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    )
+}
+
+/// `ENTRIESREAD`, -1 for unknown.
+fn arg_entries_read(arg: &[u8]) -> Result<Option<u64>> {
+    match arg_i64(arg)? {
+        -1 => Ok(None),
+        n if n >= 0 => Ok(Some(n as u64)),
+        _ => Err(anyhow!("ERR value for ENTRIESREAD must be positive or -1")),
+    }
+}
+
+/// A group ID argument, `$` is the last ID of the stream.
+fn arg_group_id(arg: &[u8]) -> Result<Option<StreamId>> {
+    match arg {
+        b"$" => Ok(None),
+        id => arg_id(id, 0).map(Some),
+    }
+}
+
+/// `XGROUP CREATE | SETID | DESTROY | CREATECONSUMER | DELCONSUMER key group ...`
+pub(super) fn xgroup(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("create", 5..=8) => xgroup_create(session, args),
+        ("setid", 5 | 7) => xgroup_setid(session, args),
+        ("destroy", 4) => {
+            let destroyed =
+                with_xgroup_stream(session, args, |stream| Ok(stream.destroy_group(&args[3])))?;
+            if destroyed {
+                session
+                    .backend
+                    .notify(event::STREAM, "xgroup-destroy", &args[2]);
+                // readers blocked on the group get an error now
+                session
+                    .backend
+                    .blocking
+                    .wake_all(session.backend.db, &args[2]);
+            }
+            Ok(RespFrame::Integer(destroyed as i64))
+        }
+        ("createconsumer", 5) => {
+            let now = session.backend.now_ms();
+            let created = with_xgroup_stream(session, args, |stream| {
+                let group = stream
+                    .group_mut(&args[3])
+                    .ok_or_else(|| err_xgroup_nogroup(&args[2], &args[3]))?;
+                Ok(group.create_consumer(&args[4], now))
+            })?;
+            if created {
+                session
+                    .backend
+                    .notify(event::STREAM, "xgroup-createconsumer", &args[2]);
+            }
+            Ok(RespFrame::Integer(created as i64))
+        }
+        ("delconsumer", 5) => {
+            let pending = with_xgroup_stream(session, args, |stream| {
+                let group = stream
+                    .group_mut(&args[3])
+                    .ok_or_else(|| err_xgroup_nogroup(&args[2], &args[3]))?;
+                Ok(group.delete_consumer(&args[4]))
+            })?;
+            if pending.is_some() {
+                session
+                    .backend
+                    .notify(event::STREAM, "xgroup-delconsumer", &args[2]);
+            }
+            Ok(RespFrame::Integer(pending.unwrap_or(0) as i64))
+        }
+        _ => Err(anyhow!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+            arg_str(&args[1])?
+        )),
+    }
+}
+
+/// Run `f` on the stream at the key of `XGROUP`, which must exist.
+fn with_xgroup_stream<R>(
+    session: &Session,
+    args: &[Bytes],
+    f: impl FnOnce(&mut Stream) -> Result<R>,
+) -> Result<R> {
+    session.backend.write(&args[2], |slot| {
+        let stream = typed_mut(slot, Value::as_stream_mut)?.ok_or_else(err_xgroup_no_key)?;
+        f(stream)
+    })
+}
+
+/// `XGROUP CREATE key group id | $ [MKSTREAM] [ENTRIESREAD entries-read]`
+fn xgroup_create(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (key, name) = (&args[2], &args[3]);
+    let id = arg_group_id(&args[4])?;
+    let (mut mkstream, mut entries_read) = (false, None);
+    let mut opts = args[5..].iter();
+    while let Some(opt) = opts.next() {
+        match opt.to_ascii_lowercase().as_slice() {
+            b"mkstream" => mkstream = true,
+            b"entriesread" => {
+                entries_read = arg_entries_read(opts.next().ok_or_else(err_syntax)?)?;
+            }
+            _ => return Err(err_syntax()),
+        }
+    }
+    let id = session.backend.write(key, |slot| {
+        if slot.is_none() && !mkstream {
+            return Err(err_xgroup_no_key());
+        }
+        let stream = typed_or_insert(slot, Value::as_stream_mut)?;
+        let id = id.unwrap_or(stream.last_id());
+        if !stream.create_group(name.clone(), id, entries_read) {
+            return Err(anyhow!("BUSYGROUP Consumer Group name already exists"));
+        }
+        Ok(id)
+    })?;
+    session.backend.notify(event::STREAM, "xgroup-create", key);
+    let mut propagated = args.to_vec();
+    propagated[4] = id.to_bytes();
+    session.propagate_as = Some(vec![propagated]);
+    Ok(RespFrame::ok())
+}
+
+/// `XGROUP SETID key group id | $ [ENTRIESREAD entries-read]`
+fn xgroup_setid(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let id = arg_group_id(&args[4])?;
+    let entries_read = match &args[5..] {
+        [] => None,
+        [opt, n] if opt.eq_ignore_ascii_case(b"entriesread") => arg_entries_read(n)?,
+        _ => return Err(err_syntax()),
+    };
+    let id = with_xgroup_stream(session, args, |stream| {
+        let id = id.unwrap_or(stream.last_id());
+        let group = stream
+            .group_mut(&args[3])
+            .ok_or_else(|| err_xgroup_nogroup(&args[2], &args[3]))?;
+        group.last_delivered = id;
+        group.entries_read = entries_read;
+        Ok(id)
+    })?;
+    session
+        .backend
+        .notify(event::STREAM, "xgroup-setid", &args[2]);
+    let mut propagated = args.to_vec();
+    propagated[4] = id.to_bytes();
+    session.propagate_as = Some(vec![propagated]);
+    Ok(RespFrame::ok())
+}
+
+/// `XINFO STREAM key [FULL [COUNT count]] | GROUPS key | CONSUMERS key group`
+///
+/// Like redis without the radix tree internals, there is no radix tree.
+pub(super) fn xinfo(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let sub = arg_str(&args[1])?.to_ascii_lowercase();
+    let now = session.backend.now_ms();
+    let reply = match (sub.as_str(), args.len()) {
+        ("stream", 3) => read_stream(session, &args[2], info_stream)?,
+        ("stream", 4 | 6) if args[3].eq_ignore_ascii_case(b"full") => {
+            let count = match &args[4..] {
+                [] => 10,
+                [opt, n] if opt.eq_ignore_ascii_case(b"count") => match arg_ms(n)? {
+                    0 => usize::MAX,
+                    n => n as usize,
+                },
+                _ => return Err(err_syntax()),
+            };
+            read_stream(session, &args[2], |s| info_stream_full(s, count))?
+        }
+        ("groups", 3) => read_stream(session, &args[2], |s| {
+            let groups = s.groups().map(|(name, group)| {
+                info_map([
+                    ("name", RespFrame::bulk(name.clone())),
+                    (
+                        "consumers",
+                        RespFrame::Integer(group.consumers().count() as i64),
+                    ),
+                    ("pending", RespFrame::Integer(group.pending().len() as i64)),
+                    ("last-delivered-id", id_frame(group.last_delivered)),
+                    ("entries-read", opt_integer(group.entries_read)),
+                    ("lag", opt_integer(s.lag(group))),
+                ])
+            });
+            RespFrame::Array(groups.collect())
+        })?,
+        ("consumers", 4) => {
+            let consumers = read_stream(session, &args[2], |s| {
+                let group = s.group(&args[3])?;
+                let consumers = group.consumers().map(|(name, c)| {
+                    let inactive = c.active_time.map_or(-1, |t| now.saturating_sub(t) as i64);
+                    info_map([
+                        ("name", RespFrame::bulk(name.clone())),
+                        ("pending", RespFrame::Integer(c.pending().len() as i64)),
+                        (
+                            "idle",
+                            RespFrame::Integer(now.saturating_sub(c.seen_time) as i64),
+                        ),
+                        ("inactive", RespFrame::Integer(inactive)),
+                    ])
+                });
+                Some(RespFrame::Array(consumers.collect()))
+            })?;
+            let Some(consumers) = consumers else {
+                return Err(err_no_key());
+            };
+            Some(consumers.ok_or_else(|| err_xgroup_nogroup(&args[2], &args[3]))?)
+        }
+        _ => {
+            return Err(anyhow!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try XINFO HELP.",
+                arg_str(&args[1])?
+            ))
+        }
+    };
+    reply.ok_or_else(err_no_key)
+}
+
+fn info_stream(stream: &Stream) -> RespFrame {
+    let entry = |e: Option<(&StreamId, &StreamFields)>| {
+        e.map_or(RespFrame::NullArray, |(id, fields)| entry_frame(id, fields))
+    };
+    info_map([
+        ("length", RespFrame::Integer(stream.len() as i64)),
+        ("last-generated-id", id_frame(stream.last_id())),
+        ("max-deleted-entry-id", id_frame(stream.max_deleted_id())),
+        (
+            "entries-added",
+            RespFrame::Integer(stream.entries_added() as i64),
+        ),
+        ("recorded-first-entry-id", id_frame(first_id(stream))),
+        ("groups", RespFrame::Integer(stream.groups().count() as i64)),
+        ("first-entry", entry(stream.first())),
+        ("last-entry", entry(stream.last())),
+    ])
+}
+
+fn first_id(stream: &Stream) -> StreamId {
+    stream.first().map_or(StreamId::MIN, |(&id, _)| id)
+}
+
+/// The entries, groups, consumers and pending entries, at most `count` of
+/// each list.
+fn info_stream_full(stream: &Stream, count: usize) -> RespFrame {
+    let entries = stream
+        .iter()
+        .take(count)
+        .map(|(id, fields)| entry_frame(id, fields));
+    let groups = stream.groups().map(|(name, group)| {
+        let pel = group.pending().iter().take(count).map(|(&id, p)| {
+            RespFrame::array([
+                id_frame(id),
+                RespFrame::bulk(p.consumer.clone()),
+                RespFrame::Integer(p.delivery_time as i64),
+                RespFrame::Integer(p.delivery_count as i64),
+            ])
+        });
+        let consumers = group.consumers().take(count).map(|(name, c)| {
+            let pel = c.pending().iter().take(count).map(|id| {
+                let p = &group.pending()[id];
+                RespFrame::array([
+                    id_frame(*id),
+                    RespFrame::Integer(p.delivery_time as i64),
+                    RespFrame::Integer(p.delivery_count as i64),
+                ])
+            });
+            info_map([
+                ("name", RespFrame::bulk(name.clone())),
+                ("seen-time", RespFrame::Integer(c.seen_time as i64)),
+                (
+                    "active-time",
+                    c.active_time
+                        .map_or(RespFrame::Integer(-1), |t| RespFrame::Integer(t as i64)),
+                ),
+                ("pel-count", RespFrame::Integer(c.pending().len() as i64)),
+                ("pending", RespFrame::Array(pel.collect())),
+            ])
+        });
+        info_map([
+            ("name", RespFrame::bulk(name.clone())),
+            ("last-delivered-id", id_frame(group.last_delivered)),
+            ("entries-read", opt_integer(group.entries_read)),
+            ("lag", opt_integer(stream.lag(group))),
+            (
+                "pel-count",
+                RespFrame::Integer(group.pending().len() as i64),
+            ),
+            ("pending", RespFrame::Array(pel.collect())),
+            ("consumers", RespFrame::Array(consumers.collect())),
+        ])
+    });
+    info_map([
+        ("length", RespFrame::Integer(stream.len() as i64)),
+        ("last-generated-id", id_frame(stream.last_id())),
+        ("max-deleted-entry-id", id_frame(stream.max_deleted_id())),
+        (
+            "entries-added",
+            RespFrame::Integer(stream.entries_added() as i64),
+        ),
+        ("recorded-first-entry-id", id_frame(first_id(stream))),
+        ("entries", RespFrame::Array(entries.collect())),
+        ("groups", RespFrame::Array(groups.collect())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+
+    use crate::dredis::cmd::run;
+    use crate::{execute_blocking, Backend, ManualClock, RespFrame, RespVersion, Session};
+
+    fn session_with_clock() -> (Session, ManualClock) {
+        let clock = ManualClock::new(1_000);
+        let session = Session::new(Backend::with_clock(Arc::new(clock.clone())));
+        (session, clock)
+    }
+
+    fn int(n: i64) -> RespFrame {
+        RespFrame::Integer(n)
+    }
+
+    fn bulks(items: &[&str]) -> RespFrame {
+        RespFrame::Array(
+            items
+                .iter()
+                .map(|i| RespFrame::bulk(i.to_string()))
+                .collect(),
+        )
+    }
+
+    fn entry(id: &str, fields: &[&str]) -> RespFrame {
+        RespFrame::array([RespFrame::bulk(id.to_string()), bulks(fields)])
+    }
+
+    #[test]
+    fn test_add_and_range() {
+        let (mut s, clock) = session_with_clock();
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "*", "a", "1"]),
+            RespFrame::bulk("1000-0")
+        );
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "*", "b", "2"]),
+            RespFrame::bulk("1000-1")
+        );
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "1005-*", "c", "3"]),
+            RespFrame::bulk("1005-0")
+        );
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "2000-7", "d", "4", "e", "5"]),
+            RespFrame::bulk("2000-7")
+        );
+        // the clock is behind the last ID now
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "*", "f", "6"]),
+            RespFrame::bulk("2000-8")
+        );
+        assert_eq!(run(&mut s, &["XLEN", "s"]), int(5));
+        assert_eq!(run(&mut s, &["TYPE", "s"]), RespFrame::simple("stream"));
+
+        assert_eq!(
+            run(&mut s, &["XRANGE", "s", "-", "+", "COUNT", "2"]),
+            RespFrame::array([entry("1000-0", &["a", "1"]), entry("1000-1", &["b", "2"])])
+        );
+        assert_eq!(
+            run(&mut s, &["XRANGE", "s", "(1000-1", "2000"]),
+            RespFrame::array([
+                entry("1005-0", &["c", "3"]),
+                entry("2000-7", &["d", "4", "e", "5"]),
+                entry("2000-8", &["f", "6"]),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["XREVRANGE", "s", "+", "1005", "COUNT", "2"]),
+            RespFrame::array([
+                entry("2000-8", &["f", "6"]),
+                entry("2000-7", &["d", "4", "e", "5"])
+            ])
+        );
+        assert_eq!(run(&mut s, &["XRANGE", "s", "3000", "+"]), bulks(&[]));
+        assert_eq!(run(&mut s, &["XRANGE", "x", "-", "+"]), bulks(&[]));
+        assert_eq!(
+            run(&mut s, &["XRANGE", "s", "-", "+", "COUNT", "0"]),
+            RespFrame::NullArray
+        );
+
+        assert_eq!(run(&mut s, &["XDEL", "s", "1000-1", "9-9"]), int(1));
+        assert_eq!(run(&mut s, &["XLEN", "s"]), int(4));
+        assert_eq!(run(&mut s, &["XTRIM", "s", "MAXLEN", "=", "2"]), int(2));
+        assert_eq!(
+            run(&mut s, &["XRANGE", "s", "-", "+"]),
+            RespFrame::array([
+                entry("2000-7", &["d", "4", "e", "5"]),
+                entry("2000-8", &["f", "6"])
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "MAXLEN", "1", "*", "g", "7"]),
+            RespFrame::bulk("2000-9")
+        );
+        assert_eq!(run(&mut s, &["XLEN", "s"]), int(1));
+        assert_eq!(run(&mut s, &["XTRIM", "s", "MINID", "3000"]), int(1));
+        // an emptied stream stays, with its last ID
+        assert_eq!(run(&mut s, &["EXISTS", "s"]), int(1));
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "2000-9", "h", "8"]),
+            RespFrame::error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        assert_eq!(run(&mut s, &["XSETID", "s", "5000-0"]), RespFrame::ok());
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "*", "h", "8"]),
+            RespFrame::bulk("5000-1")
+        );
+    }
+
+    #[test]
+    fn test_add_errors() {
+        let (mut s, _) = session_with_clock();
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "NOMKSTREAM", "*", "a", "1"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(run(&mut s, &["EXISTS", "s"]), int(0));
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "0-0", "a", "1"]),
+            RespFrame::error("ERR The ID specified in XADD must be greater than 0-0")
+        );
+        // a failed XADD creates no stream
+        assert_eq!(run(&mut s, &["EXISTS", "s"]), int(0));
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "1-x", "a", "1"]),
+            RespFrame::error("ERR Invalid stream ID specified as stream command argument")
+        );
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "*", "a"]),
+            RespFrame::error("ERR wrong number of arguments for 'xadd' command")
+        );
+        assert_eq!(
+            run(&mut s, &["XADD", "s", "MAXLEN", "-1", "*", "a", "1"]),
+            RespFrame::error("ERR The MAXLEN argument must be >= 0.")
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["XADD", "s", "MAXLEN", "1", "LIMIT", "5", "*", "a", "1"]
+            ),
+            RespFrame::error("ERR syntax error, LIMIT cannot be used without the special ~ option")
+        );
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(
+            run(&mut s, &["XADD", "k", "*", "a", "1"]),
+            RespFrame::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(run(&mut s, &["XRANGE", "s", "(0-0", "+"]), bulks(&[]));
+        assert_eq!(
+            run(&mut s, &["XRANGE", "s", "-", "(0-0"]),
+            RespFrame::error("ERR invalid end ID for the interval")
+        );
+    }
+
+    #[test]
+    fn test_read() {
+        let (mut s, _) = session_with_clock();
+        run(&mut s, &["XADD", "a", "1-1", "f", "1"]);
+        run(&mut s, &["XADD", "a", "1-2", "f", "2"]);
+        run(&mut s, &["XADD", "b", "2-1", "g", "1"]);
+        assert_eq!(
+            run(
+                &mut s,
+                &["XREAD", "COUNT", "1", "STREAMS", "a", "b", "0", "0"]
+            ),
+            RespFrame::array([
+                RespFrame::array([
+                    RespFrame::bulk("a"),
+                    RespFrame::array([entry("1-1", &["f", "1"])])
+                ]),
+                RespFrame::array([
+                    RespFrame::bulk("b"),
+                    RespFrame::array([entry("2-1", &["g", "1"])])
+                ]),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["XREAD", "STREAMS", "a", "b", "1-1", "$"]),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("a"),
+                RespFrame::array([entry("1-2", &["f", "2"])])
+            ])])
+        );
+        assert_eq!(
+            run(&mut s, &["XREAD", "STREAMS", "a", "$"]),
+            RespFrame::NullArray
+        );
+        s.protocol = RespVersion::Resp3;
+        assert_eq!(
+            run(&mut s, &["XREAD", "STREAMS", "b", "0"]),
+            RespFrame::map([(
+                RespFrame::bulk("b"),
+                RespFrame::array([entry("2-1", &["g", "1"])])
+            )])
+        );
+        assert_eq!(
+            run(&mut s, &["XREAD", "STREAMS", "a", "b", "0"]),
+            RespFrame::error(
+                "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+            )
+        );
+        assert_eq!(
+            run(&mut s, &["XREAD", "GROUP", "g", "c", "STREAMS", "a", "0"]),
+            RespFrame::error(
+                "ERR The GROUP option is only supported by XREADGROUP. You called XREAD instead."
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_read_waits_for_new_entries() {
+        let mut s = Session::default();
+        let mut writer = Session::new(s.backend.clone());
+        run(&mut writer, &["XADD", "s", "1-1", "old", "1"]);
+        let waiter = tokio::spawn(async move {
+            let args = ["XREAD", "BLOCK", "5000", "STREAMS", "s", "$"]
+                .map(Bytes::from)
+                .to_vec();
+            execute_blocking(&mut s, &args).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // an entry on another key does not wake it
+        run(&mut writer, &["XADD", "other", "1-1", "x", "1"]);
+        run(&mut writer, &["XADD", "s", "2-1", "new", "1"]);
+        assert_eq!(
+            waiter.await.unwrap(),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("s"),
+                RespFrame::array([entry("2-1", &["new", "1"])])
+            ])])
+        );
+
+        let mut s = Session::new(writer.backend.clone());
+        let args = ["XREAD", "BLOCK", "50", "STREAMS", "s", "$"]
+            .map(Bytes::from)
+            .to_vec();
+        assert_eq!(execute_blocking(&mut s, &args).await, RespFrame::NullArray);
+        assert_eq!(s.backend.blocking.blocked_clients(), 0);
+        // without BLOCK it returns right away
+        let args = ["XREAD", "STREAMS", "s", "$"].map(Bytes::from).to_vec();
+        assert_eq!(execute_blocking(&mut s, &args).await, RespFrame::NullArray);
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let (mut s, clock) = session_with_clock();
+        assert_eq!(
+            run(&mut s, &["XGROUP", "CREATE", "s", "g", "$"]),
+            RespFrame::error("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.")
+        );
+        assert_eq!(
+            run(&mut s, &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]),
+            RespFrame::ok()
+        );
+        assert_eq!(
+            run(&mut s, &["XGROUP", "CREATE", "s", "g", "0"]),
+            RespFrame::error("BUSYGROUP Consumer Group name already exists")
+        );
+        for i in 1..=3 {
+            run(
+                &mut s,
+                &["XADD", "s", &format!("{}-0", i), "n", &i.to_string()],
+            );
+        }
+        assert_eq!(
+            run(
+                &mut s,
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "alice",
+                    "COUNT",
+                    "2",
+                    "STREAMS",
+                    "s",
+                    ">"
+                ]
+            ),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("s"),
+                RespFrame::array([entry("1-0", &["n", "1"]), entry("2-0", &["n", "2"])])
+            ])])
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+            ),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("s"),
+                RespFrame::array([entry("3-0", &["n", "3"])])
+            ])])
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"]
+            ),
+            RespFrame::NullArray
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["XREADGROUP", "GROUP", "nope", "bob", "STREAMS", "s", ">"]
+            ),
+            RespFrame::error(
+                "NOGROUP No such key 's' or consumer group 'nope' in XREADGROUP with GROUP option"
+            )
+        );
+        assert_eq!(
+            run(&mut s, &["XPENDING", "s", "g"]),
+            RespFrame::array([
+                int(3),
+                RespFrame::bulk("1-0"),
+                RespFrame::bulk("3-0"),
+                RespFrame::array([bulks(&["alice", "2"]), bulks(&["bob", "1"])]),
+            ])
+        );
+
+        // alice's history is delivered again
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(
+            run(
+                &mut s,
+                &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "1-0"]
+            ),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("s"),
+                RespFrame::array([entry("2-0", &["n", "2"])])
+            ])])
+        );
+        assert_eq!(
+            run(&mut s, &["XPENDING", "s", "g", "-", "+", "10", "alice"]),
+            RespFrame::array([
+                RespFrame::array([
+                    RespFrame::bulk("1-0"),
+                    RespFrame::bulk("alice"),
+                    int(100),
+                    int(1)
+                ]),
+                RespFrame::array([
+                    RespFrame::bulk("2-0"),
+                    RespFrame::bulk("alice"),
+                    int(0),
+                    int(2)
+                ]),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["XACK", "s", "g", "2-0", "2-0", "9-0"]),
+            int(1)
+        );
+
+        // bob takes over alice's idle entry, the deleted one is dropped
+        clock.advance(Duration::from_millis(100));
+        run(&mut s, &["XDEL", "s", "3-0"]);
+        assert_eq!(
+            run(&mut s, &["XCLAIM", "s", "g", "bob", "150", "1-0", "3-0"]),
+            RespFrame::array([entry("1-0", &["n", "1"])])
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["XCLAIM", "s", "g", "alice", "150", "1-0", "JUSTID"]
+            ),
+            bulks(&[])
+        );
+        assert_eq!(
+            run(&mut s, &["XPENDING", "s", "g", "IDLE", "0", "-", "+", "10"]),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("1-0"),
+                RespFrame::bulk("bob"),
+                int(0),
+                int(2)
+            ])])
+        );
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(
+            run(
+                &mut s,
+                &["XAUTOCLAIM", "s", "g", "carol", "50", "0", "COUNT", "1"]
+            ),
+            RespFrame::array([
+                RespFrame::bulk("0-0"),
+                RespFrame::array([entry("1-0", &["n", "1"])]),
+                bulks(&[]),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["XGROUP", "DELCONSUMER", "s", "g", "carol"]),
+            int(1)
+        );
+        assert_eq!(
+            run(&mut s, &["XPENDING", "s", "g"]),
+            RespFrame::array([
+                int(0),
+                RespFrame::NullBulkString,
+                RespFrame::NullBulkString,
+                RespFrame::NullArray,
+            ])
+        );
+        assert_eq!(run(&mut s, &["XGROUP", "DESTROY", "s", "g"]), int(1));
+        assert_eq!(run(&mut s, &["XGROUP", "DESTROY", "s", "g"]), int(0));
+        assert_eq!(
+            run(&mut s, &["XPENDING", "s", "g"]),
+            RespFrame::error("NOGROUP No such key 's' or consumer group 'g'")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_readgroup() {
+        let mut s = Session::default();
+        let mut writer = Session::new(s.backend.clone());
+        run(
+            &mut writer,
+            &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"],
+        );
+        let waiter = tokio::spawn(async move {
+            let args = [
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "c",
+                "BLOCK",
+                "0",
+                "STREAMS",
+                "s",
+                ">",
+            ]
+            .map(Bytes::from)
+            .to_vec();
+            execute_blocking(&mut s, &args).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        run(&mut writer, &["XADD", "s", "1-1", "job", "1"]);
+        assert_eq!(
+            waiter.await.unwrap(),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("s"),
+                RespFrame::array([entry("1-1", &["job", "1"])])
+            ])])
+        );
+        assert_eq!(
+            run(&mut writer, &["XPENDING", "s", "g"]),
+            RespFrame::array([
+                int(1),
+                RespFrame::bulk("1-1"),
+                RespFrame::bulk("1-1"),
+                RespFrame::array([bulks(&["c", "1"])]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_info() {
+        let (mut s, _) = session_with_clock();
+        run(&mut s, &["XADD", "s", "1-1", "a", "1"]);
+        run(&mut s, &["XADD", "s", "2-1", "b", "2"]);
+        run(&mut s, &["XGROUP", "CREATE", "s", "g", "0"]);
+        run(
+            &mut s,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "c",
+                "COUNT",
+                "1",
+                "STREAMS",
+                "s",
+                ">",
+            ],
+        );
+        s.protocol = RespVersion::Resp3;
+        let info = |s: &mut Session, args: &[&str]| match run(s, args) {
+            RespFrame::Map(fields) => fields,
+            RespFrame::Array(mut items) => match items.pop() {
+                Some(RespFrame::Map(fields)) => fields,
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        };
+        let field = |fields: &[(RespFrame, RespFrame)], name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| *k == RespFrame::bulk(name.to_string()))
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        let stream = info(&mut s, &["XINFO", "STREAM", "s"]);
+        assert_eq!(field(&stream, "length"), int(2));
+        assert_eq!(field(&stream, "last-generated-id"), RespFrame::bulk("2-1"));
+        assert_eq!(field(&stream, "groups"), int(1));
+        assert_eq!(field(&stream, "first-entry"), entry("1-1", &["a", "1"]));
+
+        let group = info(&mut s, &["XINFO", "GROUPS", "s"]);
+        assert_eq!(field(&group, "consumers"), int(1));
+        assert_eq!(field(&group, "pending"), int(1));
+        assert_eq!(field(&group, "last-delivered-id"), RespFrame::bulk("1-1"));
+        assert_eq!(field(&group, "entries-read"), int(1));
+        assert_eq!(field(&group, "lag"), int(1));
+
+        let consumer = info(&mut s, &["XINFO", "CONSUMERS", "s", "g"]);
+        assert_eq!(field(&consumer, "name"), RespFrame::bulk("c"));
+        assert_eq!(field(&consumer, "pending"), int(1));
+        assert_eq!(field(&consumer, "inactive"), int(0));
+
+        let full = info(&mut s, &["XINFO", "STREAM", "s", "FULL"]);
+        assert_eq!(field(&full, "entries-added"), int(2));
+        assert!(matches!(field(&full, "groups"), RespFrame::Array(g) if g.len() == 1));
+        assert_eq!(
+            run(&mut s, &["XINFO", "STREAM", "x"]),
+            RespFrame::error("ERR no such key")
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{arg_f64, arg_i64, arg_str, err_arity, err_syntax, err_wrongtype};
+use crate::{event, format_double, Entry, RespFrame, Session, Value};
+
+fn string_value(entry: Option<&Entry>) -> Result<Option<Bytes>> {
+    entry
+        .map(|e| e.value.as_string().cloned().ok_or_else(err_wrongtype))
+        .transpose()
+}
+
+fn bulk_or_nil(value: Option<Bytes>) -> RespFrame {
+    value
+        .map(RespFrame::bulk)
+        .unwrap_or(RespFrame::NullBulkString)
+}
+
+pub(super) fn get(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let value = session.backend.read(&args[1], string_value)?;
+    Ok(bulk_or_nil(value))
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+/// EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]`
+pub(super) fn set(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (mut nx, mut xx, mut get, mut keepttl) = (false, false, false, false);
+    let mut expire_at = None;
+    let mut expire_arg = None;
+    let mut i = 3;
+    while i < args.len() {
+        let opt = arg_str(&args[i])?.to_ascii_lowercase();
+        match opt.as_str() {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            "get" => get = true,
+            "keepttl" if expire_at.is_none() => keepttl = true,
+            "ex" | "px" | "exat" | "pxat" if !keepttl && expire_at.is_none() => {
+                let Some(arg) = args.get(i + 1) else {
+                    return Err(err_syntax());
+                };
+                expire_at = Some(parse_expire(session, &opt, arg, "set")?);
+                expire_arg = Some(i);
+                i += 1;
+            }
+            _ => return Err(err_syntax()),
+        }
+        i += 1;
+    }
+
+    // logged with an absolute time, replaying it later must not extend the ttl
+    if let (Some(i), Some(at)) = (expire_arg, expire_at) {
+        let mut cmd = [&args[..i], &args[i + 2..]].concat();
+        cmd.extend(["PXAT".into(), at.to_string().into()]);
+        session.propagate_as = Some(vec![cmd]);
+    }
+    let value = args[2].clone();
+    let (reply, written) = session.backend.write(&args[1], |slot| {
+        let old = if get {
+            string_value(slot.as_ref())?
+        } else {
+            None
+        };
+        if (nx && slot.is_some()) || (xx && slot.is_none()) {
+            return Ok::<_, anyhow::Error>((bulk_or_nil(old), false));
+        }
+        let expire_at = if keepttl {
+            slot.as_ref().and_then(|e| e.expire_at)
+        } else {
+            expire_at
+        };
+        *slot = Some(Entry::with_expire_at(Value::String(value), expire_at));
+        let reply = if get {
+            bulk_or_nil(old)
+        } else {
+            RespFrame::ok()
+        };
+        Ok((reply, true))
+    })?;
+    if written {
+        session.backend.notify(event::STRING, "set", &args[1]);
+        if expire_at.is_some() {
+            session.backend.notify(event::GENERIC, "expire", &args[1]);
+        }
+    }
+    Ok(reply)
+}
+
+/// Turn an `EX`/`PX`/`EXAT`/`PXAT` argument into an absolute time in milliseconds.
+pub(super) fn parse_expire(session: &Session, unit: &str, arg: &[u8], cmd: &str) -> Result<u64> {
+    let invalid = || anyhow!("ERR invalid expire time in '{}' command", cmd);
+    let n = arg_i64(arg)?;
+    if n <= 0 {
+        return Err(invalid());
+    }
+    let n = n as u64;
+    let at = match unit {
+        "ex" => n
+            .checked_mul(1000)
+            .and_then(|ms| ms.checked_add(session.backend.now_ms())),
+        "px" => n.checked_add(session.backend.now_ms()),
+        "exat" => n.checked_mul(1000),
+        _ => Some(n),
+    };
+    at.filter(|&at| at <= i64::MAX as u64).ok_or_else(invalid)
+}
+
+pub(super) fn mget(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let values = args[1..]
+        .iter()
+        .map(|key| {
+            let value = session
+                .backend
+                .read(key, |e| e.and_then(|e| e.value.as_string().cloned()));
+            bulk_or_nil(value)
+        })
+        .collect::<Vec<_>>();
+    Ok(RespFrame::Array(values))
+}
+
+pub(super) fn mset(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if args.len().is_multiple_of(2) {
+        return Err(anyhow!(err_arity("mset")));
+    }
+    for pair in args[1..].chunks(2) {
+        let value = Value::String(pair[1].clone());
+        session
+            .backend
+            .write(&pair[0], |slot| *slot = Some(Entry::new(value)));
+        session.backend.notify(event::STRING, "set", &pair[0]);
+    }
+    Ok(RespFrame::ok())
+}
+
+pub(super) fn append(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = session.backend.write(&args[1], |slot| {
+        let len = match slot {
+            Some(entry) => {
+                let old = entry.value.as_string().ok_or_else(err_wrongtype)?;
+                let mut value = Vec::with_capacity(old.len() + args[2].len());
+                value.extend_from_slice(old);
+                value.extend_from_slice(&args[2]);
+                let len = value.len();
+                entry.value = Value::String(value.into());
+                len
+            }
+            None => {
+                *slot = Some(Entry::new(Value::String(args[2].clone())));
+                args[2].len()
+            }
+        };
+        Ok::<_, anyhow::Error>(len)
+    })?;
+    session.backend.notify(event::STRING, "append", &args[1]);
+    Ok(RespFrame::Integer(len as i64))
+}
+
+pub(super) fn strlen(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let value = session.backend.read(&args[1], string_value)?;
+    Ok(RespFrame::Integer(value.map_or(0, |v| v.len()) as i64))
+}
+
+pub(super) fn incr(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    incr_by(session, &args[1], 1)
+}
+
+pub(super) fn decr(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    incr_by(session, &args[1], -1)
+}
+
+pub(super) fn incrby(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    incr_by(session, &args[1], arg_i64(&args[2])?)
+}
+
+pub(super) fn decrby(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let delta = arg_i64(&args[2])?
+        .checked_neg()
+        .ok_or_else(|| anyhow!("ERR decrement would overflow"))?;
+    incr_by(session, &args[1], delta)
+}
+
+fn incr_by(session: &mut Session, key: &Bytes, delta: i64) -> Result<RespFrame> {
+    let n = session.backend.write(key, |slot| {
+        let current = match string_value(slot.as_ref())? {
+            Some(v) => arg_i64(&v)?,
+            None => 0,
+        };
+        let n = current
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("ERR increment or decrement would overflow"))?;
+        set_keep_ttl(slot, n.to_string().into());
+        Ok::<_, anyhow::Error>(n)
+    })?;
+    session.backend.notify(event::STRING, "incrby", key);
+    Ok(RespFrame::Integer(n))
+}
+
+pub(super) fn incrbyfloat(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let delta = arg_f64(&args[2])?;
+    let value = session.backend.write(&args[1], |slot| {
+        let current = match string_value(slot.as_ref())? {
+            Some(v) => arg_f64(&v)?,
+            None => 0.0,
+        };
+        let n = current + delta;
+        if !n.is_finite() {
+            return Err(anyhow!("ERR increment would produce NaN or Infinity"));
+        }
+        let value = Bytes::from(format_double(n));
+        set_keep_ttl(slot, value.clone());
+        Ok::<_, anyhow::Error>(value)
+    })?;
+    session
+        .backend
+        .notify(event::STRING, "incrbyfloat", &args[1]);
+    Ok(RespFrame::bulk(value))
+}
+
+fn set_keep_ttl(slot: &mut Option<Entry>, value: Bytes) {
+    match slot {
+        Some(entry) => entry.value = Value::String(value),
+        None => *slot = Some(Entry::new(Value::String(value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, Session};
+
+    fn err(msg: &str) -> RespFrame {
+        RespFrame::error(msg)
+    }
+
+    #[test]
+    fn test_get_set() {
+        let mut s = Session::default();
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::NullBulkString);
+        assert_eq!(run(&mut s, &["SET", "k", "v"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::bulk("v"));
+        assert_eq!(
+            run(&mut s, &["SET", "k", "v2", "NX"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(
+            run(&mut s, &["SET", "x", "v", "XX"]),
+            RespFrame::NullBulkString
+        );
+        assert_eq!(
+            run(&mut s, &["SET", "k", "v3", "XX", "GET"]),
+            RespFrame::bulk("v")
+        );
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::bulk("v3"));
+        assert_eq!(
+            run(&mut s, &["SET", "k", "v", "NX", "XX"]),
+            err("ERR syntax error")
+        );
+        assert_eq!(
+            run(&mut s, &["SET", "k", "v", "EX", "0"]),
+            err("ERR invalid expire time in 'set' command")
+        );
+    }
+
+    #[test]
+    fn test_set_with_ttl() {
+        let mut s = Session::default();
+        run(&mut s, &["SET", "k", "v", "EX", "100"]);
+        let ttl = s
+            .backend
+            .read(b"k", |e| e.and_then(|e| e.expire_at))
+            .unwrap();
+        assert!(ttl > s.backend.now_ms());
+        run(&mut s, &["SET", "k", "v2", "KEEPTTL"]);
+        let kept = s.backend.read(b"k", |e| e.and_then(|e| e.expire_at));
+        assert_eq!(kept, Some(ttl));
+        run(&mut s, &["SET", "k", "v3"]);
+        assert_eq!(s.backend.read(b"k", |e| e.and_then(|e| e.expire_at)), None);
+        run(&mut s, &["SET", "k", "v", "PXAT", "1"]);
+        assert_eq!(run(&mut s, &["GET", "k"]), RespFrame::NullBulkString);
+    }
+
+    #[test]
+    fn test_mget_mset_del_exists() {
+        let mut s = Session::default();
+        assert_eq!(run(&mut s, &["MSET", "a", "1", "b", "2"]), RespFrame::ok());
+        assert_eq!(
+            run(&mut s, &["MGET", "a", "x", "b"]),
+            RespFrame::array([
+                RespFrame::bulk("1"),
+                RespFrame::NullBulkString,
+                RespFrame::bulk("2")
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["MSET", "a", "1", "b"]),
+            err("ERR wrong number of arguments for 'mset' command")
+        );
+        assert_eq!(
+            run(&mut s, &["EXISTS", "a", "a", "x"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(run(&mut s, &["DEL", "a", "b", "x"]), RespFrame::Integer(2));
+        assert_eq!(run(&mut s, &["EXISTS", "a", "b"]), RespFrame::Integer(0));
+    }
+
+    #[test]
+    fn test_append_strlen() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["APPEND", "k", "hello"]),
+            RespFrame::Integer(5)
+        );
+        assert_eq!(
+            run(&mut s, &["APPEND", "k", " world"]),
+            RespFrame::Integer(11)
+        );
+        assert_eq!(run(&mut s, &["STRLEN", "k"]), RespFrame::Integer(11));
+        assert_eq!(run(&mut s, &["STRLEN", "x"]), RespFrame::Integer(0));
+    }
+
+    #[test]
+    fn test_incr_family() {
+        let mut s = Session::default();
+        assert_eq!(run(&mut s, &["INCR", "n"]), RespFrame::Integer(1));
+        assert_eq!(run(&mut s, &["INCRBY", "n", "10"]), RespFrame::Integer(11));
+        assert_eq!(run(&mut s, &["DECR", "n"]), RespFrame::Integer(10));
+        assert_eq!(run(&mut s, &["DECRBY", "n", "3"]), RespFrame::Integer(7));
+        assert_eq!(
+            run(&mut s, &["INCRBYFLOAT", "n", "0.5"]),
+            RespFrame::bulk("7.5")
+        );
+        assert_eq!(
+            run(&mut s, &["INCRBYFLOAT", "n", "2.5"]),
+            RespFrame::bulk("10")
+        );
+        assert_eq!(run(&mut s, &["GET", "n"]), RespFrame::bulk("10"));
+
+        run(&mut s, &["SET", "n", "9223372036854775807"]);
+        assert_eq!(
+            run(&mut s, &["INCR", "n"]),
+            err("ERR increment or decrement would overflow")
+        );
+        run(&mut s, &["SET", "n", "abc"]);
+        assert_eq!(
+            run(&mut s, &["INCR", "n"]),
+            err("ERR value is not an integer or out of range")
+        );
+        assert_eq!(
+            run(&mut s, &["INCRBYFLOAT", "n", "1"]),
+            err("ERR value is not a valid float")
+        );
+    }
+}
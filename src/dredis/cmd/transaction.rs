@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{call, lookup_command};
+use crate::{RespFrame, Session, Transaction, WatchedKeys};
+
+/// Commands run right away inside `MULTI` instead of being queued.
+pub(super) const NOT_QUEUED: &[&str] = &["multi", "exec", "discard", "watch", "quit"];
+
+pub(super) fn multi(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    if session.multi.is_some() {
+        return Err(anyhow!("ERR MULTI calls can not be nested"));
+    }
+    session.multi = Some(Transaction::default());
+    Ok(RespFrame::ok())
+}
+
+pub(super) fn discard(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    if session.multi.take().is_none() {
+        return Err(anyhow!("ERR DISCARD without MULTI"));
+    }
+    session.watched = None;
+    Ok(RespFrame::ok())
+}
+
+/// Run the queued commands, `EXEC` holds the exec lock exclusively. Replies
+/// with a null when a watched key changed, nothing runs then.
+pub(super) fn exec(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    let Some(tx) = session.multi.take() else {
+        return Err(anyhow!("ERR EXEC without MULTI"));
+    };
+    let watched = session.watched.take();
+    if tx.aborted {
+        return Err(anyhow!(
+            "EXECABORT Transaction discarded because of previous errors."
+        ));
+    }
+    if watched.is_some_and(|w| w.is_dirty()) {
+        return Ok(RespFrame::NullArray);
+    }
+    let cmds = tx
+        .queued
+        .iter()
+        .map(|args| {
+            (
+                lookup_command(&args[0]).expect("queued commands exist"),
+                args,
+            )
+        })
+        .collect::<Vec<_>>();
+    // the writes are logged as a transaction too
+    let propagate = session.backend.propagating() && cmds.iter().any(|(c, _)| c.is_write());
+    if propagate {
+        session.propagate(&[Bytes::from("MULTI")]);
+    }
+    session.propagating_multi = propagate;
+    let replies = cmds
+        .into_iter()
+        .map(|(cmd, args)| call(session, cmd, args))
+        .collect();
+    session.propagating_multi = false;
+    if propagate {
+        session.propagate(&[Bytes::from("EXEC")]);
+    }
+    Ok(RespFrame::Array(replies))
+}
+
+pub(super) fn watch(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    if session.multi.is_some() {
+        return Err(anyhow!("ERR WATCH inside MULTI is not allowed"));
+    }
+    let watched = session
+        .watched
+        .get_or_insert_with(|| WatchedKeys::new(session.backend.watches.clone()));
+    for key in &args[1..] {
+        watched.watch(session.backend.db, key);
+    }
+    Ok(RespFrame::ok())
+}
+
+pub(super) fn unwatch(session: &mut Session, _args: &[Bytes]) -> Result<RespFrame> {
+    session.watched = None;
+    Ok(RespFrame::ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, Session};
+
+    #[test]
+    fn test_multi_exec() {
+        let mut s = Session::default();
+        assert_eq!(run(&mut s, &["MULTI"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["SET", "a", "1"]), RespFrame::simple("QUEUED"));
+        assert_eq!(run(&mut s, &["INCR", "a"]), RespFrame::simple("QUEUED"));
+        assert_eq!(
+            run(&mut s, &["LPUSH", "a", "x"]),
+            RespFrame::simple("QUEUED")
+        );
+        assert_eq!(
+            run(&mut s, &["MULTI"]),
+            RespFrame::error("ERR MULTI calls can not be nested")
+        );
+        // runtime errors are part of the reply, the other commands still run
+        assert_eq!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::array([
+                RespFrame::ok(),
+                RespFrame::Integer(2),
+                RespFrame::error(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value"
+                ),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::error("ERR EXEC without MULTI")
+        );
+    }
+
+    #[test]
+    fn test_discard() {
+        let mut s = Session::default();
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["SET", "a", "1"]);
+        assert_eq!(run(&mut s, &["DISCARD"]), RespFrame::ok());
+        assert_eq!(run(&mut s, &["EXISTS", "a"]), RespFrame::Integer(0));
+        assert_eq!(
+            run(&mut s, &["DISCARD"]),
+            RespFrame::error("ERR DISCARD without MULTI")
+        );
+    }
+
+    #[test]
+    fn test_queue_errors_abort_exec() {
+        let mut s = Session::default();
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["SET", "a", "1"]);
+        assert!(matches!(run(&mut s, &["NOPE"]), RespFrame::Error(_)));
+        assert!(matches!(run(&mut s, &["GET"]), RespFrame::Error(_)));
+        assert_eq!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        assert_eq!(run(&mut s, &["EXISTS", "a"]), RespFrame::Integer(0));
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut s = Session::default();
+        let mut other = Session::new(s.backend.clone());
+        run(&mut s, &["SET", "a", "1"]);
+
+        // a read by someone else does not matter
+        run(&mut s, &["WATCH", "a"]);
+        run(&mut other, &["GET", "a"]);
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["INCR", "a"]);
+        assert_eq!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::array([RespFrame::Integer(2)])
+        );
+
+        run(&mut s, &["WATCH", "a", "b"]);
+        run(&mut other, &["SET", "b", "x"]);
+        run(&mut s, &["MULTI"]);
+        assert_eq!(
+            run(&mut s, &["WATCH", "a"]),
+            RespFrame::error("ERR WATCH inside MULTI is not allowed")
+        );
+        run(&mut s, &["INCR", "a"]);
+        assert_eq!(run(&mut s, &["EXEC"]), RespFrame::NullArray);
+        assert_eq!(run(&mut s, &["GET", "a"]), RespFrame::bulk("2"));
+
+        // EXEC unwatched everything
+        run(&mut other, &["SET", "a", "5"]);
+        run(&mut s, &["MULTI"]);
+        run(&mut s, &["INCR", "a"]);
+        assert_eq!(
+            run(&mut s, &["EXEC"]),
+            RespFrame::array([RespFrame::Integer(6)])
+        );
+    }
+
+    #[test]
+    fn test_watch_ignores_writes_without_change() {
+        let mut s = Session::default();
+        let mut other = Session::new(s.backend.clone());
+        run(&mut s, &["SET", "a", "1"]);
+        run(&mut s, &["WATCH", "a", "missing"]);
+        run(&mut other, &["SET", "a", "2", "NX"]);
+        run(&mut other, &["LPOP", "missing"]);
+        run(&mut s, &["MULTI"]);
+        assert_eq!(run(&mut s, &["EXEC"]), RespFrame::array([]));
+
+        run(&mut s, &["WATCH", "a"]);
+        run(&mut other, &["DEL", "a"]);
+        run(&mut s, &["MULTI"]);
+        assert_eq!(run(&mut s, &["EXEC"]), RespFrame::NullArray);
+    }
+}
@@ -0,0 +1,445 @@
+use std::ops::Bound;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use super::{
+    arg_f64, arg_i64, arg_str, err_syntax, index_range, typed, typed_mut, typed_or_insert, ScanArgs,
+};
+use crate::{event, format_double, scan_aggregate, RespFrame, RespVersion, Session, Value, ZSet};
+
+/// `ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]`
+pub(super) fn zadd(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) =
+        (false, false, false, false, false, false);
+    let mut i = 2;
+    while i < args.len() {
+        match arg_str(&args[i])?.to_ascii_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            "ch" => ch = true,
+            "incr" => incr = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let pairs = &args[i..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(err_syntax());
+    }
+    if nx && xx {
+        return Err(anyhow!(
+            "ERR XX and NX options at the same time are not compatible"
+        ));
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err(anyhow!(
+            "ERR GT, LT, and/or NX options at the same time are not compatible"
+        ));
+    }
+    if incr && pairs.len() > 2 {
+        return Err(anyhow!(
+            "ERR INCR option supports a single increment-element pair"
+        ));
+    }
+    let pairs = pairs
+        .chunks(2)
+        .map(|p| Ok((arg_f64(&p[0])?, p[1].clone())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let protocol = session.protocol;
+    let (reply, updated) = session.backend.write(&args[1], |slot| {
+        if xx && slot.is_none() {
+            let reply = if incr {
+                RespFrame::Null
+            } else {
+                RespFrame::Integer(0)
+            };
+            return Ok((reply, false));
+        }
+        let zset = typed_or_insert(slot, Value::as_zset_mut)?;
+        let (mut added, mut changed) = (0, 0);
+        let mut incr_result = None;
+        for (score, member) in pairs {
+            let current = zset.score(&member);
+            let score = match (incr, current) {
+                (true, Some(current)) => current + score,
+                _ => score,
+            };
+            if score.is_nan() {
+                return Err(anyhow!("ERR resulting score is not a number (NaN)"));
+            }
+            let allowed = match current {
+                Some(current) => !nx && (!gt || score > current) && (!lt || score < current),
+                None => !xx,
+            };
+            if !allowed {
+                continue;
+            }
+            incr_result = Some(score);
+            if current != Some(score) {
+                if zset.insert(member, score) {
+                    added += 1;
+                } else {
+                    changed += 1;
+                }
+            }
+        }
+        let reply = if incr {
+            incr_result.map_or(RespFrame::Null, |s| score_frame(s, protocol))
+        } else if ch {
+            RespFrame::Integer(added + changed)
+        } else {
+            RespFrame::Integer(added)
+        };
+        Ok((reply, added + changed > 0))
+    })?;
+    if updated {
+        let name = if incr { "zincr" } else { "zadd" };
+        session.backend.notify(event::ZSET, name, &args[1]);
+    }
+    Ok(reply)
+}
+
+pub(super) fn zincrby(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let args = [
+        args[0].clone(),
+        args[1].clone(),
+        Bytes::from_static(b"INCR"),
+        args[2].clone(),
+        args[3].clone(),
+    ];
+    zadd(session, &args)
+}
+
+pub(super) fn zrem(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let removed = session.backend.write(&args[1], |slot| {
+        let removed = match typed_mut(slot, Value::as_zset_mut)? {
+            Some(zset) => args[2..].iter().filter(|m| zset.remove(m)).count(),
+            None => 0,
+        };
+        Ok::<_, anyhow::Error>(removed)
+    })?;
+    if removed > 0 {
+        session
+            .backend
+            .notify_removing(event::ZSET, "zrem", &args[1]);
+    }
+    Ok(RespFrame::Integer(removed as i64))
+}
+
+pub(super) fn zscore(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let score = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_zset)?.and_then(|z| z.score(&args[2])))
+    })?;
+    Ok(score.map_or(RespFrame::Null, |s| score_frame(s, session.protocol)))
+}
+
+/// `ZSCAN key cursor [MATCH pattern] [COUNT count]`, scores come as bulk
+/// strings whatever the protocol, like redis.
+pub(super) fn zscan(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let scan = ScanArgs::parse(&args[2..], false)?;
+    session.backend.read(&args[1], |e| {
+        let Some(zset) = typed(e, Value::as_zset)? else {
+            return Ok(ScanArgs::reply(0, Vec::new()));
+        };
+        let members = zset.iter().collect::<Vec<_>>();
+        let (page, cursor) =
+            scan_aggregate(members.into_iter(), |(m, _)| m, scan.cursor, scan.count);
+        let items = page
+            .into_iter()
+            .filter(|(m, _)| scan.matches(m))
+            .flat_map(|(m, score)| {
+                [
+                    RespFrame::bulk(m.clone()),
+                    RespFrame::bulk(format_double(score)),
+                ]
+            })
+            .collect();
+        Ok(ScanArgs::reply(cursor, items))
+    })
+}
+
+pub(super) fn zcard(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let len = session.backend.read(&args[1], |e| {
+        Ok::<_, anyhow::Error>(typed(e, Value::as_zset)?.map_or(0, |z| z.len()))
+    })?;
+    Ok(RespFrame::Integer(len as i64))
+}
+
+#[derive(Debug, Default)]
+struct RangeOptions {
+    by_score: bool,
+    rev: bool,
+    limit: Option<(i64, i64)>,
+    with_scores: bool,
+}
+
+/// `ZRANGE key start stop [BYSCORE] [REV] [LIMIT offset count] [WITHSCORES]`
+pub(super) fn zrange(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut opts = RangeOptions::default();
+    parse_range_options(&args[4..], &mut opts, true)?;
+    if opts.limit.is_some() && !opts.by_score {
+        return Err(anyhow!(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+        ));
+    }
+    let (start, stop) = if opts.rev && opts.by_score {
+        (&args[3], &args[2])
+    } else {
+        (&args[2], &args[3])
+    };
+    range(session, &args[1], start, stop, opts)
+}
+
+/// `ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]`
+pub(super) fn zrangebyscore(session: &mut Session, args: &[Bytes]) -> Result<RespFrame> {
+    let mut opts = RangeOptions {
+        by_score: true,
+        ..Default::default()
+    };
+    parse_range_options(&args[4..], &mut opts, false)?;
+    range(session, &args[1], &args[2], &args[3], opts)
+}
+
+fn parse_range_options(args: &[Bytes], opts: &mut RangeOptions, zrange: bool) -> Result<()> {
+    let mut i = 0;
+    while i < args.len() {
+        match arg_str(&args[i])?.to_ascii_lowercase().as_str() {
+            "byscore" if zrange => opts.by_score = true,
+            "rev" if zrange => opts.rev = true,
+            "withscores" => opts.with_scores = true,
+            "limit" if i + 2 < args.len() => {
+                opts.limit = Some((arg_i64(&args[i + 1])?, arg_i64(&args[i + 2])?));
+                i += 2;
+            }
+            _ => return Err(err_syntax()),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+fn range(
+    session: &Session,
+    key: &[u8],
+    start: &[u8],
+    stop: &[u8],
+    opts: RangeOptions,
+) -> Result<RespFrame> {
+    let items = session.backend.read(key, |e| {
+        let Some(zset) = typed(e, Value::as_zset)? else {
+            return Ok::<_, anyhow::Error>(Vec::new());
+        };
+        let items = if opts.by_score {
+            let (min, max) = (parse_score_bound(start)?, parse_score_bound(stop)?);
+            let (offset, count) = opts.limit.unwrap_or((0, -1));
+            let count = usize::try_from(count).unwrap_or(usize::MAX);
+            let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+            let iter = zset.range_by_score(min, max);
+            let iter: Box<dyn Iterator<Item = _>> = if opts.rev {
+                Box::new(iter.rev())
+            } else {
+                Box::new(iter)
+            };
+            iter.skip(offset)
+                .take(count)
+                .map(|(m, s)| (m.clone(), s))
+                .collect()
+        } else {
+            by_index(zset, arg_i64(start)?, arg_i64(stop)?, opts.rev)
+        };
+        Ok(items)
+    })?;
+
+    let mut frames = Vec::with_capacity(items.len());
+    for (member, score) in items {
+        let member = RespFrame::bulk(member);
+        match (opts.with_scores, session.protocol) {
+            (false, _) => frames.push(member),
+            (true, RespVersion::Resp2) => {
+                frames.push(member);
+                frames.push(score_frame(score, RespVersion::Resp2));
+            }
+            (true, RespVersion::Resp3) => {
+                frames.push(RespFrame::array([member, RespFrame::Double(score)]))
+            }
+        }
+    }
+    Ok(RespFrame::Array(frames))
+}
+
+fn by_index(zset: &ZSet, start: i64, stop: i64, rev: bool) -> Vec<(Bytes, f64)> {
+    let Some(range) = index_range(start, stop, zset.len()) else {
+        return Vec::new();
+    };
+    let (skip, take) = (*range.start(), range.end() - range.start() + 1);
+    let iter: Box<dyn Iterator<Item = _>> = if rev {
+        Box::new(zset.iter().rev())
+    } else {
+        Box::new(zset.iter())
+    };
+    iter.skip(skip)
+        .take(take)
+        .map(|(m, s)| (m.clone(), s))
+        .collect()
+}
+
+/// `1.5`, `(1.5` for an exclusive bound, `-inf` and `+inf`.
+fn parse_score_bound(arg: &[u8]) -> Result<Bound<f64>> {
+    let invalid = || anyhow!("ERR min or max is not a float");
+    let (exclusive, arg) = match arg.strip_prefix(b"(") {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let score = arg_f64(arg).map_err(|_| invalid())?;
+    Ok(if exclusive {
+        Bound::Excluded(score)
+    } else {
+        Bound::Included(score)
+    })
+}
+
+/// Scores are doubles for RESP3 clients and bulk strings for RESP2 clients.
+fn score_frame(score: f64, protocol: RespVersion) -> RespFrame {
+    match protocol {
+        RespVersion::Resp2 => RespFrame::bulk(crate::format_double(score)),
+        RespVersion::Resp3 => RespFrame::Double(score),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dredis::cmd::run;
+    use crate::{RespFrame, Session};
+
+    fn bulks(items: &[&str]) -> RespFrame {
+        RespFrame::Array(
+            items
+                .iter()
+                .map(|i| RespFrame::bulk(i.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_zadd_options() {
+        let mut s = Session::default();
+        assert_eq!(
+            run(&mut s, &["ZADD", "z", "1", "a", "2", "b"]),
+            RespFrame::Integer(2)
+        );
+        assert_eq!(
+            run(&mut s, &["ZADD", "z", "NX", "5", "a", "3", "c"]),
+            RespFrame::Integer(1)
+        );
+        assert_eq!(run(&mut s, &["ZSCORE", "z", "a"]), RespFrame::bulk("1"));
+        assert_eq!(
+            run(&mut s, &["ZADD", "z", "XX", "CH", "5", "a", "9", "d"]),
+            RespFrame::Integer(1)
+        );
+        assert_eq!(
+            run(&mut s, &["ZADD", "z", "GT", "CH", "1", "a"]),
+            RespFrame::Integer(0)
+        );
+        assert_eq!(
+            run(&mut s, &["ZADD", "z", "INCR", "1.5", "a"]),
+            RespFrame::bulk("6.5")
+        );
+        assert_eq!(
+            run(&mut s, &["ZINCRBY", "z", "-0.5", "a"]),
+            RespFrame::bulk("6")
+        );
+        assert_eq!(run(&mut s, &["ZCARD", "z"]), RespFrame::Integer(3));
+        assert_eq!(run(&mut s, &["ZREM", "z", "a", "x"]), RespFrame::Integer(1));
+        assert!(matches!(
+            run(&mut s, &["ZADD", "z", "NX", "XX", "1", "a"]),
+            RespFrame::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut s, &["ZADD", "z", "x", "a"]),
+            RespFrame::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_zscan() {
+        let mut s = Session::default();
+        run(&mut s, &["ZADD", "z", "1.5", "a", "2", "b"]);
+        let RespFrame::Array(reply) = run(&mut s, &["ZSCAN", "z", "0", "MATCH", "a"]) else {
+            panic!("ZSCAN should reply with an array");
+        };
+        assert_eq!(reply[0], RespFrame::bulk("0"));
+        assert_eq!(reply[1], bulks(&["a", "1.5"]));
+        run(&mut s, &["SET", "str", "v"]);
+        assert!(matches!(
+            run(&mut s, &["ZSCAN", "str", "0"]),
+            RespFrame::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_zrange() {
+        let mut s = Session::default();
+        run(
+            &mut s,
+            &["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGE", "z", "0", "-1"]),
+            bulks(&["a", "b", "c", "d"])
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGE", "z", "0", "1", "REV"]),
+            bulks(&["d", "c"])
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGE", "z", "1", "1", "WITHSCORES"]),
+            bulks(&["b", "2"])
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGE", "z", "(1", "3", "BYSCORE"]),
+            bulks(&["b", "c"])
+        );
+        assert_eq!(
+            run(
+                &mut s,
+                &["ZRANGE", "z", "+inf", "-inf", "BYSCORE", "REV", "LIMIT", "1", "2"]
+            ),
+            bulks(&["c", "b"])
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGEBYSCORE", "z", "-inf", "(3", "WITHSCORES"]),
+            bulks(&["a", "1", "b", "2"])
+        );
+        assert_eq!(
+            run(&mut s, &["ZRANGEBYSCORE", "z", "5", "+inf"]),
+            bulks(&[])
+        );
+        assert!(matches!(
+            run(&mut s, &["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(
+            run(&mut s, &["ZRANGEBYSCORE", "z", "x", "1"]),
+            RespFrame::error("ERR min or max is not a float")
+        );
+    }
+
+    #[test]
+    fn test_zrange_withscores_resp3() {
+        let mut s = Session::default();
+        run(&mut s, &["HELLO", "3"]);
+        run(&mut s, &["ZADD", "z", "1.5", "a"]);
+        assert_eq!(
+            run(&mut s, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]),
+            RespFrame::array([RespFrame::array([
+                RespFrame::bulk("a"),
+                RespFrame::Double(1.5)
+            ])])
+        );
+        assert_eq!(run(&mut s, &["ZSCORE", "z", "a"]), RespFrame::Double(1.5));
+    }
+}
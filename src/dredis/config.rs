@@ -0,0 +1,753 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    glob_match, keyspace_events_string, parse_keyspace_events, AppendFsync, Backend, ClientClass,
+    OutputLimit,
+};
+
+/// A parameter of `CONFIG GET` and `CONFIG SET`, read from and written to
+/// wherever the server keeps it.
+struct ConfigParam {
+    name: &'static str,
+    access: Access,
+}
+
+enum Access {
+    /// Kept by the running server, `CONFIG SET` changes it right away.
+    Live {
+        get: fn(&Backend) -> String,
+        set: fn(&Backend, &str) -> Result<()>,
+    },
+    /// Only read when the server starts, `CONFIG SET` refuses it.
+    Startup {
+        default: &'static str,
+        check: fn(&str) -> Result<()>,
+    },
+}
+
+const fn live(
+    name: &'static str,
+    get: fn(&Backend) -> String,
+    set: fn(&Backend, &str) -> Result<()>,
+) -> ConfigParam {
+    ConfigParam {
+        name,
+        access: Access::Live { get, set },
+    }
+}
+
+const fn startup(
+    name: &'static str,
+    default: &'static str,
+    check: fn(&str) -> Result<()>,
+) -> ConfigParam {
+    ConfigParam {
+        name,
+        access: Access::Startup { default, check },
+    }
+}
+
+static PARAMS: &[ConfigParam] = &[
+    startup("bind", "0.0.0.0", check_ip),
+    startup("port", "6380", check_port),
+    startup("tls-port", "0", check_port),
+    startup("tls-cert-file", "", |_| Ok(())),
+    startup("tls-key-file", "", |_| Ok(())),
+    startup("tls-ca-cert-file", "", |_| Ok(())),
+    startup("tls-auth-clients", "yes", |v| match v {
+        "yes" | "no" | "optional" => Ok(()),
+        _ => bail!("argument must be 'yes', 'no' or 'optional'"),
+    }),
+    startup("loglevel", "notice", |v| match v {
+        "debug" | "verbose" | "notice" | "warning" | "nothing" => Ok(()),
+        _ => bail!(
+            "argument(s) must be one of the following: debug, verbose, notice, warning, nothing"
+        ),
+    }),
+    startup("dir", ".", |_| Ok(())),
+    startup("appendonly", "no", |v| parse_bool(v).map(|_| ())),
+    startup("appendfilename", "appendonly.aof", |_| Ok(())),
+    startup("appendfsync", "everysec", |v| {
+        v.parse::<AppendFsync>().map(|_| ())
+    }),
+    startup("databases", "16", |v| match parse_number(v)? {
+        0 => bail!("argument must be between 1 and {}", i32::MAX),
+        _ => Ok(()),
+    }),
+    // 0 keeps the keyspace in one map, more split it among worker threads
+    startup("shards", "0", |v| parse_number(v).map(|_| ())),
+    startup("cluster-enabled", "no", |v| parse_bool(v).map(|_| ())),
+    // the nodes of the static cluster, all started by this server
+    startup("cluster-ports", "", |v| {
+        v.split_whitespace().try_for_each(check_port)
+    }),
+    startup("cluster-announce-ip", "127.0.0.1", check_ip),
+    // the master a replica follows from the start, "host port"
+    startup("replicaof", "", |v| parse_replicaof(v).map(|_| ())),
+    live(
+        "dbfilename",
+        |b| b.rdb_path().display().to_string(),
+        |b, v| {
+            if Path::new(v).components().count() != 1 {
+                bail!("dbfilename can't be a path, just a filename");
+            }
+            b.set_rdb_path(v);
+            Ok(())
+        },
+    ),
+    live(
+        "maxmemory",
+        |b| b.maxmemory().to_string(),
+        |b, v| {
+            b.set_maxmemory(parse_memory(v)?);
+            Ok(())
+        },
+    ),
+    live(
+        "maxmemory-policy",
+        |b| b.maxmemory_policy().as_str().to_string(),
+        |b, v| {
+            b.set_maxmemory_policy(v.parse()?);
+            Ok(())
+        },
+    ),
+    live(
+        "maxmemory-samples",
+        |b| b.maxmemory_samples().to_string(),
+        |b, v| match parse_number(v)? {
+            samples @ 1..=64 => {
+                b.set_maxmemory_samples(samples);
+                Ok(())
+            }
+            _ => bail!("argument must be between 1 and 64 inclusive"),
+        },
+    ),
+    live(
+        "notify-keyspace-events",
+        |b| keyspace_events_string(b.keyspace_events()),
+        |b, v| {
+            b.set_keyspace_events(parse_keyspace_events(v)?);
+            Ok(())
+        },
+    ),
+    live(
+        "maxclients",
+        |b| b.maxclients().to_string(),
+        |b, v| {
+            let max = parse_number(v)?;
+            if max == 0 {
+                bail!("argument must be between 1 and {}", usize::MAX);
+            }
+            b.set_maxclients(max);
+            Ok(())
+        },
+    ),
+    live(
+        "requirepass",
+        |b| b.requirepass(),
+        |b, v| {
+            b.set_requirepass(v);
+            Ok(())
+        },
+    ),
+    live(
+        "timeout",
+        |b| b.idle_timeout().map_or(0, |t| t.as_secs()).to_string(),
+        |b, v| {
+            let secs = parse_number(v)? as u64;
+            b.set_idle_timeout((secs > 0).then(|| Duration::from_secs(secs)));
+            Ok(())
+        },
+    ),
+    live(
+        "shutdown-timeout",
+        |b| b.shutdown_timeout().as_secs().to_string(),
+        |b, v| {
+            b.set_shutdown_timeout(Duration::from_secs(parse_number(v)? as u64));
+            Ok(())
+        },
+    ),
+    live(
+        "busy-reply-threshold",
+        |b| b.busy_reply_threshold().as_millis().to_string(),
+        |b, v| {
+            b.set_busy_reply_threshold(Duration::from_millis(parse_number(v)? as u64));
+            Ok(())
+        },
+    ),
+    live(
+        "repl-backlog-size",
+        |b| b.repl_backlog_size().to_string(),
+        |b, v| {
+            b.set_repl_backlog_size(parse_memory(v)?);
+            Ok(())
+        },
+    ),
+    live(
+        "client-query-buffer-limit",
+        |b| b.query_buffer_limit().to_string(),
+        |b, v| {
+            let limit = parse_memory(v)?;
+            if limit < 1024 * 1024 {
+                bail!("argument must be a memory value of at least 1mb");
+            }
+            b.set_query_buffer_limit(limit);
+            Ok(())
+        },
+    ),
+    live(
+        "client-output-buffer-limit",
+        |b| {
+            ClientClass::ALL
+                .iter()
+                .map(|class| format!("{} {}", class.as_str(), b.output_limit(*class)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        |b, v| {
+            let words = v.split_whitespace().collect::<Vec<_>>();
+            if words.is_empty() || !words.len().is_multiple_of(4) {
+                bail!("Wrong number of arguments in buffer limit configuration.");
+            }
+            let limits = words
+                .chunks(4)
+                .map(|w| {
+                    let class = w[0].parse::<ClientClass>()?;
+                    let limit = OutputLimit::new(
+                        parse_memory(w[1])?,
+                        parse_memory(w[2])?,
+                        parse_number(w[3])? as u64,
+                    );
+                    Ok((class, limit))
+                })
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| {
+                    anyhow!(
+                        "Error in hard, soft or soft_seconds setting in buffer limit configuration: {}",
+                        e
+                    )
+                })?;
+            for (class, limit) in limits {
+                b.set_output_limit(class, limit);
+            }
+            Ok(())
+        },
+    ),
+];
+
+/// Parameters whose value is several arguments, written unquoted.
+const MULTI_ARG_PARAMS: &[&str] = &["client-output-buffer-limit"];
+
+fn lookup_param(name: &str) -> Option<&'static ConfigParam> {
+    PARAMS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// A non negative integer.
+fn parse_number(s: &str) -> Result<usize> {
+    s.parse()
+        .map_err(|_| anyhow!("argument couldn't be parsed into an integer"))
+}
+
+fn parse_bool(s: &str) -> Result<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => bail!("argument must be 'yes' or 'no'"),
+    }
+}
+
+fn check_ip(s: &str) -> Result<()> {
+    s.parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|_| anyhow!("argument must be a single IP address"))
+}
+
+fn check_port(s: &str) -> Result<()> {
+    s.parse::<u16>()
+        .map(|_| ())
+        .map_err(|_| anyhow!("argument must be between 0 and 65535"))
+}
+
+/// The master of a `replicaof host port` directive, none when it is empty
+/// or `no one`.
+pub fn parse_replicaof(s: &str) -> Result<Option<(String, u16)>> {
+    match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [] => Ok(None),
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Ok(None),
+        [host, port] => {
+            let port = port.parse().map_err(|_| anyhow!("Invalid master port"))?;
+            Ok(Some((host.to_string(), port)))
+        }
+        _ => bail!("wrong number of arguments"),
+    }
+}
+
+/// A size as redis.conf writes it: bytes, or with a unit where `k`, `m`
+/// and `g` are powers of 1000 and `kb`, `mb` and `gb` powers of 1024.
+pub fn parse_memory(s: &str) -> Result<usize> {
+    let lower = s.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let unit: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => bail!("argument must be a memory value"),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| anyhow!("argument must be a memory value"))
+}
+
+/// The directives of a redis.conf file as name and value, the arguments of
+/// a directive joined by spaces. Arguments may be quoted like in redis:
+/// `"..."` with `\n`, `\t`, `\"`, `\\` and `\xHH` escapes, or `'...'`.
+pub fn parse_config(text: &str) -> Result<Vec<(String, String)>> {
+    let mut directives = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let args = split_args(line).with_context(|| {
+            format!("*** FATAL CONFIG FILE ERROR *** line {}: '{}'", i + 1, line)
+        })?;
+        let [name, values @ ..] = args.as_slice() else {
+            continue;
+        };
+        if values.is_empty() {
+            bail!(
+                "*** FATAL CONFIG FILE ERROR *** line {}: '{}': wrong number of arguments",
+                i + 1,
+                line
+            );
+        }
+        directives.push((name.to_ascii_lowercase(), values.join(" ")));
+    }
+    Ok(directives)
+}
+
+fn split_args(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => arg.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('r') => '\r',
+                            Some('t') => '\t',
+                            Some('x') => {
+                                let hex = chars.by_ref().take(2).collect::<String>();
+                                u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| anyhow!("invalid escape \\x{}", hex))?
+                                    as char
+                            }
+                            Some(c) => c,
+                            None => bail!("unbalanced quotes"),
+                        }),
+                        Some(c) => arg.push(c),
+                        None => bail!("unbalanced quotes"),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => bail!("unbalanced quotes"),
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+        }
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            bail!("closing quote must be followed by a space");
+        }
+        args.push(arg);
+    }
+}
+
+/// A value the way `parse_config` reads it back.
+fn quote_value(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\');
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The values of the startup parameters and the file they came from.
+#[derive(Debug, Default)]
+pub(crate) struct ConfigState {
+    startup: Mutex<HashMap<&'static str, String>>,
+    /// The file `CONFIG REWRITE` writes to.
+    file: Mutex<Option<PathBuf>>,
+}
+
+impl ConfigParam {
+    fn get(&self, backend: &Backend) -> String {
+        match self.access {
+            Access::Live { get, .. } => get(backend),
+            Access::Startup { default, .. } => {
+                let startup = backend.config.startup.lock().unwrap();
+                startup
+                    .get(self.name)
+                    .cloned()
+                    .unwrap_or_else(|| default.to_string())
+            }
+        }
+    }
+
+    /// The line of the parameter in a config file.
+    fn directive(&self, backend: &Backend) -> String {
+        let value = self.get(backend);
+        if MULTI_ARG_PARAMS.contains(&self.name) {
+            format!("{} {}", self.name, value)
+        } else {
+            format!("{} {}", self.name, quote_value(&value))
+        }
+    }
+}
+
+impl Backend {
+    /// The parameters matching the glob `pattern` and their values.
+    pub fn config_get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_ascii_lowercase();
+        PARAMS
+            .iter()
+            .filter(|p| glob_match(pattern.as_bytes(), p.name.as_bytes()))
+            .map(|p| (p.name, p.get(self)))
+            .collect()
+    }
+
+    /// The value of the parameter `name`.
+    pub fn config_value(&self, name: &str) -> Option<String> {
+        lookup_param(name).map(|p| p.get(self))
+    }
+
+    /// Set every parameter of `pairs` or, when one fails, none of them.
+    pub fn config_set(&self, pairs: &[(&str, &str)]) -> Result<()> {
+        let mut params = Vec::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            let param = lookup_param(name).ok_or_else(|| {
+                anyhow!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                )
+            })?;
+            if params
+                .iter()
+                .any(|(p, _): &(&ConfigParam, _)| p.name == param.name)
+            {
+                bail!("ERR duplicate parameter - {}", param.name);
+            }
+            let Access::Live { get, set } = param.access else {
+                bail!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                    param.name
+                );
+            };
+            params.push((param, (get, set, *value)));
+        }
+        let old = params
+            .iter()
+            .map(|(_, (get, _, _))| get(self))
+            .collect::<Vec<_>>();
+        for (i, (param, (_, set, value))) in params.iter().enumerate() {
+            if let Err(e) = set(self, value) {
+                // put back what was already applied
+                for ((_, (_, set, _)), old) in params[..i].iter().zip(&old) {
+                    let _ = set(self, old);
+                }
+                bail!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    param.name,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the directives of a config file or the command line, startup
+    /// parameters included. Later directives win over earlier ones.
+    pub fn apply_config(&self, directives: &[(String, String)]) -> Result<()> {
+        for (name, value) in directives {
+            let param = lookup_param(name)
+                .ok_or_else(|| anyhow!("Bad directive or wrong number of arguments: '{}'", name))?;
+            let res = match param.access {
+                Access::Live { set, .. } => set(self, value),
+                Access::Startup { check, .. } => check(value).map(|_| {
+                    let mut startup = self.config.startup.lock().unwrap();
+                    startup.insert(param.name, value.clone());
+                }),
+            };
+            res.with_context(|| format!("invalid '{}' value '{}'", param.name, value))?;
+        }
+        Ok(())
+    }
+
+    /// Remember `path` as the config file for `CONFIG REWRITE`.
+    pub fn set_config_file(&self, path: impl Into<PathBuf>) {
+        *self.config.file.lock().unwrap() = Some(path.into());
+    }
+
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.config.file.lock().unwrap().clone()
+    }
+
+    /// Write the current values to the config file. Directives are
+    /// replaced where they are, comments and unknown lines are kept and
+    /// parameters changed from their default are appended.
+    pub fn config_rewrite(&self) -> Result<()> {
+        let path = self
+            .config_file()
+            .ok_or_else(|| anyhow!("ERR The server is running without a config file"))?;
+        let old = match fs::read_to_string(&path) {
+            Ok(old) => old,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("ERR Rewriting config file: {}", e)),
+        };
+        let mut written = HashSet::new();
+        let mut lines = Vec::new();
+        for line in old.lines() {
+            let name = line.split_whitespace().next().unwrap_or("");
+            match lookup_param(name).filter(|_| !line.trim_start().starts_with('#')) {
+                // a parameter given several times is written once
+                Some(param) => {
+                    if written.insert(param.name) {
+                        lines.push(param.directive(self));
+                    }
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        let defaults = Backend::new();
+        let changed = PARAMS
+            .iter()
+            .filter(|p| !written.contains(p.name) && p.get(self) != p.get(&defaults))
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            lines.push("# Generated by CONFIG REWRITE".into());
+            lines.extend(changed.iter().map(|p| p.directive(self)));
+        }
+        let mut text = lines.join("\n");
+        text.push('\n');
+        let tmp = path.with_file_name(format!("temp-{}.conf", std::process::id()));
+        fs::write(&tmp, text)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| anyhow!("ERR Rewriting config file: {}", e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1KB").unwrap(), 1024);
+        assert_eq!(parse_memory("256mb").unwrap(), 256 * 1024 * 1024);
+        assert_eq!(parse_memory("2g").unwrap(), 2_000_000_000);
+        assert!(parse_memory("-1").is_err());
+        assert!(parse_memory("1tb").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn test_config_get_and_set() {
+        let backend = Backend::new();
+        assert_eq!(
+            backend.config_get("client-output-buffer-limit"),
+            vec![(
+                "client-output-buffer-limit",
+                "normal 0 0 0 slave 268435456 67108864 60 pubsub 33554432 8388608 60".to_string()
+            )]
+        );
+        backend
+            .config_set(&[
+                ("MAXCLIENTS", "5"),
+                ("timeout", "30"),
+                ("requirepass", "pw"),
+                (
+                    "client-output-buffer-limit",
+                    "normal 1mb 512kb 10 replica 0 0 0",
+                ),
+            ])
+            .unwrap();
+        assert_eq!(backend.maxclients(), 5);
+        assert_eq!(backend.idle_timeout(), Some(Duration::from_secs(30)));
+        assert!(backend.authenticate("default", b"pw"));
+        assert_eq!(
+            backend.output_limit(ClientClass::Normal),
+            OutputLimit::new(1024 * 1024, 512 * 1024, 10)
+        );
+        assert_eq!(
+            backend.output_limit(ClientClass::Replica),
+            OutputLimit::default()
+        );
+        assert_eq!(
+            backend.config_get("max*"),
+            vec![
+                ("maxmemory", "0".to_string()),
+                ("maxmemory-policy", "noeviction".to_string()),
+                ("maxmemory-samples", "5".to_string()),
+                ("maxclients", "5".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_set_is_all_or_nothing() {
+        let backend = Backend::new();
+        let err = backend
+            .config_set(&[("maxclients", "5"), ("timeout", "soon")])
+            .unwrap_err();
+        assert!(err.to_string().starts_with("ERR CONFIG SET failed"));
+        assert_eq!(backend.maxclients(), 10000);
+        assert!(backend.config_set(&[("nope", "1")]).is_err());
+        assert!(backend
+            .config_set(&[("timeout", "1"), ("TIMEOUT", "2")])
+            .is_err());
+        assert!(backend
+            .config_set(&[("client-output-buffer-limit", "normal 1 2")])
+            .is_err());
+        let err = backend.config_set(&[("port", "7000")]).unwrap_err();
+        assert!(err.to_string().ends_with("can't set immutable config"));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+# a comment
+bind 127.0.0.1
+port 7000
+requirepass "with space\x21"
+client-output-buffer-limit normal 1mb 512kb 10
+  appendonly yes
+"#;
+        let directives = parse_config(text).unwrap();
+        assert_eq!(
+            directives,
+            [
+                ("bind", "127.0.0.1"),
+                ("port", "7000"),
+                ("requirepass", "with space!"),
+                ("client-output-buffer-limit", "normal 1mb 512kb 10"),
+                ("appendonly", "yes"),
+            ]
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+        );
+        assert!(parse_config("port").is_err());
+        assert!(parse_config("requirepass \"open").is_err());
+        assert!(parse_config("requirepass \"a\"b").is_err());
+
+        let backend = Backend::new();
+        backend.apply_config(&directives).unwrap();
+        assert_eq!(backend.config_value("port").as_deref(), Some("7000"));
+        assert_eq!(backend.config_value("appendonly").as_deref(), Some("yes"));
+        assert!(backend.authenticate("default", b"with space!"));
+        let bad = [("port".to_string(), "70000".to_string())];
+        assert!(backend.apply_config(&bad).is_err());
+        let unknown = [("nope".to_string(), "1".to_string())];
+        assert!(backend.apply_config(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_replicaof_directive() -> Result<()> {
+        let backend = Backend::new();
+        backend.apply_config(&parse_config("port 6381\nreplicaof 127.0.0.1 6380\n")?)?;
+        assert_eq!(
+            parse_replicaof(&backend.config_value("replicaof").unwrap_or_default())?,
+            Some(("127.0.0.1".to_string(), 6380))
+        );
+        backend.apply_config(&parse_config("replicaof no one")?)?;
+        assert_eq!(
+            parse_replicaof(&backend.config_value("replicaof").unwrap_or_default())?,
+            None
+        );
+        assert!(backend
+            .apply_config(&parse_config("replicaof 127.0.0.1 nope")?)
+            .is_err());
+        assert!(backend
+            .apply_config(&parse_config("replicaof 127.0.0.1")?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_rewrite() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("dredis-{}-rewrite.conf", std::process::id()));
+        fs::write(
+            &path,
+            "# keep me\nport 7000\ntimeout 10\nmaxclients 100\ntimeout 20\n",
+        )?;
+        let backend = Backend::new();
+        assert!(backend.config_rewrite().is_err());
+        backend.apply_config(&parse_config(&fs::read_to_string(&path)?)?)?;
+        backend.set_config_file(&path);
+        backend.config_set(&[("timeout", "30"), ("requirepass", "a b")])?;
+        backend.config_rewrite()?;
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            "# keep me\nport 7000\ntimeout 30\nmaxclients 100\n# Generated by CONFIG REWRITE\nrequirepass \"a b\"\n"
+        );
+        // what was written reads back the same
+        let again = Backend::new();
+        again.apply_config(&parse_config(&fs::read_to_string(&path)?)?)?;
+        assert_eq!(again.config_get("*"), backend.config_get("*"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
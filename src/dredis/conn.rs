@@ -0,0 +1,467 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    execute, execute_blocking, frame_to_args, lookup_command, Backend, ClientClass, ClientHandle,
+    OutputLimitCheck, ReplicaFeed, RespDecoder, RespFrame, RespVersion, Subscriptions, Transaction,
+    WatchedKeys, DEFAULT_USER,
+};
+
+const BUF_SIZE: usize = 4096;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Per connection state.
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    pub protocol: RespVersion,
+    pub name: Option<String>,
+    pub backend: Backend,
+    pub(crate) subscriptions: Option<Subscriptions>,
+    pub(crate) multi: Option<Transaction>,
+    pub(crate) watched: Option<WatchedKeys>,
+    /// Replies sent ahead of the command's own reply, for commands such as
+    /// `SUBSCRIBE` that answer once per argument.
+    pub(crate) pushes: Vec<RespFrame>,
+    /// What a write command is logged as when not as sent, e.g. with
+    /// relative expire times made absolute.
+    pub(crate) propagate_as: Option<Vec<Vec<Bytes>>>,
+    /// Set while `EXEC` logs the writes of its commands inside `MULTI` and
+    /// `EXEC`, the scripts among them then leave theirs unwrapped.
+    pub(crate) propagating_multi: bool,
+    /// What a blocked command runs as when it tries again, set when its
+    /// arguments were relative to the keyspace it first saw, like the `$`
+    /// of `XREAD`.
+    pub(crate) retry_as: Option<Vec<Bytes>>,
+    /// Set by `ASKING`, the next command may use a slot being imported.
+    pub(crate) asking: bool,
+    /// Set by `QUIT`, the connection closes after the reply.
+    pub(crate) quit: bool,
+    /// The port a replica listens on, from `REPLCONF listening-port`.
+    pub(crate) replica_port: Option<u16>,
+    /// Set by `PSYNC`, the connection streams the writes to the replica
+    /// from then on.
+    pub(crate) replica_feed: Option<ReplicaFeed>,
+    /// The link of a replica to its master: its writes are allowed and not
+    /// sent on to the replicas of this server by `propagate`.
+    pub(crate) master_link: bool,
+    /// The ACL user the client runs commands as, `None` for the server's
+    /// own sessions which may run anything.
+    pub(crate) user: Option<String>,
+    /// Whether the client logged in, it may only `AUTH` until then.
+    pub(crate) authenticated: bool,
+    /// What `CLIENT LIST` shows of this connection.
+    pub(crate) client: Arc<ClientHandle>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(Backend::new())
+    }
+}
+
+impl Session {
+    pub fn new(backend: Backend) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        Session {
+            id,
+            addr: None,
+            protocol: RespVersion::default(),
+            name: None,
+            backend,
+            subscriptions: None,
+            multi: None,
+            watched: None,
+            pushes: Vec::new(),
+            propagate_as: None,
+            propagating_multi: false,
+            retry_as: None,
+            asking: false,
+            quit: false,
+            replica_port: None,
+            replica_feed: None,
+            master_link: false,
+            user: None,
+            authenticated: true,
+            client: Arc::new(ClientHandle::new(id)),
+        }
+    }
+
+    /// Log a write command and replicate it, unless it came from the master.
+    pub(crate) fn propagate(&self, args: &[Bytes]) {
+        if self.master_link {
+            self.backend.log(args);
+        } else {
+            self.backend.propagate(args);
+        }
+    }
+
+    /// Whether the client subscribed to channels or patterns with RESP2,
+    /// where only the pubsub commands can be used.
+    pub fn in_subscribed_mode(&self) -> bool {
+        self.protocol == RespVersion::Resp2 && self.subscriptions.is_some()
+    }
+}
+
+pub async fn process_redis_conn(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    backend: Backend,
+) -> Result<()> {
+    let local_addr = stream.local_addr()?;
+    serve_client(stream, client_addr, local_addr, backend).await
+}
+
+/// Serve one client on `stream`, plain TCP or TLS, until it disconnects.
+pub(crate) async fn serve_client<S>(
+    mut stream: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    backend: Backend,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = Session::new(backend.clone());
+    session.addr = Some(client_addr);
+    session.user = Some(DEFAULT_USER.to_string());
+    session.authenticated = backend.default_user_nopass();
+    let client = session.client.clone();
+    let Some(_slot) = backend.register_client(&client, client_addr, local_addr) else {
+        warn!("max number of clients reached, rejecting {}", client_addr);
+        stream
+            .write_all(b"-ERR max number of clients reached\r\n")
+            .await?;
+        return Ok(());
+    };
+    // keep unparsed bytes across reads, a frame may arrive in several pieces
+    let mut buf = BytesMut::with_capacity(BUF_SIZE);
+    let mut decoder = RespDecoder::default();
+    let mut output_check = OutputLimitCheck::default();
+    'conn: loop {
+        let idle = idle(&backend, session.subscriptions.is_some());
+        let read = tokio::select! {
+            res = stream.read_buf(&mut buf) => res?,
+            message = next_message(&mut session) => {
+                // no more messages once the client overran its output buffer
+                let Some(message) = message else {
+                    break;
+                };
+                stream.write_all(&message.to_bytes_with(session.protocol)).await?;
+                continue;
+            }
+            // commands already read were answered, the connection closes
+            _ = backend.shutdown_requested() => break,
+            _ = client.killed() => {
+                info!("client {} killed", client_addr);
+                break;
+            }
+            _ = idle => {
+                info!("closing idle client {}", client_addr);
+                break;
+            }
+        };
+        match read {
+            0 => break,
+            n => {
+                debug!("read {} bytes from {}", n, client_addr);
+                let mut out = BytesMut::new();
+                loop {
+                    let frame = match decoder.decode(&mut buf) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            RespFrame::error(format!("ERR {}", e)).encode(&mut out);
+                            stream.write_all(&out).await?;
+                            return Err(e);
+                        }
+                    };
+                    let reply = match frame_to_args(frame) {
+                        Ok(args) if is_blocking(&args) => {
+                            // replies to earlier pipelined commands must not wait
+                            stream.write_all(&out).await?;
+                            out.clear();
+                            client.set_blocked(true);
+                            let reply = tokio::select! {
+                                reply = execute_blocking(&mut session, &args) => reply,
+                                // dropping the blocked command unregisters the client
+                                res = closed(&mut stream, &mut buf) => {
+                                    res?;
+                                    break 'conn;
+                                }
+                                _ = backend.shutdown_requested() => break 'conn,
+                                _ = client.killed() => break 'conn,
+                            };
+                            client.set_blocked(false);
+                            reply
+                        }
+                        Ok(args) => execute(&mut session, &args),
+                        Err(e) => RespFrame::error(format!("ERR {}", e)),
+                    };
+                    for push in session.pushes.drain(..) {
+                        push.encode_with(session.protocol, &mut out);
+                    }
+                    reply.encode_with(session.protocol, &mut out);
+                    client.update(&session);
+                    client.set_buffers(buf.len(), out.len());
+                    let class = match session.subscriptions {
+                        Some(_) => ClientClass::PubSub,
+                        None => ClientClass::Normal,
+                    };
+                    if output_check.exceeded(&backend.output_limit(class), out.len()) {
+                        warn!(
+                            "client {} closed for overcoming of output buffer limits ({} bytes)",
+                            client_addr,
+                            out.len()
+                        );
+                        break 'conn;
+                    }
+                    if session.quit {
+                        stream.write_all(&out).await?;
+                        break 'conn;
+                    }
+                    if let Some(feed) = session.replica_feed.take() {
+                        stream.write_all(&out).await?;
+                        info!("redis client {} is a replica now", client_addr);
+                        client.set_replica();
+                        return tokio::select! {
+                            res = feed.serve(&mut stream, buf) => res,
+                            _ = client.killed() => Ok(()),
+                        };
+                    }
+                }
+                stream.write_all(&out).await?;
+                // what is left is an incomplete command
+                if buf.len() > backend.query_buffer_limit() {
+                    warn!(
+                        "closing client {} that reached max query buffer length ({} bytes)",
+                        client_addr,
+                        buf.len()
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    debug!("redis client {} closed", client_addr);
+    Ok(())
+}
+
+/// Resolves once a client was idle for the idle timeout. Subscribers wait
+/// for messages, they are never idle.
+async fn idle(backend: &Backend, subscribed: bool) {
+    match backend.idle_timeout() {
+        Some(timeout) if !subscribed => tokio::time::sleep(timeout).await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// The next message for a subscribed client, never resolves for others.
+pub(crate) async fn next_message(session: &mut Session) -> Option<RespFrame> {
+    match session.subscriptions.as_mut() {
+        Some(subs) => subs.next_message().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn is_blocking(args: &[Bytes]) -> bool {
+    args.first()
+        .and_then(|name| lookup_command(name))
+        .is_some_and(|cmd| cmd.is_blocking())
+}
+
+/// Resolves once the peer closes the connection. Anything it sends meanwhile
+/// is kept in `buf` and handled after the blocked command returns.
+async fn closed<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<()> {
+    while stream.read_buf(buf).await? > 0 {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    async fn serve(backend: &Backend) -> Result<(TcpStream, JoinHandle<Result<()>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn({
+            let backend = backend.clone();
+            async move {
+                let (stream, client_addr) = listener.accept().await?;
+                process_redis_conn(stream, client_addr, backend).await
+            }
+        });
+        Ok((TcpStream::connect(addr).await?, server))
+    }
+
+    async fn read_exactly(client: &mut TcpStream, expected: &[u8]) -> Result<()> {
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_receives_published_messages() -> Result<()> {
+        let backend = Backend::new();
+        let (mut client, server) = serve(&backend).await?;
+        client
+            .write_all(b"*3\r\n$9\r\nSUBSCRIBE\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .await?;
+        read_exactly(
+            &mut client,
+            b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n",
+        )
+        .await?;
+
+        let mut publisher = Session::new(backend.clone());
+        let publish = ["PUBLISH", "b", "hi"].map(bytes::Bytes::from);
+        assert_eq!(execute(&mut publisher, &publish), RespFrame::Integer(1));
+        read_exactly(
+            &mut client,
+            b"*3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n",
+        )
+        .await?;
+
+        client.write_all(b"*1\r\n$4\r\nQUIT\r\n").await?;
+        read_exactly(&mut client, b"+OK\r\n").await?;
+        server.await??;
+        assert_eq!(execute(&mut publisher, &publish), RespFrame::Integer(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocked_client_disconnect_unregisters() -> Result<()> {
+        let backend = Backend::new();
+        let (mut client, server) = serve(&backend).await?;
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n")
+            .await?;
+        read_exactly(&mut client, b"+PONG\r\n").await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(backend.blocking.blocked_clients(), 1);
+
+        drop(client);
+        server.await??;
+        assert_eq!(backend.blocking.blocked_clients(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_maxclients_rejects_connections() -> Result<()> {
+        let backend = Backend::new();
+        backend.set_maxclients(1);
+        let other = Session::new(backend.clone()).client;
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let _taken = backend.register_client(&other, addr, addr);
+        let (mut client, server) = serve(&backend).await?;
+        read_exactly(&mut client, b"-ERR max number of clients reached\r\n").await?;
+        server.await??;
+        assert_eq!(backend.connected_clients(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_killed_client_is_closed() -> Result<()> {
+        let backend = Backend::new();
+        let (mut client, server) = serve(&backend).await?;
+        client
+            .write_all(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$4\r\nleak\r\n")
+            .await?;
+        read_exactly(&mut client, b"+OK\r\n").await?;
+        let list = backend.client_list();
+        assert_eq!(list.len(), 1);
+        assert!(list[0].describe().contains(" name=leak "));
+        assert!(list[0].describe().contains(" cmd=client "));
+
+        let mut admin = Session::new(backend.clone());
+        let reply = execute(
+            &mut admin,
+            &[
+                "CLIENT".into(),
+                "KILL".into(),
+                "TYPE".into(),
+                "normal".into(),
+            ],
+        );
+        assert_eq!(reply, RespFrame::Integer(1));
+        tokio::time::timeout(Duration::from_secs(3), server).await???;
+        let mut buf = [0u8; 16];
+        assert_eq!(client.read(&mut buf).await?, 0);
+        assert!(backend.client_list().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clients_must_authenticate() -> Result<()> {
+        let backend = Backend::new();
+        backend.set_requirepass("pw");
+        let (mut client, server) = serve(&backend).await?;
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await?;
+        read_exactly(&mut client, b"-NOAUTH Authentication required.\r\n").await?;
+        client
+            .write_all(b"*2\r\n$4\r\nAUTH\r\n$2\r\npw\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await?;
+        read_exactly(&mut client, b"+OK\r\n$-1\r\n").await?;
+        drop(client);
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_closed() -> Result<()> {
+        let backend = Backend::new();
+        backend.set_idle_timeout(Some(Duration::from_secs(1)));
+        let (mut client, server) = serve(&backend).await?;
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+        read_exactly(&mut client, b"+PONG\r\n").await?;
+        tokio::time::timeout(Duration::from_secs(3), server).await???;
+        assert_eq!(client.read(&mut [0u8; 16]).await?, 0);
+        assert_eq!(backend.connected_clients(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_buffer_limit_closes_client() -> Result<()> {
+        let backend = Backend::new();
+        backend.set_output_limit(ClientClass::Normal, crate::OutputLimit::new(100, 0, 0));
+        let (mut client, server) = serve(&backend).await?;
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$60\r\n012345678901234567890123456789012345678901234567890123456789\r\n")
+            .await?;
+        read_exactly(&mut client, b"+OK\r\n").await?;
+        // one reply fits, two in a single batch do not
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await?;
+        server.await??;
+        assert_eq!(client.read(&mut [0u8; 16]).await?, 0);
+        Ok(())
+    }
+}
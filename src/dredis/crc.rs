@@ -0,0 +1,62 @@
+use std::sync::OnceLock;
+
+/// CRC-64/Jones as redis uses it to checksum RDB files: reflected, no final
+/// xor, polynomial 0xad93d23594c935a9.
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    static TABLE: OnceLock<[u64; 256]> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        const POLY: u64 = 0x95ac_9329_ac4b_c9b5; // 0xad93d23594c935a9 reflected
+        let mut table = [0u64; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            *slot = crc;
+        }
+        table
+    });
+    data.iter().fold(crc, |crc, &b| {
+        table[((crc ^ b as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// CRC-16/XMODEM as redis cluster uses it to hash keys to slots: no
+/// reflection, polynomial 0x1021, initial value 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &b| {
+        let mut crc = crc ^ (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        // the check value from redis' crc64.c
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        let split = crc64(crc64(0, b"1234"), b"56789");
+        assert_eq!(split, 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn test_crc16() {
+        // the check value from redis' crc16.c
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::{Backend, Entry, KeyIndex, ScanIndex};
+
+/// Number of logical databases unless `databases` says otherwise.
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// One logical database, `SELECT` picks which one a connection works on.
+#[derive(Debug, Default)]
+pub(crate) struct Db {
+    pub(crate) data: DashMap<Bytes, Entry>,
+    pub(crate) expires: Mutex<KeyIndex>,
+    /// Every key, for `SCAN` and for eviction to sample from.
+    pub(crate) keys: Mutex<ScanIndex>,
+    /// The accounted size of every entry in the database.
+    pub(crate) used_memory: AtomicUsize,
+}
+
+impl Db {
+    pub(crate) fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) fn new_dbs(n: usize) -> Vec<Arc<Db>> {
+    (0..n).map(|_| Arc::new(Db::default())).collect()
+}
+
+impl Backend {
+    /// The database this handle works on.
+    pub(crate) fn db(&self) -> Arc<Db> {
+        self.dbs.read().unwrap()[self.db].clone()
+    }
+
+    /// A handle on the same keyspace working on database `index`.
+    pub fn select(&self, index: usize) -> Backend {
+        let mut backend = self.clone();
+        backend.db = index;
+        backend
+    }
+
+    /// Index of the database this handle works on.
+    pub fn db_index(&self) -> usize {
+        self.db
+    }
+
+    pub fn databases(&self) -> usize {
+        self.dbs.read().unwrap().len()
+    }
+
+    /// Use `n` empty databases, only meant for startup before any key is set.
+    pub fn set_databases(&self, n: usize) {
+        *self.dbs.write().unwrap() = new_dbs(n);
+    }
+
+    /// Every database with its index.
+    pub(crate) fn all_dbs(&self) -> Vec<(usize, Arc<Db>)> {
+        self.dbs
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .enumerate()
+            .collect()
+    }
+
+    /// Remove every key of the current database. With `lazy` the memory is
+    /// given back on a background thread.
+    pub fn flushdb(&self, lazy: bool) {
+        self.flush(self.db, lazy);
+    }
+
+    /// Remove every key of every database.
+    pub fn flushall(&self, lazy: bool) {
+        for index in 0..self.databases() {
+            self.flush(index, lazy);
+        }
+    }
+
+    fn flush(&self, index: usize, lazy: bool) {
+        let old = std::mem::take(&mut self.dbs.write().unwrap()[index]);
+        self.watches
+            .touch_db(index, |key| old.data.contains_key(key));
+        if lazy {
+            thread::spawn(move || drop(old));
+        }
+    }
+
+    /// Swap the keys of databases `a` and `b`, connections stay on the index
+    /// they selected and see the other keys.
+    pub fn swapdb(&self, a: usize, b: usize) {
+        let mut dbs = self.dbs.write().unwrap();
+        let (db_a, db_b) = (dbs[a].clone(), dbs[b].clone());
+        let exists = |key: &[u8]| db_a.data.contains_key(key) || db_b.data.contains_key(key);
+        self.watches.touch_db(a, exists);
+        self.watches.touch_db(b, exists);
+        dbs.swap(a, b);
+        drop(dbs);
+        // the new keys may serve clients blocked on them
+        self.blocking.wake_db(a);
+        self.blocking.wake_db(b);
+    }
+
+    /// Move `key` to database `dst`, returns whether it moved: it must exist
+    /// here and not there. The caller holds the exec lock exclusively.
+    pub fn move_key(&self, key: &Bytes, dst: usize) -> bool {
+        let target = self.select(dst);
+        if !self.exists(key) || target.exists(key) {
+            return false;
+        }
+        let entry = self.write(key, |slot| slot.take());
+        target.write(key, |slot| *slot = entry);
+        target.blocking.wake(dst, key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn set(backend: &Backend, key: &str) {
+        let key = Bytes::copy_from_slice(key.as_bytes());
+        backend.write(&key, |slot| {
+            *slot = Some(Entry::new(Value::String("v".into())))
+        });
+    }
+
+    #[test]
+    fn test_databases_are_separate() {
+        let backend = Backend::new();
+        assert_eq!(backend.databases(), DEFAULT_DATABASES);
+        let db1 = backend.select(1);
+        set(&backend, "a");
+        set(&db1, "b");
+        assert!(backend.exists(b"a") && !backend.exists(b"b"));
+        assert!(db1.exists(b"b") && !db1.exists(b"a"));
+        assert_eq!((backend.len(), db1.len()), (1, 1));
+    }
+
+    #[test]
+    fn test_flush_swap_move() {
+        let backend = Backend::new();
+        let db1 = backend.select(1);
+        set(&backend, "a");
+        set(&backend, "b");
+        set(&db1, "c");
+        let used = backend.used_memory();
+
+        backend.swapdb(0, 1);
+        assert!(backend.exists(b"c") && db1.exists(b"a"));
+        assert_eq!(backend.used_memory(), used);
+
+        assert!(db1.move_key(&"a".into(), 0));
+        assert!(!db1.move_key(&"a".into(), 0));
+        set(&db1, "c");
+        assert!(!db1.move_key(&"c".into(), 0));
+        assert_eq!((backend.len(), db1.len()), (2, 2));
+
+        db1.flushdb(true);
+        assert_eq!((backend.len(), db1.len()), (2, 0));
+        backend.flushall(false);
+        assert!(backend.is_empty());
+        assert_eq!(backend.used_memory(), 0);
+    }
+}
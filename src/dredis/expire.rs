@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::Backend;
+
+// same knobs as redis' activeExpireCycle
+pub(crate) const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE: usize = ACTIVE_EXPIRE_KEYS_PER_LOOP / 4;
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+/// Source of the current unix time in milliseconds, swapped out in tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        ManualClock {
+            now: Arc::new(AtomicU64::new(now_ms)),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.now.fetch_add(d.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+/// The keys with a ttl, kept in a vector so a random sample is cheap.
+#[derive(Debug, Default)]
+pub(crate) struct KeyIndex {
+    keys: Vec<Bytes>,
+    pos: HashMap<Bytes, usize>,
+}
+
+impl KeyIndex {
+    pub(crate) fn insert(&mut self, key: &Bytes) {
+        if !self.pos.contains_key(key) {
+            self.pos.insert(key.clone(), self.keys.len());
+            self.keys.push(key.clone());
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Some(idx) = self.pos.remove(key) {
+            self.keys.swap_remove(idx);
+            if let Some(moved) = self.keys.get(idx) {
+                self.pos.insert(moved.clone(), idx);
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Up to `n` distinct keys picked at random.
+    pub(crate) fn sample(&self, n: usize) -> Vec<Bytes> {
+        let mut rng = rand::thread_rng();
+        rand::seq::index::sample(&mut rng, self.keys.len(), n.min(self.keys.len()))
+            .into_iter()
+            .map(|i| self.keys[i].clone())
+            .collect()
+    }
+}
+
+impl Backend {
+    /// One round of redis style active expiry over every database: sample
+    /// keys with a ttl and drop the expired ones, keep going while more than a
+    /// quarter of a sample was stale and the time budget allows. Returns the
+    /// number of removed keys.
+    pub fn active_expire_cycle(&self) -> usize {
+        // keys must not vanish in the middle of a transaction
+        let _shared = self.exec_lock.read().unwrap();
+        let start = Instant::now();
+        let mut removed = 0;
+        for (index, db) in self.all_dbs() {
+            let backend = self.select(index);
+            loop {
+                let sample = db
+                    .expires
+                    .lock()
+                    .unwrap()
+                    .sample(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+                let expired = sample
+                    .iter()
+                    .filter(|key| backend.remove_expired(key))
+                    .count();
+                removed += expired;
+                if expired <= ACTIVE_EXPIRE_ACCEPTABLE_STALE {
+                    break;
+                }
+                if start.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT {
+                    return removed;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Run the active expiry cycle in the background every 100ms, until the
+    /// server shuts down.
+    pub fn spawn_active_expire(&self) -> JoinHandle<()> {
+        let backend = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = backend.shutdown_requested() => break,
+                }
+                let removed = backend.active_expire_cycle();
+                if removed > 0 {
+                    debug!("active expire removed {} keys", removed);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Entry, Value};
+
+    fn set_with_ttl(backend: &Backend, key: &str, ttl_ms: u64) {
+        let key = Bytes::copy_from_slice(key.as_bytes());
+        let expire_at = backend.now_ms() + ttl_ms;
+        backend.write(&key, |slot| {
+            *slot = Some(Entry::with_expire_at(
+                Value::String("v".into()),
+                Some(expire_at),
+            ))
+        });
+    }
+
+    #[test]
+    fn test_key_index() {
+        let mut index = KeyIndex::default();
+        let (a, b, c) = (Bytes::from("a"), Bytes::from("b"), Bytes::from("c"));
+        index.insert(&a);
+        index.insert(&b);
+        index.insert(&c);
+        index.insert(&a);
+        assert_eq!(index.len(), 3);
+        index.remove(b"a");
+        index.remove(b"x");
+        assert_eq!(index.len(), 2);
+        let mut sample = index.sample(10);
+        sample.sort();
+        sample.dedup();
+        assert!(sample.iter().all(|k| k == &b || k == &c));
+    }
+
+    #[test]
+    fn test_lazy_expire_with_manual_clock() {
+        let clock = ManualClock::new(1_000);
+        let backend = Backend::with_clock(Arc::new(clock.clone()));
+        set_with_ttl(&backend, "k", 500);
+        assert!(backend.exists(b"k"));
+        clock.advance(Duration::from_millis(500));
+        assert!(!backend.exists(b"k"));
+        assert_eq!(backend.len(), 0);
+        assert_eq!(backend.volatile_len(), 0);
+    }
+
+    #[test]
+    fn test_active_expire_cycle() {
+        let clock = ManualClock::new(1_000);
+        let backend = Backend::with_clock(Arc::new(clock.clone()));
+        for i in 0..200 {
+            set_with_ttl(&backend, &format!("short:{}", i), 10);
+        }
+        for i in 0..10 {
+            set_with_ttl(&backend, &format!("long:{}", i), 10_000);
+        }
+        assert_eq!(backend.active_expire_cycle(), 0);
+
+        clock.advance(Duration::from_millis(10));
+        let mut removed = 0;
+        while backend.volatile_len() > 10 {
+            removed += backend.active_expire_cycle();
+        }
+        assert_eq!(removed, 200);
+        assert_eq!(backend.len(), 10);
+    }
+}
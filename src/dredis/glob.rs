@@ -0,0 +1,128 @@
+/// Redis style glob matching as used by `PSUBSCRIBE`, `KEYS` and friends:
+/// `*` matches any run of bytes, `?` a single byte, `[abc]`, `[^abc]` and
+/// `[a-z]` byte classes, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where to resume after the last `*`: pattern after the star, and string
+    let mut backtrack = None;
+    while s < string.len() {
+        match match_one(pattern, p, string[s]) {
+            Step::Star => {
+                backtrack = Some((p + 1, s));
+                p += 1;
+                continue;
+            }
+            Step::Matched(next) => {
+                p = next;
+                s += 1;
+                continue;
+            }
+            Step::Failed => {}
+        }
+        match backtrack {
+            Some((bp, bs)) => {
+                p = bp;
+                s = bs + 1;
+                backtrack = Some((bp, bs + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+enum Step {
+    Star,
+    Matched(usize),
+    Failed,
+}
+
+/// Match the pattern element at `p` against one byte.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Step {
+    let Some(&pc) = pattern.get(p) else {
+        return Step::Failed;
+    };
+    match pc {
+        b'*' => Step::Star,
+        b'?' => Step::Matched(p + 1),
+        b'[' => match_class(pattern, p + 1, c),
+        b'\\' if p + 1 < pattern.len() => {
+            if pattern[p + 1] == c {
+                Step::Matched(p + 2)
+            } else {
+                Step::Failed
+            }
+        }
+        _ if pc == c => Step::Matched(p + 1),
+        _ => Step::Failed,
+    }
+}
+
+/// Match a `[...]` class starting right after the `[`. An unterminated class
+/// extends to the end of the pattern, like redis.
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> Step {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = (
+                pattern[p].min(pattern[p + 2]),
+                pattern[p].max(pattern[p + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    if matched != negate {
+        Step::Matched((p + 1).min(pattern.len()))
+    } else {
+        Step::Failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("news.*", "news.sport"));
+        assert!(!matches("news.*", "new.sport"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxaxxbxx"));
+        assert!(matches("a**", "a"));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn test_glob_classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        assert!(matches("a\\*", "a*"));
+        assert!(!matches("a\\*", "ab"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("a[bc", "ab"));
+    }
+}
@@ -0,0 +1,496 @@
+use std::{
+    fmt::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::{event, Backend, Db, Entry, Value};
+
+/// What an entry costs besides its key and value, about a dict entry and a
+/// redis object.
+const ENTRY_OVERHEAD: usize = 48;
+/// What a string, or an element of an aggregate, costs besides its bytes.
+const STRING_OVERHEAD: usize = 16;
+/// Elements an aggregate's size is estimated from, like `MEMORY USAGE`.
+const SIZE_SAMPLES: usize = 5;
+
+// same as redis' defaults for lfu-log-factor and lfu-decay-time
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MS: u64 = 60 * 1000;
+
+/// Which keys go when the keyspace outgrows `maxmemory`, redis'
+/// `maxmemory-policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Nothing is evicted, commands that would use more memory fail.
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    /// The keys closest to expiring.
+    VolatileTtl,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Ok(EvictionPolicy::VolatileLfu),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-random" => Ok(EvictionPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(anyhow!("invalid maxmemory-policy value '{}'", s)),
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileRandom => "volatile-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    fn volatile(&self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileRandom
+                | EvictionPolicy::VolatileTtl
+        )
+    }
+}
+
+/// What eviction knows about a key: when it was last used, a logarithmic
+/// access counter like redis' LFU and the memory it was accounted with.
+/// Bookkeeping only, two entries never differ by it.
+#[derive(Debug)]
+pub(crate) struct KeyMeta {
+    /// Unix time in milliseconds, 0 until the entry is first stored.
+    last_access: AtomicU64,
+    freq: AtomicU8,
+    pub(crate) size: usize,
+}
+
+impl Default for KeyMeta {
+    fn default() -> Self {
+        KeyMeta {
+            last_access: AtomicU64::new(0),
+            freq: AtomicU8::new(LFU_INIT_VAL),
+            size: 0,
+        }
+    }
+}
+
+impl Clone for KeyMeta {
+    fn clone(&self) -> Self {
+        KeyMeta {
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            freq: AtomicU8::new(self.freq.load(Ordering::Relaxed)),
+            size: self.size,
+        }
+    }
+}
+
+impl PartialEq for KeyMeta {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl KeyMeta {
+    /// Record an access at `now`, entries are touched through shared
+    /// references by readers.
+    pub(crate) fn touch(&self, now: u64) {
+        let mut freq = self.freq(now);
+        if freq < u8::MAX {
+            let base = freq.saturating_sub(LFU_INIT_VAL) as f64;
+            if rand::thread_rng().gen::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                freq += 1;
+            }
+        }
+        self.freq.store(freq, Ordering::Relaxed);
+        self.last_access.store(now, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the last access.
+    pub(crate) fn idle_ms(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_access.load(Ordering::Relaxed))
+    }
+
+    /// The access counter, decremented for every minute without access.
+    pub(crate) fn freq(&self, now: u64) -> u8 {
+        let freq = self.freq.load(Ordering::Relaxed);
+        match self.last_access.load(Ordering::Relaxed) {
+            0 => freq,
+            last => {
+                let periods = now.saturating_sub(last) / LFU_DECAY_MS;
+                freq.saturating_sub(periods.min(u8::MAX as u64) as u8)
+            }
+        }
+    }
+}
+
+/// The estimated bytes `value` takes: exact for strings, from a few
+/// elements for aggregates so it stays cheap however big they are.
+fn value_size(value: &Value) -> usize {
+    fn estimate(len: usize, sample: impl Iterator<Item = usize>) -> usize {
+        let (n, total) = sample
+            .take(SIZE_SAMPLES)
+            .fold((0, 0), |(n, total), size| (n + 1, total + size));
+        let avg = total.checked_div(n).unwrap_or(0);
+        STRING_OVERHEAD + len * (STRING_OVERHEAD + avg)
+    }
+    match value {
+        Value::String(s) => STRING_OVERHEAD + s.len(),
+        Value::List(l) => estimate(l.len(), l.iter().map(Bytes::len)),
+        Value::Hash(h) => estimate(h.len(), h.iter().map(|(f, v)| f.len() + v.len())),
+        Value::Set(s) => estimate(s.len(), s.iter().map(Bytes::len)),
+        // the score and a skiplist node
+        Value::ZSet(z) => estimate(z.len(), z.iter().map(|(m, _)| m.len() + 24)),
+        // the ID, consumer groups are not counted
+        Value::Stream(s) => estimate(
+            s.len(),
+            s.iter().map(|(_, fields)| {
+                16 + fields.iter().map(|(f, v)| f.len() + v.len()).sum::<usize>()
+            }),
+        ),
+    }
+}
+
+pub(crate) fn entry_size(key: &[u8], entry: &Entry) -> usize {
+    ENTRY_OVERHEAD + key.len() + value_size(&entry.value)
+}
+
+#[derive(Debug)]
+pub(crate) struct Memory {
+    /// 0 is no limit.
+    maxmemory: AtomicUsize,
+    policy: Mutex<EvictionPolicy>,
+    samples: AtomicUsize,
+    evicted: AtomicU64,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory {
+            maxmemory: AtomicUsize::new(0),
+            policy: Mutex::new(EvictionPolicy::default()),
+            samples: AtomicUsize::new(5),
+            evicted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Backend {
+    /// Account `entry`, about to be stored under `key` of `db` in place of an
+    /// entry of `old_size` bytes, and mark it used.
+    pub(crate) fn account(&self, db: &Db, key: &[u8], old_size: usize, entry: Option<&mut Entry>) {
+        let new_size = match entry {
+            Some(entry) => {
+                entry.meta.size = entry_size(key, entry);
+                entry.meta.touch(self.now_ms());
+                entry.meta.size
+            }
+            None => 0,
+        };
+        if new_size >= old_size {
+            db.used_memory
+                .fetch_add(new_size - old_size, Ordering::Relaxed);
+        } else {
+            db.used_memory
+                .fetch_sub(old_size - new_size, Ordering::Relaxed);
+        }
+    }
+
+    /// Forget the memory of a removed entry.
+    pub(crate) fn unaccount(&self, db: &Db, entry: &Entry) {
+        db.used_memory.fetch_sub(entry.meta.size, Ordering::Relaxed);
+    }
+
+    /// The estimated bytes the keyspace takes, every database included.
+    pub fn used_memory(&self) -> usize {
+        self.all_dbs().iter().map(|(_, db)| db.used_memory()).sum()
+    }
+
+    /// The estimated bytes `key` and its value take.
+    pub fn memory_usage(&self, key: &[u8]) -> Option<usize> {
+        self.read(key, |e| e.map(|e| e.meta.size))
+    }
+
+    /// Milliseconds since `key` was last used and its access counter, as
+    /// `OBJECT IDLETIME` and `OBJECT FREQ` see them. Looking does not count
+    /// as a use.
+    pub fn key_access(&self, key: &[u8]) -> Option<(u64, u8)> {
+        let now = self.now_ms();
+        self.peek(key, |e| e.map(|e| (e.meta.idle_ms(now), e.meta.freq(now))))
+    }
+
+    /// Limit the keyspace to `bytes`, 0 is no limit.
+    pub fn set_maxmemory(&self, bytes: usize) {
+        self.memory.maxmemory.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.memory.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maxmemory_policy(&self, policy: EvictionPolicy) {
+        *self.memory.policy.lock().unwrap() = policy;
+    }
+
+    pub fn maxmemory_policy(&self) -> EvictionPolicy {
+        *self.memory.policy.lock().unwrap()
+    }
+
+    /// How many keys are sampled to pick one to evict.
+    pub fn set_maxmemory_samples(&self, samples: usize) {
+        self.memory.samples.store(samples, Ordering::Relaxed);
+    }
+
+    pub fn maxmemory_samples(&self) -> usize {
+        self.memory.samples.load(Ordering::Relaxed)
+    }
+
+    /// Number of keys evicted since the server started.
+    pub fn evicted_keys(&self) -> u64 {
+        self.memory.evicted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn over_maxmemory(&self) -> bool {
+        let max = self.maxmemory();
+        max > 0 && self.used_memory() > max
+    }
+
+    /// Evict keys, as the policy picks them, until the keyspace fits in
+    /// `maxmemory` again. Returns whether it fits, which is never the case
+    /// over the limit with `noeviction` or nothing left to evict.
+    pub fn free_memory(&self) -> bool {
+        if !self.over_maxmemory() {
+            return true;
+        }
+        let policy = self.maxmemory_policy();
+        if policy == EvictionPolicy::NoEviction {
+            return false;
+        }
+        // keys must not vanish in the middle of a transaction
+        let _shared = self.exec_lock.read().unwrap();
+        while self.over_maxmemory() {
+            let Some((db, key)) = self.pick_victim(policy) else {
+                return false;
+            };
+            let backend = self.select(db);
+            let _ordered = self
+                .propagating()
+                .then(|| self.propagate_lock.lock().unwrap());
+            backend.remove(&key);
+            self.memory.evicted.fetch_add(1, Ordering::Relaxed);
+            backend.notify(event::EVICTED, "evicted", &key);
+            // replicas and the AOF see the eviction as a DEL
+            if self.propagating() {
+                backend.propagate(&[Bytes::from("DEL"), key]);
+            }
+        }
+        true
+    }
+
+    /// The best key to evict among a random sample of every database, with
+    /// its database. `None` when there are no keys the policy may evict.
+    fn pick_victim(&self, policy: EvictionPolicy) -> Option<(usize, Bytes)> {
+        let samples = self.maxmemory_samples().max(1);
+        let random =
+            policy == EvictionPolicy::AllKeysRandom || policy == EvictionPolicy::VolatileRandom;
+        let now = self.now_ms();
+        let mut candidates = Vec::new();
+        for (index, db) in self.all_dbs() {
+            let sample = if policy.volatile() {
+                db.expires.lock().unwrap().sample(samples)
+            } else {
+                db.keys.lock().unwrap().sample(samples)
+            };
+            for key in sample {
+                let Some(entry) = db.data.get(&key) else {
+                    continue;
+                };
+                // the lower the better
+                let score = match policy {
+                    _ if random => rand::thread_rng().gen(),
+                    EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                        entry.meta.freq(now) as u64
+                    }
+                    EvictionPolicy::VolatileTtl => entry.expire_at.unwrap_or(u64::MAX),
+                    _ => u64::MAX - entry.meta.idle_ms(now),
+                };
+                drop(entry);
+                candidates.push((score, index, key));
+            }
+        }
+        candidates
+            .into_iter()
+            .min_by_key(|(score, ..)| *score)
+            .map(|(_, index, key)| (index, key))
+    }
+
+    pub fn info_memory(&self) -> String {
+        let mut info = String::from("# Memory\r\n");
+        let _ = write!(
+            info,
+            "used_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n",
+            self.used_memory(),
+            self.maxmemory(),
+            self.maxmemory_policy().as_str()
+        );
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::ManualClock;
+
+    fn set(backend: &Backend, key: &str, value: &str) {
+        let key = Bytes::copy_from_slice(key.as_bytes());
+        let value = Value::String(Bytes::copy_from_slice(value.as_bytes()));
+        backend.write(&key, |slot| *slot = Some(Entry::new(value)));
+    }
+
+    #[test]
+    fn test_used_memory() {
+        let backend = Backend::new();
+        assert_eq!(backend.used_memory(), 0);
+        set(&backend, "k", "value");
+        let one = backend.used_memory();
+        assert_eq!(one, ENTRY_OVERHEAD + 1 + STRING_OVERHEAD + 5);
+        assert_eq!(backend.memory_usage(b"k"), Some(one));
+        set(&backend, "k", "a longer value");
+        assert_eq!(backend.used_memory(), one + 9);
+        let list = Bytes::from("list");
+        backend.write(&list, |slot| {
+            *slot = Some(Entry::new(Value::from(
+                (0..100)
+                    .map(|i| Bytes::from(format!("{:04}", i)))
+                    .collect::<std::collections::VecDeque<_>>(),
+            )))
+        });
+        assert!(backend.memory_usage(b"list").unwrap() > 100 * 4);
+        backend.remove(b"list");
+        backend.remove(b"k");
+        assert_eq!(backend.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_noeviction() {
+        let backend = Backend::new();
+        set(&backend, "a", "1");
+        backend.set_maxmemory(1);
+        assert!(!backend.free_memory());
+        assert!(backend.exists(b"a"));
+        backend.set_maxmemory(0);
+        assert!(backend.free_memory());
+    }
+
+    #[test]
+    fn test_allkeys_lru() {
+        let clock = ManualClock::new(1_000);
+        let backend = Backend::with_clock(Arc::new(clock.clone()));
+        backend.set_maxmemory_policy(EvictionPolicy::AllKeysLru);
+        backend.set_maxmemory_samples(100);
+        for i in 0..10 {
+            set(&backend, &format!("k{}", i), "v");
+            clock.advance(Duration::from_secs(1));
+        }
+        // k0 was used last, k1 is now the least recently used
+        assert!(backend.exists(b"k0"));
+        let per_key = backend.used_memory() / 10;
+        backend.set_maxmemory(per_key * 9);
+        assert!(backend.free_memory());
+        assert!(!backend.exists(b"k1"));
+        assert!(backend.exists(b"k0"));
+        assert_eq!(backend.len(), 9);
+        assert_eq!(backend.evicted_keys(), 1);
+        assert_eq!(backend.key_access(b"k2"), Some((8_000, LFU_INIT_VAL + 1)));
+    }
+
+    #[test]
+    fn test_allkeys_lfu() {
+        let backend = Backend::new();
+        backend.set_maxmemory_policy(EvictionPolicy::AllKeysLfu);
+        backend.set_maxmemory_samples(100);
+        for i in 0..10 {
+            set(&backend, &format!("k{}", i), "v");
+        }
+        for _ in 0..1000 {
+            for i in 1..10 {
+                backend.exists(format!("k{}", i).as_bytes());
+            }
+        }
+        backend.set_maxmemory(backend.used_memory() - 1);
+        assert!(backend.free_memory());
+        assert!(!backend.exists(b"k0"));
+        assert_eq!(backend.len(), 9);
+    }
+
+    #[test]
+    fn test_volatile_ttl() {
+        let backend = Backend::new();
+        backend.set_maxmemory_policy(EvictionPolicy::VolatileTtl);
+        let now = backend.now_ms();
+        for (key, ttl) in [("a", Some(10_000)), ("b", Some(5_000)), ("c", None)] {
+            let mut entry = Entry::new(Value::String("v".into()));
+            entry.expire_at = ttl.map(|ttl| now + ttl);
+            backend.write(&Bytes::from(key), |slot| *slot = Some(entry));
+        }
+        backend.set_maxmemory(backend.used_memory() - 1);
+        assert!(backend.free_memory());
+        assert_eq!(
+            ["a", "b", "c"].map(|k| backend.exists(k.as_bytes())),
+            [true, false, true]
+        );
+        // only keys with a ttl may go
+        backend.set_maxmemory(1);
+        assert!(!backend.free_memory());
+        assert!(backend.exists(b"c"));
+        assert_eq!(backend.len(), 1);
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for name in [
+            "noeviction",
+            "allkeys-lru",
+            "volatile-ttl",
+            "allkeys-random",
+        ] {
+            assert_eq!(name.parse::<EvictionPolicy>().unwrap().as_str(), name);
+        }
+        assert!("lru".parse::<EvictionPolicy>().is_err());
+    }
+}
@@ -0,0 +1,58 @@
+mod acl;
+mod aof;
+mod backend;
+mod blocking;
+mod clients;
+mod cluster;
+mod cmd;
+mod config;
+mod conn;
+mod crc;
+mod db;
+mod expire;
+mod glob;
+mod memory;
+mod multi;
+mod notify;
+mod pubsub;
+mod rdb;
+mod replication;
+mod resp;
+mod scan;
+mod scripting;
+mod server;
+mod shard;
+mod stream;
+mod tls;
+mod zset;
+
+pub use acl::*;
+pub use aof::*;
+pub use backend::*;
+pub(crate) use blocking::*;
+pub use clients::*;
+pub use cluster::*;
+pub use cmd::*;
+pub use config::*;
+pub use conn::*;
+pub use crc::*;
+pub use db::*;
+pub use expire::*;
+pub use glob::*;
+pub use memory::*;
+pub(crate) use multi::*;
+pub use notify::*;
+pub(crate) use pubsub::*;
+pub use rdb::*;
+pub(crate) use replication::*;
+pub use resp::*;
+pub(crate) use scan::*;
+pub use scripting::*;
+pub use server::*;
+pub use shard::*;
+pub use stream::*;
+pub use tls::*;
+pub use zset::*;
+
+/// The redis version dredis reports to clients.
+pub const REDIS_VERSION: &str = "7.2.0";
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+
+/// Commands queued by `MULTI`, run by `EXEC`.
+#[derive(Debug, Default)]
+pub(crate) struct Transaction {
+    pub(crate) queued: Vec<Vec<Bytes>>,
+    /// A command failed to queue, `EXEC` discards the transaction.
+    pub(crate) aborted: bool,
+    /// The hash slot of the keys queued so far, in cluster mode.
+    pub(crate) slot: Option<u16>,
+}
+
+/// The dirty flags of the clients watching each key of a database.
+type DbWatches = HashMap<Bytes, Vec<Arc<AtomicBool>>>;
+
+/// Keys watched by clients with `WATCH`, per database. Modifying a key flags
+/// every client watching it, their next `EXEC` fails.
+#[derive(Debug, Default)]
+pub(crate) struct Watches {
+    // lets writes skip the lock while nobody watches anything
+    watched: AtomicUsize,
+    keys: Mutex<HashMap<usize, DbWatches>>,
+}
+
+impl Watches {
+    pub(crate) fn is_watched(&self, db: usize, key: &[u8]) -> bool {
+        self.watched.load(Ordering::Acquire) > 0
+            && self
+                .keys
+                .lock()
+                .unwrap()
+                .get(&db)
+                .is_some_and(|keys| keys.contains_key(key))
+    }
+
+    /// Flag the clients watching `key` of `db`.
+    pub(crate) fn touch(&self, db: usize, key: &[u8]) {
+        if self.watched.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let keys = self.keys.lock().unwrap();
+        if let Some(clients) = keys.get(&db).and_then(|keys| keys.get(key)) {
+            for dirty in clients {
+                dirty.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Flag the clients watching a key of `db` that `changed`, when the
+    /// whole database is flushed or swapped.
+    pub(crate) fn touch_db(&self, db: usize, changed: impl Fn(&[u8]) -> bool) {
+        if self.watched.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let keys = self.keys.lock().unwrap();
+        for (key, clients) in keys.get(&db).into_iter().flatten() {
+            if changed(key) {
+                for dirty in clients {
+                    dirty.store(true, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    fn add(&self, db: usize, key: &Bytes, dirty: &Arc<AtomicBool>) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.entry(db)
+            .or_default()
+            .entry(key.clone())
+            .or_default()
+            .push(dirty.clone());
+        self.watched.fetch_add(1, Ordering::Release);
+    }
+
+    fn remove(&self, db: usize, key: &[u8], dirty: &Arc<AtomicBool>) {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(db_keys) = keys.get_mut(&db) else {
+            return;
+        };
+        if let Some(clients) = db_keys.get_mut(key) {
+            clients.retain(|d| !Arc::ptr_eq(d, dirty));
+            if clients.is_empty() {
+                db_keys.remove(key);
+            }
+            self.watched.fetch_sub(1, Ordering::Release);
+        }
+        if db_keys.is_empty() {
+            keys.remove(&db);
+        }
+    }
+}
+
+/// The keys one client watches, dropping it unwatches them.
+#[derive(Debug)]
+pub(crate) struct WatchedKeys {
+    watches: Arc<Watches>,
+    keys: Vec<(usize, Bytes)>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl WatchedKeys {
+    pub(crate) fn new(watches: Arc<Watches>) -> Self {
+        WatchedKeys {
+            watches,
+            keys: Vec::new(),
+            dirty: Arc::default(),
+        }
+    }
+
+    pub(crate) fn watch(&mut self, db: usize, key: &Bytes) {
+        if !self.keys.iter().any(|(d, k)| *d == db && k == key) {
+            self.watches.add(db, key, &self.dirty);
+            self.keys.push((db, key.clone()));
+        }
+    }
+
+    /// Whether one of the keys was modified since it was watched.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        for (db, key) in &self.keys {
+            self.watches.remove(*db, key, &self.dirty);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_touch_flags_watchers() {
+        let watches = Arc::new(Watches::default());
+        let mut a = WatchedKeys::new(watches.clone());
+        let mut b = WatchedKeys::new(watches.clone());
+        a.watch(0, &Bytes::from("x"));
+        a.watch(0, &Bytes::from("x"));
+        b.watch(0, &Bytes::from("y"));
+        b.watch(1, &Bytes::from("x"));
+        watches.touch(0, b"x");
+        assert!(a.is_dirty());
+        assert!(!b.is_dirty());
+
+        drop(a);
+        assert!(!watches.is_watched(0, b"x"));
+        assert!(watches.is_watched(1, b"x"));
+        drop(b);
+        assert_eq!(watches.watched.load(Ordering::Acquire), 0);
+    }
+}
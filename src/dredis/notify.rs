@@ -0,0 +1,245 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::Backend;
+
+/// The classes of keyspace events, a bit each like redis' `NOTIFY_*`.
+pub mod event {
+    /// `K`: publish on `__keyspace@<db>__:<key>` with the event as message.
+    pub const KEYSPACE: u32 = 1 << 0;
+    /// `E`: publish on `__keyevent@<db>__:<event>` with the key as message.
+    pub const KEYEVENT: u32 = 1 << 1;
+    pub const GENERIC: u32 = 1 << 2;
+    pub const STRING: u32 = 1 << 3;
+    pub const LIST: u32 = 1 << 4;
+    pub const SET: u32 = 1 << 5;
+    pub const HASH: u32 = 1 << 6;
+    pub const ZSET: u32 = 1 << 7;
+    pub const EXPIRED: u32 = 1 << 8;
+    pub const EVICTED: u32 = 1 << 9;
+    pub const STREAM: u32 = 1 << 10;
+    /// A key created, left out of `A` like in redis.
+    pub const NEW: u32 = 1 << 11;
+    /// `A`, every class but `n`.
+    pub const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+    /// The flag characters of `notify-keyspace-events`, in the order
+    /// `CONFIG GET` writes them.
+    pub(crate) const FLAGS: &[(char, u32)] = &[
+        ('g', GENERIC),
+        ('$', STRING),
+        ('l', LIST),
+        ('s', SET),
+        ('h', HASH),
+        ('z', ZSET),
+        ('x', EXPIRED),
+        ('e', EVICTED),
+        ('t', STREAM),
+        ('K', KEYSPACE),
+        ('E', KEYEVENT),
+        ('n', NEW),
+    ];
+}
+
+/// The classes a `notify-keyspace-events` value enables.
+pub fn parse_keyspace_events(s: &str) -> Result<u32> {
+    s.chars().try_fold(0, |flags, c| {
+        let class = match c {
+            'A' => event::ALL,
+            c => match event::FLAGS.iter().find(|(flag, _)| *flag == c) {
+                Some((_, class)) => *class,
+                None => bail!("Invalid event class character. Use 'Ag$lshzxetKEn'."),
+            },
+        };
+        Ok(flags | class)
+    })
+}
+
+/// The `notify-keyspace-events` value of `flags`, with `A` for all the
+/// classes it stands for.
+pub fn keyspace_events_string(flags: u32) -> String {
+    let mut s = String::new();
+    let mut rest = flags;
+    if flags & event::ALL == event::ALL {
+        s.push('A');
+        rest &= !event::ALL;
+    }
+    for (c, class) in event::FLAGS {
+        if rest & class != 0 {
+            s.push(*c);
+        }
+    }
+    s
+}
+
+impl Backend {
+    pub fn keyspace_events(&self) -> u32 {
+        self.pubsub.keyspace_events()
+    }
+
+    pub fn set_keyspace_events(&self, flags: u32) {
+        self.pubsub.set_keyspace_events(flags)
+    }
+
+    /// Publish the event `name` of `class` on `key` of the current
+    /// database, as far as `notify-keyspace-events` asks for it.
+    pub(crate) fn notify(&self, class: u32, name: &str, key: &[u8]) {
+        let flags = self.keyspace_events();
+        if flags & class == 0 {
+            return;
+        }
+        if flags & event::KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", self.db).into_bytes();
+            channel.extend_from_slice(key);
+            self.pubsub
+                .publish(&channel.into(), &Bytes::copy_from_slice(name.as_bytes()));
+        }
+        if flags & event::KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", self.db, name);
+            self.pubsub
+                .publish(&channel.into(), &Bytes::copy_from_slice(key));
+        }
+    }
+
+    /// Publish the event `name` and, when the command took the last element
+    /// of the aggregate at `key`, the `del` of the key that went with it.
+    pub(crate) fn notify_removing(&self, class: u32, name: &str, key: &[u8]) {
+        self.notify(class, name, key);
+        let generic = self.keyspace_events() & event::GENERIC != 0;
+        if generic && !self.peek(key, |e| e.is_some()) {
+            self.notify(event::GENERIC, "del", key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::dredis::cmd::run;
+    use crate::{ManualClock, RespFrame, Session};
+
+    /// The `(channel, message)` pairs `subscriber` got, up to the `end`
+    /// published after them.
+    async fn received(publisher: &mut Session, subscriber: &mut Session) -> Vec<(String, String)> {
+        run(publisher, &["PUBLISH", "__key_end", "end"]);
+        let subs = subscriber.subscriptions.as_mut().unwrap();
+        let mut messages = Vec::new();
+        loop {
+            let Some(RespFrame::Push(frame)) = subs.next_message().await else {
+                panic!("expected a pmessage");
+            };
+            let [_, _, RespFrame::BulkString(channel), RespFrame::BulkString(message)] = &frame[..]
+            else {
+                panic!("unexpected message {:?}", frame);
+            };
+            if channel.as_ref() == b"__key_end" {
+                return messages;
+            }
+            messages.push((
+                String::from_utf8_lossy(channel).into_owned(),
+                String::from_utf8_lossy(message).into_owned(),
+            ));
+        }
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(c, m)| (c.to_string(), m.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_notifications() {
+        let clock = ManualClock::new(1_000_000);
+        let mut s = Session::new(Backend::with_clock(Arc::new(clock.clone())));
+        let mut sub = Session::new(s.backend.clone());
+        run(&mut sub, &["PSUBSCRIBE", "__key*"]);
+
+        // nothing is published until asked for
+        run(&mut s, &["SET", "k", "v"]);
+        assert_eq!(received(&mut s, &mut sub).await, pairs(&[]));
+
+        run(&mut s, &["CONFIG", "SET", "notify-keyspace-events", "KEA"]);
+        assert_eq!(keyspace_events_string(s.backend.keyspace_events()), "AKE");
+        run(&mut s, &["SET", "k", "v"]);
+        run(&mut s, &["DEL", "k", "missing"]);
+        assert_eq!(
+            received(&mut s, &mut sub).await,
+            pairs(&[
+                ("__keyspace@0__:k", "set"),
+                ("__keyevent@0__:set", "k"),
+                ("__keyspace@0__:k", "del"),
+                ("__keyevent@0__:del", "k"),
+            ])
+        );
+
+        // only key-space events for lists, taking the last element deletes
+        run(&mut s, &["CONFIG", "SET", "notify-keyspace-events", "Klg"]);
+        run(&mut s, &["RPUSH", "l", "a", "b"]);
+        run(&mut s, &["LPOP", "l", "2"]);
+        run(&mut s, &["SADD", "s", "m"]);
+        assert_eq!(
+            received(&mut s, &mut sub).await,
+            pairs(&[
+                ("__keyspace@0__:l", "rpush"),
+                ("__keyspace@0__:l", "lpop"),
+                ("__keyspace@0__:l", "del"),
+            ])
+        );
+
+        run(&mut s, &["CONFIG", "SET", "notify-keyspace-events", "Egxn"]);
+        run(&mut s, &["SET", "t", "v", "EX", "1"]);
+        run(&mut s, &["EXPIRE", "t", "10"]);
+        clock.advance(Duration::from_secs(10));
+        run(&mut s, &["GET", "t"]);
+        run(&mut s, &["SELECT", "1"]);
+        run(&mut s, &["SET", "other", "v"]);
+        assert_eq!(
+            received(&mut s, &mut sub).await,
+            pairs(&[
+                ("__keyevent@0__:new", "t"),
+                ("__keyevent@0__:expire", "t"),
+                ("__keyevent@0__:expire", "t"),
+                ("__keyevent@0__:expired", "t"),
+                ("__keyevent@1__:new", "other"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_invalid_keyspace_events() {
+        let mut s = Session::default();
+        assert!(matches!(
+            run(&mut s, &["CONFIG", "SET", "notify-keyspace-events", "Kq"]),
+            RespFrame::Error(_)
+        ));
+        assert_eq!(s.backend.keyspace_events(), 0);
+    }
+
+    #[test]
+    fn test_parse_keyspace_events() {
+        assert_eq!(parse_keyspace_events("").unwrap(), 0);
+        assert_eq!(
+            parse_keyspace_events("Kx").unwrap(),
+            event::KEYSPACE | event::EXPIRED
+        );
+        assert_eq!(
+            parse_keyspace_events("AKE").unwrap(),
+            event::ALL | event::KEYSPACE | event::KEYEVENT
+        );
+        assert!(parse_keyspace_events("Kq").is_err());
+
+        assert_eq!(keyspace_events_string(0), "");
+        assert_eq!(
+            keyspace_events_string(parse_keyspace_events("EKlg$shzxet").unwrap()),
+            "AKE"
+        );
+        assert_eq!(
+            keyspace_events_string(parse_keyspace_events("nEl").unwrap()),
+            "lEn"
+        );
+    }
+}
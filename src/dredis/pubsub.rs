@@ -0,0 +1,476 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::{poll_fn, Future},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+};
+
+use bytes::Bytes;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+use tracing::warn;
+
+use crate::{glob_match, OutputLimit, OutputLimitCheck, RespFrame};
+
+/// Redis' default limit for pubsub clients, `pubsub 32mb 8mb 60`.
+const DEFAULT_OUTPUT_LIMIT: OutputLimit = OutputLimit::new(32 * 1024 * 1024, 8 * 1024 * 1024, 60);
+
+/// The messages a topic keeps for its slowest subscriber. One that lags
+/// further behind is over its output buffer limit.
+const TOPIC_CAPACITY: usize = 1024;
+
+/// Channels and patterns, each fanning its messages out over a broadcast channel.
+#[derive(Debug)]
+pub(crate) struct PubSub {
+    topics: Mutex<Topics>,
+    output_limit: Mutex<OutputLimit>,
+    /// The `notify-keyspace-events` classes.
+    keyspace_events: AtomicU32,
+}
+
+#[derive(Debug, Default)]
+struct Topics {
+    channels: HashMap<Bytes, Topic>,
+    patterns: HashMap<Bytes, Topic>,
+    /// The number of the next message sent.
+    seq: u64,
+}
+
+/// A channel or pattern with at least one subscriber.
+#[derive(Debug)]
+struct Topic {
+    tx: broadcast::Sender<Message>,
+    /// The bytes of all messages sent so far.
+    sent: Arc<AtomicUsize>,
+}
+
+/// A message numbered in the order messages were sent, over all topics.
+#[derive(Debug, Clone)]
+struct Message {
+    seq: u64,
+    frame: RespFrame,
+    size: usize,
+}
+
+impl Topic {
+    fn new() -> Self {
+        Topic {
+            tx: broadcast::channel(TOPIC_CAPACITY).0,
+            sent: Arc::default(),
+        }
+    }
+
+    /// Returns how many clients received `frame`.
+    fn send(&self, seq: &mut u64, frame: RespFrame) -> usize {
+        let size = frame.to_bytes().len();
+        self.sent.fetch_add(size, Ordering::Relaxed);
+        *seq += 1;
+        let message = Message {
+            seq: *seq,
+            frame,
+            size,
+        };
+        self.tx.send(message).unwrap_or(0)
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub {
+            topics: Mutex::default(),
+            output_limit: Mutex::new(DEFAULT_OUTPUT_LIMIT),
+            keyspace_events: AtomicU32::new(0),
+        }
+    }
+}
+
+impl PubSub {
+    pub(crate) fn set_output_limit(&self, limit: OutputLimit) {
+        *self.output_limit.lock().unwrap() = limit;
+    }
+
+    pub(crate) fn output_limit(&self) -> OutputLimit {
+        *self.output_limit.lock().unwrap()
+    }
+
+    pub(crate) fn keyspace_events(&self) -> u32 {
+        self.keyspace_events.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_keyspace_events(&self, flags: u32) {
+        self.keyspace_events.store(flags, Ordering::Relaxed);
+    }
+
+    /// Send `message` to subscribers of `channel` and of every matching
+    /// pattern, returns how many clients received it.
+    pub(crate) fn publish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+        let Topics {
+            channels,
+            patterns,
+            seq,
+        } = &mut *topics;
+        let mut receivers = 0;
+        if let Some(topic) = channels.get(channel) {
+            let frame = RespFrame::Push(vec![
+                RespFrame::bulk("message"),
+                RespFrame::bulk(channel.clone()),
+                RespFrame::bulk(message.clone()),
+            ]);
+            receivers += topic.send(seq, frame);
+        }
+        for (pattern, topic) in patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let frame = RespFrame::Push(vec![
+                RespFrame::bulk("pmessage"),
+                RespFrame::bulk(pattern.clone()),
+                RespFrame::bulk(channel.clone()),
+                RespFrame::bulk(message.clone()),
+            ]);
+            receivers += topic.send(seq, frame);
+        }
+        receivers
+    }
+
+    /// Active channels, those with at least one subscriber, matching `pattern`.
+    pub(crate) fn channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let topics = self.topics.lock().unwrap();
+        topics
+            .channels
+            .keys()
+            .filter(|c| pattern.is_none_or(|p| glob_match(p, c)))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn numsub(&self, channel: &[u8]) -> usize {
+        let topics = self.topics.lock().unwrap();
+        topics
+            .channels
+            .get(channel)
+            .map_or(0, |t| t.tx.receiver_count())
+    }
+
+    pub(crate) fn numpat(&self) -> usize {
+        let topics = self.topics.lock().unwrap();
+        topics.patterns.len()
+    }
+
+    fn add(&self, kind: Kind, topic: &Bytes) -> Feed {
+        let mut topics = self.topics.lock().unwrap();
+        let entry = topics
+            .get_mut(kind)
+            .entry(topic.clone())
+            .or_insert_with(Topic::new);
+        Feed {
+            kind,
+            topic: topic.clone(),
+            rx: entry.tx.subscribe(),
+            sent: entry.sent.clone(),
+            seen: entry.sent.load(Ordering::Relaxed),
+            head: None,
+        }
+    }
+
+    /// Drop `feed`, and its topic when that was the last subscriber.
+    fn remove(&self, feed: Feed) {
+        let Feed {
+            kind, topic, rx, ..
+        } = feed;
+        drop(rx);
+        let mut topics = self.topics.lock().unwrap();
+        let map = topics.get_mut(kind);
+        if map.get(&topic).is_some_and(|t| t.tx.receiver_count() == 0) {
+            map.remove(&topic);
+        }
+    }
+}
+
+impl Topics {
+    fn get_mut(&mut self, kind: Kind) -> &mut HashMap<Bytes, Topic> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Channel,
+    Pattern,
+}
+
+/// What a client receives from one topic.
+#[derive(Debug)]
+struct Feed {
+    kind: Kind,
+    topic: Bytes,
+    rx: broadcast::Receiver<Message>,
+    sent: Arc<AtomicUsize>,
+    /// The bytes the topic had sent when the client took a message last.
+    seen: usize,
+    /// A message received but not taken yet, an older one may wait elsewhere.
+    head: Option<Message>,
+}
+
+impl Feed {
+    fn pending(&self) -> usize {
+        self.sent.load(Ordering::Relaxed) - self.seen
+    }
+}
+
+/// The subscriptions of one client, the client is in subscribed mode while
+/// it has any. Dropping them unsubscribes from everything.
+#[derive(Debug)]
+pub(crate) struct Subscriptions {
+    pubsub: Arc<PubSub>,
+    id: u64,
+    feeds: Vec<Feed>,
+    check: OutputLimitCheck,
+    /// Set once the client overran its output buffer.
+    closed: bool,
+    pub(crate) channels: BTreeSet<Bytes>,
+    pub(crate) patterns: BTreeSet<Bytes>,
+}
+
+impl Subscriptions {
+    pub(crate) fn new(pubsub: Arc<PubSub>, id: u64) -> Self {
+        Subscriptions {
+            pubsub,
+            id,
+            feeds: Vec::new(),
+            check: OutputLimitCheck::default(),
+            closed: false,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn set(&mut self, kind: Kind) -> &mut BTreeSet<Bytes> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+
+    /// Returns false when already subscribed to `topic`.
+    pub(crate) fn subscribe(&mut self, kind: Kind, topic: &Bytes) -> bool {
+        if !self.set(kind).insert(topic.clone()) {
+            return false;
+        }
+        let feed = self.pubsub.add(kind, topic);
+        self.feeds.push(feed);
+        true
+    }
+
+    pub(crate) fn unsubscribe(&mut self, kind: Kind, topic: &[u8]) -> bool {
+        if !self.set(kind).remove(topic) {
+            return false;
+        }
+        if let Some(i) = self
+            .feeds
+            .iter()
+            .position(|f| f.kind == kind && f.topic == topic)
+        {
+            self.pubsub.remove(self.feeds.swap_remove(i));
+        }
+        true
+    }
+
+    /// The next published message, `None` once the client was disconnected
+    /// for exceeding its output buffer limit.
+    pub(crate) async fn next_message(&mut self) -> Option<RespFrame> {
+        loop {
+            if self.closed {
+                return None;
+            }
+            // what already arrived everywhere, so the oldest message goes first
+            for feed in self.feeds.iter_mut().filter(|f| f.head.is_none()) {
+                match feed.rx.try_recv() {
+                    Ok(message) => feed.head = Some(message),
+                    Err(TryRecvError::Lagged(_)) => return self.overrun(),
+                    Err(_) => {}
+                }
+            }
+            let oldest = self
+                .feeds
+                .iter()
+                .enumerate()
+                .filter_map(|(i, f)| Some((f.head.as_ref()?.seq, i)))
+                .min();
+            if let Some((_, i)) = oldest {
+                let pending = self.feeds.iter().map(Feed::pending).sum();
+                if self.check.exceeded(&self.pubsub.output_limit(), pending) {
+                    return self.overrun();
+                }
+                let feed = &mut self.feeds[i];
+                let message = feed.head.take()?;
+                feed.seen += message.size;
+                return Some(message.frame);
+            }
+            let (i, received) = {
+                let mut recvs = self
+                    .feeds
+                    .iter_mut()
+                    .map(|f| Box::pin(f.rx.recv()))
+                    .collect::<Vec<_>>();
+                poll_fn(|cx| {
+                    recvs
+                        .iter_mut()
+                        .enumerate()
+                        .find_map(|(i, recv)| match recv.as_mut().poll(cx) {
+                            Poll::Ready(res) => Some((i, res)),
+                            Poll::Pending => None,
+                        })
+                        .map_or(Poll::Pending, Poll::Ready)
+                })
+                .await
+            };
+            match received {
+                Ok(message) => self.feeds[i].head = Some(message),
+                Err(RecvError::Lagged(_)) => return self.overrun(),
+                Err(RecvError::Closed) => unreachable!("a topic lives while it has subscribers"),
+            }
+        }
+    }
+
+    fn overrun(&mut self) -> Option<RespFrame> {
+        let pending = self.feeds.iter().map(Feed::pending).sum::<usize>();
+        warn!(
+            "client {} closed for overcoming of output buffer limits ({} bytes)",
+            self.id, pending
+        );
+        self.closed = true;
+        None
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for feed in self.feeds.drain(..) {
+            self.pubsub.remove(feed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_fans_out() {
+        let pubsub = Arc::new(PubSub::default());
+        let mut a = Subscriptions::new(pubsub.clone(), 1);
+        let mut b = Subscriptions::new(pubsub.clone(), 2);
+        a.subscribe(Kind::Channel, &Bytes::from("news"));
+        b.subscribe(Kind::Pattern, &Bytes::from("n*"));
+        assert_eq!(pubsub.publish(&"news".into(), &"hi".into()), 2);
+        assert_eq!(pubsub.publish(&"other".into(), &"hi".into()), 0);
+        assert_eq!(
+            a.next_message().await,
+            Some(RespFrame::Push(vec![
+                RespFrame::bulk("message"),
+                RespFrame::bulk("news"),
+                RespFrame::bulk("hi"),
+            ]))
+        );
+        assert_eq!(
+            b.next_message().await,
+            Some(RespFrame::Push(vec![
+                RespFrame::bulk("pmessage"),
+                RespFrame::bulk("n*"),
+                RespFrame::bulk("news"),
+                RespFrame::bulk("hi"),
+            ]))
+        );
+
+        drop(a);
+        assert_eq!(pubsub.numsub(b"news"), 0);
+        assert_eq!(pubsub.publish(&"news".into(), &"hi".into()), 1);
+        b.next_message().await;
+
+        // a waiting subscriber gets what is published meanwhile
+        let publisher = pubsub.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            publisher.publish(&"now".into(), &"hi".into());
+        });
+        let message = tokio::time::timeout(std::time::Duration::from_secs(1), b.next_message());
+        assert!(message.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_disconnected() {
+        let pubsub = Arc::new(PubSub::default());
+        pubsub.set_output_limit(OutputLimit::new(100, 0, 0));
+        let mut slow = Subscriptions::new(pubsub.clone(), 1);
+        slow.subscribe(Kind::Channel, &Bytes::from("c"));
+        let message = Bytes::from(vec![b'x'; 40]);
+        for _ in 0..3 {
+            assert_eq!(pubsub.publish(&"c".into(), &message), 1);
+        }
+        assert_eq!(slow.next_message().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_is_disconnected() {
+        let pubsub = Arc::new(PubSub::default());
+        pubsub.set_output_limit(OutputLimit::new(0, 0, 0));
+        let mut slow = Subscriptions::new(pubsub.clone(), 1);
+        slow.subscribe(Kind::Channel, &Bytes::from("c"));
+        for _ in 0..=TOPIC_CAPACITY {
+            pubsub.publish(&"c".into(), &"m".into());
+        }
+        assert_eq!(slow.next_message().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_messages_arrive_in_publish_order() {
+        let pubsub = Arc::new(PubSub::default());
+        let mut subs = Subscriptions::new(pubsub.clone(), 1);
+        for channel in ["b", "a"] {
+            subs.subscribe(Kind::Channel, &Bytes::from(channel));
+        }
+        subs.subscribe(Kind::Pattern, &Bytes::from("*"));
+        for channel in ["a", "b"] {
+            pubsub.publish(&channel.into(), &"m".into());
+        }
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let Some(RespFrame::Push(items)) = subs.next_message().await else {
+                panic!("expected a message");
+            };
+            received.push((items[0].clone(), items[items.len() - 2].clone()));
+        }
+        let expected = [
+            ("message", "a"),
+            ("pmessage", "a"),
+            ("message", "b"),
+            ("pmessage", "b"),
+        ];
+        assert_eq!(
+            received,
+            expected.map(|(kind, channel)| (RespFrame::bulk(kind), RespFrame::bulk(channel)))
+        );
+
+        subs.unsubscribe(Kind::Channel, b"a");
+        assert_eq!(pubsub.numsub(b"a"), 0);
+        assert_eq!(pubsub.channels(None), vec![Bytes::from("b")]);
+    }
+}
@@ -0,0 +1,1210 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::{crc64, Backend, Entry, Stream, StreamFields, StreamId, Value, ZSet, REDIS_VERSION};
+
+const RDB_VERSION: u32 = 11;
+
+const OP_FUNCTION2: u8 = 245;
+const OP_MODULE_AUX: u8 = 247;
+const OP_IDLE: u8 = 248;
+const OP_FREQ: u8 = 249;
+const OP_AUX: u8 = 250;
+const OP_RESIZEDB: u8 = 251;
+const OP_EXPIRETIME_MS: u8 = 252;
+const OP_EXPIRETIME: u8 = 253;
+const OP_SELECTDB: u8 = 254;
+const OP_EOF: u8 = 255;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// special string encodings, after a length byte with the top bits 11
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+// flags of an entry in a stream listpack node
+const STREAM_ITEM_DELETED: i64 = 1;
+const STREAM_ITEM_SAMEFIELDS: i64 = 2;
+/// Entries per listpack node of a saved stream, redis' default
+/// `stream-node-max-entries`.
+const STREAM_NODE_ENTRIES: usize = 100;
+
+/// A point-in-time copy of the keyspace, the entries of every non-empty
+/// database with its index. Cheap to take since the values share their
+/// aggregates with the live keyspace until those are written.
+pub type Snapshot = Vec<(usize, Vec<(Bytes, Entry)>)>;
+
+/// Number of keys in `snapshot`.
+pub fn snapshot_len(snapshot: &Snapshot) -> usize {
+    snapshot.iter().map(|(_, entries)| entries.len()).sum()
+}
+
+/// Serialize `snapshot` as an RDB file. Values use the plain encodings every
+/// redis version since 2.x loads, except streams which only ever had the
+/// listpack one, saved as redis 7.2 does.
+pub fn encode_rdb(snapshot: &Snapshot, now_ms: u64) -> Vec<u8> {
+    let mut buf = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+    for (key, value) in [
+        ("redis-ver", REDIS_VERSION.to_string()),
+        ("redis-bits", "64".to_string()),
+        ("ctime", (now_ms / 1000).to_string()),
+        ("aof-base", "0".to_string()),
+    ] {
+        buf.push(OP_AUX);
+        put_string(&mut buf, key.as_bytes());
+        put_string(&mut buf, value.as_bytes());
+    }
+    for (db, entries) in snapshot {
+        buf.push(OP_SELECTDB);
+        put_len(&mut buf, *db as u64);
+        buf.push(OP_RESIZEDB);
+        put_len(&mut buf, entries.len() as u64);
+        let volatile = entries.iter().filter(|(_, e)| e.expire_at.is_some());
+        put_len(&mut buf, volatile.count() as u64);
+        for (key, entry) in entries {
+            if let Some(at) = entry.expire_at {
+                buf.push(OP_EXPIRETIME_MS);
+                buf.extend_from_slice(&at.to_le_bytes());
+            }
+            put_value(&mut buf, key, &entry.value);
+        }
+    }
+    buf.push(OP_EOF);
+    let checksum = crc64(0, &buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn put_value(buf: &mut Vec<u8>, key: &[u8], value: &Value) {
+    match value {
+        Value::String(s) => {
+            buf.push(TYPE_STRING);
+            put_string(buf, key);
+            put_string(buf, s);
+        }
+        Value::List(list) => {
+            buf.push(TYPE_LIST);
+            put_string(buf, key);
+            put_len(buf, list.len() as u64);
+            for item in list.iter() {
+                put_string(buf, item);
+            }
+        }
+        Value::Set(set) => {
+            buf.push(TYPE_SET);
+            put_string(buf, key);
+            put_len(buf, set.len() as u64);
+            for member in set.iter() {
+                put_string(buf, member);
+            }
+        }
+        Value::Hash(hash) => {
+            buf.push(TYPE_HASH);
+            put_string(buf, key);
+            put_len(buf, hash.len() as u64);
+            for (field, value) in hash.iter() {
+                put_string(buf, field);
+                put_string(buf, value);
+            }
+        }
+        Value::ZSet(zset) => {
+            buf.push(TYPE_ZSET_2);
+            put_string(buf, key);
+            put_len(buf, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                put_string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Stream(stream) => {
+            buf.push(TYPE_STREAM_LISTPACKS_3);
+            put_string(buf, key);
+            put_stream(buf, stream);
+        }
+    }
+}
+
+/// The entries in listpack nodes keyed by the ID of their first entry, then
+/// the stream IDs and counters, then the consumer groups with their pending
+/// entries. Unknown counters and times are saved as -1.
+fn put_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    let entries = stream.iter().collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_ENTRIES);
+    put_len(buf, nodes.len() as u64);
+    for node in nodes {
+        let (&master, _) = node[0];
+        put_string(buf, &raw_stream_id(master));
+        put_string(buf, &stream_node(master, node));
+    }
+    put_len(buf, stream.len() as u64);
+    put_stream_id(buf, stream.last_id());
+    put_stream_id(buf, stream.first().map_or(StreamId::MIN, |(&id, _)| id));
+    put_stream_id(buf, stream.max_deleted_id());
+    put_len(buf, stream.entries_added());
+    put_len(buf, stream.groups().count() as u64);
+    for (name, group) in stream.groups() {
+        put_string(buf, name);
+        put_stream_id(buf, group.last_delivered);
+        put_len(buf, group.entries_read.unwrap_or(u64::MAX));
+        put_len(buf, group.pending().len() as u64);
+        for (&id, pending) in group.pending() {
+            buf.extend_from_slice(&raw_stream_id(id));
+            buf.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            put_len(buf, pending.delivery_count);
+        }
+        put_len(buf, group.consumers().count() as u64);
+        for (name, consumer) in group.consumers() {
+            put_string(buf, name);
+            buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            let active_time = consumer.active_time.unwrap_or(u64::MAX);
+            buf.extend_from_slice(&active_time.to_le_bytes());
+            put_len(buf, consumer.pending().len() as u64);
+            for &id in consumer.pending() {
+                buf.extend_from_slice(&raw_stream_id(id));
+            }
+        }
+    }
+}
+
+fn put_stream_id(buf: &mut Vec<u8>, id: StreamId) {
+    put_len(buf, id.ms);
+    put_len(buf, id.seq);
+}
+
+fn raw_stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// A listpack node of a stream: a master entry with the field names of the
+/// first entry, then the entries with their ID relative to the first one and
+/// only their values when they have the master fields.
+fn stream_node(master: StreamId, node: &[(&StreamId, &StreamFields)]) -> Vec<u8> {
+    let int = |n: i64| Bytes::from(n.to_string());
+    let (_, master_fields) = node[0];
+    let mut items = vec![
+        int(node.len() as i64),
+        int(0),
+        int(master_fields.len() as i64),
+    ];
+    items.extend(master_fields.iter().map(|(f, _)| f.clone()));
+    items.push(int(0));
+    for (id, fields) in node {
+        let same = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields.iter())
+                .all(|(a, b)| a.0 == b.0);
+        items.push(int(if same { STREAM_ITEM_SAMEFIELDS } else { 0 }));
+        items.push(int(id.ms.wrapping_sub(master.ms) as i64));
+        items.push(int(id.seq.wrapping_sub(master.seq) as i64));
+        if same {
+            items.extend(fields.iter().map(|(_, v)| v.clone()));
+        } else {
+            items.push(int(fields.len() as i64));
+            items.extend(fields.iter().flat_map(|(f, v)| [f.clone(), v.clone()]));
+        }
+        // the number of items of the entry, to walk the node backwards
+        let count = if same {
+            fields.len() + 3
+        } else {
+            2 * fields.len() + 4
+        };
+        items.push(int(count as i64));
+    }
+    put_listpack(&items)
+}
+
+/// Encode `items` as a listpack, the ones holding a canonical integer as an
+/// integer like redis does.
+fn put_listpack(items: &[Bytes]) -> Vec<u8> {
+    let mut lp = vec![0; 6];
+    for item in items {
+        let start = lp.len();
+        let int = std::str::from_utf8(item)
+            .ok()
+            .filter(|s| s.len() <= 20)
+            .and_then(|s| s.parse::<i64>().ok().filter(|n| n.to_string() == s));
+        match int {
+            Some(n @ 0..=127) => lp.push(n as u8),
+            Some(n @ -4096..=4095) => {
+                lp.extend_from_slice(&[0xc0 | ((n >> 8) as u8 & 0x1f), n as u8])
+            }
+            Some(n) if i16::try_from(n).is_ok() => {
+                lp.push(0xf1);
+                lp.extend_from_slice(&(n as i16).to_le_bytes());
+            }
+            Some(n) if (-(1 << 23)..1 << 23).contains(&n) => {
+                lp.push(0xf2);
+                lp.extend_from_slice(&n.to_le_bytes()[..3]);
+            }
+            Some(n) if i32::try_from(n).is_ok() => {
+                lp.push(0xf3);
+                lp.extend_from_slice(&(n as i32).to_le_bytes());
+            }
+            Some(n) => {
+                lp.push(0xf4);
+                lp.extend_from_slice(&n.to_le_bytes());
+            }
+            None if item.len() < 64 => lp.push(0x80 | item.len() as u8),
+            None if item.len() < 4096 => {
+                lp.extend_from_slice(&[0xe0 | (item.len() >> 8) as u8, item.len() as u8])
+            }
+            None => {
+                lp.push(0xf0);
+                lp.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            }
+        }
+        if int.is_none() {
+            lp.extend_from_slice(item);
+        }
+        // the size of the element, 7 bits per byte and the top ones first
+        let size = lp.len() - start;
+        let n = lp_backlen_len(size);
+        for i in (0..n).rev() {
+            let byte = (size >> (7 * i)) as u8 & 0x7f;
+            lp.push(if i == n - 1 { byte } else { byte | 0x80 });
+        }
+    }
+    lp.push(0xff);
+    let total = lp.len() as u32;
+    lp[..4].copy_from_slice(&total.to_le_bytes());
+    let count = items.len().min(u16::MAX as usize) as u16;
+    lp[4..6].copy_from_slice(&count.to_le_bytes());
+    lp
+}
+
+/// Bytes the back length of a listpack element of `size` bytes takes.
+fn lp_backlen_len(size: usize) -> usize {
+    match size {
+        0..128 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    }
+}
+
+fn put_len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Strings holding a small integer are stored as one, like redis does.
+fn put_string(buf: &mut Vec<u8>, s: &[u8]) {
+    let int = std::str::from_utf8(s)
+        .ok()
+        .filter(|s| s.len() <= 11)
+        .and_then(|s| s.parse::<i32>().ok().filter(|n| n.to_string() == s));
+    match int {
+        Some(n) if i8::try_from(n).is_ok() => {
+            buf.extend_from_slice(&[0xc0 | ENC_INT8, n as i8 as u8]);
+        }
+        Some(n) if i16::try_from(n).is_ok() => {
+            buf.push(0xc0 | ENC_INT16);
+            buf.extend_from_slice(&(n as i16).to_le_bytes());
+        }
+        Some(n) => {
+            buf.push(0xc0 | ENC_INT32);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        None => {
+            put_len(buf, s.len() as u64);
+            buf.extend_from_slice(s);
+        }
+    }
+}
+
+/// Parse an RDB file into the keys of database 0, including expired ones.
+/// Besides what `encode_rdb` writes this reads the compact ziplist, listpack
+/// and intset encodings real redis servers save small aggregates with.
+pub fn decode_rdb(data: &[u8]) -> Result<Snapshot> {
+    let mut r = Reader { data, pos: 0 };
+    let magic = r.take(9)?;
+    if &magic[..5] != b"REDIS" {
+        bail!("not an RDB file");
+    }
+    let version = std::str::from_utf8(&magic[5..])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| anyhow!("invalid RDB version"))?;
+    if version > 12 {
+        bail!("can't handle RDB format version {}", version);
+    }
+
+    let mut dbs: Snapshot = Vec::new();
+    let mut db = 0;
+    let mut expire_at = None;
+    loop {
+        let op = r.u8()?;
+        match op {
+            OP_EOF => {
+                if version >= 5 {
+                    let end = r.pos;
+                    let expected = u64::from_le_bytes(r.take(8)?.try_into()?);
+                    // a zero checksum means the writer had checksums disabled
+                    if expected != 0 && expected != crc64(0, &data[..end]) {
+                        bail!("wrong RDB checksum");
+                    }
+                }
+                return Ok(dbs);
+            }
+            OP_SELECTDB => db = r.len()?,
+            OP_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_EXPIRETIME_MS => expire_at = Some(u64::from_le_bytes(r.take(8)?.try_into()?)),
+            OP_EXPIRETIME => {
+                let secs = u32::from_le_bytes(r.take(4)?.try_into()?);
+                expire_at = Some(secs as u64 * 1000);
+            }
+            OP_IDLE => {
+                r.len()?;
+            }
+            OP_FREQ => {
+                r.u8()?;
+            }
+            OP_FUNCTION2 => {
+                warn!("skipping a function library, functions are not supported");
+                r.string()?;
+            }
+            OP_MODULE_AUX => bail!("RDB files with module data are not supported"),
+            ty => {
+                let key = r.string()?;
+                let value = r.value(ty)?;
+                let entry = (key, Entry::with_expire_at(value, expire_at.take()));
+                match dbs.last_mut() {
+                    Some((last, entries)) if *last == db => entries.push(entry),
+                    _ => dbs.push((db, vec![entry])),
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| anyhow!("unexpected end of RDB file"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A length, or `Err(encoding)` for a specially encoded string.
+    fn len_or_encoding(&mut self) -> Result<Result<u64, u8>> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Ok((first & 0x3f) as u64),
+            1 => Ok((((first & 0x3f) as u64) << 8) | self.u8()? as u64),
+            2 if first == 0x80 => Ok(u32::from_be_bytes(self.take(4)?.try_into()?) as u64),
+            2 if first == 0x81 => Ok(u64::from_be_bytes(self.take(8)?.try_into()?)),
+            2 => bail!("unknown length encoding {:#x}", first),
+            _ => Err(first & 0x3f),
+        })
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        usize::try_from(self.len64()?).context("length out of range")
+    }
+
+    /// A length field holding a number, like stream IDs and counters.
+    fn len64(&mut self) -> Result<u64> {
+        match self.len_or_encoding()? {
+            Ok(len) => Ok(len),
+            Err(_) => bail!("expected a length"),
+        }
+    }
+
+    fn ms_time(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId> {
+        Ok(StreamId::new(self.len64()?, self.len64()?))
+    }
+
+    fn raw_stream_id(&mut self) -> Result<StreamId> {
+        parse_raw_stream_id(self.take(16)?)
+    }
+
+    fn string(&mut self) -> Result<Bytes> {
+        match self.len_or_encoding()? {
+            Ok(len) => Ok(Bytes::copy_from_slice(self.take(len as usize)?)),
+            Err(ENC_INT8) => Ok((self.u8()? as i8).to_string().into()),
+            Err(ENC_INT16) => Ok(i16::from_le_bytes(self.take(2)?.try_into()?)
+                .to_string()
+                .into()),
+            Err(ENC_INT32) => Ok(i32::from_le_bytes(self.take(4)?.try_into()?)
+                .to_string()
+                .into()),
+            Err(ENC_LZF) => {
+                let compressed = self.len()?;
+                let len = self.len()?;
+                let data = self.take(compressed)?;
+                Ok(lzf_decompress(data, len)?.into())
+            }
+            Err(enc) => bail!("unknown string encoding {}", enc),
+        }
+    }
+
+    /// A score stored as text by the old zset encoding.
+    fn text_double(&mut self) -> Result<f64> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as usize)?),
+        }
+    }
+
+    fn value(&mut self, ty: u8) -> Result<Value> {
+        Ok(match ty {
+            TYPE_STRING => Value::String(self.string()?),
+            TYPE_LIST => {
+                let n = self.len()?;
+                (0..n)
+                    .map(|_| self.string())
+                    .collect::<Result<VecDeque<_>>>()?
+                    .into()
+            }
+            TYPE_SET => {
+                let n = self.len()?;
+                (0..n)
+                    .map(|_| self.string())
+                    .collect::<Result<HashSet<_>>>()?
+                    .into()
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut zset = ZSet::default();
+                for _ in 0..self.len()? {
+                    let member = self.string()?;
+                    let score = if ty == TYPE_ZSET {
+                        self.text_double()?
+                    } else {
+                        f64::from_le_bytes(self.take(8)?.try_into()?)
+                    };
+                    zset.insert(member, score);
+                }
+                zset.into()
+            }
+            TYPE_HASH => {
+                let n = self.len()?;
+                (0..n)
+                    .map(|_| Ok((self.string()?, self.string()?)))
+                    .collect::<Result<HashMap<_, _>>>()?
+                    .into()
+            }
+            TYPE_LIST_ZIPLIST => VecDeque::from(ziplist(&self.string()?)?).into(),
+            TYPE_SET_INTSET => intset(&self.string()?)?
+                .into_iter()
+                .collect::<HashSet<_>>()
+                .into(),
+            TYPE_SET_LISTPACK => listpack(&self.string()?)?
+                .into_iter()
+                .collect::<HashSet<_>>()
+                .into(),
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.string()?;
+                let items = if ty == TYPE_HASH_ZIPLIST {
+                    ziplist(&blob)?
+                } else {
+                    listpack(&blob)?
+                };
+                pairs(items)?.into_iter().collect::<HashMap<_, _>>().into()
+            }
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.string()?;
+                let items = if ty == TYPE_ZSET_ZIPLIST {
+                    ziplist(&blob)?
+                } else {
+                    listpack(&blob)?
+                };
+                let mut zset = ZSet::default();
+                for (member, score) in pairs(items)? {
+                    zset.insert(member, parse_score(&score)?);
+                }
+                zset.into()
+            }
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.len()? {
+                    if ty == TYPE_LIST_QUICKLIST {
+                        list.extend(ziplist(&self.string()?)?);
+                    } else if self.len()? as u64 == QUICKLIST_NODE_PLAIN {
+                        list.push_back(self.string()?);
+                    } else {
+                        list.extend(listpack(&self.string()?)?);
+                    }
+                }
+                list.into()
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.stream(ty)?.into()
+            }
+            ty => bail!("unsupported RDB value type {}", ty),
+        })
+    }
+
+    /// A stream as `put_stream` writes it. The older types lack the counters
+    /// and the consumers' active time.
+    fn stream(&mut self, ty: u8) -> Result<Stream> {
+        let mut stream = Stream::default();
+        for _ in 0..self.len()? {
+            let master = parse_raw_stream_id(&self.string()?)?;
+            for (id, fields) in stream_entries(master, listpack(&self.string()?)?)? {
+                if stream.last().is_some_and(|(&last, _)| id <= last) || id == StreamId::MIN {
+                    bail!("stream IDs out of order");
+                }
+                stream.add(id, fields);
+            }
+        }
+        if self.len()? != stream.len() {
+            bail!("wrong stream length");
+        }
+        let last_id = self.stream_id()?;
+        let (max_deleted_id, entries_added) = if ty == TYPE_STREAM_LISTPACKS {
+            (StreamId::MIN, stream.len() as u64)
+        } else {
+            // the first ID is the one of the first entry
+            self.stream_id()?;
+            (self.stream_id()?, self.len64()?)
+        };
+        stream.set_meta(last_id, max_deleted_id, entries_added);
+
+        for _ in 0..self.len()? {
+            let name = self.string()?;
+            let last_delivered = self.stream_id()?;
+            let entries_read = match ty {
+                TYPE_STREAM_LISTPACKS => None,
+                _ => Some(self.len64()?).filter(|&n| n != u64::MAX),
+            };
+            if !stream.create_group(name.clone(), last_delivered, entries_read) {
+                bail!("duplicate stream consumer group");
+            }
+            let group = stream.group_mut(&name).expect("group just created");
+            let mut pending = HashMap::new();
+            for _ in 0..self.len()? {
+                let id = self.raw_stream_id()?;
+                pending.insert(id, (self.ms_time()?, self.len64()?));
+            }
+            for _ in 0..self.len()? {
+                let consumer = self.string()?;
+                let seen_time = self.ms_time()?;
+                let active_time = match ty {
+                    TYPE_STREAM_LISTPACKS_3 => Some(self.ms_time()?).filter(|&t| t != u64::MAX),
+                    _ => Some(seen_time),
+                };
+                for _ in 0..self.len()? {
+                    let id = self.raw_stream_id()?;
+                    let (time, count) = pending
+                        .remove(&id)
+                        .ok_or_else(|| anyhow!("consumer pending entry not in its group"))?;
+                    group.claim(id, &consumer, time, count);
+                }
+                group.seen(&consumer, seen_time).active_time = active_time;
+            }
+            if !pending.is_empty() {
+                bail!("stream pending entry without a consumer");
+            }
+        }
+        Ok(stream)
+    }
+}
+
+fn parse_raw_stream_id(raw: &[u8]) -> Result<StreamId> {
+    if raw.len() != 16 {
+        bail!("invalid stream ID");
+    }
+    Ok(StreamId::new(
+        u64::from_be_bytes(raw[..8].try_into()?),
+        u64::from_be_bytes(raw[8..].try_into()?),
+    ))
+}
+
+/// The live entries of a stream listpack node, see `stream_node`.
+fn stream_entries(master: StreamId, items: Vec<Bytes>) -> Result<Vec<(StreamId, StreamFields)>> {
+    let invalid = || anyhow!("invalid stream listpack node");
+    let mut items = items.into_iter();
+    let mut next = || items.next().ok_or_else(invalid);
+    let int = |item: Bytes| -> Result<i64> {
+        std::str::from_utf8(&item)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let count = int(next()?)? + int(next()?)?;
+    let master_fields = (0..int(next()?)?)
+        .map(|_| next())
+        .collect::<Result<Vec<_>>>()?;
+    // the end of the master entry
+    next()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let flags = int(next()?)?;
+        let ms = master.ms.wrapping_add(int(next()?)? as u64);
+        let seq = master.seq.wrapping_add(int(next()?)? as u64);
+        let fields = if flags & STREAM_ITEM_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|f| Ok((f.clone(), next()?)))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            (0..int(next()?)?)
+                .map(|_| Ok((next()?, next()?)))
+                .collect::<Result<Vec<_>>>()?
+        };
+        next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            entries.push((StreamId::new(ms, seq), fields));
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_score(s: &[u8]) -> Result<f64> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or_else(|| anyhow!("invalid zset score"))
+}
+
+fn pairs(items: Vec<Bytes>) -> Result<Vec<(Bytes, Bytes)>> {
+    if !items.len().is_multiple_of(2) {
+        bail!("odd number of elements in a hash or zset");
+    }
+    let mut items = items.into_iter();
+    Ok(std::iter::from_fn(|| Some((items.next()?, items.next()?))).collect())
+}
+
+fn int_le(bytes: &[u8]) -> i64 {
+    // sign extend from the width of `bytes`
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let shift = 64 - 8 * bytes.len() as u32;
+    (i64::from_le_bytes(buf) << shift) >> shift
+}
+
+/// The elements of a ziplist, integers turned into their decimal form.
+fn ziplist(blob: &[u8]) -> Result<Vec<Bytes>> {
+    let mut r = Reader {
+        data: blob,
+        pos: 10,
+    };
+    let mut items = Vec::new();
+    loop {
+        let prevlen = r.u8()?;
+        if prevlen == 0xff {
+            return Ok(items);
+        }
+        if prevlen == 0xfe {
+            r.take(4)?;
+        }
+        let enc = r.u8()?;
+        let item = match enc >> 6 {
+            0 => Bytes::copy_from_slice(r.take((enc & 0x3f) as usize)?),
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | r.u8()? as usize;
+                Bytes::copy_from_slice(r.take(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.take(4)?.try_into()?) as usize;
+                Bytes::copy_from_slice(r.take(len)?)
+            }
+            _ => {
+                let n = match enc {
+                    0xc0 => int_le(r.take(2)?),
+                    0xd0 => int_le(r.take(4)?),
+                    0xe0 => int_le(r.take(8)?),
+                    0xf0 => int_le(r.take(3)?),
+                    0xfe => int_le(r.take(1)?),
+                    0xf1..=0xfd => (enc & 0x0f) as i64 - 1,
+                    _ => bail!("invalid ziplist encoding {:#x}", enc),
+                };
+                n.to_string().into()
+            }
+        };
+        items.push(item);
+    }
+}
+
+/// The elements of a listpack, integers turned into their decimal form.
+fn listpack(blob: &[u8]) -> Result<Vec<Bytes>> {
+    let mut r = Reader { data: blob, pos: 6 };
+    let mut items = Vec::new();
+    loop {
+        let start = r.pos;
+        let enc = r.u8()?;
+        let item: Bytes = match enc {
+            0xff => return Ok(items),
+            0x00..=0x7f => enc.to_string().into(),
+            0x80..=0xbf => Bytes::copy_from_slice(r.take((enc & 0x3f) as usize)?),
+            0xc0..=0xdf => {
+                let n = (((enc & 0x1f) as i64) << 8) | r.u8()? as i64;
+                let n = if n >= 1 << 12 { n - (1 << 13) } else { n };
+                n.to_string().into()
+            }
+            0xe0..=0xef => {
+                let len = (((enc & 0x0f) as usize) << 8) | r.u8()? as usize;
+                Bytes::copy_from_slice(r.take(len)?)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(r.take(4)?.try_into()?) as usize;
+                Bytes::copy_from_slice(r.take(len)?)
+            }
+            0xf1 => int_le(r.take(2)?).to_string().into(),
+            0xf2 => int_le(r.take(3)?).to_string().into(),
+            0xf3 => int_le(r.take(4)?).to_string().into(),
+            0xf4 => int_le(r.take(8)?).to_string().into(),
+            _ => bail!("invalid listpack encoding {:#x}", enc),
+        };
+        // skip the back length, it takes one byte per 7 bits of entry size
+        r.take(lp_backlen_len(r.pos - start))?;
+        items.push(item);
+    }
+}
+
+fn intset(blob: &[u8]) -> Result<Vec<Bytes>> {
+    let mut r = Reader { data: blob, pos: 0 };
+    let width = u32::from_le_bytes(r.take(4)?.try_into()?) as usize;
+    if ![2, 4, 8].contains(&width) {
+        bail!("invalid intset encoding {}", width);
+    }
+    let n = u32::from_le_bytes(r.take(4)?.try_into()?);
+    (0..n)
+        .map(|_| Ok(int_le(r.take(width)?).to_string().into()))
+        .collect()
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    let invalid = || anyhow!("invalid LZF compressed string");
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(invalid)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(invalid)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(invalid)? as usize + 1;
+            i += 1;
+            let from = out.len().checked_sub(offset).ok_or_else(invalid)?;
+            // the reference may overlap the bytes being produced
+            for j in 0..run + 2 {
+                out.push(out[from + j]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(invalid());
+    }
+    Ok(out)
+}
+
+/// Where the RDB file lives and the state of background saves.
+#[derive(Debug)]
+pub(crate) struct RdbState {
+    path: Mutex<PathBuf>,
+    bgsave: Mutex<Option<JoinHandle<Result<()>>>>,
+    /// Unix time of the last successful save.
+    last_save: AtomicU64,
+}
+
+impl Default for RdbState {
+    fn default() -> Self {
+        RdbState {
+            path: Mutex::new(PathBuf::from("dump.rdb")),
+            bgsave: Mutex::new(None),
+            last_save: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Write the file next to `path` first so a crash never leaves a partial
+/// RDB file behind. The temporary file is named after `path` too, shards
+/// and cluster nodes of one process save side by side.
+fn write_rdb(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!("temp-{}-{}", std::process::id(), name));
+    let mut file = fs::File::create(&tmp).with_context(|| format!("create {:?}", tmp))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("rename {:?} to {:?}", tmp, path))?;
+    Ok(())
+}
+
+impl Backend {
+    pub fn set_rdb_path(&self, path: impl Into<PathBuf>) {
+        *self.rdb.path.lock().unwrap() = path.into();
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        self.rdb.path.lock().unwrap().clone()
+    }
+
+    /// Unix time of the last successful save.
+    pub fn last_save(&self) -> u64 {
+        self.rdb.last_save.load(Ordering::Relaxed)
+    }
+
+    /// The live keys right now. Only consistent while the caller holds the
+    /// exec lock exclusively.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        let now = self.now_ms();
+        self.all_dbs()
+            .into_iter()
+            .map(|(index, db)| {
+                let entries = db
+                    .data
+                    .iter()
+                    .filter(|e| !e.value().is_expired(now))
+                    .map(|e| (e.key().clone(), e.value().clone()))
+                    .collect::<Vec<_>>();
+                (index, entries)
+            })
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect()
+    }
+
+    /// Save the keyspace in the foreground, everyone else waits.
+    pub fn save(&self) -> Result<()> {
+        let _exclusive = self.exec_lock.write().unwrap();
+        self.save_snapshot()
+    }
+
+    /// `save` for callers that already hold the exec lock exclusively.
+    pub(crate) fn save_snapshot(&self) -> Result<()> {
+        if self.bgsave_in_progress() {
+            bail!("ERR Background save already in progress");
+        }
+        let data = encode_rdb(&self.snapshot(), self.now_ms());
+        write_rdb(&self.rdb_path(), &data)?;
+        self.rdb
+            .last_save
+            .store(self.now_ms() / 1000, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Take a snapshot and write it out on a background thread. Writers are
+    /// only held up while the snapshot is taken.
+    pub fn bgsave(&self) -> Result<()> {
+        let _exclusive = self.exec_lock.write().unwrap();
+        self.bgsave_snapshot()
+    }
+
+    /// `bgsave` for callers that already hold the exec lock exclusively.
+    pub(crate) fn bgsave_snapshot(&self) -> Result<()> {
+        let mut bgsave = self.rdb.bgsave.lock().unwrap();
+        if bgsave.as_ref().is_some_and(|h| !h.is_finished()) {
+            bail!("ERR Background save already in progress");
+        }
+        if let Some(done) = bgsave.take() {
+            log_bgsave(done);
+        }
+        let snapshot = self.snapshot();
+        let backend = self.clone();
+        let path = self.rdb_path();
+        *bgsave = Some(thread::spawn(move || {
+            let data = encode_rdb(&snapshot, backend.now_ms());
+            write_rdb(&path, &data)?;
+            backend
+                .rdb
+                .last_save
+                .store(backend.now_ms() / 1000, Ordering::Relaxed);
+            info!(
+                "background saving of {} keys terminated",
+                snapshot_len(&snapshot)
+            );
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        let bgsave = self.rdb.bgsave.lock().unwrap();
+        bgsave.as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    /// Wait for a running background save.
+    pub fn wait_bgsave(&self) -> Result<()> {
+        let handle = self.rdb.bgsave.lock().unwrap().take();
+        match handle {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| anyhow!("background save panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Load the RDB file if there is one, returns the number of keys loaded.
+    /// Keys that expired in the meantime are skipped.
+    pub fn load_rdb(&self) -> Result<usize> {
+        let path = self.rdb_path();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("read {:?}", path)),
+        };
+        let snapshot = decode_rdb(&data).with_context(|| format!("load {:?}", path))?;
+        Ok(self.load_snapshot(snapshot))
+    }
+
+    /// Add the live keys of `snapshot` to the keyspace, returns how many.
+    /// Databases this server does not have are skipped.
+    pub(crate) fn load_snapshot(&self, snapshot: Snapshot) -> usize {
+        let now = self.now_ms();
+        let mut loaded = 0;
+        for (index, entries) in snapshot {
+            if index >= self.databases() {
+                warn!("skipping the keys of database {}, out of range", index);
+                continue;
+            }
+            let backend = self.select(index);
+            for (key, entry) in entries {
+                if entry.is_expired(now) {
+                    continue;
+                }
+                backend.write(&key, |slot| *slot = Some(entry));
+                loaded += 1;
+            }
+        }
+        loaded
+    }
+}
+
+fn log_bgsave(handle: JoinHandle<Result<()>>) {
+    match handle.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("background saving error: {:#}", e),
+        Err(_) => warn!("background saving panicked"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::ManualClock;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("dredis-{}-{}-{}", std::process::id(), n, name))
+    }
+
+    fn sample() -> Vec<(Bytes, Entry)> {
+        let mut zset = ZSet::default();
+        zset.insert("a".into(), 1.5);
+        zset.insert("b".into(), f64::NEG_INFINITY);
+        let mut expiring = Entry::new(Value::String("12345".into()));
+        expiring.expire_at = Some(4_000_000_000_000);
+        let fields = |pairs: &[(&'static str, &'static str)]| -> StreamFields {
+            pairs.iter().map(|&(f, v)| (f.into(), v.into())).collect()
+        };
+        let mut stream = Stream::default();
+        stream.add(StreamId::new(1, 0), fields(&[("a", "1"), ("b", "x")]));
+        stream.add(StreamId::new(1, 1), fields(&[("a", "-20"), ("b", "y")]));
+        stream.add(StreamId::new(5, 0), fields(&[("other", "v")]));
+        stream.add(StreamId::new(9, 3), fields(&[("a", "3")]));
+        stream.remove(StreamId::new(1, 1));
+        stream.create_group("g".into(), StreamId::new(5, 0), Some(2));
+        stream.create_group("unread".into(), StreamId::MIN, None);
+        let group = stream.group_mut(b"g").unwrap();
+        group.claim(StreamId::new(1, 0), &"alice".into(), 1_000, 2);
+        group.claim(StreamId::new(5, 0), &"bob".into(), 2_000, 1);
+        group.seen(&"bob".into(), 2_000).active_time = Some(2_000);
+        group.create_consumer(&"idle".into(), 3_000);
+        let mut emptied = Stream::default();
+        emptied.add(StreamId::new(7, 7), fields(&[("f", "v")]));
+        emptied.remove(StreamId::new(7, 7));
+        vec![
+            ("str".into(), Entry::new(Value::String("hello".into()))),
+            ("int".into(), Entry::new(Value::String("-7".into()))),
+            ("big".into(), Entry::new(Value::String("9999999999".into()))),
+            ("ttl".into(), expiring),
+            (
+                "list".into(),
+                Entry::new(VecDeque::from(["x".into(), "300".into()]).into()),
+            ),
+            ("set".into(), Entry::new(HashSet::from(["m".into()]).into())),
+            (
+                "hash".into(),
+                Entry::new(HashMap::from([("f".into(), "v".into())]).into()),
+            ),
+            ("zset".into(), Entry::new(zset.into())),
+            ("stream".into(), Entry::new(stream.into())),
+            ("emptied".into(), Entry::new(emptied.into())),
+        ]
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let snapshot = vec![(0, sample()), (3, sample()[..2].to_vec())];
+        let data = encode_rdb(&snapshot, 0);
+        assert!(data.starts_with(b"REDIS0011"));
+        assert_eq!(decode_rdb(&data).unwrap(), snapshot);
+
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 1;
+        assert!(decode_rdb(&corrupt).is_err());
+        assert!(decode_rdb(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        // listpack of "a", 5, -3
+        let lp = [0, 0, 0, 0, 3, 0, 0x81, b'a', 2, 5, 1, 0xdf, 0xfd, 2, 0xff];
+        assert_eq!(listpack(&lp).unwrap(), vec!["a", "5", "-3"]);
+        // ziplist of "ab", 12 (immediate), 1000 (int16)
+        let zl = [
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0x02, b'a', b'b', 4, 0xfd, 2, 0xc0, 0xe8, 0x03, 0xff,
+        ];
+        assert_eq!(ziplist(&zl).unwrap(), vec!["ab", "12", "1000"]);
+        let is = [2, 0, 0, 0, 2, 0, 0, 0, 0xff, 0xff, 7, 0];
+        assert_eq!(intset(&is).unwrap(), vec!["-1", "7"]);
+        // a literal "a" followed by a back reference repeating it 9 times
+        assert_eq!(
+            lzf_decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 10).unwrap(),
+            b"aaaaaaaaaa"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let backend = Backend::with_clock(clock.clone());
+        let path = temp_path("dump.rdb");
+        backend.set_rdb_path(&path);
+        assert_eq!(backend.load_rdb()?, 0);
+        for (key, entry) in sample() {
+            backend.write(&key, |slot| *slot = Some(entry));
+        }
+        let db2 = backend.select(2);
+        db2.write(&"other".into(), |slot| {
+            *slot = Some(Entry::new(Value::String("db2".into())))
+        });
+        let mut short = Entry::new(Value::String("v".into()));
+        short.expire_at = Some(1_000_500);
+        backend.write(&"short".into(), |slot| *slot = Some(short));
+        backend.save()?;
+        assert_eq!(backend.last_save(), 1000);
+
+        clock.advance(Duration::from_secs(1));
+        let restarted = Backend::with_clock(clock);
+        restarted.set_rdb_path(&path);
+        assert_eq!(restarted.load_rdb()?, sample().len() + 1);
+        assert_eq!(restarted.volatile_len(), 1);
+        assert!(restarted.select(2).exists(b"other"));
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgsave_uses_a_point_in_time_snapshot() -> Result<()> {
+        let backend = Backend::new();
+        let path = temp_path("bg.rdb");
+        backend.set_rdb_path(&path);
+        let key = Bytes::from("list");
+        backend.write(&key, |slot| {
+            *slot = Some(Entry::new(VecDeque::from(["a".into()]).into()))
+        });
+        backend.bgsave()?;
+        // changes after BGSAVE returned are not part of the file
+        backend.write(&key, |slot| {
+            slot.as_mut()
+                .unwrap()
+                .value
+                .as_list_mut()
+                .unwrap()
+                .push_back("b".into())
+        });
+        backend.wait_bgsave()?;
+        let saved = decode_rdb(&fs::read(&path)?)?;
+        assert_eq!(
+            saved,
+            vec![(
+                0,
+                vec![(key, Entry::new(VecDeque::from(["a".into()]).into()))]
+            )]
+        );
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
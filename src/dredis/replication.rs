@@ -0,0 +1,904 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use rand::Rng;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{
+    decode_rdb, encode_rdb, execute, frame_to_args, Backend, OutputLimit, OutputLimitCheck,
+    RespDecoder, RespFrame, Session, Snapshot,
+};
+
+/// Bytes of the write stream kept for partial resyncs, redis'
+/// `repl-backlog-size`.
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+/// Redis' default limit for replicas, `replica 256mb 64mb 60`.
+const DEFAULT_OUTPUT_LIMIT: OutputLimit = OutputLimit::new(256 * 1024 * 1024, 64 * 1024 * 1024, 60);
+/// How often a replica reports its offset to the master.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// How long a replica waits before connecting again after the link failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The replication state of a server: the id and offset of the write stream
+/// it has, the backlog of that stream, its replicas and, on a replica, the
+/// link to its master.
+#[derive(Debug)]
+pub(crate) struct Replication {
+    state: Mutex<ReplState>,
+    // lets writes skip the lock while nobody replicates
+    active: AtomicBool,
+}
+
+#[derive(Debug)]
+struct ReplState {
+    replid: String,
+    /// The id this server had before its last switch to master, partial
+    /// resyncs up to `second_replid_offset` are still accepted for it.
+    replid2: String,
+    second_replid_offset: Option<u64>,
+    /// Bytes of the write stream produced (or, on a replica, applied) so far.
+    offset: u64,
+    /// The tail of the write stream, created on the first `PSYNC`.
+    backlog: Option<VecDeque<u8>>,
+    backlog_size: usize,
+    replicas: Vec<Replica>,
+    output_limit: OutputLimit,
+    master: Option<MasterLink>,
+    /// The port announced to the master with `REPLCONF listening-port`.
+    listening_port: u16,
+    /// The database of the last command fed, `None` when the next one needs
+    /// a `SELECT` whatever its database.
+    selected_db: Option<usize>,
+    /// On a replica, the database the master's stream is on, kept across
+    /// reconnects since a partial resync continues where the stream was.
+    master_db: usize,
+}
+
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    addr: Option<SocketAddr>,
+    port: u16,
+    online: bool,
+    ack_offset: u64,
+    last_ack: Instant,
+    tx: mpsc::UnboundedSender<Bytes>,
+    /// Bytes sent to `tx` and not written to the socket yet.
+    pending: Arc<AtomicUsize>,
+    check: OutputLimitCheck,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkStatus {
+    Connecting,
+    Sync,
+    Connected,
+}
+
+#[derive(Debug)]
+struct MasterLink {
+    host: String,
+    port: u16,
+    status: LinkStatus,
+    last_io: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            state: Mutex::new(ReplState {
+                replid: random_replid(),
+                replid2: "0".repeat(40),
+                second_replid_offset: None,
+                offset: 0,
+                backlog: None,
+                backlog_size: DEFAULT_BACKLOG_SIZE,
+                replicas: Vec::new(),
+                output_limit: DEFAULT_OUTPUT_LIMIT,
+                master: None,
+                listening_port: 6379,
+                selected_db: None,
+                master_db: 0,
+            }),
+            active: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Replication {
+    /// Whether write commands are fed to a backlog.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    /// Append a write command run on database `db` to the stream, preceded
+    /// by a `SELECT` when the last one ran on another.
+    pub(crate) fn feed_command(&self, db: usize, args: &[Bytes]) {
+        if !self.is_active() {
+            return;
+        }
+        let mut data = BytesMut::new();
+        if self.state.lock().unwrap().selected_db.replace(db) != Some(db) {
+            let select = ["SELECT".into(), db.to_string().into()];
+            RespFrame::command(select).encode(&mut data);
+        }
+        RespFrame::command(args.iter().cloned()).encode(&mut data);
+        self.feed(data.freeze());
+    }
+
+    fn master_db(&self) -> usize {
+        self.state.lock().unwrap().master_db
+    }
+
+    fn set_master_db(&self, db: usize) {
+        self.state.lock().unwrap().master_db = db;
+    }
+
+    /// Append raw bytes of the write stream: to the backlog, the offset and
+    /// every replica.
+    pub(crate) fn feed(&self, data: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        let size = state.backlog_size;
+        let Some(backlog) = state.backlog.as_mut() else {
+            return;
+        };
+        backlog.extend(&data[..]);
+        if backlog.len() > size {
+            backlog.drain(..backlog.len() - size);
+        }
+        state.offset += data.len() as u64;
+        // a replica whose connection went away is removed when its feed drops,
+        // one too far behind is dropped here and its feed ends
+        let limit = state.output_limit;
+        state.replicas.retain_mut(|replica| {
+            let pending = replica.pending.fetch_add(data.len(), Ordering::Relaxed) + data.len();
+            if replica.check.exceeded(&limit, pending) {
+                warn!(
+                    "replica {} closed for overcoming of output buffer limits ({} bytes)",
+                    replica.id, pending
+                );
+                return false;
+            }
+            let _ = replica.tx.send(data.clone());
+            true
+        });
+    }
+
+    pub(crate) fn set_output_limit(&self, limit: OutputLimit) {
+        self.state.lock().unwrap().output_limit = limit;
+    }
+
+    pub(crate) fn output_limit(&self) -> OutputLimit {
+        self.state.lock().unwrap().output_limit
+    }
+
+    /// Stop following the master, on shutdown.
+    pub(crate) fn stop_link(&self) {
+        if let Some(link) = self.state.lock().unwrap().master.take() {
+            link.task.abort();
+        }
+    }
+
+    fn remove_replica(&self, id: u64) {
+        self.state.lock().unwrap().replicas.retain(|r| r.id != id);
+    }
+
+    fn replica_online(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+            replica.online = true;
+        }
+    }
+
+    fn replica_ack(&self, id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    fn set_link_status(&self, status: LinkStatus) {
+        if let Some(link) = self.state.lock().unwrap().master.as_mut() {
+            link.status = status;
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn touch_master_io(&self) {
+        if let Some(link) = self.state.lock().unwrap().master.as_mut() {
+            link.last_io = Instant::now();
+        }
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    /// What a replica asks for with `PSYNC`: the stream it has and the next
+    /// byte it needs, or `? -1` when it never replicated.
+    fn psync_args(&self) -> (String, String) {
+        let state = self.state.lock().unwrap();
+        match state.backlog {
+            Some(_) => (state.replid.clone(), (state.offset + 1).to_string()),
+            None => ("?".into(), "-1".into()),
+        }
+    }
+
+    /// Start over at `offset` of the stream `replid`, after a full sync. The
+    /// replicas of this server have to sync again.
+    fn reset(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = "0".repeat(40);
+        state.second_replid_offset = None;
+        state.offset = offset;
+        state.backlog = Some(VecDeque::new());
+        state.replicas.clear();
+        state.selected_db = None;
+        state.master_db = 0;
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// The master continues the stream with a new id after a failover, the
+    /// old id is still good for replicas behind up to here.
+    fn switch_replid(&self, replid: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.replid != replid {
+            state.replid2 = std::mem::replace(&mut state.replid, replid);
+            state.second_replid_offset = Some(state.offset + 1);
+        }
+    }
+}
+
+impl ReplState {
+    /// The offset of the first byte in the backlog.
+    fn backlog_first_offset(&self) -> u64 {
+        let len = self.backlog.as_ref().map_or(0, |b| b.len());
+        self.offset - len as u64 + 1
+    }
+
+    /// Whether the backlog can continue the stream `replid` at `offset`.
+    fn can_continue(&self, replid: &str, offset: u64) -> bool {
+        let known = replid == self.replid
+            || (replid == self.replid2 && self.second_replid_offset.is_some_and(|o| offset <= o));
+        known
+            && self.backlog.is_some()
+            && offset >= self.backlog_first_offset()
+            && offset <= self.offset + 1
+    }
+}
+
+/// The connection of a replica after `PSYNC`: the snapshot to ship for a full
+/// sync, then the write stream. Dropping it removes the replica.
+#[derive(Debug)]
+pub(crate) struct ReplicaFeed {
+    backend: Backend,
+    id: u64,
+    snapshot: Option<Snapshot>,
+    rx: mpsc::UnboundedReceiver<Bytes>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl ReplicaFeed {
+    pub(crate) async fn serve<S>(mut self, stream: &mut S, mut buf: BytesMut) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(snapshot) = self.snapshot.take() {
+            let now = self.backend.now_ms();
+            let data = tokio::task::spawn_blocking(move || encode_rdb(&snapshot, now)).await?;
+            // like redis the payload has no trailing CRLF
+            stream
+                .write_all(format!("${}\r\n", data.len()).as_bytes())
+                .await?;
+            stream.write_all(&data).await?;
+        }
+        self.backend.repl.replica_online(self.id);
+        loop {
+            tokio::select! {
+                data = self.rx.recv() => match data {
+                    Some(data) => {
+                        stream.write_all(&data).await?;
+                        self.pending.fetch_sub(data.len(), Ordering::Relaxed);
+                    }
+                    // dropped by the master, e.g. it synced from a new master
+                    None => return Ok(()),
+                },
+                _ = self.backend.shutdown_requested() => return Ok(()),
+                res = stream.read_buf(&mut buf) => {
+                    if res? == 0 {
+                        return Ok(());
+                    }
+                    while let Some(frame) = RespFrame::decode(&mut buf)? {
+                        let args = frame_to_args(frame)?;
+                        if let Some(offset) = parse_ack(&args) {
+                            self.backend.repl.replica_ack(self.id, offset);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ReplicaFeed {
+    fn drop(&mut self) {
+        self.backend.repl.remove_replica(self.id);
+    }
+}
+
+/// The offset of a `REPLCONF ACK <offset>`.
+fn parse_ack(args: &[Bytes]) -> Option<u64> {
+    match args {
+        [cmd, sub, offset, ..]
+            if cmd.eq_ignore_ascii_case(b"replconf") && sub.eq_ignore_ascii_case(b"ack") =>
+        {
+            std::str::from_utf8(offset).ok()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+fn random_replid() -> String {
+    let id: [u8; 20] = rand::thread_rng().gen();
+    id.iter().fold(String::with_capacity(40), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+impl Backend {
+    /// The port this server announces to its master.
+    pub fn set_listening_port(&self, port: u16) {
+        self.repl.state.lock().unwrap().listening_port = port;
+    }
+
+    /// Bytes of the write stream kept for replicas to resync partially.
+    pub fn repl_backlog_size(&self) -> usize {
+        self.repl.state.lock().unwrap().backlog_size
+    }
+
+    pub fn set_repl_backlog_size(&self, size: usize) {
+        let mut state = self.repl.state.lock().unwrap();
+        state.backlog_size = size.max(1);
+        if let Some(backlog) = state.backlog.as_mut() {
+            if backlog.len() > size {
+                backlog.drain(..backlog.len() - size);
+            }
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.repl.is_replica()
+    }
+
+    /// The replication id and offset of the write stream this server has.
+    pub fn repl_offset(&self) -> (String, u64) {
+        let state = self.repl.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    }
+
+    /// Replicate `master` from now on, or stop replicating with `None` and
+    /// keep the data. Returns false when already replicating `master`.
+    pub fn replicaof(&self, master: Option<(String, u16)>) -> bool {
+        let mut state = self.repl.state.lock().unwrap();
+        if let (Some(link), Some((host, port))) = (state.master.as_ref(), master.as_ref()) {
+            if link.host == *host && link.port == *port {
+                return false;
+            }
+        }
+        if let Some(link) = state.master.take() {
+            link.task.abort();
+        }
+        match master {
+            Some((host, port)) => {
+                info!("connecting to master {}:{}", host, port);
+                let task = tokio::spawn(replication_link(self.clone(), host.clone(), port));
+                state.master = Some(MasterLink {
+                    host,
+                    port,
+                    status: LinkStatus::Connecting,
+                    last_io: Instant::now(),
+                    task,
+                });
+            }
+            None => {
+                // replicas of the old master may continue with us
+                info!("promoted to master");
+                let offset = state.offset;
+                state.replid2 = std::mem::replace(&mut state.replid, random_replid());
+                state.second_replid_offset = Some(offset + 1);
+            }
+        }
+        true
+    }
+
+    /// Answer the `PSYNC` of a replica, continuing from the backlog when it
+    /// can or else with a full sync. The caller must hold the exec lock
+    /// exclusively, the snapshot then matches the offset replied.
+    pub(crate) fn psync(
+        &self,
+        session: &Session,
+        replid: &str,
+        offset: i64,
+    ) -> Result<(RespFrame, ReplicaFeed)> {
+        let mut state = self.repl.state.lock().unwrap();
+        if state
+            .master
+            .as_ref()
+            .is_some_and(|l| l.status != LinkStatus::Connected)
+        {
+            bail!("NOMASTERLINK Can't SYNC while not connected with my master");
+        }
+        if state.backlog.is_none() {
+            state.backlog = Some(VecDeque::new());
+            self.repl.active.store(true, Ordering::Release);
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let partial = u64::try_from(offset)
+            .ok()
+            .filter(|&o| state.can_continue(replid, o));
+        let (reply, snapshot) = match partial {
+            Some(offset) => {
+                let skip = (offset - state.backlog_first_offset()) as usize;
+                let backlog = state
+                    .backlog
+                    .as_ref()
+                    .map(|b| b.iter().skip(skip).copied().collect::<Vec<u8>>())
+                    .unwrap_or_default();
+                if !backlog.is_empty() {
+                    pending.fetch_add(backlog.len(), Ordering::Relaxed);
+                    let _ = tx.send(backlog.into());
+                }
+                info!("partial resync of replica {} from {}", session.id, offset);
+                (
+                    RespFrame::simple(format!("CONTINUE {}", state.replid)),
+                    None,
+                )
+            }
+            None => {
+                info!("full resync of replica {}", session.id);
+                let reply =
+                    RespFrame::simple(format!("FULLRESYNC {} {}", state.replid, state.offset));
+                // the snapshot replica starts on db 0, whatever the stream is on
+                state.selected_db = None;
+                (reply, Some(self.snapshot()))
+            }
+        };
+        state.replicas.push(Replica {
+            id: session.id,
+            addr: session.addr,
+            port: session.replica_port.unwrap_or(0),
+            online: false,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+            tx,
+            pending: pending.clone(),
+            check: OutputLimitCheck::default(),
+        });
+        let feed = ReplicaFeed {
+            backend: self.clone(),
+            id: session.id,
+            snapshot,
+            rx,
+            pending,
+        };
+        Ok((reply, feed))
+    }
+
+    /// The `# Replication` section of `INFO`.
+    pub fn info_replication(&self) -> String {
+        let state = self.repl.state.lock().unwrap();
+        let mut info = String::from("# Replication\r\n");
+        match state.master.as_ref() {
+            Some(link) => {
+                let up = link.status == LinkStatus::Connected;
+                let _ = write!(
+                    info,
+                    "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\nmaster_sync_in_progress:{}\r\nslave_read_repl_offset:{}\r\nslave_repl_offset:{}\r\nslave_read_only:1\r\n",
+                    link.host,
+                    link.port,
+                    if up { "up" } else { "down" },
+                    if up { link.last_io.elapsed().as_secs() as i64 } else { -1 },
+                    (link.status == LinkStatus::Sync) as u8,
+                    state.offset,
+                    state.offset,
+                );
+            }
+            None => info.push_str("role:master\r\n"),
+        }
+        let _ = write!(info, "connected_slaves:{}\r\n", state.replicas.len());
+        for (i, replica) in state.replicas.iter().enumerate() {
+            let _ = write!(
+                info,
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                replica.addr.map(|a| a.ip().to_string()).unwrap_or_default(),
+                replica.port,
+                if replica.online {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs(),
+            );
+        }
+        let backlog_len = state.backlog.as_ref().map_or(0, |b| b.len());
+        let _ = write!(
+            info,
+            "master_replid:{}\r\nmaster_replid2:{}\r\nmaster_repl_offset:{}\r\nsecond_repl_offset:{}\r\nrepl_backlog_active:{}\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+            state.replid,
+            state.replid2,
+            state.offset,
+            state.second_replid_offset.map_or(-1, |o| o as i64),
+            state.backlog.is_some() as u8,
+            state.backlog_size,
+            if state.backlog.is_some() { state.backlog_first_offset() } else { 0 },
+            backlog_len,
+        );
+        info
+    }
+}
+
+/// Keep a replica in sync with its master, connecting again whenever the
+/// link breaks. Runs until `REPLICAOF` aborts it.
+async fn replication_link(backend: Backend, host: String, port: u16) {
+    loop {
+        if let Err(e) = sync_with_master(&backend, &host, port).await {
+            warn!("replication with master {}:{} failed: {:#}", host, port, e);
+        }
+        backend.repl.set_link_status(LinkStatus::Connecting);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(backend: &Backend, host: &str, port: u16) -> Result<()> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let mut buf = BytesMut::new();
+    let listening_port = backend
+        .repl
+        .state
+        .lock()
+        .unwrap()
+        .listening_port
+        .to_string();
+    let handshake: [&[&str]; 3] = [
+        &["PING"],
+        &["REPLCONF", "listening-port", &listening_port],
+        &["REPLCONF", "capa", "psync2"],
+    ];
+    for cmd in handshake {
+        send(&mut stream, cmd).await?;
+        if let RespFrame::Error(e) = read_frame(&mut stream, &mut buf).await? {
+            bail!("master replied to {} with: {}", cmd[0], e);
+        }
+    }
+
+    let (replid, offset) = backend.repl.psync_args();
+    send(&mut stream, &["PSYNC", &replid, &offset]).await?;
+    let reply = match read_frame(&mut stream, &mut buf).await? {
+        RespFrame::SimpleString(s) => s,
+        other => bail!("unexpected reply to PSYNC: {:?}", other),
+    };
+    let mut words = reply.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset.parse()?;
+            backend.repl.set_link_status(LinkStatus::Sync);
+            let data = read_rdb_payload(&mut stream, &mut buf).await?;
+            let snapshot = decode_rdb(&data)?;
+            let loaded = full_sync(backend, snapshot, replid.to_string(), offset);
+            info!(
+                "full resync from master: {} keys at offset {}",
+                loaded, offset
+            );
+        }
+        (Some("CONTINUE"), replid, _) => {
+            if let Some(replid) = replid {
+                backend.repl.switch_replid(replid.to_string());
+            }
+            info!(
+                "partial resync from master at offset {}",
+                backend.repl.offset()
+            );
+        }
+        _ => bail!("unexpected reply to PSYNC: {}", reply),
+    }
+    backend.repl.set_link_status(LinkStatus::Connected);
+
+    let mut session = Session::new(backend.select(backend.repl.master_db()));
+    session.master_link = true;
+    let mut ack = tokio::time::interval(ACK_PERIOD);
+    let mut decoder = RespDecoder::default();
+    loop {
+        tokio::select! {
+            res = stream.read_buf(&mut buf) => {
+                if res? == 0 {
+                    bail!("connection closed by master");
+                }
+            }
+            _ = ack.tick() => {
+                let offset = backend.repl.offset().to_string();
+                send(&mut stream, &["REPLCONF", "ACK", &offset]).await?;
+                continue;
+            }
+        }
+        backend.repl.touch_master_io();
+        while let Some(frame) = decoder.decode(&mut buf)? {
+            // the master encodes commands the one way we do too
+            let raw = frame.to_bytes();
+            let args = frame_to_args(frame)?;
+            if is_getack(&args) {
+                let offset = backend.repl.offset().to_string();
+                send(&mut stream, &["REPLCONF", "ACK", &offset]).await?;
+            } else if let RespFrame::Error(e) = execute(&mut session, &args) {
+                warn!("command from master failed: {}", e);
+            }
+            backend.repl.set_master_db(session.backend.db);
+            // sub-replicas get the stream as it came
+            backend.repl.feed(raw);
+        }
+    }
+}
+
+/// Replace the keyspace with what the master sent.
+fn full_sync(backend: &Backend, snapshot: Snapshot, replid: String, offset: u64) -> usize {
+    let _exclusive = backend.exec_lock.write().unwrap();
+    backend.flushall(true);
+    let loaded = backend.load_snapshot(snapshot);
+    backend.repl.reset(replid, offset);
+    loaded
+}
+
+fn is_getack(args: &[Bytes]) -> bool {
+    matches!(args, [cmd, sub, ..]
+        if cmd.eq_ignore_ascii_case(b"replconf") && sub.eq_ignore_ascii_case(b"getack"))
+}
+
+async fn send(stream: &mut TcpStream, args: &[&str]) -> Result<()> {
+    let cmd = RespFrame::command(args.iter().map(|a| Bytes::copy_from_slice(a.as_bytes())));
+    stream.write_all(&cmd.to_bytes()).await?;
+    Ok(())
+}
+
+async fn read_frame(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<RespFrame> {
+    loop {
+        if let Some(frame) = RespFrame::decode(buf)? {
+            return Ok(frame);
+        }
+        if stream.read_buf(buf).await? == 0 {
+            bail!("connection closed by master");
+        }
+    }
+}
+
+/// The snapshot of a full sync: `$<len>\r\n` and the RDB file, without the
+/// CRLF a bulk string ends with.
+async fn read_rdb_payload(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<Vec<u8>> {
+    let len = loop {
+        // masters may send newlines to keep the link alive while saving
+        while buf.first() == Some(&b'\n') {
+            buf.advance(1);
+        }
+        if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(end + 2);
+            let len = line
+                .strip_prefix(b"$")
+                .and_then(|l| std::str::from_utf8(&l[..l.len() - 2]).ok())
+                .and_then(|l| l.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("bad snapshot header from master"))?;
+            break len;
+        }
+        if stream.read_buf(buf).await? == 0 {
+            bail!("connection closed by master");
+        }
+    };
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            bail!("connection closed by master");
+        }
+    }
+    Ok(buf.split_to(len).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{process_redis_conn, snapshot_len};
+
+    async fn listen(backend: &Backend) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, client_addr)) = listener.accept().await {
+                tokio::spawn(process_redis_conn(stream, client_addr, backend.clone()));
+            }
+        });
+        Ok(addr)
+    }
+
+    fn cmd(session: &mut Session, args: &[&str]) -> RespFrame {
+        let args = args.iter().map(|a| Bytes::copy_from_slice(a.as_bytes()));
+        execute(session, &args.collect::<Vec<_>>())
+    }
+
+    async fn eventually(mut f: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached in time");
+    }
+
+    async fn read_line(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<String> {
+        match read_frame(stream, buf).await? {
+            RespFrame::SimpleString(s) => Ok(s),
+            other => bail!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_master() -> Result<()> {
+        let master = Backend::new();
+        let addr = listen(&master).await?;
+        let mut m = Session::new(master.clone());
+        cmd(&mut m, &["SET", "before", "1"]);
+        cmd(&mut m, &["RPUSH", "list", "a", "b"]);
+
+        let replica = Backend::new();
+        let mut r = Session::new(replica.clone());
+        cmd(&mut r, &["SET", "stale", "x"]);
+        let port = addr.port().to_string();
+        assert_eq!(
+            cmd(&mut r, &["REPLICAOF", "127.0.0.1", &port]),
+            RespFrame::ok()
+        );
+        eventually(|| replica.exists(b"before")).await;
+        assert!(!replica.exists(b"stale"));
+
+        cmd(&mut m, &["INCR", "before"]);
+        cmd(&mut m, &["SET", "ttl", "v", "EX", "100"]);
+        cmd(&mut m, &["MULTI"]);
+        cmd(&mut m, &["LPOP", "list"]);
+        cmd(&mut m, &["DEL", "ttl"]);
+        cmd(&mut m, &["EXEC"]);
+        eventually(|| replica.repl_offset() == master.repl_offset()).await;
+        assert_eq!(cmd(&mut r, &["GET", "before"]), RespFrame::bulk("2"));
+        assert_eq!(
+            cmd(&mut r, &["LRANGE", "list", "0", "-1"]),
+            RespFrame::array([RespFrame::bulk("b")])
+        );
+        assert!(!replica.exists(b"ttl"));
+
+        assert_eq!(
+            cmd(&mut r, &["SET", "k", "v"]),
+            RespFrame::error("READONLY You can't write against a read only replica.")
+        );
+        let RespFrame::VerbatimString(_, info) = cmd(&mut r, &["INFO", "replication"]) else {
+            panic!("INFO should reply with text");
+        };
+        let info = String::from_utf8_lossy(&info);
+        assert!(info.contains("role:slave\r\n"));
+        assert!(info.contains("master_link_status:up\r\n"));
+        eventually(|| master.info_replication().contains(",state=online,")).await;
+
+        assert_eq!(
+            cmd(&mut r, &["REPLICAOF", "127.0.0.1", &port]),
+            RespFrame::simple("OK Already connected to specified master")
+        );
+        assert_eq!(cmd(&mut r, &["REPLICAOF", "NO", "ONE"]), RespFrame::ok());
+        assert_eq!(cmd(&mut r, &["SET", "k", "v"]), RespFrame::ok());
+        assert!(replica.info_replication().contains("role:master\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_psync_continues_from_backlog() -> Result<()> {
+        let master = Backend::new();
+        let addr = listen(&master).await?;
+        let mut m = Session::new(master.clone());
+        cmd(&mut m, &["SET", "a", "1"]);
+
+        // a full sync first, the snapshot has what was there before
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buf = BytesMut::new();
+        send(&mut stream, &["PSYNC", "?", "-1"]).await?;
+        let reply = read_line(&mut stream, &mut buf).await?;
+        let (replid, offset) = master.repl_offset();
+        assert_eq!(reply, format!("FULLRESYNC {} {}", replid, offset));
+        let rdb = read_rdb_payload(&mut stream, &mut buf).await?;
+        assert_eq!(snapshot_len(&decode_rdb(&rdb)?), 1);
+        drop(stream);
+
+        // writes while the replica is away are kept in the backlog, the
+        // first one after a full sync selects its database
+        cmd(&mut m, &["SET", "b", "2"]);
+        let mut set = RespFrame::command(["SELECT", "0"].map(Bytes::from))
+            .to_bytes()
+            .to_vec();
+        set.extend_from_slice(&RespFrame::command(["SET", "b", "2"].map(Bytes::from)).to_bytes());
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buf = BytesMut::new();
+        let next = (offset + 1).to_string();
+        send(&mut stream, &["PSYNC", &replid, &next]).await?;
+        assert_eq!(
+            read_line(&mut stream, &mut buf).await?,
+            format!("CONTINUE {}", replid)
+        );
+        while buf.len() < set.len() {
+            stream.read_buf(&mut buf).await?;
+        }
+        assert_eq!(&buf[..], &set[..]);
+        assert_eq!(master.repl_offset().1, offset + set.len() as u64);
+
+        // an unknown id or an offset out of the backlog needs a full sync
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut buf = BytesMut::new();
+        send(&mut stream, &["PSYNC", &replid, "1000000"]).await?;
+        assert!(read_line(&mut stream, &mut buf)
+            .await?
+            .starts_with("FULLRESYNC "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lagging_replica_is_dropped() {
+        let backend = Backend::new();
+        backend.set_output_limit(crate::ClientClass::Replica, OutputLimit::new(10, 0, 0));
+        let replica = Session::new(backend.clone());
+        let (_, feed) = {
+            let _exclusive = backend.exec_lock.write().unwrap();
+            backend.psync(&replica, "?", -1).unwrap()
+        };
+        backend.repl.feed(Bytes::from("12345"));
+        assert_eq!(backend.repl.state.lock().unwrap().replicas.len(), 1);
+        backend.repl.feed(Bytes::from("678901"));
+        assert!(backend.repl.state.lock().unwrap().replicas.is_empty());
+        drop(feed);
+    }
+
+    #[test]
+    fn test_backlog_is_bounded() {
+        let backend = Backend::new();
+        backend.set_repl_backlog_size(10);
+        let (replid, _) = backend.repl_offset();
+        backend.repl.reset(replid.clone(), 0);
+        backend.repl.feed(Bytes::from("0123456789abcdef"));
+        let state = backend.repl.state.lock().unwrap();
+        assert_eq!(state.offset, 16);
+        assert_eq!(state.backlog_first_offset(), 7);
+        assert!(state.can_continue(&replid, 7));
+        assert!(state.can_continue(&replid, 17));
+        assert!(!state.can_continue(&replid, 6));
+        assert!(!state.can_continue("other", 10));
+    }
+}
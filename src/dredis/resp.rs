@@ -0,0 +1,707 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const CRLF: &[u8] = b"\r\n";
+// same limits as redis: proto-max-bulk-len and the multibulk length check
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+// aggregates nested deeper than this are refused before they exhaust the stack
+const MAX_DEPTH: usize = 128;
+// the shortest frame is three bytes, "_\r\n"
+const MIN_FRAME_LEN: usize = 3;
+// a line without its CRLF, as proto-inline-max-size
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Protocol version negotiated by a connection with `HELLO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RespVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespFrame {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Bytes),
+    NullBulkString,
+    Array(Vec<RespFrame>),
+    NullArray,
+    // RESP3 only types, downgraded to their RESP2 equivalents for RESP2 clients
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    BulkError(String),
+    VerbatimString(String, Bytes),
+    Map(Vec<(RespFrame, RespFrame)>),
+    Set(Vec<RespFrame>),
+    Push(Vec<RespFrame>),
+    Attribute(Vec<(RespFrame, RespFrame)>, Box<RespFrame>),
+}
+
+impl RespFrame {
+    pub fn ok() -> Self {
+        RespFrame::SimpleString("OK".to_string())
+    }
+
+    pub fn simple(s: impl Into<String>) -> Self {
+        RespFrame::SimpleString(s.into())
+    }
+
+    pub fn error(s: impl Into<String>) -> Self {
+        RespFrame::Error(s.into())
+    }
+
+    pub fn bulk(b: impl Into<Bytes>) -> Self {
+        RespFrame::BulkString(b.into())
+    }
+
+    pub fn array(items: impl Into<Vec<RespFrame>>) -> Self {
+        RespFrame::Array(items.into())
+    }
+
+    pub fn map(items: impl Into<Vec<(RespFrame, RespFrame)>>) -> Self {
+        RespFrame::Map(items.into())
+    }
+
+    /// A command as clients send it, an array of bulk strings.
+    pub fn command(args: impl IntoIterator<Item = Bytes>) -> Self {
+        RespFrame::Array(args.into_iter().map(RespFrame::BulkString).collect())
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(
+            self,
+            RespFrame::Null | RespFrame::NullBulkString | RespFrame::NullArray
+        )
+    }
+
+    /// Encode the frame into the end of `buf` using its own wire type.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        self.write(None, buf)
+    }
+
+    /// Encode the frame for a client speaking `version`: RESP3 only types are
+    /// written the way redis replies to a RESP2 client, and RESP2 nulls become
+    /// the RESP3 null for a RESP3 client.
+    pub fn encode_with(&self, version: RespVersion, buf: &mut BytesMut) {
+        self.write(Some(version), buf)
+    }
+
+    fn write(&self, version: Option<RespVersion>, buf: &mut BytesMut) {
+        let resp3 = version == Some(RespVersion::Resp3);
+        let resp2 = version == Some(RespVersion::Resp2);
+        match self {
+            RespFrame::SimpleString(s) => put_line(buf, b'+', s.as_bytes()),
+            RespFrame::Error(s) => put_line(buf, b'-', s.as_bytes()),
+            RespFrame::Integer(n) => put_line(buf, b':', n.to_string().as_bytes()),
+            RespFrame::BulkString(b) => put_blob(buf, b'$', b),
+            RespFrame::NullBulkString if resp3 => buf.put_slice(b"_\r\n"),
+            RespFrame::NullBulkString => buf.put_slice(b"$-1\r\n"),
+            RespFrame::Array(items) => put_aggregate(buf, b'*', items, version),
+            RespFrame::NullArray if resp3 => buf.put_slice(b"_\r\n"),
+            RespFrame::NullArray => buf.put_slice(b"*-1\r\n"),
+            RespFrame::Null if !resp2 => buf.put_slice(b"_\r\n"),
+            RespFrame::Null => buf.put_slice(b"$-1\r\n"),
+            RespFrame::Boolean(b) if !resp2 => {
+                buf.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" })
+            }
+            RespFrame::Boolean(b) => put_line(buf, b':', if *b { b"1" } else { b"0" }),
+            RespFrame::Double(d) if !resp2 => put_line(buf, b',', format_double(*d).as_bytes()),
+            RespFrame::Double(d) => put_blob(buf, b'$', format_double(*d).as_bytes()),
+            RespFrame::BigNumber(n) if !resp2 => put_line(buf, b'(', n.as_bytes()),
+            RespFrame::BigNumber(n) => put_blob(buf, b'$', n.as_bytes()),
+            RespFrame::BulkError(s) if !resp2 => put_blob(buf, b'!', s.as_bytes()),
+            RespFrame::BulkError(s) => put_line(buf, b'-', s.as_bytes()),
+            RespFrame::VerbatimString(format, data) if !resp2 => {
+                let mut blob = Vec::with_capacity(format.len() + 1 + data.len());
+                blob.extend_from_slice(format.as_bytes());
+                blob.push(b':');
+                blob.extend_from_slice(data);
+                put_blob(buf, b'=', &blob);
+            }
+            RespFrame::VerbatimString(_, data) => put_blob(buf, b'$', data),
+            RespFrame::Map(pairs) => {
+                if resp2 {
+                    put_line(buf, b'*', (pairs.len() * 2).to_string().as_bytes());
+                } else {
+                    put_line(buf, b'%', pairs.len().to_string().as_bytes());
+                }
+                for (k, v) in pairs {
+                    k.write(version, buf);
+                    v.write(version, buf);
+                }
+            }
+            RespFrame::Set(items) if !resp2 => put_aggregate(buf, b'~', items, version),
+            RespFrame::Push(items) if !resp2 => put_aggregate(buf, b'>', items, version),
+            RespFrame::Set(items) | RespFrame::Push(items) => {
+                put_aggregate(buf, b'*', items, version)
+            }
+            RespFrame::Attribute(attrs, frame) => {
+                if !resp2 {
+                    put_line(buf, b'|', attrs.len().to_string().as_bytes());
+                    for (k, v) in attrs {
+                        k.write(version, buf);
+                        v.write(version, buf);
+                    }
+                }
+                frame.write(version, buf);
+            }
+        }
+    }
+
+    pub fn to_bytes_with(&self, version: RespVersion) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.encode_with(version, &mut buf);
+        buf.freeze()
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        buf.freeze()
+    }
+
+    /// Try to decode one frame from the front of `buf`.
+    ///
+    /// Returns `Ok(None)` and leaves `buf` untouched when the frame is not complete
+    /// yet, so the caller can read more bytes and try again. On success the frame's
+    /// bytes are consumed, any pipelined frames after it stay in `buf`.
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<RespFrame>> {
+        match parse(buf, 0, 0)? {
+            Some((frame, end)) => {
+                buf.advance(end);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Decodes the frames arriving on a connection. A frame split over many
+/// reads is checked from where the last read stopped instead of from its
+/// start, and parsed once all of it is there.
+#[derive(Debug, Default)]
+pub struct RespDecoder {
+    /// Where the frame at the front of the buffer is checked up to.
+    checked: usize,
+    /// The items still missing in each aggregate open at `checked`.
+    missing: Vec<usize>,
+}
+
+impl RespDecoder {
+    /// `RespFrame::decode` for a buffer that only grows at its end between
+    /// calls.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RespFrame>> {
+        if !self.complete(buf)? {
+            return Ok(None);
+        }
+        self.checked = 0;
+        RespFrame::decode(buf)
+    }
+
+    /// Whether the frame at the front of `buf` is all there. Only the
+    /// header of a bulk string is read until its data arrived.
+    fn complete(&mut self, buf: &[u8]) -> Result<bool> {
+        loop {
+            if buf.len() <= self.checked {
+                return Ok(false);
+            }
+            if self.missing.len() > MAX_DEPTH {
+                return Err(anyhow!("Protocol error: too many nested aggregates"));
+            }
+            let prefix = buf[self.checked];
+            let Some((line, next)) = read_line(buf, self.checked + 1)? else {
+                return Ok(false);
+            };
+            let items = match prefix {
+                b'$' | b'!' | b'=' if line != b"-1" => {
+                    let len = parse_int(line)?;
+                    if !(0..=MAX_BULK_LEN).contains(&len) {
+                        return Err(anyhow!("Protocol error: invalid bulk length"));
+                    }
+                    let end = next + len as usize + CRLF.len();
+                    if buf.len() < end {
+                        return Ok(false);
+                    }
+                    self.checked = end;
+                    0
+                }
+                b'*' | b'~' | b'>' if line != b"-1" => parse_len(line)?,
+                b'%' => parse_len(line)? * 2,
+                // and the reply the attribute describes
+                b'|' => parse_len(line)? * 2 + 1,
+                _ => 0,
+            };
+            if self.checked < next {
+                self.checked = next;
+            }
+            if items > 0 {
+                self.missing.push(items);
+                continue;
+            }
+            // the frame ends here, so may the aggregates it is the last item of
+            loop {
+                match self.missing.last_mut() {
+                    None => return Ok(true),
+                    Some(1) => {
+                        self.missing.pop();
+                    }
+                    Some(n) => {
+                        *n -= 1;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn put_line(buf: &mut BytesMut, prefix: u8, line: &[u8]) {
+    buf.put_u8(prefix);
+    buf.put_slice(line);
+    buf.put_slice(CRLF);
+}
+
+fn put_blob(buf: &mut BytesMut, prefix: u8, data: &[u8]) {
+    put_line(buf, prefix, data.len().to_string().as_bytes());
+    buf.put_slice(data);
+    buf.put_slice(CRLF);
+}
+
+fn put_aggregate(
+    buf: &mut BytesMut,
+    prefix: u8,
+    items: &[RespFrame],
+    version: Option<RespVersion>,
+) {
+    put_line(buf, prefix, items.len().to_string().as_bytes());
+    for item in items {
+        item.write(version, buf);
+    }
+}
+
+/// Format a double the way redis does: `inf`, `-inf`, `nan` or the shortest
+/// representation that round trips.
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+type Parsed = Option<(RespFrame, usize)>;
+
+/// Parse the frame at `pos`, nested `depth` aggregates deep.
+fn parse(buf: &[u8], pos: usize, depth: usize) -> Result<Parsed> {
+    let Some(&prefix) = buf.get(pos) else {
+        return Ok(None);
+    };
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Protocol error: too many nested aggregates"));
+    }
+    let Some((line, next)) = read_line(buf, pos + 1)? else {
+        return Ok(None);
+    };
+    let frame = match prefix {
+        b'+' => RespFrame::SimpleString(parse_str(line)?),
+        b'-' => RespFrame::Error(parse_str(line)?),
+        b':' => RespFrame::Integer(parse_int(line)?),
+        b'_' => RespFrame::Null,
+        b'#' => match line {
+            b"t" => RespFrame::Boolean(true),
+            b"f" => RespFrame::Boolean(false),
+            _ => return Err(anyhow!("Protocol error: invalid boolean")),
+        },
+        b',' => RespFrame::Double(parse_double(line)?),
+        b'(' => RespFrame::BigNumber(parse_str(line)?),
+        b'$' if line == b"-1" => RespFrame::NullBulkString,
+        b'*' if line == b"-1" => RespFrame::NullArray,
+        b'$' | b'!' | b'=' => {
+            let Some((data, end)) = parse_blob(buf, line, next)? else {
+                return Ok(None);
+            };
+            let frame = match prefix {
+                b'$' => RespFrame::BulkString(Bytes::copy_from_slice(data)),
+                b'!' => RespFrame::BulkError(parse_str(data)?),
+                _ => {
+                    if data.len() < 4 || data[3] != b':' {
+                        return Err(anyhow!("Protocol error: invalid verbatim string"));
+                    }
+                    RespFrame::VerbatimString(
+                        parse_str(&data[..3])?,
+                        Bytes::copy_from_slice(&data[4..]),
+                    )
+                }
+            };
+            return Ok(Some((frame, end)));
+        }
+        b'*' | b'~' | b'>' => {
+            let Some((items, end)) = parse_items(buf, parse_len(line)?, next, depth)? else {
+                return Ok(None);
+            };
+            let frame = match prefix {
+                b'*' => RespFrame::Array(items),
+                b'~' => RespFrame::Set(items),
+                _ => RespFrame::Push(items),
+            };
+            return Ok(Some((frame, end)));
+        }
+        b'%' | b'|' => {
+            let Some((items, end)) = parse_items(buf, parse_len(line)? * 2, next, depth)? else {
+                return Ok(None);
+            };
+            let pairs = into_pairs(items);
+            if prefix == b'%' {
+                return Ok(Some((RespFrame::Map(pairs), end)));
+            }
+            // an attribute is followed by the reply it describes
+            return Ok(parse(buf, end, depth + 1)?
+                .map(|(frame, end)| (RespFrame::Attribute(pairs, Box::new(frame)), end)));
+        }
+        other => {
+            return Err(anyhow!(
+                "Protocol error: unexpected type byte '{}'",
+                other as char
+            ))
+        }
+    };
+    Ok(Some((frame, next)))
+}
+
+/// Parse the payload of a length prefixed string whose header line is `line`.
+fn parse_blob<'a>(buf: &'a [u8], line: &[u8], next: usize) -> Result<Option<(&'a [u8], usize)>> {
+    let len = parse_int(line)?;
+    if !(0..=MAX_BULK_LEN).contains(&len) {
+        return Err(anyhow!("Protocol error: invalid bulk length"));
+    }
+    let end = next + len as usize;
+    if buf.len() < end + CRLF.len() {
+        return Ok(None);
+    }
+    if &buf[end..end + CRLF.len()] != CRLF {
+        return Err(anyhow!("Protocol error: expected '\\r\\n' after bulk data"));
+    }
+    Ok(Some((&buf[next..end], end + CRLF.len())))
+}
+
+fn parse_items(
+    buf: &[u8],
+    len: usize,
+    mut pos: usize,
+    depth: usize,
+) -> Result<Option<(Vec<RespFrame>, usize)>> {
+    // the header alone can't make us allocate more than the bytes received
+    let mut items = Vec::with_capacity(len.min(buf.len().saturating_sub(pos) / MIN_FRAME_LEN));
+    for _ in 0..len {
+        match parse(buf, pos, depth + 1)? {
+            Some((item, end)) => {
+                items.push(item);
+                pos = end;
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((items, pos)))
+}
+
+fn into_pairs(items: Vec<RespFrame>) -> Vec<(RespFrame, RespFrame)> {
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut iter = items.into_iter();
+    while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+        pairs.push((k, v));
+    }
+    pairs
+}
+
+/// Find the `\r\n` terminated line starting at `pos`, returning the line without
+/// the terminator and the position right after it. Lines longer than
+/// `MAX_LINE_LEN` are an error, found without looking further.
+fn read_line(buf: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>> {
+    let Some(rest) = buf.get(pos..) else {
+        return Ok(None);
+    };
+    let head = &rest[..rest.len().min(MAX_LINE_LEN + CRLF.len())];
+    match head.windows(2).position(|w| w == CRLF) {
+        Some(idx) => Ok(Some((&rest[..idx], pos + idx + CRLF.len()))),
+        None if head.len() < rest.len() || head.len() == MAX_LINE_LEN + CRLF.len() => {
+            Err(anyhow!("Protocol error: too big line"))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_str(line: &[u8]) -> Result<String> {
+    Ok(std::str::from_utf8(line)?.to_string())
+}
+
+fn parse_len(line: &[u8]) -> Result<usize> {
+    let len = parse_int(line)?;
+    if !(0..=MAX_ARRAY_LEN).contains(&len) {
+        return Err(anyhow!("Protocol error: invalid multibulk length"));
+    }
+    Ok(len as usize)
+}
+
+fn parse_double(line: &[u8]) -> Result<f64> {
+    match line {
+        b"inf" => Ok(f64::INFINITY),
+        b"-inf" => Ok(f64::NEG_INFINITY),
+        b"nan" => Ok(f64::NAN),
+        _ => std::str::from_utf8(line)?
+            .parse()
+            .map_err(|_| anyhow!("Protocol error: invalid double {:?}", line)),
+    }
+}
+
+fn parse_int(line: &[u8]) -> Result<i64> {
+    std::str::from_utf8(line)?
+        .parse()
+        .map_err(|_| anyhow!("Protocol error: invalid integer {:?}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(data: &[u8]) -> Result<Vec<RespFrame>> {
+        let mut buf = BytesMut::from(data);
+        let mut frames = Vec::new();
+        while let Some(frame) = RespFrame::decode(&mut buf)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn test_encode_resp2_frames() {
+        assert_eq!(RespFrame::ok().to_bytes(), "+OK\r\n");
+        assert_eq!(RespFrame::error("ERR oops").to_bytes(), "-ERR oops\r\n");
+        assert_eq!(RespFrame::Integer(-42).to_bytes(), ":-42\r\n");
+        assert_eq!(RespFrame::bulk("hello").to_bytes(), "$5\r\nhello\r\n");
+        assert_eq!(RespFrame::bulk("").to_bytes(), "$0\r\n\r\n");
+        assert_eq!(RespFrame::NullBulkString.to_bytes(), "$-1\r\n");
+        assert_eq!(RespFrame::NullArray.to_bytes(), "*-1\r\n");
+        let frame = RespFrame::array([RespFrame::bulk("GET"), RespFrame::Integer(1)]);
+        assert_eq!(frame.to_bytes(), "*2\r\n$3\r\nGET\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_decode_roundtrip() -> Result<()> {
+        let frame = RespFrame::array([
+            RespFrame::simple("OK"),
+            RespFrame::error("ERR bad"),
+            RespFrame::Integer(7),
+            RespFrame::bulk("a\r\nb"),
+            RespFrame::NullBulkString,
+            RespFrame::array([]),
+            RespFrame::NullArray,
+        ]);
+        assert_eq!(decode_all(&frame.to_bytes())?, vec![frame]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_partial_frame() -> Result<()> {
+        let data = b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n";
+        let mut buf = BytesMut::new();
+        for (i, &b) in data.iter().enumerate() {
+            buf.put_u8(b);
+            let frame = RespFrame::decode(&mut buf)?;
+            if i == data.len() - 1 {
+                assert_eq!(
+                    frame,
+                    Some(RespFrame::array([
+                        RespFrame::bulk("GET"),
+                        RespFrame::bulk("hello")
+                    ]))
+                );
+                assert!(buf.is_empty());
+            } else {
+                assert_eq!(frame, None);
+                assert_eq!(buf.len(), i + 1);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_pipelined_frames() -> Result<()> {
+        let frames = decode_all(b"+PONG\r\n:1\r\n$-1\r\n*1\r\n$4\r\nPING\r\n")?;
+        assert_eq!(
+            frames,
+            vec![
+                RespFrame::simple("PONG"),
+                RespFrame::Integer(1),
+                RespFrame::NullBulkString,
+                RespFrame::array([RespFrame::bulk("PING")]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_resp3_frames() {
+        let v3 = RespVersion::Resp3;
+        assert_eq!(RespFrame::Null.to_bytes_with(v3), "_\r\n");
+        assert_eq!(RespFrame::NullBulkString.to_bytes_with(v3), "_\r\n");
+        assert_eq!(RespFrame::Boolean(true).to_bytes_with(v3), "#t\r\n");
+        assert_eq!(RespFrame::Double(1.5).to_bytes_with(v3), ",1.5\r\n");
+        assert_eq!(
+            RespFrame::Double(f64::NEG_INFINITY).to_bytes_with(v3),
+            ",-inf\r\n"
+        );
+        assert_eq!(
+            RespFrame::BigNumber("12345678901234567890".into()).to_bytes_with(v3),
+            "(12345678901234567890\r\n"
+        );
+        assert_eq!(
+            RespFrame::BulkError("SYNTAX invalid".into()).to_bytes_with(v3),
+            "!14\r\nSYNTAX invalid\r\n"
+        );
+        assert_eq!(
+            RespFrame::VerbatimString("txt".into(), "Some".into()).to_bytes_with(v3),
+            "=8\r\ntxt:Some\r\n"
+        );
+        let map = RespFrame::map([(RespFrame::bulk("f"), RespFrame::bulk("v"))]);
+        assert_eq!(map.to_bytes_with(v3), "%1\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        let set = RespFrame::Set(vec![RespFrame::Integer(1)]);
+        assert_eq!(set.to_bytes_with(v3), "~1\r\n:1\r\n");
+        let push = RespFrame::Push(vec![RespFrame::bulk("message")]);
+        assert_eq!(push.to_bytes_with(v3), ">1\r\n$7\r\nmessage\r\n");
+    }
+
+    #[test]
+    fn test_encode_resp3_frames_for_resp2() {
+        let v2 = RespVersion::Resp2;
+        assert_eq!(RespFrame::Null.to_bytes_with(v2), "$-1\r\n");
+        assert_eq!(RespFrame::Boolean(false).to_bytes_with(v2), ":0\r\n");
+        assert_eq!(RespFrame::Double(3.25).to_bytes_with(v2), "$4\r\n3.25\r\n");
+        assert_eq!(
+            RespFrame::VerbatimString("txt".into(), "Some".into()).to_bytes_with(v2),
+            "$4\r\nSome\r\n"
+        );
+        let map = RespFrame::map([(RespFrame::bulk("f"), RespFrame::Integer(1))]);
+        assert_eq!(map.to_bytes_with(v2), "*2\r\n$1\r\nf\r\n:1\r\n");
+        let attr = RespFrame::Attribute(
+            vec![(RespFrame::simple("ttl"), RespFrame::Integer(3))],
+            Box::new(RespFrame::Integer(1)),
+        );
+        assert_eq!(attr.to_bytes_with(v2), ":1\r\n");
+    }
+
+    #[test]
+    fn test_decode_resp3_roundtrip() -> Result<()> {
+        let frame = RespFrame::array([
+            RespFrame::Null,
+            RespFrame::Boolean(false),
+            RespFrame::Double(-0.5),
+            RespFrame::BigNumber("-3492890328409238509324850943850943825024385".into()),
+            RespFrame::BulkError("ERR x".into()),
+            RespFrame::VerbatimString("mkd".into(), "# title".into()),
+            RespFrame::map([(RespFrame::simple("k"), RespFrame::Set(vec![]))]),
+            RespFrame::Push(vec![RespFrame::bulk("pubsub")]),
+            RespFrame::Attribute(
+                vec![(RespFrame::simple("key"), RespFrame::Integer(1))],
+                Box::new(RespFrame::bulk("value")),
+            ),
+        ]);
+        assert_eq!(decode_all(&frame.to_bytes())?, vec![frame]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_invalid_frame() {
+        assert!(decode_all(b"?1\r\n").is_err());
+        assert!(decode_all(b":abc\r\n").is_err());
+        assert!(decode_all(b"$3\r\nabcd\r\n").is_err());
+        assert!(decode_all(b"*-2\r\n").is_err());
+        assert!(decode_all(b"#x\r\n").is_err());
+        assert!(decode_all(b"=3\r\nabc\r\n").is_err());
+    }
+
+    #[test]
+    fn test_decoder_resumes() -> Result<()> {
+        let frame = RespFrame::array([
+            RespFrame::bulk("x".repeat(1000)),
+            RespFrame::map([(RespFrame::simple("k"), RespFrame::array([]))]),
+            RespFrame::Attribute(
+                vec![(RespFrame::simple("a"), RespFrame::Integer(1))],
+                Box::new(RespFrame::NullBulkString),
+            ),
+        ]);
+        let mut data = frame.to_bytes().to_vec();
+        data.extend_from_slice(b":2\r\n");
+        let mut decoder = RespDecoder::default();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        let mut checked = 0;
+        for chunk in data.chunks(7) {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = decoder.decode(&mut buf)? {
+                frames.push(frame);
+            }
+            // the check goes on where it stopped
+            assert!(decoder.checked >= checked || decoder.checked == 0);
+            checked = decoder.checked;
+        }
+        assert_eq!(frames, vec![frame, RespFrame::Integer(2)]);
+        assert!(buf.is_empty());
+
+        // the data of a bulk string is not looked at until it is all there
+        let mut buf = BytesMut::from(&b"*2\r\n$5\r\nhel"[..]);
+        assert_eq!(decoder.decode(&mut buf)?, None);
+        assert_eq!(decoder.checked, 4);
+        buf.extend_from_slice(b"lo\r\n");
+        assert_eq!(decoder.decode(&mut buf)?, None);
+        assert_eq!(decoder.checked, 15);
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let long = vec![b'1'; MAX_LINE_LEN + 10];
+        let mut buf = BytesMut::from(&b"*"[..]);
+        buf.extend_from_slice(&long);
+        assert!(RespFrame::decode(&mut buf).is_err());
+        assert!(RespDecoder::default().decode(&mut buf).is_err());
+        let mut line = b"+".to_vec();
+        line.extend_from_slice(&long);
+        line.extend_from_slice(CRLF);
+        assert!(decode_all(&line).is_err());
+        // up to the limit a line is waited for
+        let mut buf = BytesMut::from(&b"+"[..]);
+        buf.extend_from_slice(&vec![b'a'; MAX_LINE_LEN]);
+        assert_eq!(RespDecoder::default().decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(CRLF);
+        assert!(RespDecoder::default().decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_decode_deeply_nested_frame() -> Result<()> {
+        let nested = |depth: usize| {
+            let mut data = b"*1\r\n".repeat(depth);
+            data.extend_from_slice(b":1\r\n");
+            data
+        };
+        let mut frame = RespFrame::Integer(1);
+        for _ in 0..MAX_DEPTH {
+            frame = RespFrame::array([frame]);
+        }
+        assert_eq!(decode_all(&nested(MAX_DEPTH))?, vec![frame]);
+        assert!(decode_all(&nested(MAX_DEPTH + 1)).is_err());
+        // refused long before the stack runs out
+        assert!(decode_all(&nested(200_000)).is_err());
+        // an incomplete deep frame is refused as well, not buffered
+        assert!(decode_all(&b"*1\r\n".repeat(200_000)).is_err());
+        let mut buf = BytesMut::from(&b"*1\r\n".repeat(200_000)[..]);
+        assert!(RespDecoder::default().decode(&mut buf).is_err());
+        Ok(())
+    }
+}
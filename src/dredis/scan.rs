@@ -0,0 +1,208 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeSet},
+    hash::BuildHasher,
+    sync::OnceLock,
+};
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::{glob_match, Backend};
+
+/// Aggregates up to this size are scanned in one go, like redis does for its
+/// listpack encoded ones.
+const SCAN_SMALL_AGGREGATE: usize = 128;
+
+/// The hash that orders keys and elements for `SCAN` and friends. Seeded once
+/// per process, so a cursor is only good on the server that returned it.
+pub(crate) fn scan_hash(key: &[u8]) -> u64 {
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    STATE.get_or_init(RandomState::new).hash_one(key)
+}
+
+/// Every key of a database in hash order.
+///
+/// A `SCAN` cursor is the hash to resume from: the order does not depend on
+/// how many keys there are, so a key present for the whole iteration is
+/// returned exactly once however much the keyspace grows or shrinks in
+/// between. That is redis' guarantee without its reverse binary cursor,
+/// which only exists to survive the rehashing of its tables.
+#[derive(Debug, Default)]
+pub(crate) struct ScanIndex {
+    keys: BTreeSet<(u64, Bytes)>,
+}
+
+impl ScanIndex {
+    pub(crate) fn insert(&mut self, key: &Bytes) {
+        self.keys.insert((scan_hash(key), key.clone()));
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        let hash = scan_hash(key);
+        // keys sharing a hash sit next to each other
+        let found = self
+            .keys
+            .range((hash, Bytes::new())..)
+            .take_while(|(h, _)| *h == hash)
+            .find(|(_, k)| k == key)
+            .cloned();
+        if let Some(found) = found {
+            self.keys.remove(&found);
+        }
+    }
+
+    /// At least `count` keys from `cursor` on, and the cursor to continue
+    /// from, 0 once done.
+    pub(crate) fn scan(&self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        next_page(
+            self.keys
+                .range((cursor, Bytes::new())..)
+                .map(|(h, k)| (*h, k.clone())),
+            count,
+        )
+    }
+
+    /// Up to `n` distinct keys: a run of keys from a random point, like redis'
+    /// `dictGetSomeKeys`, which is as random as the hash.
+    pub(crate) fn sample(&self, n: usize) -> Vec<Bytes> {
+        if n >= self.keys.len() {
+            return self.keys.iter().map(|(_, k)| k.clone()).collect();
+        }
+        let start = rand::thread_rng().gen::<u64>();
+        self.keys
+            .range((start, Bytes::new())..)
+            .chain(&self.keys)
+            .take(n)
+            .map(|(_, k)| k.clone())
+            .collect()
+    }
+}
+
+/// At least `count` of `items`, in hash order, and the cursor to continue
+/// from: every item sharing a hash goes in the same page.
+fn next_page<T>(items: impl Iterator<Item = (u64, T)>, count: usize) -> (Vec<T>, u64) {
+    let mut page = Vec::new();
+    let mut last = None;
+    for (hash, item) in items {
+        if page.len() >= count && last != Some(hash) {
+            return (page, hash);
+        }
+        page.push(item);
+        last = Some(hash);
+    }
+    (page, 0)
+}
+
+/// A page of an aggregate, which has no index in hash order: small ones come
+/// whole, larger ones are sorted by hash on every call.
+pub(crate) fn scan_aggregate<T>(
+    items: impl ExactSizeIterator<Item = T>,
+    key: impl Fn(&T) -> &[u8],
+    cursor: u64,
+    count: usize,
+) -> (Vec<T>, u64) {
+    if items.len() <= SCAN_SMALL_AGGREGATE {
+        return (items.collect(), 0);
+    }
+    let mut items = items
+        .map(|item| (scan_hash(key(&item)), item))
+        .filter(|(hash, _)| *hash >= cursor)
+        .collect::<Vec<_>>();
+    items.sort_unstable_by_key(|(hash, _)| *hash);
+    next_page(items.into_iter(), count)
+}
+
+impl Backend {
+    /// A page of `SCAN` over the current database: live keys only.
+    pub fn scan(&self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        let (mut keys, cursor) = self.db().keys.lock().unwrap().scan(cursor, count);
+        keys.retain(|key| !self.remove_expired(key));
+        (keys, cursor)
+    }
+
+    /// The live keys of the current database matching the glob `pattern`.
+    pub fn keys_matching(&self, pattern: &[u8]) -> Vec<Bytes> {
+        let now = self.now_ms();
+        self.db()
+            .data
+            .iter()
+            .filter(|e| !e.value().is_expired(now) && glob_match(pattern, e.key()))
+            .map(|e| e.key().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn index(keys: impl IntoIterator<Item = String>) -> ScanIndex {
+        let mut index = ScanIndex::default();
+        for key in keys {
+            index.insert(&Bytes::from(key));
+        }
+        index
+    }
+
+    #[test]
+    fn test_scan_survives_growing_and_shrinking() {
+        let mut index = index((0..1000).map(|i| format!("stable:{}", i)));
+        let mut seen = HashSet::new();
+        let (mut cursor, mut calls) = (0, 0);
+        loop {
+            let (page, next) = index.scan(cursor, 10);
+            seen.extend(page);
+            calls += 1;
+            // the keyspace changes size under the iteration
+            if calls % 2 == 0 {
+                for i in 0..50 {
+                    index.insert(&format!("new:{}:{}", calls, i).into());
+                }
+            } else {
+                for i in 0..50 {
+                    index.remove(format!("new:{}:{}", calls - 1, i).as_bytes());
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!((0..1000).all(|i| seen.contains(format!("stable:{}", i).as_bytes())));
+    }
+
+    #[test]
+    fn test_sample_is_distinct() {
+        let mut index = index((0..100).map(|i| i.to_string()));
+        let sample = index.sample(30);
+        assert_eq!(sample.iter().collect::<HashSet<_>>().len(), 30);
+        assert_eq!(index.sample(1000).len(), 100);
+        index.remove(b"7");
+        index.remove(b"missing");
+        assert_eq!(index.sample(1000).len(), 99);
+    }
+
+    #[test]
+    fn test_scan_aggregate() {
+        let small = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        let (page, cursor) = scan_aggregate(small.iter(), |s| s.as_bytes(), 0, 1);
+        assert_eq!((page.len(), cursor), (10, 0));
+
+        let big = (0..1000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (page, next) = scan_aggregate(big.iter(), |s| s.as_bytes(), cursor, 100);
+            seen.extend(page);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 1000);
+    }
+}
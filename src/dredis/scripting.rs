@@ -0,0 +1,589 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LockResult, Mutex, RwLock, TryLockResult,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value as LuaValue,
+    Variadic,
+};
+use sha1::{Digest, Sha1};
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task::block_in_place,
+};
+use tracing::{debug, info, warn};
+
+use crate::{format_double, Backend, RespFrame};
+
+/// How long a script runs before other clients get `BUSY`, unless
+/// `busy-reply-threshold` says otherwise.
+pub const DEFAULT_BUSY_REPLY_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Lua instructions between two looks at whether the script was killed.
+const KILL_CHECK_INSTRUCTIONS: u32 = 10_000;
+
+/// How often a command waiting for the exec lock looks whether the script
+/// holding it became busy.
+const BUSY_POLL: Duration = Duration::from_millis(1);
+
+const ERR_KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+/// Run once in every interpreter. `redis.call` raises the errors
+/// `redis.pcall` returns, then the globals are locked: a script may not
+/// leave state behind for the next one.
+const PRELUDE: &str = r#"
+function redis.call(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+
+function redis.error_reply(err)
+    return {err = err}
+end
+
+function redis.status_reply(status)
+    return {ok = status}
+end
+
+loadfile = nil
+dofile = nil
+
+setmetatable(_G, {
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+})
+"#;
+
+/// The SHA1 of a script body, in hex: the name `EVALSHA` knows it by.
+pub(crate) fn sha1_hex(body: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(body))
+}
+
+/// The Lua interpreter every script runs in, one at a time, and what it
+/// knows about scripts.
+#[derive(Debug)]
+pub(crate) struct Scripting {
+    interpreter: Mutex<Interpreter>,
+    /// When the running script started.
+    running: Mutex<Option<Instant>>,
+    /// Whether the running script called a write command, it can't be
+    /// killed anymore then.
+    wrote: AtomicBool,
+    /// Set by `SCRIPT KILL`, the interpreter's hook sees it and fails the
+    /// script.
+    kill: Arc<AtomicBool>,
+    busy_threshold_ms: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Interpreter {
+    lua: Lua,
+    /// The compiled scripts by their SHA1.
+    scripts: HashMap<String, RegistryKey>,
+    /// Lua's `pcall` as it was before any script could replace it.
+    pcall: RegistryKey,
+}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        let kill = Arc::new(AtomicBool::new(false));
+        let interpreter = Interpreter::new(kill.clone()).expect("the Lua interpreter starts");
+        Scripting {
+            interpreter: Mutex::new(interpreter),
+            running: Mutex::new(None),
+            wrote: AtomicBool::new(false),
+            kill,
+            busy_threshold_ms: AtomicU64::new(DEFAULT_BUSY_REPLY_THRESHOLD.as_millis() as u64),
+        }
+    }
+}
+
+impl Interpreter {
+    fn new(kill: Arc<AtomicBool>) -> mlua::Result<Self> {
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+            LuaOptions::default(),
+        )?;
+        let redis = lua.create_table()?;
+        redis.raw_set(
+            "sha1hex",
+            lua.create_function(|_, body: mlua::String| Ok(sha1_hex(body.as_bytes())))?,
+        )?;
+        redis.raw_set("log", lua.create_function(log)?)?;
+        for (i, name) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+            .into_iter()
+            .enumerate()
+        {
+            redis.raw_set(name, i)?;
+        }
+        lua.globals().raw_set("redis", redis)?;
+        let pcall = lua.globals().raw_get::<_, Function>("pcall")?;
+        let pcall = lua.create_registry_value(pcall)?;
+        lua.load(PRELUDE).set_name("=prelude").exec()?;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS),
+            move |_, _| match kill.load(Ordering::Relaxed) {
+                true => Err(mlua::Error::RuntimeError(ERR_KILLED.into())),
+                false => Ok(()),
+            },
+        );
+        Ok(Interpreter {
+            lua,
+            scripts: HashMap::new(),
+            pcall,
+        })
+    }
+}
+
+/// `redis.log(level, message, ...)`
+fn log(_: &Lua, (level, words): (i64, Variadic<mlua::String>)) -> mlua::Result<()> {
+    let message = words
+        .iter()
+        .map(|w| w.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    match level {
+        0 => debug!("script: {}", message),
+        1 | 2 => info!("script: {}", message),
+        3 => warn!("script: {}", message),
+        _ => return Err(mlua::Error::RuntimeError("Invalid debug level.".into())),
+    }
+    Ok(())
+}
+
+impl Scripting {
+    /// Compile `body` unless it is known already, returns its SHA1.
+    pub(crate) fn load(&self, body: &[u8]) -> Result<String> {
+        let sha = sha1_hex(body);
+        let mut interpreter = self.interpreter.lock().unwrap();
+        let interpreter = &mut *interpreter;
+        if !interpreter.scripts.contains_key(&sha) {
+            let function = interpreter
+                .lua
+                .load(body)
+                .set_name("@user_script")
+                .into_function()
+                .map_err(|e| {
+                    anyhow!(
+                        "ERR Error compiling script (new function): {}",
+                        error_message(&e)
+                    )
+                })?;
+            let key = interpreter.lua.create_registry_value(function)?;
+            interpreter.scripts.insert(sha.clone(), key);
+        }
+        Ok(sha)
+    }
+
+    pub(crate) fn exists(&self, sha: &str) -> bool {
+        let interpreter = self.interpreter.lock().unwrap();
+        interpreter.scripts.contains_key(&sha.to_ascii_lowercase())
+    }
+
+    /// Forget every script.
+    pub(crate) fn flush(&self) {
+        let mut interpreter = self.interpreter.lock().unwrap();
+        for (_, key) in std::mem::take(&mut interpreter.scripts) {
+            let _ = interpreter.lua.remove_registry_value(key);
+        }
+    }
+
+    /// Run the script `sha` with `KEYS` and `ARGV`, the commands it calls
+    /// go to `call`. Fails with `NOSCRIPT` for an unknown script, errors of
+    /// the script itself are part of the reply.
+    pub(crate) fn run(
+        &self,
+        sha: &str,
+        keys: &[Bytes],
+        argv: &[Bytes],
+        call: impl FnMut(&[Bytes]) -> RespFrame,
+    ) -> Result<RespFrame> {
+        // the script may run up to the busy threshold and beyond
+        blocking(|| self.run_blocking(sha, keys, argv, call))
+    }
+
+    fn run_blocking(
+        &self,
+        sha: &str,
+        keys: &[Bytes],
+        argv: &[Bytes],
+        mut call: impl FnMut(&[Bytes]) -> RespFrame,
+    ) -> Result<RespFrame> {
+        let sha = sha.to_ascii_lowercase();
+        let interpreter = self.interpreter.lock().unwrap();
+        let Some(function) = interpreter.scripts.get(&sha) else {
+            bail!("NOSCRIPT No matching script. Please use EVAL.");
+        };
+        let lua = &interpreter.lua;
+        let _running = self.start();
+        let reply = lua.scope(|scope| {
+            let globals = lua.globals();
+            globals.raw_set("KEYS", strings(lua, keys)?)?;
+            globals.raw_set("ARGV", strings(lua, argv)?)?;
+            let pcall = scope.create_function_mut(|lua, args: Variadic<LuaValue>| {
+                let reply = match command_args(lua, args) {
+                    Some(args) => call(&args),
+                    None => RespFrame::error(
+                        "ERR Lua redis lib command arguments must be strings or integers",
+                    ),
+                };
+                resp_to_lua(lua, reply)
+            })?;
+            globals
+                .raw_get::<_, Table>("redis")?
+                .raw_set("pcall", pcall)?;
+            let runner = lua.registry_value::<Function>(&interpreter.pcall)?;
+            let function = lua.registry_value::<Function>(function)?;
+            let (ok, value) = runner.call::<_, (bool, LuaValue)>(function)?;
+            Ok(match ok {
+                _ if self.kill.load(Ordering::Relaxed) => RespFrame::error(ERR_KILLED),
+                true => lua_to_resp(&value),
+                false => script_error(&sha, &value),
+            })
+        });
+        Ok(reply.unwrap_or_else(|e| {
+            RespFrame::error(format!(
+                "ERR Error running script (call to f_{}): {}",
+                sha,
+                error_message(&e)
+            ))
+        }))
+    }
+
+    fn start(&self) -> RunningScript<'_> {
+        self.wrote.store(false, Ordering::Relaxed);
+        self.kill.store(false, Ordering::Relaxed);
+        *self.running.lock().unwrap() = Some(Instant::now());
+        RunningScript(self)
+    }
+
+    /// The running script called a write command.
+    pub(crate) fn mark_write(&self) {
+        self.wrote.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn wrote(&self) -> bool {
+        self.wrote.load(Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.lock().unwrap().is_some()
+    }
+
+    /// Whether a script runs for longer than `threshold` already.
+    fn is_busy(&self, threshold: Duration) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .is_some_and(|started| started.elapsed() >= threshold)
+    }
+}
+
+/// Marks a script as running until dropped.
+struct RunningScript<'a>(&'a Scripting);
+
+impl Drop for RunningScript<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() = None;
+        self.0.kill.store(false, Ordering::Relaxed);
+    }
+}
+
+fn strings<'lua>(lua: &'lua Lua, items: &[Bytes]) -> mlua::Result<Table<'lua>> {
+    lua.create_sequence_from(
+        items
+            .iter()
+            .map(|item| lua.create_string(item))
+            .collect::<mlua::Result<Vec<_>>>()?,
+    )
+}
+
+/// The arguments of `redis.call`, which must be strings or numbers.
+fn command_args(lua: &Lua, args: Variadic<LuaValue>) -> Option<Vec<Bytes>> {
+    args.into_iter()
+        .map(|arg| match arg {
+            LuaValue::String(_) | LuaValue::Integer(_) | LuaValue::Number(_) => lua
+                .coerce_string(arg)
+                .ok()
+                .flatten()
+                .map(|s| Bytes::copy_from_slice(s.as_bytes())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A reply as the Lua value a script sees, the RESP2 way: statuses and
+/// errors are tables with an `ok` or `err` field, nulls are `false`.
+pub(crate) fn resp_to_lua(lua: &Lua, frame: RespFrame) -> mlua::Result<LuaValue<'_>> {
+    let field = |name, value: String| -> mlua::Result<LuaValue> {
+        let table = lua.create_table()?;
+        table.raw_set(name, value)?;
+        Ok(LuaValue::Table(table))
+    };
+    let sequence = |items: Vec<RespFrame>| -> mlua::Result<LuaValue> {
+        let items = items
+            .into_iter()
+            .map(|item| resp_to_lua(lua, item))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        Ok(LuaValue::Table(lua.create_sequence_from(items)?))
+    };
+    Ok(match frame {
+        RespFrame::SimpleString(s) => field("ok", s)?,
+        RespFrame::Error(e) | RespFrame::BulkError(e) => field("err", e)?,
+        RespFrame::Integer(n) => LuaValue::Integer(n as mlua::Integer),
+        RespFrame::Boolean(b) => LuaValue::Integer(b as mlua::Integer),
+        RespFrame::BulkString(b) | RespFrame::VerbatimString(_, b) => {
+            LuaValue::String(lua.create_string(&b)?)
+        }
+        RespFrame::Double(d) => LuaValue::String(lua.create_string(format_double(d))?),
+        RespFrame::BigNumber(n) => LuaValue::String(lua.create_string(n)?),
+        RespFrame::NullBulkString | RespFrame::NullArray | RespFrame::Null => {
+            LuaValue::Boolean(false)
+        }
+        RespFrame::Array(items) | RespFrame::Set(items) | RespFrame::Push(items) => {
+            sequence(items)?
+        }
+        RespFrame::Map(pairs) => sequence(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect())?,
+        RespFrame::Attribute(_, frame) => resp_to_lua(lua, *frame)?,
+    })
+}
+
+/// What a script returned as its reply: numbers are truncated to integers,
+/// tables with an `ok` or `err` field are statuses and errors, other tables
+/// arrays up to their first nil. `false` and nil are nulls.
+pub(crate) fn lua_to_resp(value: &LuaValue) -> RespFrame {
+    match value {
+        LuaValue::Integer(n) => RespFrame::Integer(*n),
+        LuaValue::Number(n) => RespFrame::Integer(*n as i64),
+        LuaValue::String(s) => RespFrame::bulk(Bytes::copy_from_slice(s.as_bytes())),
+        LuaValue::Boolean(true) => RespFrame::Integer(1),
+        LuaValue::Table(table) => {
+            if let Ok(LuaValue::String(err)) = table.raw_get("err") {
+                return RespFrame::error(err.to_string_lossy());
+            }
+            if let Ok(LuaValue::String(ok)) = table.raw_get("ok") {
+                return RespFrame::simple(ok.to_string_lossy());
+            }
+            let items = (1..)
+                .map_while(|i| match table.raw_get::<_, LuaValue>(i) {
+                    Ok(LuaValue::Nil) | Err(_) => None,
+                    Ok(item) => Some(lua_to_resp(&item)),
+                })
+                .collect();
+            RespFrame::Array(items)
+        }
+        _ => RespFrame::NullBulkString,
+    }
+}
+
+/// The reply of a script that raised `value`: error tables are the error
+/// they hold, anything else is a runtime error of the script.
+fn script_error(sha: &str, value: &LuaValue) -> RespFrame {
+    let message = match value {
+        LuaValue::Table(table) => match table.raw_get("err") {
+            Ok(LuaValue::String(err)) => return RespFrame::error(err.to_string_lossy()),
+            _ => "the script raised a table without an err field".to_string(),
+        },
+        LuaValue::String(s) => s.to_string_lossy().into_owned(),
+        LuaValue::Error(e) => error_message(e),
+        other => format!("the script raised a {}", other.type_name()),
+    };
+    RespFrame::error(format!(
+        "ERR Error running script (call to f_{}): {}",
+        sha, message
+    ))
+}
+
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::SyntaxError { message, .. } | mlua::Error::RuntimeError(message) => {
+            message.clone()
+        }
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        e => e.to_string(),
+    }
+}
+
+/// Run `f`, which may take as long as a script, off the async workers: on a
+/// multi-threaded runtime the worker hands its other tasks to another thread
+/// first, so a running script can't keep them from `SCRIPT KILL` or `BUSY`.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => block_in_place(f),
+        _ => f(),
+    }
+}
+
+impl Backend {
+    /// Take the exec lock with `lock`, unless a script holds it for longer
+    /// than the busy threshold: fail with `BUSY` then instead of waiting.
+    pub(crate) fn lock_unless_busy<'a, G>(
+        &'a self,
+        try_lock: impl Fn(&'a RwLock<()>) -> TryLockResult<G>,
+        lock: impl FnOnce(&'a RwLock<()>) -> LockResult<G>,
+    ) -> Result<G> {
+        if !self.scripting.is_running() {
+            return Ok(lock(&self.exec_lock).unwrap());
+        }
+        blocking(|| loop {
+            if let Ok(guard) = try_lock(&self.exec_lock) {
+                return Ok(guard);
+            }
+            if self.scripting.is_busy(self.busy_reply_threshold()) {
+                bail!("BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.");
+            }
+            thread::sleep(BUSY_POLL);
+        })
+    }
+
+    /// Stop the running script, which fails with an error. One that wrote
+    /// already is only stopped when `force`d, it would leave half of its
+    /// writes behind.
+    pub fn kill_script(&self, force: bool) -> Result<()> {
+        if !self.scripting.is_running() {
+            bail!("NOTBUSY No scripts in execution right now.");
+        }
+        if self.scripting.wrote() && !force {
+            bail!("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.");
+        }
+        self.scripting.kill.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn busy_reply_threshold(&self) -> Duration {
+        Duration::from_millis(self.scripting.busy_threshold_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_busy_reply_threshold(&self, threshold: Duration) {
+        self.scripting
+            .busy_threshold_ms
+            .store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(scripting: &Scripting, body: &str, argv: &[&str]) -> RespFrame {
+        let sha = match scripting.load(body.as_bytes()) {
+            Ok(sha) => sha,
+            Err(e) => return RespFrame::error(e.to_string()),
+        };
+        let argv = argv
+            .iter()
+            .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+            .collect::<Vec<_>>();
+        // every command replies with its arguments
+        scripting
+            .run(&sha, &[], &argv, |args| {
+                RespFrame::array(
+                    args.iter()
+                        .cloned()
+                        .map(RespFrame::bulk)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sha1_hex() {
+        assert_eq!(
+            sha1_hex(b"return 1"),
+            "e0e1f9fabfc9d4800c877a703b823ac0578ff8db"
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        let s = Scripting::default();
+        assert_eq!(run(&s, "return 3.9", &[]), RespFrame::Integer(3));
+        assert_eq!(run(&s, "return 'x'", &[]), RespFrame::bulk("x"));
+        assert_eq!(run(&s, "return true", &[]), RespFrame::Integer(1));
+        assert_eq!(run(&s, "return false", &[]), RespFrame::NullBulkString);
+        assert_eq!(run(&s, "return nil", &[]), RespFrame::NullBulkString);
+        assert_eq!(
+            run(&s, "return {1, 'a', {2}, nil, 3}", &[]),
+            RespFrame::array([
+                RespFrame::Integer(1),
+                RespFrame::bulk("a"),
+                RespFrame::array([RespFrame::Integer(2)]),
+            ])
+        );
+        assert_eq!(
+            run(&s, "return redis.status_reply('FINE')", &[]),
+            RespFrame::simple("FINE")
+        );
+        assert_eq!(
+            run(&s, "return redis.error_reply('MY error')", &[]),
+            RespFrame::error("MY error")
+        );
+        assert_eq!(
+            run(&s, "return redis.call('echo', ARGV[1], 7)", &["a"]),
+            RespFrame::array([
+                RespFrame::bulk("echo"),
+                RespFrame::bulk("a"),
+                RespFrame::bulk("7")
+            ])
+        );
+        assert_eq!(
+            run(&s, "return redis.pcall('echo', {})", &[]),
+            RespFrame::error("ERR Lua redis lib command arguments must be strings or integers")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let s = Scripting::default();
+        let RespFrame::Error(e) = run(&s, "return +", &[]) else {
+            panic!("compiled")
+        };
+        assert!(e.starts_with("ERR Error compiling script (new function): user_script:1:"));
+        assert_eq!(
+            run(&s, "error('boom')", &[]),
+            RespFrame::error(format!(
+                "ERR Error running script (call to f_{}): user_script:1: boom",
+                sha1_hex(b"error('boom')")
+            ))
+        );
+        assert_eq!(
+            run(&s, "error({err = 'CUSTOM failure'})", &[]),
+            RespFrame::error("CUSTOM failure")
+        );
+        let RespFrame::Error(e) = run(&s, "x = 1", &[]) else {
+            panic!("created a global")
+        };
+        assert!(e.ends_with("Script attempted to create global variable 'x'"));
+        assert_eq!(
+            s.run("0000", &[], &[], |_| RespFrame::ok())
+                .unwrap_err()
+                .to_string(),
+            "NOSCRIPT No matching script. Please use EVAL."
+        );
+    }
+
+    #[test]
+    fn test_load_exists_flush() {
+        let s = Scripting::default();
+        let sha = s.load(b"return 1").unwrap();
+        assert!(s.exists(&sha) && s.exists(&sha.to_ascii_uppercase()));
+        s.flush();
+        assert!(!s.exists(&sha));
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{info, warn};
+
+use crate::{process_redis_conn, process_tls_conn, Backend, TlsListener};
+
+/// How long connections get to finish on shutdown, redis' `shutdown-timeout`.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the keyspace is saved to the RDB file on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownSave {
+    /// As configured with `Backend::set_save_on_shutdown`.
+    #[default]
+    Default,
+    Save,
+    NoSave,
+}
+
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    requested: watch::Sender<Option<ShutdownSave>>,
+    save_by_default: AtomicBool,
+    timeout: Mutex<Duration>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            requested: watch::channel(None).0,
+            save_by_default: AtomicBool::new(false),
+            timeout: Mutex::new(DEFAULT_SHUTDOWN_TIMEOUT),
+        }
+    }
+}
+
+impl Backend {
+    /// Whether a plain `SHUTDOWN`, SIGINT or SIGTERM saves the keyspace.
+    pub fn set_save_on_shutdown(&self, save: bool) {
+        self.shutdown.save_by_default.store(save, Ordering::Relaxed);
+    }
+
+    /// How long connections get to finish what they are doing on shutdown
+    /// before they are closed anyway.
+    pub fn set_shutdown_timeout(&self, timeout: Duration) {
+        *self.shutdown.timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        *self.shutdown.timeout.lock().unwrap()
+    }
+
+    /// Ask the server to stop, the first request decides about saving.
+    pub fn request_shutdown(&self, save: ShutdownSave) {
+        self.shutdown.requested.send_if_modified(|requested| {
+            let first = requested.is_none();
+            if first {
+                *requested = Some(save);
+            }
+            first
+        });
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.requested.borrow().is_some()
+    }
+
+    /// Resolves once a shutdown was requested.
+    pub async fn shutdown_requested(&self) -> ShutdownSave {
+        let mut requested = self.shutdown.requested.subscribe();
+        let save = *requested
+            .wait_for(Option::is_some)
+            .await
+            .expect("the backend keeps the sender");
+        save.unwrap_or_default()
+    }
+
+    /// What is left to do once no connection runs commands anymore: wait for
+    /// background saves, flush the AOF and save when asked to.
+    pub(crate) fn finish_shutdown(&self, save: ShutdownSave) -> Result<()> {
+        self.repl.stop_link();
+        if let Err(e) = self.wait_bgsave() {
+            warn!("background saving error: {:#}", e);
+        }
+        if let Err(e) = self.wait_aof_rewrite() {
+            warn!("background AOF rewrite error: {:#}", e);
+        }
+        self.aof.sync()?;
+        let save = match save {
+            ShutdownSave::Default => self.shutdown.save_by_default.load(Ordering::Relaxed),
+            ShutdownSave::Save => true,
+            ShutdownSave::NoSave => false,
+        };
+        if save {
+            info!("saving the final RDB snapshot before exiting");
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// Accept connections on `listener` until `signal` resolves or a client
+/// sends `SHUTDOWN`. Then stop accepting, let the connections finish the
+/// commands they have read within the shutdown timeout, close them and
+/// save. Returns once every connection task ended.
+pub async fn run_server(
+    listener: TcpListener,
+    backend: Backend,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    run_server_with_tls(listener, None, backend, signal).await
+}
+
+/// `run_server` that also accepts TLS clients on `tls`, like redis with
+/// both `port` and `tls-port` set.
+pub async fn run_server_with_tls(
+    listener: TcpListener,
+    tls: Option<TlsListener>,
+    backend: Backend,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    tokio::pin!(signal);
+    let mut conns = JoinSet::new();
+    let save = loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let Some((stream, client_addr)) = accepted(res) else {
+                    continue;
+                };
+                let backend = backend.clone();
+                conns.spawn(async move {
+                    let res = process_redis_conn(stream, client_addr, backend).await;
+                    log_conn_end(client_addr, res);
+                });
+            }
+            res = accept_tls(tls.as_ref()) => {
+                let Some((stream, client_addr)) = accepted(res) else {
+                    continue;
+                };
+                let acceptor = tls.as_ref().expect("accepted on it").acceptor.clone();
+                let backend = backend.clone();
+                conns.spawn(async move {
+                    let res = process_tls_conn(stream, client_addr, acceptor, backend).await;
+                    log_conn_end(client_addr, res);
+                });
+            }
+            // reap finished connections so the set does not grow
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            save = backend.shutdown_requested() => break save,
+            _ = &mut signal => break ShutdownSave::Default,
+        }
+    };
+    drop(listener);
+    drop(tls);
+    backend.request_shutdown(save);
+    info!("shutting down, waiting for {} connections", conns.len());
+
+    let timeout = *backend.shutdown.timeout.lock().unwrap();
+    let drain = async { while conns.join_next().await.is_some() {} };
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        warn!(
+            "{} connections still busy after {:?}, closing them",
+            conns.len(),
+            timeout
+        );
+        conns.shutdown().await;
+    }
+    backend.finish_shutdown(save)?;
+    info!("redis server is now ready to exit, bye bye");
+    Ok(())
+}
+
+fn accepted(res: io::Result<(TcpStream, SocketAddr)>) -> Option<(TcpStream, SocketAddr)> {
+    match res {
+        Ok((stream, client_addr)) => {
+            info!("redis client address: {}", client_addr);
+            Some((stream, client_addr))
+        }
+        Err(e) => {
+            warn!("accepting a connection failed: {}", e);
+            None
+        }
+    }
+}
+
+/// The next client of the TLS listener, never resolves without one.
+async fn accept_tls(tls: Option<&TlsListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match tls {
+        Some(tls) => tls.listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn log_conn_end(client_addr: SocketAddr, res: Result<()>) {
+    if let Err(e) = res {
+        warn!("Error processing conn with {}: {:?}", client_addr, e);
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("listening for SIGTERM failed: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("received SIGINT, scheduling shutdown"),
+            _ = terminate.recv() => info!("received SIGTERM, scheduling shutdown"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("received SIGINT, scheduling shutdown");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+        task::JoinHandle,
+    };
+
+    use super::*;
+    use crate::{decode_rdb, dredis::tls::tests::TestPki, execute, snapshot_len, Session};
+
+    async fn start(
+        backend: &Backend,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(std::net::SocketAddr, JoinHandle<Result<()>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(run_server(listener, backend.clone(), signal));
+        Ok((addr, server))
+    }
+
+    async fn request(stream: &mut TcpStream, req: &[u8], reply: &[u8]) -> Result<()> {
+        stream.write_all(req).await?;
+        let mut buf = vec![0u8; reply.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, reply);
+        Ok(())
+    }
+
+    async fn assert_closed(stream: &mut TcpStream) -> Result<()> {
+        let mut buf = [0u8; 64];
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections_and_saves() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("dredis-{}-shutdown.rdb", std::process::id()));
+        let backend = Backend::new();
+        backend.set_rdb_path(&path);
+        let (addr, server) = start(&backend, std::future::pending()).await?;
+
+        let mut writer = TcpStream::connect(addr).await?;
+        request(
+            &mut writer,
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+            b"+OK\r\n",
+        )
+        .await?;
+        let mut blocked = TcpStream::connect(addr).await?;
+        blocked
+            .write_all(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n")
+            .await?;
+        let mut subscriber = TcpStream::connect(addr).await?;
+        request(
+            &mut subscriber,
+            b"*2\r\n$9\r\nSUBSCRIBE\r\n$2\r\nch\r\n",
+            b"*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n",
+        )
+        .await?;
+
+        let mut admin = TcpStream::connect(addr).await?;
+        request(
+            &mut admin,
+            b"*2\r\n$8\r\nSHUTDOWN\r\n$4\r\nSAVE\r\n",
+            b"+OK\r\n",
+        )
+        .await?;
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+
+        for stream in [&mut writer, &mut blocked, &mut subscriber, &mut admin] {
+            assert_closed(stream).await?;
+        }
+        assert!(TcpStream::connect(addr).await.is_err());
+        // every connection task is gone along with its handle on the keyspace
+        assert_eq!(Arc::strong_count(&backend.dbs), 1);
+        let saved = decode_rdb(&std::fs::read(&path)?)?;
+        assert_eq!(snapshot_len(&saved), 1);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_next_to_plain() -> Result<()> {
+        let pki = TestPki::new("server-tls")?;
+        let backend = Backend::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let tls = TlsListener::new(
+            TcpListener::bind("127.0.0.1:0").await?,
+            &pki.server_config(),
+        )?;
+        let tls_addr = tls.local_addr()?;
+        let server = tokio::spawn(run_server_with_tls(
+            listener,
+            Some(tls),
+            backend.clone(),
+            std::future::pending(),
+        ));
+
+        let mut plain = TcpStream::connect(addr).await?;
+        request(
+            &mut plain,
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+            b"+OK\r\n",
+        )
+        .await?;
+        let mut secure = pki.connect(tls_addr, false).await?;
+        secure.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await?;
+        let mut reply = [0u8; 7];
+        secure.read_exact(&mut reply).await?;
+        assert_eq!(&reply, b"$1\r\nv\r\n");
+        assert_eq!(backend.connected_clients(), 2);
+
+        backend.request_shutdown(ShutdownSave::NoSave);
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        assert!(TcpStream::connect(tls_addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout_closes_busy_connections() -> Result<()> {
+        let backend = Backend::new();
+        backend.set_shutdown_timeout(Duration::from_millis(100));
+        let big = Bytes::from(vec![b'x'; 1 << 20]);
+        let mut s = Session::new(backend.clone());
+        execute(&mut s, &["SET".into(), "big".into(), big]);
+        let (tx, rx) = oneshot::channel::<()>();
+        let (addr, server) = start(&backend, async {
+            let _ = rx.await;
+        })
+        .await?;
+
+        // a client that never reads its replies keeps its connection busy
+        let mut slow = TcpStream::connect(addr).await?;
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n".repeat(64);
+        slow.write_all(&get).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        assert!(backend.is_shutting_down());
+        assert_eq!(Arc::strong_count(&backend.dbs), 2);
+        drop(s);
+        assert_eq!(Arc::strong_count(&backend.dbs), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,1306 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tracing::{info, warn};
+
+use crate::{
+    execute, frame_to_args, key_hash_slot, lookup_command, next_message, Backend, Entry,
+    RespDecoder, RespFrame, RespVersion, Session, ShutdownSave, ACTIVE_EXPIRE_INTERVAL,
+    DEFAULT_USER,
+};
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Commands about the AOF, replication and cluster mode, which a sharded
+/// keyspace doesn't have.
+const UNSHARDED: &[&str] = &[
+    "bgrewriteaof",
+    "replicaof",
+    "slaveof",
+    "replconf",
+    "psync",
+    "cluster",
+    "asking",
+];
+
+/// Commands about the connection or the server, they run on every shard
+/// so each has the same view of both.
+const BROADCAST: &[&str] = &[
+    "hello", "auth", "select", "flushdb", "flushall", "swapdb", "config", "acl",
+];
+
+/// The broadcast commands the connection itself needs to see too.
+const CONNECTION: &[&str] = &["hello", "auth", "select"];
+
+/// Commands about the connection rather than keys, the connection runs
+/// them itself: subscriptions, `CLIENT` and `SHUTDOWN`, which stops the
+/// server every shard is in.
+const LOCAL: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "client",
+    "shutdown",
+];
+
+/// How a command runs on the shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    /// On the shard of its first key, the first for keyless commands. Its
+    /// keys on other shards move there while it runs.
+    Keys,
+    /// On every shard, the replies merged.
+    All(Merge),
+    /// On the first shard, which runs the transactions and keeps the
+    /// watched keys.
+    Multi,
+    /// On the connection, no shard needs to know.
+    Local,
+    /// `SCAN`, one shard after the other.
+    Scan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Merge {
+    /// The reply of the first shard, or the first error.
+    First,
+    /// The integers added up.
+    Sum,
+    /// The arrays one after the other.
+    Concat,
+    /// The smallest integer.
+    Min,
+}
+
+/// Where `args` run, an error for the commands a sharded keyspace can't run.
+fn route(args: &[Bytes]) -> Result<Route, String> {
+    let Some(cmd) = args.first().and_then(|name| lookup_command(name)) else {
+        // the shard replies with the error
+        return Ok(Route::Keys);
+    };
+    if !cmd.check_arity(args.len()) {
+        return Ok(Route::Keys);
+    }
+    if UNSHARDED.contains(&cmd.name) {
+        return Err(format!(
+            "ERR '{}' is not supported with a sharded keyspace",
+            cmd.name
+        ));
+    }
+    if BROADCAST.contains(&cmd.name) {
+        return Ok(Route::All(Merge::First));
+    }
+    if LOCAL.contains(&cmd.name) {
+        return Ok(Route::Local);
+    }
+    Ok(match cmd.name {
+        "dbsize" => Route::All(Merge::Sum),
+        // each shard saves its keys to its own file
+        "save" | "bgsave" => Route::All(Merge::First),
+        "lastsave" => Route::All(Merge::Min),
+        "keys" => Route::All(Merge::Concat),
+        "multi" | "exec" | "discard" | "watch" | "unwatch" => Route::Multi,
+        "scan" => Route::Scan,
+        // it must not wait behind the script it stops
+        "script" if args[1].eq_ignore_ascii_case(b"kill") => Route::Local,
+        _ => Route::Keys,
+    })
+}
+
+/// The keys of `args`, none for commands that fail before they run.
+fn keys_of(args: &[Bytes]) -> Vec<Bytes> {
+    args.first()
+        .and_then(|name| lookup_command(name))
+        .filter(|cmd| cmd.check_arity(args.len()))
+        .map_or_else(Vec::new, |cmd| cmd.key_args(args).cloned().collect())
+}
+
+/// The shard of `key`, by its hash slot so keys with the same hash tag are
+/// on the same shard.
+fn shard_of(key: &[u8], shards: usize) -> usize {
+    key_hash_slot(key) as usize % shards
+}
+
+struct MsgInput {
+    conn: u64,
+    args: Vec<Bytes>,
+}
+
+impl MsgInput {
+    fn new(conn: u64, args: Vec<Bytes>) -> Self {
+        Self { conn, args }
+    }
+}
+
+struct MsgOutput {
+    reply: RespFrame,
+    /// The protocol of the connection on the shard, which `HELLO` changes.
+    protocol: RespVersion,
+}
+
+impl MsgOutput {
+    fn new(reply: RespFrame, protocol: RespVersion) -> Self {
+        Self { reply, protocol }
+    }
+}
+
+struct Msg {
+    input: MsgInput,
+    sender: oneshot::Sender<MsgOutput>,
+}
+
+impl Msg {
+    fn new(input: MsgInput, sender: oneshot::Sender<MsgOutput>) -> Self {
+        Self { input, sender }
+    }
+}
+
+/// A step of a command spanning shards, run with the session of the
+/// connection on the shard.
+type Op = Box<dyn FnOnce(&mut Session) + Send>;
+
+/// What a shard worker gets over its channel.
+enum Work {
+    Run(Msg),
+    /// Run nothing but the steps of the connection until it lets go.
+    Lock {
+        conn: u64,
+        ops: mpsc::Receiver<Op>,
+        locked: oneshot::Sender<()>,
+    },
+    /// The connection is gone, the shard forgets about it.
+    Close(u64),
+    /// The server stops, the shard finishes like a single keyspace does.
+    Shutdown(ShutdownSave, oneshot::Sender<Result<()>>),
+}
+
+/// The keyspace split into shards, each owned by a worker thread that runs
+/// the commands on its keys. Connections forward the commands to the
+/// workers over channels and wait for the replies, so no two threads ever
+/// touch the same keys. A command with keys on several shards locks them
+/// and runs on one with the others' keys moved in.
+#[derive(Debug, Clone)]
+pub struct ShardedBackend {
+    senders: Arc<Vec<mpsc::Sender<Work>>>,
+    /// The first shard, every shard shares what is not about keys with it:
+    /// subscriptions, watched keys, scripts and clients.
+    shared: Backend,
+}
+
+impl ShardedBackend {
+    /// A worker for each backend, a shard of the keyspace each. The
+    /// workers stop once every handle on the sharded backend is gone.
+    pub fn new(mut backends: Vec<Backend>) -> Self {
+        assert!(!backends.is_empty(), "a sharded keyspace has a shard");
+        let shared = backends[0].clone();
+        for backend in &mut backends[1..] {
+            backend.pubsub = shared.pubsub.clone();
+            backend.watches = shared.watches.clone();
+            backend.scripting = shared.scripting.clone();
+            backend.clients = shared.clients.clone();
+        }
+        let senders = backends
+            .into_iter()
+            .enumerate()
+            .map(|(i, backend)| {
+                let (tx, rx) = mpsc::channel::<Work>();
+                thread::Builder::new()
+                    .name(format!("dredis-shard-{}", i))
+                    .spawn(move || run_shard(backend, rx))
+                    .expect("spawning a shard worker");
+                tx
+            })
+            .collect();
+        ShardedBackend {
+            senders: Arc::new(senders),
+            shared,
+        }
+    }
+
+    /// `shards` shards with the default configuration.
+    pub fn with_shards(shards: usize) -> Self {
+        Self::new((0..shards).map(|_| Backend::new()).collect())
+    }
+
+    pub fn shards(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// A new connection to the sharded keyspace.
+    pub fn connect(&self) -> ShardedSession {
+        ShardedSession {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: RespVersion::default(),
+            shards: self.clone(),
+            local: client_session(&self.shared),
+            multi: None,
+        }
+    }
+
+    /// What is left once no connection runs commands anymore, on every
+    /// shard: each saves its keys to its own file when asked to.
+    async fn finish_shutdown(&self, save: ShutdownSave) -> Result<()> {
+        let mut done = Vec::new();
+        for sender in self.senders.iter() {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(Work::Shutdown(save, tx))
+                .map_err(|_| anyhow!("shard worker is gone"))?;
+            done.push(rx);
+        }
+        for rx in done {
+            rx.await.map_err(|_| anyhow!("shard worker is gone"))??;
+        }
+        Ok(())
+    }
+}
+
+/// Load the RDB file of every backend, a shard each, then move the keys to
+/// their shard: they are elsewhere when the files were saved with fewer
+/// shards. Returns the number of keys loaded.
+pub fn load_shards(backends: &[Backend]) -> Result<usize> {
+    let mut loaded = 0;
+    for backend in backends {
+        loaded += backend.load_rdb()?;
+    }
+    for (shard, backend) in backends.iter().enumerate() {
+        for (index, db) in backend.all_dbs() {
+            let strays = db
+                .data
+                .iter()
+                .map(|e| e.key().clone())
+                .filter(|key| shard_of(key, backends.len()) != shard)
+                .collect::<Vec<_>>();
+            let from = backend.select(index);
+            for key in strays {
+                if let Some(entry) = from.take_entry(&key) {
+                    let home = &backends[shard_of(&key, backends.len())];
+                    home.select(index).put_entry(&key, entry);
+                }
+            }
+        }
+    }
+    Ok(loaded)
+}
+
+/// Run the commands sent to a shard until every sender is gone, and expire
+/// its keys in between like the active expiry of a single keyspace.
+fn run_shard(backend: Backend, rx: mpsc::Receiver<Work>) {
+    let mut sessions = HashMap::new();
+    let mut next_expire = Instant::now() + ACTIVE_EXPIRE_INTERVAL;
+    loop {
+        // a busy shard expires keys all the same
+        if Instant::now() >= next_expire {
+            backend.active_expire_cycle();
+            next_expire = Instant::now() + ACTIVE_EXPIRE_INTERVAL;
+        }
+        let work = match rx.recv_timeout(next_expire.saturating_duration_since(Instant::now())) {
+            Ok(work) => work,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match work {
+            Work::Run(msg) => {
+                let session = sessions
+                    .entry(msg.input.conn)
+                    .or_insert_with(|| client_session(&backend));
+                // the connection may be gone already
+                let _ = msg.sender.send(run_on(session, &msg.input.args));
+            }
+            Work::Lock { conn, ops, locked } => {
+                let session = sessions
+                    .entry(conn)
+                    .or_insert_with(|| client_session(&backend));
+                if locked.send(()).is_ok() {
+                    for op in ops {
+                        op(session);
+                    }
+                }
+            }
+            Work::Close(conn) => {
+                sessions.remove(&conn);
+            }
+            Work::Shutdown(save, done) => {
+                let _ = done.send(backend.finish_shutdown(save));
+            }
+        }
+    }
+}
+
+/// A session on a shard with the rights of a client connection.
+fn client_session(backend: &Backend) -> Session {
+    let mut session = Session::new(backend.clone());
+    session.user = Some(DEFAULT_USER.to_string());
+    session.authenticated = backend.default_user_nopass();
+    session
+}
+
+/// Run `args` with the session of a connection on a shard.
+fn run_on(session: &mut Session, args: &[Bytes]) -> MsgOutput {
+    let reply = execute(session, args);
+    session.pushes.clear();
+    MsgOutput::new(reply, session.protocol)
+}
+
+/// Shards locked by one connection, they run nothing but its steps until
+/// it lets go by dropping them.
+struct Locked {
+    ops: BTreeMap<usize, mpsc::Sender<Op>>,
+}
+
+impl Locked {
+    /// Lock `shards` in ascending order, so connections locking some of the
+    /// same shards never wait for each other in a circle.
+    fn new(senders: &[mpsc::Sender<Work>], conn: u64, shards: BTreeSet<usize>) -> Option<Self> {
+        let mut ops = BTreeMap::new();
+        for shard in shards {
+            let (tx, rx) = mpsc::channel();
+            let (locked, is_locked) = oneshot::channel();
+            let lock = Work::Lock {
+                conn,
+                ops: rx,
+                locked,
+            };
+            senders[shard].send(lock).ok()?;
+            is_locked.recv().ok()?;
+            ops.insert(shard, tx);
+        }
+        Some(Locked { ops })
+    }
+
+    /// Start `f` on `shard`, its result comes on the receiver.
+    fn start<R: Send + 'static>(
+        &self,
+        shard: usize,
+        f: impl FnOnce(&mut Session) -> R + Send + 'static,
+    ) -> Option<oneshot::Receiver<R>> {
+        let (tx, rx) = oneshot::channel();
+        let op: Op = Box::new(move |session| {
+            let _ = tx.send(f(session));
+        });
+        self.ops.get(&shard)?.send(op).ok()?;
+        Some(rx)
+    }
+
+    fn run<R: Send + 'static>(
+        &self,
+        shard: usize,
+        f: impl FnOnce(&mut Session) -> R + Send + 'static,
+    ) -> Option<R> {
+        self.start(shard, f)?.recv().ok()
+    }
+
+    /// Run `args` on `home` as if every key lived there: the `keys` of
+    /// other shards, by shard, move to it first and back after.
+    fn hop(
+        &self,
+        home: usize,
+        keys: BTreeMap<usize, BTreeSet<Bytes>>,
+        args: Vec<Bytes>,
+    ) -> Option<MsgOutput> {
+        let mut moved = Vec::new();
+        for (&shard, keys) in &keys {
+            let keys = keys.clone();
+            moved.extend(self.run(shard, move |s| take_entries(&s.backend, keys))?);
+        }
+        self.run(home, move |s| put_entries(&s.backend, moved))?;
+        let output = self.run(home, move |s| run_on(s, &args))?;
+        for (shard, keys) in keys {
+            let back = self.run(home, move |s| take_entries(&s.backend, keys))?;
+            self.run(shard, move |s| put_entries(&s.backend, back))?;
+        }
+        Some(output)
+    }
+}
+
+fn take_entries(backend: &Backend, keys: BTreeSet<Bytes>) -> Vec<(Bytes, Option<Entry>)> {
+    keys.into_iter()
+        .map(|key| {
+            let entry = backend.take_entry(&key);
+            (key, entry)
+        })
+        .collect()
+}
+
+fn put_entries(backend: &Backend, entries: Vec<(Bytes, Option<Entry>)>) {
+    for (key, entry) in entries {
+        if let Some(entry) = entry {
+            backend.put_entry(&key, entry);
+        }
+    }
+}
+
+/// A transaction being queued: the commands go to the first shard, their
+/// keys are gathered there for `EXEC`.
+#[derive(Debug, Default)]
+struct Multi {
+    keys: Vec<Bytes>,
+    /// A command can't be queued, `EXEC` discards the transaction.
+    aborted: bool,
+}
+
+/// A connection to a sharded keyspace: its commands go to the shards with
+/// their keys. Each shard keeps the state of the connection, like the
+/// selected database, which the commands about it change on all of them.
+#[derive(Debug)]
+pub struct ShardedSession {
+    id: u64,
+    pub protocol: RespVersion,
+    shards: ShardedBackend,
+    /// Runs the commands about the connection itself, on the first shard's
+    /// backend without touching its keys.
+    local: Session,
+    multi: Option<Multi>,
+}
+
+impl ShardedSession {
+    /// Run one command on the shards it needs. A command with keys on
+    /// several shards runs atomically, one that finds nothing and would
+    /// block is refused.
+    pub async fn execute(&mut self, args: &[Bytes]) -> RespFrame {
+        // only subscription commands are left, the connection has them all
+        if self.local.in_subscribed_mode() {
+            return execute(&mut self.local, args);
+        }
+        let route = match route(args) {
+            Ok(route) => route,
+            Err(e) => return self.refuse(e),
+        };
+        if route == Route::Multi {
+            return self.transaction(args).await;
+        }
+        if self.multi.is_some() {
+            return self.queue(route, args).await;
+        }
+        match route {
+            Route::Keys => {
+                let keys = keys_of(args);
+                let home = keys
+                    .first()
+                    .map_or(0, |key| shard_of(key, self.shards.shards()));
+                let reply = self.run_with_keys(home, keys, args.to_vec()).await;
+                // it found nothing and would wait, which no shard can while
+                // the others go on
+                match lookup_command(&args[0]) {
+                    Some(cmd) if reply.is_null() && cmd.blocks(args) => RespFrame::error(format!(
+                        "ERR '{}' can't block with a sharded keyspace",
+                        cmd.name
+                    )),
+                    _ => reply,
+                }
+            }
+            Route::All(merge) => {
+                let reply = self.run_on_all(merge, args.to_vec()).await;
+                if CONNECTION
+                    .iter()
+                    .any(|name| args[0].eq_ignore_ascii_case(name.as_bytes()))
+                {
+                    execute(&mut self.local, args);
+                }
+                reply
+            }
+            Route::Multi => unreachable!("transactions are handled above"),
+            Route::Local => execute(&mut self.local, args),
+            Route::Scan => self.scan(args).await,
+        }
+    }
+
+    /// Reply with `e`, a transaction being queued is discarded on `EXEC`.
+    fn refuse(&mut self, e: String) -> RespFrame {
+        if let Some(multi) = self.multi.as_mut() {
+            multi.aborted = true;
+        }
+        RespFrame::error(e)
+    }
+
+    /// `MULTI`, `EXEC`, `DISCARD`, `WATCH` and `UNWATCH` on the first shard.
+    /// `EXEC` runs there with the keys of the queued commands moved in.
+    async fn transaction(&mut self, args: &[Bytes]) -> RespFrame {
+        let exec = args[0].eq_ignore_ascii_case(b"exec");
+        let reply = match self.multi.take() {
+            Some(multi) if exec && multi.aborted => {
+                let output = self.send(0, vec![Bytes::from("DISCARD")]);
+                self.receive(output).await;
+                return RespFrame::error(
+                    "EXECABORT Transaction discarded because of previous errors.",
+                );
+            }
+            Some(multi) if exec => return self.run_with_keys(0, multi.keys, args.to_vec()).await,
+            multi => {
+                self.multi = multi;
+                let output = self.send(0, args.to_vec());
+                self.receive(output).await
+            }
+        };
+        if args[0].eq_ignore_ascii_case(b"multi") && reply == RespFrame::ok() {
+            self.multi = Some(Multi::default());
+        } else if args[0].eq_ignore_ascii_case(b"discard") {
+            self.multi = None;
+        }
+        reply
+    }
+
+    /// Queue a command of a transaction on the first shard.
+    async fn queue(&mut self, route: Route, args: &[Bytes]) -> RespFrame {
+        if route != Route::Keys {
+            let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+            return self.refuse(format!(
+                "ERR '{}' can't be queued with a sharded keyspace",
+                name
+            ));
+        }
+        let output = self.send(0, args.to_vec());
+        let reply = self.receive(output).await;
+        if let (Some(multi), RespFrame::SimpleString(queued)) = (self.multi.as_mut(), &reply) {
+            if queued == "QUEUED" {
+                multi.keys.extend(keys_of(args));
+            }
+        }
+        reply
+    }
+
+    /// Run `args` on `home` with `keys`, those on other shards move there
+    /// while it runs.
+    async fn run_with_keys(
+        &mut self,
+        home: usize,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    ) -> RespFrame {
+        let shards = self.shards.shards();
+        let mut foreign = BTreeMap::<usize, BTreeSet<Bytes>>::new();
+        for key in keys {
+            let shard = shard_of(&key, shards);
+            if shard != home {
+                foreign.entry(shard).or_default().insert(key);
+            }
+        }
+        if foreign.is_empty() {
+            let output = self.send(home, args);
+            return self.receive(output).await;
+        }
+        let (senders, conn) = (self.shards.senders.clone(), self.id);
+        // a blocking task runs to the end even if the connection goes away,
+        // the keys always make it back
+        let output = tokio::task::spawn_blocking(move || {
+            let mut locking = foreign.keys().copied().collect::<BTreeSet<_>>();
+            locking.insert(home);
+            let locked = Locked::new(&senders, conn, locking)?;
+            locked.hop(home, foreign, args)
+        })
+        .await;
+        self.output(output.ok().flatten())
+    }
+
+    /// Run `args` on every shard while they are all locked, so they all
+    /// run it at the same point.
+    async fn run_on_all(&mut self, merge: Merge, args: Vec<Bytes>) -> RespFrame {
+        let (senders, conn) = (self.shards.senders.clone(), self.id);
+        let outputs = tokio::task::spawn_blocking(move || {
+            let locked = Locked::new(&senders, conn, (0..senders.len()).collect())?;
+            let started = (0..senders.len())
+                .map(|shard| {
+                    let args = args.clone();
+                    locked.start(shard, move |s| run_on(s, &args))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            started
+                .into_iter()
+                .map(|output| output.recv().ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .await;
+        let Some(outputs) = outputs.ok().flatten() else {
+            return RespFrame::error("ERR shard worker is gone");
+        };
+        let replies = outputs
+            .into_iter()
+            .map(|output| {
+                self.protocol = output.protocol;
+                output.reply
+            })
+            .collect();
+        merge_replies(merge, replies)
+    }
+
+    /// `SCAN` the shards one after the other. The cursor has the shard in
+    /// its low bits and the shard's cursor rounded down in the others, a key
+    /// may come twice then, which `SCAN` allows.
+    async fn scan(&mut self, args: &[Bytes]) -> RespFrame {
+        let shards = self.shards.shards() as u64;
+        let Some(cursor) = std::str::from_utf8(&args[1])
+            .ok()
+            .and_then(|c| c.parse::<u64>().ok())
+        else {
+            // the shard replies with the error
+            let output = self.send(0, args.to_vec());
+            return self.receive(output).await;
+        };
+        let mask = shards.next_power_of_two() - 1;
+        let shard = (cursor & mask) % shards;
+        let mut part = args.to_vec();
+        part[1] = Bytes::from((cursor & !mask).to_string());
+        let output = self.send(shard as usize, part);
+        let mut reply = self.receive(output).await;
+        if let RespFrame::Array(items) = &mut reply {
+            let next = match &items[0] {
+                RespFrame::BulkString(c) => {
+                    std::str::from_utf8(c).ok().and_then(|c| c.parse().ok())
+                }
+                _ => None,
+            };
+            let next = match next.unwrap_or(0) {
+                0 if shard + 1 < shards => shard + 1,
+                0 => 0,
+                next => next & !mask | shard,
+            };
+            items[0] = RespFrame::bulk(next.to_string());
+        }
+        reply
+    }
+
+    /// Send a command to a worker, the reply comes on the receiver.
+    fn send(&self, shard: usize, args: Vec<Bytes>) -> Option<oneshot::Receiver<MsgOutput>> {
+        let (tx, rx) = oneshot::channel();
+        let msg = Msg::new(MsgInput::new(self.id, args), tx);
+        self.shards.senders[shard].send(Work::Run(msg)).ok()?;
+        Some(rx)
+    }
+
+    async fn receive(&mut self, output: Option<oneshot::Receiver<MsgOutput>>) -> RespFrame {
+        let output = match output {
+            Some(rx) => rx.await.ok(),
+            None => None,
+        };
+        self.output(output)
+    }
+
+    fn output(&mut self, output: Option<MsgOutput>) -> RespFrame {
+        match output {
+            Some(output) => {
+                self.protocol = output.protocol;
+                output.reply
+            }
+            None => RespFrame::error("ERR shard worker is gone"),
+        }
+    }
+}
+
+impl Drop for ShardedSession {
+    fn drop(&mut self) {
+        for sender in self.shards.senders.iter() {
+            let _ = sender.send(Work::Close(self.id));
+        }
+    }
+}
+
+fn merge_replies(merge: Merge, replies: Vec<RespFrame>) -> RespFrame {
+    if let Some(error) = replies.iter().find(|r| matches!(r, RespFrame::Error(_))) {
+        return error.clone();
+    }
+    match merge {
+        Merge::First => replies.into_iter().next().unwrap_or(RespFrame::Null),
+        Merge::Sum => RespFrame::Integer(
+            replies
+                .iter()
+                .map(|r| match r {
+                    RespFrame::Integer(n) => *n,
+                    _ => 0,
+                })
+                .sum(),
+        ),
+        Merge::Concat => RespFrame::Array(
+            replies
+                .into_iter()
+                .flat_map(|r| match r {
+                    RespFrame::Array(items) => items,
+                    _ => Vec::new(),
+                })
+                .collect(),
+        ),
+        Merge::Min => replies
+            .into_iter()
+            .filter(|r| matches!(r, RespFrame::Integer(_)))
+            .min_by_key(|r| match r {
+                RespFrame::Integer(n) => *n,
+                _ => 0,
+            })
+            .unwrap_or(RespFrame::Null),
+    }
+}
+
+/// Accept connections to the sharded keyspace on `listener` until `signal`
+/// resolves or a client sends `SHUTDOWN`, then close them and let every
+/// shard save its keys to its RDB file.
+pub async fn run_sharded_server(
+    listener: TcpListener,
+    shards: ShardedBackend,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    tokio::pin!(signal);
+    let mut conns = JoinSet::new();
+    let save = loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, client_addr) = match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("accepting a connection failed: {}", e);
+                        continue;
+                    }
+                };
+                info!("redis client address: {}", client_addr);
+                let session = shards.connect();
+                conns.spawn(async move {
+                    if let Err(e) = serve_sharded_client(stream, client_addr, session).await {
+                        warn!("Error processing conn with {}: {:?}", client_addr, e);
+                    }
+                });
+            }
+            // reap finished connections so the set does not grow
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            save = shards.shared.shutdown_requested() => break save,
+            _ = &mut signal => break ShutdownSave::Default,
+        }
+    };
+    drop(listener);
+    shards.shared.request_shutdown(save);
+    info!("shutting down, closing {} connections", conns.len());
+    conns.shutdown().await;
+    shards.finish_shutdown(save).await?;
+    info!("redis server is now ready to exit, bye bye");
+    Ok(())
+}
+
+async fn serve_sharded_client(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    mut session: ShardedSession,
+) -> Result<()> {
+    session.local.addr = Some(client_addr);
+    let client = session.local.client.clone();
+    let shared = &session.shards.shared;
+    let Some(_slot) = shared.register_client(&client, client_addr, stream.local_addr()?) else {
+        warn!("max number of clients reached, rejecting {}", client_addr);
+        stream
+            .write_all(b"-ERR max number of clients reached\r\n")
+            .await?;
+        return Ok(());
+    };
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut decoder = RespDecoder::default();
+    loop {
+        let read = tokio::select! {
+            res = stream.read_buf(&mut buf) => res?,
+            message = next_message(&mut session.local) => {
+                // no more messages once the client overran its output buffer
+                let Some(message) = message else {
+                    break;
+                };
+                stream.write_all(&message.to_bytes_with(session.protocol)).await?;
+                continue;
+            }
+            _ = client.killed() => {
+                info!("client {} killed", client_addr);
+                break;
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        let mut out = BytesMut::new();
+        while let Some(frame) = decoder
+            .decode(&mut buf)
+            .map_err(|e| anyhow!("protocol error from {}: {}", client_addr, e))?
+        {
+            let (reply, quit) = match frame_to_args(frame) {
+                Ok(args)
+                    if args
+                        .first()
+                        .is_some_and(|a| a.eq_ignore_ascii_case(b"quit")) =>
+                {
+                    (RespFrame::ok(), true)
+                }
+                Ok(args) => (session.execute(&args).await, false),
+                Err(e) => (RespFrame::error(format!("ERR {}", e)), false),
+            };
+            for push in session.local.pushes.drain(..) {
+                push.encode_with(session.protocol, &mut out);
+            }
+            reply.encode_with(session.protocol, &mut out);
+            client.update(&session.local);
+            if quit || session.local.quit {
+                stream.write_all(&out).await?;
+                return Ok(());
+            }
+        }
+        stream.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &[&str]) -> Vec<Bytes> {
+        cmd.iter().map(|a| Bytes::from(a.to_string())).collect()
+    }
+
+    async fn run(session: &mut ShardedSession, cmd: &[&str]) -> RespFrame {
+        session.execute(&args(cmd)).await
+    }
+
+    #[test]
+    fn test_route() {
+        let route = |cmd: &[&str]| route(&args(cmd));
+        assert_eq!(route(&["GET", "a"]), Ok(Route::Keys));
+        assert_eq!(route(&["PING"]), Ok(Route::Keys));
+        assert_eq!(route(&["NOSUCH", "a"]), Ok(Route::Keys));
+        assert_eq!(route(&["GET"]), Ok(Route::Keys));
+        assert_eq!(route(&["MGET", "a", "b"]), Ok(Route::Keys));
+        assert_eq!(
+            route(&["LMOVE", "a", "b", "LEFT", "RIGHT"]),
+            Ok(Route::Keys)
+        );
+        assert_eq!(route(&["SELECT", "1"]), Ok(Route::All(Merge::First)));
+        assert_eq!(route(&["DBSIZE"]), Ok(Route::All(Merge::Sum)));
+        assert_eq!(route(&["MULTI"]), Ok(Route::Multi));
+        assert_eq!(route(&["WATCH", "a"]), Ok(Route::Multi));
+        assert_eq!(route(&["SUBSCRIBE", "c"]), Ok(Route::Local));
+        assert_eq!(route(&["CLIENT", "ID"]), Ok(Route::Local));
+        assert_eq!(route(&["SCRIPT", "KILL"]), Ok(Route::Local));
+        assert_eq!(route(&["SCRIPT", "FLUSH"]), Ok(Route::Keys));
+        assert_eq!(route(&["SCAN", "0"]), Ok(Route::Scan));
+        assert_eq!(route(&["SAVE"]), Ok(Route::All(Merge::First)));
+        assert_eq!(route(&["LASTSAVE"]), Ok(Route::All(Merge::Min)));
+        assert_eq!(route(&["SHUTDOWN", "NOSAVE"]), Ok(Route::Local));
+        assert_eq!(
+            route(&["REPLICAOF", "NO", "ONE"]),
+            Err("ERR 'replicaof' is not supported with a sharded keyspace".into())
+        );
+        for cmd in [
+            &["BLPOP", "a", "0"][..],
+            &["BLMOVE", "a", "b", "LEFT", "RIGHT", "0"],
+            &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"],
+        ] {
+            assert_eq!(route(cmd), Ok(Route::Keys));
+        }
+    }
+
+    /// Whether every key of `backends` is on its own shard.
+    fn keys_at_home(backends: &[Backend]) -> bool {
+        backends.iter().enumerate().all(|(i, backend)| {
+            let db = backend.db();
+            let keys = db.data.iter().map(|e| e.key().clone()).collect::<Vec<_>>();
+            keys.iter().all(|key| shard_of(key, backends.len()) == i)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_commands_across_shards() {
+        let backends = (0..4).map(|_| Backend::new()).collect::<Vec<_>>();
+        let shards = ShardedBackend::new(backends.clone());
+        let mut s = shards.connect();
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for key in keys {
+            assert_eq!(run(&mut s, &["SET", key, key]).await, RespFrame::ok());
+        }
+        // every key is on its shard only
+        for (i, backend) in backends.iter().enumerate() {
+            for key in keys {
+                let here = backend.peek(key.as_bytes(), |e| e.is_some());
+                assert_eq!(here, shard_of(key.as_bytes(), 4) == i);
+            }
+        }
+        assert!(backends.iter().filter(|b| !b.db().data.is_empty()).count() > 1);
+
+        assert_eq!(run(&mut s, &["DBSIZE"]).await, RespFrame::Integer(8));
+        assert_eq!(
+            run(&mut s, &["MGET", "h", "nope", "a", "d"]).await,
+            RespFrame::array([
+                RespFrame::bulk("h"),
+                RespFrame::NullBulkString,
+                RespFrame::bulk("a"),
+                RespFrame::bulk("d"),
+            ])
+        );
+        assert_eq!(
+            run(&mut s, &["MSET", "a", "1", "b", "2", "x", "3"]).await,
+            RespFrame::ok()
+        );
+        assert_eq!(run(&mut s, &["GET", "b"]).await, RespFrame::bulk("2"));
+        assert_eq!(
+            run(&mut s, &["DEL", "a", "b", "x", "nope"]).await,
+            RespFrame::Integer(3)
+        );
+        let RespFrame::Array(mut all) = run(&mut s, &["KEYS", "*"]).await else {
+            panic!("KEYS should reply with an array");
+        };
+        all.sort_by_key(|k| format!("{:?}", k));
+        assert_eq!(
+            all,
+            ["c", "d", "e", "f", "g", "h"].map(RespFrame::bulk).to_vec()
+        );
+        assert_eq!(
+            run(&mut s, &["MSET", "a"]).await,
+            RespFrame::error("ERR wrong number of arguments for 'mset' command")
+        );
+
+        // keys of other shards join the first key's for the command
+        assert_ne!(shard_of(b"a", 4), shard_of(b"b", 4));
+        run(&mut s, &["RPUSH", "a", "x", "y"]).await;
+        assert_eq!(
+            run(&mut s, &["LMOVE", "a", "b", "LEFT", "RIGHT"]).await,
+            RespFrame::bulk("x")
+        );
+        assert_eq!(
+            run(&mut s, &["LRANGE", "b", "0", "-1"]).await,
+            RespFrame::array([RespFrame::bulk("x")])
+        );
+        assert_eq!(
+            run(&mut s, &["LMOVE", "b", "a", "RIGHT", "RIGHT"]).await,
+            RespFrame::bulk("x")
+        );
+        assert_eq!(
+            run(&mut s, &["LRANGE", "a", "0", "-1"]).await,
+            RespFrame::array([RespFrame::bulk("y"), RespFrame::bulk("x")])
+        );
+        assert!(keys_at_home(&backends));
+
+        // a blocking command runs, it fails only when it would wait
+        assert_eq!(
+            run(&mut s, &["BLPOP", "nope", "a", "0"]).await,
+            RespFrame::array([RespFrame::bulk("a"), RespFrame::bulk("y")])
+        );
+        assert_eq!(
+            run(&mut s, &["BLMOVE", "a", "b", "LEFT", "RIGHT", "0"]).await,
+            RespFrame::bulk("x")
+        );
+        assert_eq!(run(&mut s, &["LPOP", "b"]).await, RespFrame::bulk("x"));
+        for cmd in [
+            &["BLPOP", "a", "nope", "0"][..],
+            &["BLMOVE", "a", "b", "LEFT", "RIGHT", "0.1"],
+        ] {
+            let name = cmd[0].to_ascii_lowercase();
+            assert_eq!(
+                run(&mut s, cmd).await,
+                RespFrame::error(format!(
+                    "ERR '{}' can't block with a sharded keyspace",
+                    name
+                ))
+            );
+        }
+        run(&mut s, &["XADD", "s", "1-1", "f", "v"]).await;
+        assert!(matches!(
+            run(&mut s, &["XREAD", "BLOCK", "0", "STREAMS", "s", "0"]).await,
+            RespFrame::Array(_)
+        ));
+        assert!(matches!(
+            run(&mut s, &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]).await,
+            RespFrame::Error(_)
+        ));
+        assert_eq!(run(&mut s, &["EXISTS", "b"]).await, RespFrame::Integer(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_commands_across_shards_are_atomic() {
+        let shards = ShardedBackend::with_shards(4);
+        let mut writer = shards.connect();
+        let mut reader = shards.connect();
+        let writes = tokio::spawn(async move {
+            for i in 0..200 {
+                let i = i.to_string();
+                run(&mut writer, &["MSET", "a", &i, "b", &i, "c", &i]).await;
+            }
+        });
+        while !writes.is_finished() {
+            let RespFrame::Array(values) = run(&mut reader, &["MGET", "a", "b", "c"]).await else {
+                panic!("MGET should reply with an array");
+            };
+            assert!(values.iter().all(|v| *v == values[0]), "{:?}", values);
+        }
+        writes.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transactions_across_shards() {
+        let backends = (0..4).map(|_| Backend::new()).collect::<Vec<_>>();
+        let shards = ShardedBackend::new(backends.clone());
+        let mut s = shards.connect();
+        let mut other = shards.connect();
+        assert_eq!(run(&mut s, &["MULTI"]).await, RespFrame::ok());
+        for cmd in [&["SET", "a", "1"][..], &["INCR", "b"], &["MGET", "a", "b"]] {
+            assert_eq!(run(&mut s, cmd).await, RespFrame::simple("QUEUED"));
+        }
+        assert_eq!(
+            run(&mut s, &["EXEC"]).await,
+            RespFrame::array([
+                RespFrame::ok(),
+                RespFrame::Integer(1),
+                RespFrame::array([RespFrame::bulk("1"), RespFrame::bulk("1")]),
+            ])
+        );
+        assert!(keys_at_home(&backends));
+
+        // a watched key changed on its shard fails the transaction
+        run(&mut s, &["WATCH", "a", "c"]).await;
+        run(&mut other, &["SET", "c", "x"]).await;
+        run(&mut s, &["MULTI"]).await;
+        run(&mut s, &["SET", "a", "2"]).await;
+        assert_eq!(run(&mut s, &["EXEC"]).await, RespFrame::NullArray);
+        assert_eq!(run(&mut s, &["GET", "a"]).await, RespFrame::bulk("1"));
+
+        // what needs every shard can't be queued
+        run(&mut s, &["MULTI"]).await;
+        run(&mut s, &["SET", "a", "3"]).await;
+        assert_eq!(
+            run(&mut s, &["FLUSHALL"]).await,
+            RespFrame::error("ERR 'flushall' can't be queued with a sharded keyspace")
+        );
+        assert_eq!(
+            run(&mut s, &["EXEC"]).await,
+            RespFrame::error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        assert_eq!(run(&mut s, &["GET", "a"]).await, RespFrame::bulk("1"));
+        assert_eq!(
+            run(&mut s, &["EXEC"]).await,
+            RespFrame::error("ERR EXEC without MULTI")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_pubsub_and_scripts_across_shards() {
+        let shards = ShardedBackend::with_shards(3);
+        let mut s = shards.connect();
+        let keys = (0..20).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+        for key in &keys {
+            run(&mut s, &["SET", key, "v"]).await;
+        }
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        loop {
+            let RespFrame::Array(reply) = run(&mut s, &["SCAN", &cursor, "COUNT", "4"]).await
+            else {
+                panic!("SCAN should reply with an array");
+            };
+            let [RespFrame::BulkString(next), RespFrame::Array(found)] = &reply[..] else {
+                panic!("SCAN should reply with a cursor and keys");
+            };
+            seen.extend(found.iter().cloned());
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        seen.sort_by_key(|k| format!("{:?}", k));
+        seen.dedup();
+        let mut expected = keys
+            .iter()
+            .map(|k| RespFrame::bulk(k.clone()))
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|k| format!("{:?}", k));
+        assert_eq!(seen, expected);
+
+        // subscriptions hear from every shard
+        let mut subscriber = shards.connect();
+        run(&mut s, &["CONFIG", "SET", "notify-keyspace-events", "K$"]).await;
+        run(&mut subscriber, &["PSUBSCRIBE", "__keyspace@0__:*"]).await;
+        assert_eq!(
+            run(&mut subscriber, &["GET", "k1"]).await,
+            RespFrame::error(
+                "ERR Can't execute 'get': only SUBSCRIBE / PSUBSCRIBE / UNSUBSCRIBE / PUNSUBSCRIBE / PING / QUIT are allowed in this context"
+            )
+        );
+        for key in ["k1", "k2", "k3"] {
+            run(&mut s, &["SET", key, "w"]).await;
+            let Some(RespFrame::Push(message)) = next_message(&mut subscriber.local).await else {
+                panic!("expected a keyspace event");
+            };
+            assert_eq!(
+                message[2],
+                RespFrame::bulk(format!("__keyspace@0__:{}", key))
+            );
+        }
+        assert_eq!(
+            run(&mut s, &["PUBLISH", "c", "m"]).await,
+            RespFrame::Integer(0)
+        );
+
+        // scripts are known on every shard, their keys join the first one
+        let script = "return redis.call('MGET', KEYS[1], KEYS[2])";
+        let RespFrame::BulkString(sha) = run(&mut s, &["SCRIPT", "LOAD", script]).await else {
+            panic!("SCRIPT LOAD should reply with the sha");
+        };
+        let sha = String::from_utf8(sha.to_vec()).unwrap();
+        assert_eq!(
+            run(&mut s, &["EVALSHA", &sha, "2", "k1", "k5"]).await,
+            RespFrame::array([RespFrame::bulk("w"), RespFrame::bulk("v")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_on_every_shard() {
+        let shards = ShardedBackend::with_shards(3);
+        let mut s = shards.connect();
+        let mut other = shards.connect();
+        run(&mut s, &["SELECT", "1"]).await;
+        for key in ["a", "b", "c", "d"] {
+            run(&mut s, &["SET", key, "v"]).await;
+        }
+        assert_eq!(run(&mut s, &["DBSIZE"]).await, RespFrame::Integer(4));
+        assert_eq!(run(&mut other, &["DBSIZE"]).await, RespFrame::Integer(0));
+        assert_eq!(
+            run(&mut other, &["GET", "a"]).await,
+            RespFrame::NullBulkString
+        );
+
+        assert_eq!(s.protocol, RespVersion::Resp2);
+        assert!(matches!(
+            run(&mut s, &["HELLO", "3"]).await,
+            RespFrame::Map(_)
+        ));
+        assert_eq!(s.protocol, RespVersion::Resp3);
+
+        run(&mut s, &["FLUSHALL"]).await;
+        assert_eq!(run(&mut s, &["DBSIZE"]).await, RespFrame::Integer(0));
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("dredis-{}-{}-{}", std::process::id(), n, name))
+    }
+
+    #[tokio::test]
+    async fn test_shards_save_and_load() -> Result<()> {
+        let paths = (0..3)
+            .map(|i| temp_path(&format!("shard{}.rdb", i)))
+            .collect::<Vec<_>>();
+        let with_files = |n: usize| {
+            let backends = (0..n).map(|_| Backend::new()).collect::<Vec<_>>();
+            for (backend, path) in backends.iter().zip(&paths) {
+                backend.set_rdb_path(path.clone());
+            }
+            backends
+        };
+        let shards = ShardedBackend::new(with_files(2));
+        let mut s = shards.connect();
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for key in keys {
+            run(&mut s, &["SET", key, key]).await;
+        }
+        run(&mut s, &["SELECT", "1"]).await;
+        run(&mut s, &["SET", "z", "z"]).await;
+        assert_eq!(run(&mut s, &["LASTSAVE"]).await, RespFrame::Integer(0));
+        assert_eq!(run(&mut s, &["SAVE"]).await, RespFrame::ok());
+        assert!(matches!(
+            run(&mut s, &["LASTSAVE"]).await,
+            RespFrame::Integer(n) if n > 0
+        ));
+        assert!(paths[0].exists() && paths[1].exists());
+
+        // three shards load what two saved, every key on its shard
+        let backends = with_files(3);
+        assert_eq!(load_shards(&backends)?, 9);
+        assert!(keys_at_home(&backends));
+        let shards = ShardedBackend::new(backends);
+        let mut s = shards.connect();
+        assert_eq!(run(&mut s, &["DBSIZE"]).await, RespFrame::Integer(8));
+        run(&mut s, &["SELECT", "1"]).await;
+        assert_eq!(run(&mut s, &["GET", "z"]).await, RespFrame::bulk("z"));
+        for path in &paths {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sharded_server_saves_on_shutdown() -> Result<()> {
+        let paths = [temp_path("shutdown0.rdb"), temp_path("shutdown1.rdb")];
+        let backends = paths
+            .iter()
+            .map(|path| {
+                let backend = Backend::new();
+                backend.set_rdb_path(path.clone());
+                backend.set_save_on_shutdown(true);
+                backend
+            })
+            .collect::<Vec<_>>();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(run_sharded_server(
+            listener,
+            ShardedBackend::new(backends.clone()),
+            std::future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n")
+            .await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+OK\r\n");
+        stream.write_all(b"*1\r\n$8\r\nSHUTDOWN\r\n").await?;
+        server.await??;
+
+        let loaded = paths
+            .iter()
+            .map(|path| {
+                let backend = Backend::new();
+                backend.set_rdb_path(path.clone());
+                backend
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(load_shards(&loaded)?, 2);
+        for path in &paths {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sharded_server() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_sharded_server(
+            listener,
+            ShardedBackend::with_shards(2),
+            async {
+                let _ = rx.await;
+            },
+        ));
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .await?;
+        let reply = b"+OK\r\n*2\r\n$1\r\n1\r\n$-1\r\n";
+        let mut buf = vec![0u8; reply.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, reply);
+
+        tx.send(()).unwrap();
+        server.await??;
+        Ok(())
+    }
+}
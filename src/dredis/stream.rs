@@ -0,0 +1,508 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use bytes::Bytes;
+
+/// The ID of a stream entry: a unix time in milliseconds and a sequence
+/// number telling apart the entries of the same millisecond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId::new(0, 0);
+    pub const MAX: StreamId = StreamId::new(u64::MAX, u64::MAX);
+
+    pub const fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    /// The ID right after this one, `None` after the last possible one.
+    pub fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| StreamId::new(ms, 0)),
+        }
+    }
+
+    /// The ID right before this one, `None` before 0-0.
+    pub fn prev(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_sub(1).map(|ms| StreamId::new(ms, u64::MAX)),
+        }
+    }
+
+    /// Parse `ms-seq`, or `ms` alone which gets `seq` as sequence number.
+    pub fn parse(s: &[u8], seq: u64) -> Option<Self> {
+        let s = std::str::from_utf8(s).ok()?;
+        let number = |n: &str| match n.bytes().all(|b| b.is_ascii_digit()) {
+            true => n.parse::<u64>().ok(),
+            false => None,
+        };
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(number(ms)?, number(seq)?)),
+            None => Some(StreamId::new(number(s)?, seq)),
+        }
+    }
+
+    pub fn to_bytes(self) -> Bytes {
+        self.to_string().into()
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The fields and values of an entry, in the order they were added.
+pub type StreamFields = Vec<(Bytes, Bytes)>;
+
+/// How `XADD` and `XTRIM` cut the oldest entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trim {
+    /// Keep at most this many entries.
+    MaxLen(usize),
+    /// Drop the entries with a smaller ID.
+    MinId(StreamId),
+}
+
+/// An append-only log of entries ordered by ID, with the consumer groups
+/// reading it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    /// The greatest ID ever added, entries deleted since included.
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    /// Number of entries ever added.
+    entries_added: u64,
+    groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// What `XSETID` and loading a stream set, unchecked.
+    pub fn set_meta(&mut self, last_id: StreamId, max_deleted_id: StreamId, entries_added: u64) {
+        self.last_id = last_id;
+        self.max_deleted_id = max_deleted_id;
+        self.entries_added = entries_added;
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        self.entries.iter()
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&StreamFields> {
+        self.entries.get(&id)
+    }
+
+    pub fn first(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.first_key_value()
+    }
+
+    pub fn last(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.last_key_value()
+    }
+
+    /// The ID of an entry added at `now_ms` with `XADD *`, `None` once the
+    /// stream used up every ID.
+    pub fn next_id(&self, now_ms: u64) -> Option<StreamId> {
+        if now_ms > self.last_id.ms {
+            Some(StreamId::new(now_ms, 0))
+        } else {
+            self.last_id.next()
+        }
+    }
+
+    /// The ID of an entry added with `XADD ms-*`, `None` when it would not
+    /// be greater than the last ID.
+    pub fn next_seq(&self, ms: u64) -> Option<StreamId> {
+        match ms.cmp(&self.last_id.ms) {
+            std::cmp::Ordering::Greater => Some(StreamId::new(ms, 0)),
+            std::cmp::Ordering::Equal => self
+                .last_id
+                .seq
+                .checked_add(1)
+                .map(|s| StreamId::new(ms, s)),
+            std::cmp::Ordering::Less => None,
+        }
+        .filter(|&id| id > StreamId::MIN)
+    }
+
+    /// Append an entry, `id` must be greater than the last ID.
+    pub fn add(&mut self, id: StreamId, fields: StreamFields) {
+        debug_assert!(id > self.last_id || (id == StreamId::MIN && self.entries_added == 0));
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Delete an entry, consumer groups keep it pending until acknowledged.
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let removed = self.entries.remove(&id).is_some();
+        if removed {
+            self.max_deleted_id = self.max_deleted_id.max(id);
+        }
+        removed
+    }
+
+    /// Delete the oldest entries as `trim` says, no more than `limit` of
+    /// them. Returns how many went.
+    pub fn trim(&mut self, trim: Trim, limit: Option<usize>) -> usize {
+        let mut removed = 0;
+        while limit.is_none_or(|limit| removed < limit) {
+            let Some((&id, _)) = self.entries.first_key_value() else {
+                break;
+            };
+            let done = match trim {
+                Trim::MaxLen(max) => self.entries.len() <= max,
+                Trim::MinId(min) => id >= min,
+            };
+            if done {
+                break;
+            }
+            self.remove(id);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// The entries from `start` to `end` included, in ID order.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        (start <= end)
+            .then(|| self.entries.range(start..=end))
+            .into_iter()
+            .flatten()
+    }
+
+    /// The entries with an ID greater than `id`.
+    pub fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &StreamFields)> {
+        id.next()
+            .into_iter()
+            .flat_map(|start| self.entries.range(start..))
+    }
+
+    /// Whether entries after `id` were deleted, leaving the counters unable to
+    /// tell how many entries follow it.
+    fn has_tombstones_after(&self, id: StreamId) -> bool {
+        match self.first() {
+            Some((&first, _)) => {
+                self.max_deleted_id != StreamId::MIN
+                    && first <= self.max_deleted_id
+                    && id <= self.max_deleted_id
+            }
+            None => false,
+        }
+    }
+
+    /// The number of entries added up to `id` included, when the counters
+    /// tell: at the ends of the stream, or anywhere when it has no holes.
+    pub fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 || (self.is_empty() && id <= self.last_id) {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&self.last_id) {
+            std::cmp::Ordering::Equal => return Some(self.entries_added),
+            std::cmp::Ordering::Greater => return None,
+            std::cmp::Ordering::Less => {}
+        }
+        let first = self.first().map_or(StreamId::MIN, |(&id, _)| id);
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first {
+            let before_first = self.entries_added - self.len() as u64;
+            match id.cmp(&first) {
+                std::cmp::Ordering::Less => return Some(before_first),
+                std::cmp::Ordering::Equal => return Some(before_first + 1),
+                std::cmp::Ordering::Greater => {}
+            }
+        }
+        None
+    }
+
+    /// How many entries a group has yet to read, when the counters tell.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones_after(group.last_delivered) => read,
+            _ => self.entries_read_at(group.last_delivered)?,
+        };
+        Some(self.entries_added.saturating_sub(read))
+    }
+
+    /// Note that group `name` delivered the entry `id`, after the ones it
+    /// delivered before, keeping its read counter.
+    pub fn advance_group(&mut self, name: &[u8], id: StreamId) {
+        let tombstones = self.has_tombstones_after(id);
+        let estimate = self.entries_read_at(id);
+        let added = self.entries_added;
+        let Some(group) = self.groups.get_mut(name) else {
+            return;
+        };
+        group.last_delivered = id;
+        group.entries_read = match group.entries_read {
+            Some(read) if !tombstones => Some(read + 1),
+            _ if added > 0 => estimate,
+            read => read,
+        };
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&Bytes, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Add a group that delivers the entries after `last_delivered`, returns
+    /// whether it is new.
+    pub fn create_group(
+        &mut self,
+        name: Bytes,
+        last_delivered: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let group = ConsumerGroup {
+            last_delivered,
+            entries_read,
+            ..Default::default()
+        };
+        self.groups.insert(name, group);
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+}
+
+/// A consumer group: the last entry it delivered and the entries delivered
+/// but not acknowledged yet, each pending for one of its consumers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    /// Number of entries the group read, when known.
+    pub entries_read: Option<u64>,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Bytes, Consumer>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Consumer {
+    /// Unix time in milliseconds of the last attempt to read or claim.
+    pub seen_time: u64,
+    /// Unix time in milliseconds of the last successful read or claim.
+    pub active_time: Option<u64>,
+    pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    pub fn pending(&self) -> &BTreeSet<StreamId> {
+        &self.pending
+    }
+}
+
+impl ConsumerGroup {
+    pub fn consumers(&self) -> impl Iterator<Item = (&Bytes, &Consumer)> {
+        self.consumers.iter()
+    }
+
+    pub fn consumer(&self, name: &[u8]) -> Option<&Consumer> {
+        self.consumers.get(name)
+    }
+
+    /// Add a consumer, returns whether it is new.
+    pub fn create_consumer(&mut self, name: &Bytes, now_ms: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        let consumer = Consumer {
+            seen_time: now_ms,
+            ..Default::default()
+        };
+        self.consumers.insert(name.clone(), consumer);
+        true
+    }
+
+    /// Note that `name` tried to read or claim, creating it if needed.
+    pub fn seen(&mut self, name: &Bytes, now_ms: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.clone()).or_default();
+        consumer.seen_time = now_ms;
+        consumer
+    }
+
+    /// Delete a consumer with its pending entries, returns how many it had.
+    pub fn delete_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    /// Make `id` pending for `consumer` with the delivery time and count
+    /// given, whoever it was pending for before.
+    pub fn claim(
+        &mut self,
+        id: StreamId,
+        consumer: &Bytes,
+        delivery_time: u64,
+        delivery_count: u64,
+    ) {
+        if let Some(old) = self.pending.get(&id) {
+            if let Some(owner) = self.consumers.get_mut(&old.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        let owner = self.consumers.entry(consumer.clone()).or_default();
+        owner.pending.insert(id);
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.clone(),
+                delivery_time,
+                delivery_count,
+            },
+        );
+    }
+
+    /// Acknowledge `id`, returns whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+            owner.pending.remove(&id);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(value: &str) -> StreamFields {
+        vec![("f".into(), Bytes::copy_from_slice(value.as_bytes()))]
+    }
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(
+            StreamId::parse(b"5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        assert_eq!(StreamId::parse(b"5-", 0), None);
+        assert_eq!(StreamId::parse(b"-5", 0), None);
+        assert_eq!(StreamId::parse(b"+5", 0), None);
+        assert_eq!(StreamId::new(5, u64::MAX).next(), Some(StreamId::new(6, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(6, 0).prev(), Some(StreamId::new(5, u64::MAX)));
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::new(1, 2).to_string(), "1-2");
+    }
+
+    #[test]
+    fn test_add_and_trim() {
+        let mut stream = Stream::default();
+        assert_eq!(stream.next_id(100), Some(StreamId::new(100, 0)));
+        assert_eq!(stream.next_seq(0), Some(StreamId::new(0, 1)));
+        for i in 0..10 {
+            let id = stream.next_id(100).unwrap();
+            stream.add(id, fields(&i.to_string()));
+        }
+        // the clock went back, the IDs still grow
+        assert_eq!(stream.last_id(), StreamId::new(100, 9));
+        assert_eq!(stream.next_id(50), Some(StreamId::new(100, 10)));
+        assert_eq!(stream.next_seq(99), None);
+
+        assert_eq!(stream.trim(Trim::MaxLen(8), None), 2);
+        assert_eq!(stream.trim(Trim::MinId(StreamId::new(100, 5)), Some(1)), 1);
+        assert_eq!(stream.len(), 7);
+        assert_eq!(stream.max_deleted_id(), StreamId::new(100, 2));
+        assert_eq!(stream.entries_added(), 10);
+
+        let ids = stream
+            .range(StreamId::new(100, 4), StreamId::new(100, 6))
+            .map(|(id, _)| id.seq)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [4, 5, 6]);
+        assert_eq!(stream.range(StreamId::MAX, StreamId::MIN).count(), 0);
+        assert_eq!(stream.after(StreamId::new(100, 7)).count(), 2);
+        assert_eq!(stream.after(StreamId::MAX).count(), 0);
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let mut stream = Stream::default();
+        stream.add(StreamId::new(1, 0), fields("a"));
+        stream.add(StreamId::new(2, 0), fields("b"));
+        assert!(stream.create_group("g".into(), StreamId::MIN, None));
+        assert!(!stream.create_group("g".into(), StreamId::MIN, None));
+
+        let group = stream.group_mut(b"g").unwrap();
+        let (alice, bob) = (Bytes::from("alice"), Bytes::from("bob"));
+        group.claim(StreamId::new(1, 0), &alice, 10, 1);
+        group.claim(StreamId::new(2, 0), &alice, 10, 1);
+        group.claim(StreamId::new(2, 0), &bob, 20, 2);
+        assert_eq!(group.consumer(b"alice").unwrap().pending().len(), 1);
+        assert_eq!(group.pending()[&StreamId::new(2, 0)].consumer, bob);
+
+        assert!(group.ack(StreamId::new(1, 0)));
+        assert!(!group.ack(StreamId::new(1, 0)));
+        assert_eq!(group.delete_consumer(b"bob"), Some(1));
+        assert!(group.pending().is_empty());
+        assert!(stream.destroy_group(b"g"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::warn;
+
+use crate::{serve_client, Backend};
+
+/// How long a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate the TLS listener presents and how it checks clients,
+/// redis' `tls-cert-file`, `tls-key-file`, `tls-ca-cert-file` and
+/// `tls-auth-clients`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// The CA client certificates are verified with, without one clients
+    /// are not asked for a certificate.
+    pub ca_cert_file: Option<PathBuf>,
+    /// With a CA, whether clients without a certificate are refused.
+    pub auth_clients: bool,
+}
+
+impl TlsConfig {
+    pub fn new(cert_file: impl Into<PathBuf>, key_file: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            ca_cert_file: None,
+            auth_clients: true,
+        }
+    }
+
+    /// Verify client certificates against the CA in `ca_cert_file`,
+    /// `required` refuses clients that have none.
+    pub fn with_client_ca(mut self, ca_cert_file: impl Into<PathBuf>, required: bool) -> Self {
+        self.ca_cert_file = Some(ca_cert_file.into());
+        self.auth_clients = required;
+        self
+    }
+
+    /// The acceptor for TLS connections, reads the PEM files.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.ca_cert_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_file)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider);
+                let verifier = if self.auth_clients {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config =
+            builder.with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("reading the private key from {}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key in {}", path.display()))
+}
+
+/// A listener whose clients talk TLS, served next to the plain one by
+/// `run_server_with_tls`.
+pub struct TlsListener {
+    pub(crate) listener: TcpListener,
+    pub(crate) acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: &TlsConfig) -> Result<Self> {
+        Ok(TlsListener {
+            listener,
+            acceptor: config.acceptor()?,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+/// Complete the TLS handshake and serve the client like a plain one.
+pub(crate) async fn process_tls_conn(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    backend: Backend,
+) -> Result<()> {
+    let local_addr = stream.local_addr()?;
+    let stream = tokio::select! {
+        res = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => match res {
+            Ok(stream) => stream?,
+            Err(_) => {
+                warn!("TLS handshake with {} timed out", client_addr);
+                return Ok(());
+            }
+        },
+        _ = backend.shutdown_requested() => return Ok(()),
+    };
+    serve_client(stream, client_addr, local_addr, backend).await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig},
+        TlsConnector,
+    };
+
+    use super::*;
+
+    /// A CA, a server certificate for localhost and a client certificate,
+    /// written to PEM files in a fresh directory.
+    pub(crate) struct TestPki {
+        dir: PathBuf,
+        ca: CertifiedKey,
+    }
+
+    impl TestPki {
+        pub(crate) fn new(name: &str) -> Result<Self> {
+            let dir = std::env::temp_dir().join(format!("dredis-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir)?;
+            let key_pair = KeyPair::generate()?;
+            let mut params = CertificateParams::new(Vec::<String>::new())?;
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key_pair)?;
+            std::fs::write(dir.join("ca.crt"), cert.pem())?;
+            let pki = TestPki {
+                dir,
+                ca: CertifiedKey { cert, key_pair },
+            };
+            pki.issue("server", "localhost")?;
+            pki.issue("client", "client")?;
+            Ok(pki)
+        }
+
+        fn issue(&self, name: &str, subject: &str) -> Result<()> {
+            let key_pair = KeyPair::generate()?;
+            let cert = CertificateParams::new(vec![subject.to_string()])?.signed_by(
+                &key_pair,
+                &self.ca.cert,
+                &self.ca.key_pair,
+            )?;
+            std::fs::write(self.path(&format!("{}.crt", name)), cert.pem())?;
+            std::fs::write(
+                self.path(&format!("{}.key", name)),
+                key_pair.serialize_pem(),
+            )?;
+            Ok(())
+        }
+
+        pub(crate) fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+
+        pub(crate) fn server_config(&self) -> TlsConfig {
+            TlsConfig::new(self.path("server.crt"), self.path("server.key"))
+        }
+
+        /// Connect to `addr` trusting the CA, with the client certificate
+        /// when `with_cert`.
+        pub(crate) async fn connect(
+            &self,
+            addr: SocketAddr,
+            with_cert: bool,
+        ) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&self.path("ca.crt"))? {
+                roots.add(cert)?;
+            }
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots);
+            let config = if with_cert {
+                builder.with_client_auth_cert(
+                    load_certs(&self.path("client.crt"))?,
+                    load_key(&self.path("client.key"))?,
+                )?
+            } else {
+                builder.with_no_client_auth()
+            };
+            let stream = TcpStream::connect(addr).await?;
+            let connector = TlsConnector::from(Arc::new(config));
+            Ok(connector
+                .connect(ServerName::try_from("localhost")?, stream)
+                .await?)
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn serve_one(backend: &Backend, config: &TlsConfig) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = config.acceptor()?;
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await?;
+            process_tls_conn(stream, client_addr, acceptor, backend).await
+        });
+        Ok(addr)
+    }
+
+    async fn ping<S>(stream: &mut S) -> Result<Vec<u8>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+        let mut buf = vec![0u8; 7];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_tls_client() -> Result<()> {
+        let pki = TestPki::new("tls-client")?;
+        let backend = Backend::new();
+        let addr = serve_one(&backend, &pki.server_config()).await?;
+        let mut client = pki.connect(addr, false).await?;
+        assert_eq!(ping(&mut client).await?, b"+PONG\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() -> Result<()> {
+        let pki = TestPki::new("tls-client-auth")?;
+        let backend = Backend::new();
+        let required = pki.server_config().with_client_ca(pki.path("ca.crt"), true);
+        let addr = serve_one(&backend, &required).await?;
+        let mut client = pki.connect(addr, true).await?;
+        assert_eq!(ping(&mut client).await?, b"+PONG\r\n");
+
+        // with TLS 1.3 the refusal shows once the client reads
+        let addr = serve_one(&backend, &required).await?;
+        let refused = async {
+            let mut client = pki.connect(addr, false).await?;
+            ping(&mut client).await
+        };
+        assert!(refused.await.is_err());
+
+        let optional = pki
+            .server_config()
+            .with_client_ca(pki.path("ca.crt"), false);
+        let addr = serve_one(&backend, &optional).await?;
+        let mut client = pki.connect(addr, false).await?;
+        assert_eq!(ping(&mut client).await?, b"+PONG\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_files() -> Result<()> {
+        let pki = TestPki::new("tls-bad-files")?;
+        let missing = TlsConfig::new(pki.path("nope.crt"), pki.path("server.key"));
+        assert!(missing.acceptor().is_err());
+        let swapped = TlsConfig::new(pki.path("server.key"), pki.path("server.crt"));
+        assert!(swapped.acceptor().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use bytes::Bytes;
+
+/// A score ordered with `f64::total_cmp` so it can live in a `BTreeSet`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Sorted set: members ordered by score, then by member bytes like redis.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl ZSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Insert or update `member`, returns whether it is a new member.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        // -0.0 and 0.0 are the same score
+        let score = if score == 0.0 { 0.0 } else { score };
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove_entry(member) {
+            Some((member, score)) => {
+                self.ordered.remove(&(Score(score), member));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Members in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(s, m)| (m, s.0))
+    }
+
+    /// Members whose score lies within `min` and `max`, in ascending order.
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.iter().filter(move |(_, score)| {
+            let above = match min {
+                Bound::Included(min) => *score >= min,
+                Bound::Excluded(min) => *score > min,
+                Bound::Unbounded => true,
+            };
+            let below = match max {
+                Bound::Included(max) => *score <= max,
+                Bound::Excluded(max) => *score < max,
+                Bound::Unbounded => true,
+            };
+            above && below
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zset_order_and_update() {
+        let mut zset = ZSet::default();
+        assert!(zset.insert("b".into(), 1.0));
+        assert!(zset.insert("a".into(), 1.0));
+        assert!(zset.insert("c".into(), -2.5));
+        assert!(!zset.insert("c".into(), 3.0));
+        let members = zset.iter().map(|(m, s)| (m.clone(), s)).collect::<Vec<_>>();
+        assert_eq!(
+            members,
+            vec![("a".into(), 1.0), ("b".into(), 1.0), ("c".into(), 3.0)]
+        );
+        assert!(zset.remove(b"a"));
+        assert!(!zset.remove(b"a"));
+        assert_eq!(zset.len(), 2);
+        assert_eq!(zset.score(b"c"), Some(3.0));
+    }
+
+    #[test]
+    fn test_zset_range_by_score() {
+        let mut zset = ZSet::default();
+        for (i, m) in ["a", "b", "c", "d"].iter().enumerate() {
+            zset.insert(Bytes::from(*m), i as f64);
+        }
+        let range = zset
+            .range_by_score(Bound::Excluded(0.0), Bound::Included(2.0))
+            .map(|(m, _)| m.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(range, vec![Bytes::from("b"), Bytes::from("c")]);
+        assert_eq!(
+            zset.range_by_score(Bound::Unbounded, Bound::Unbounded)
+                .count(),
+            4
+        );
+    }
+}
@@ -3,6 +3,7 @@ mod matrix;
 mod metrics;
 mod vector;
 
+pub use dredis::*;
 pub use matrix::*;
 pub use metrics::*;
 pub use vector::*;